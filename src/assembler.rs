@@ -0,0 +1,173 @@
+use crate::pipeline::{piece_blocks, Block, BLOCK_LEN};
+use crate::torrent::Piece;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// What adding a block did to its piece.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Assembled {
+    /// More blocks are needed.
+    Incomplete,
+    /// The block was already received; nothing changed.
+    Duplicate,
+    /// The piece is complete and matches its hash.
+    Verified(Vec<u8>),
+    /// The piece is complete but its hash doesn't match. Its blocks have
+    /// been discarded so they can be requested again; the data is handed
+    /// back to find the bad blocks once the piece verifies.
+    HashMismatch(Vec<u8>),
+}
+
+/// A block that can't belong to its piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadBlock(pub Block);
+
+impl fmt::Display for BadBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "block at {} of piece {} with {} bytes is not a requestable block",
+            self.0.begin, self.0.piece, self.0.length
+        )
+    }
+}
+
+impl std::error::Error for BadBlock {}
+
+#[derive(Debug)]
+struct PartialPiece {
+    data: Vec<u8>,
+    /// Offsets of the blocks received so far.
+    received: BTreeSet<u32>,
+}
+
+/// Collects blocks into pieces and checks each finished piece against its
+/// SHA-1 before handing it out. Nothing unverified ever leaves here.
+#[derive(Debug, Default)]
+pub struct PieceAssembler {
+    partial: HashMap<u32, PartialPiece>,
+}
+
+impl PieceAssembler {
+    pub fn new() -> Self {
+        PieceAssembler::default()
+    }
+
+    /// Adds the data of one block of `piece`, starting at `begin`. Blocks
+    /// must line up with the ones `piece_blocks` requests.
+    pub fn add_block(
+        &mut self,
+        piece: &Piece,
+        begin: u32,
+        data: &[u8],
+    ) -> Result<Assembled, BadBlock> {
+        let block = Block {
+            piece: piece.index as u32,
+            begin,
+            length: data.len() as u32,
+        };
+        let expected = (piece.length.saturating_sub(begin as u64)).min(BLOCK_LEN as u64);
+        if !begin.is_multiple_of(BLOCK_LEN)
+            || begin as u64 >= piece.length
+            || data.len() as u64 != expected
+        {
+            return Err(BadBlock(block));
+        }
+
+        let partial = self
+            .partial
+            .entry(block.piece)
+            .or_insert_with(|| PartialPiece {
+                data: vec![0; piece.length as usize],
+                received: BTreeSet::new(),
+            });
+        if !partial.received.insert(begin) {
+            return Ok(Assembled::Duplicate);
+        }
+        partial.data[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+
+        if partial.received.len() < piece.length.div_ceil(BLOCK_LEN as u64) as usize {
+            return Ok(Assembled::Incomplete);
+        }
+
+        let partial = self.partial.remove(&block.piece).expect("piece is partial");
+        if Sha1::digest(&partial.data)[..] == piece.hash[..] {
+            Ok(Assembled::Verified(partial.data))
+        } else {
+            Ok(Assembled::HashMismatch(partial.data))
+        }
+    }
+
+    /// Whether `block` has been received for a piece still being put
+    /// together.
+    pub fn has_block(&self, block: &Block) -> bool {
+        self.partial
+            .get(&block.piece)
+            .is_some_and(|partial| partial.received.contains(&block.begin))
+    }
+
+    /// Pieces with some but not all blocks, which are worth finishing
+    /// before starting new ones.
+    pub fn in_progress(&self) -> impl Iterator<Item = u32> + '_ {
+        self.partial.keys().copied()
+    }
+
+    /// Each piece being put together, with how many of its blocks have
+    /// arrived and the bytes held for it.
+    pub fn progress(&self) -> impl Iterator<Item = (u32, usize, usize)> + '_ {
+        self.partial
+            .iter()
+            .map(|(piece, partial)| (*piece, partial.received.len(), partial.data.len()))
+    }
+
+    /// The blocks of `piece` not yet received.
+    pub fn missing_blocks<'a>(&'a self, piece: &Piece) -> impl Iterator<Item = Block> + 'a {
+        piece_blocks(piece.index as u32, piece.length).filter(|block| !self.has_block(block))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn piece(data: &[u8]) -> Piece {
+        Piece {
+            index: 0,
+            hash: Sha1::digest(data).into(),
+            offset: 0,
+            length: data.len() as u64,
+        }
+    }
+
+    #[test]
+    fn verifies_pieces_and_discards_mismatches() {
+        let data = vec![7; BLOCK_LEN as usize + 100];
+        let piece = piece(&data);
+        let mut assembler = PieceAssembler::new();
+
+        let (first, last) = data.split_at(BLOCK_LEN as usize);
+        assert_eq!(
+            assembler.add_block(&piece, BLOCK_LEN, last),
+            Ok(Assembled::Incomplete)
+        );
+        assert_eq!(
+            assembler.add_block(&piece, BLOCK_LEN, last),
+            Ok(Assembled::Duplicate)
+        );
+        assert_eq!(
+            assembler.add_block(&piece, 0, first),
+            Ok(Assembled::Verified(data.clone()))
+        );
+
+        assembler.add_block(&piece, BLOCK_LEN, last).unwrap();
+        let mut bad = data.clone();
+        bad[..BLOCK_LEN as usize].fill(0);
+        assert_eq!(
+            assembler.add_block(&piece, 0, &bad[..BLOCK_LEN as usize]),
+            Ok(Assembled::HashMismatch(bad))
+        );
+        assert_eq!(assembler.missing_blocks(&piece).count(), 2);
+        assert!(assembler.add_block(&piece, 0, &data[..10]).is_err());
+    }
+}
@@ -0,0 +1,457 @@
+use serde::de::DeserializeOwned;
+use serde_path_to_error::Segment;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+
+/// Deepest nesting of lists and dictionaries accepted. Peers and trackers
+/// control what we decode, and each level costs a stack frame.
+pub const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeErrorKind {
+    UnexpectedEof,
+    UnexpectedByte(u8),
+    InvalidInteger,
+    InvalidLength,
+    NotADictionary,
+    MissingKey(String),
+    TrailingData,
+    /// Lists and dictionaries nest deeper than `MAX_DEPTH`.
+    TooDeep,
+    /// The data is valid bencode but does not match the expected layout,
+    /// e.g. a string where an integer belongs.
+    Decode(String),
+}
+
+/// A decoding failure, with the byte offset and the path of keys and list
+/// indices (e.g. `info.files[3].length`) leading to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BencodeError {
+    pub kind: BencodeErrorKind,
+    pub offset: usize,
+    pub path: String,
+}
+
+/// One step of a path into a bencoded value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+impl BencodeError {
+    pub fn new(kind: BencodeErrorKind, offset: usize) -> Self {
+        BencodeError {
+            kind,
+            offset,
+            path: String::new(),
+        }
+    }
+
+    fn at(kind: BencodeErrorKind, offset: usize, path: &[PathSegment]) -> Self {
+        BencodeError {
+            kind,
+            offset,
+            path: render_path(path),
+        }
+    }
+
+    fn with_path(mut self, path: &[PathSegment]) -> Self {
+        self.path = render_path(path);
+        self
+    }
+}
+
+impl fmt::Display for BencodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BencodeErrorKind::UnexpectedEof => write!(f, "unexpected end of input"),
+            BencodeErrorKind::UnexpectedByte(byte) => write!(f, "unexpected byte 0x{:02x}", byte),
+            BencodeErrorKind::InvalidInteger => write!(f, "invalid integer"),
+            BencodeErrorKind::InvalidLength => write!(f, "invalid string length"),
+            BencodeErrorKind::NotADictionary => write!(f, "expected a dictionary"),
+            BencodeErrorKind::MissingKey(key) => write!(f, "missing key \"{}\"", key),
+            BencodeErrorKind::TrailingData => write!(f, "trailing data after the value"),
+            BencodeErrorKind::TooDeep => write!(f, "nested more than {} levels deep", MAX_DEPTH),
+            BencodeErrorKind::Decode(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at offset {}", self.kind, self.offset)?;
+        if !self.path.is_empty() {
+            write!(f, " ({})", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BencodeError {}
+
+/// Renders a path as `info.files[3].length`.
+pub fn render_path(path: &[PathSegment]) -> String {
+    let mut rendered = String::new();
+    for segment in path {
+        match segment {
+            PathSegment::Key(key) => {
+                if !rendered.is_empty() {
+                    rendered.push('.');
+                }
+                rendered.push_str(key);
+            }
+            PathSegment::Index(index) => rendered.push_str(&format!("[{}]", index)),
+        }
+    }
+    rendered
+}
+
+/// Decodes `data` into `T`. Malformed input and values of the wrong shape
+/// are both reported with the offset and path where decoding failed.
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, BencodeError> {
+    check(data)?;
+
+    let mut deserializer = serde_bencode::de::Deserializer::new(data);
+    serde_path_to_error::deserialize(&mut deserializer).map_err(|error| {
+        let mut path = Vec::new();
+        for segment in error.path().iter() {
+            match segment {
+                Segment::Map { key } => path.push(PathSegment::Key(key.clone())),
+                Segment::Seq { index } => path.push(PathSegment::Index(*index)),
+                _ => break,
+            }
+        }
+
+        // A missing key has no offset of its own; point at its parent.
+        let offset = (0..=path.len())
+            .rev()
+            .find_map(|length| locate(data, &path[..length]))
+            .unwrap_or(0);
+
+        BencodeError::at(
+            BencodeErrorKind::Decode(error.inner().to_string()),
+            offset,
+            &path,
+        )
+    })
+}
+
+/// Checks that `data` is exactly one well-formed bencoded value, reporting
+/// where and under which key the first problem is.
+pub fn check(data: &[u8]) -> Result<(), BencodeError> {
+    let mut path = Vec::new();
+    let end = check_value(data, 0, &mut path)?;
+    if end != data.len() {
+        return Err(BencodeError::new(BencodeErrorKind::TrailingData, end));
+    }
+    Ok(())
+}
+
+fn check_value(
+    data: &[u8],
+    pos: usize,
+    path: &mut Vec<PathSegment>,
+) -> Result<usize, BencodeError> {
+    match data.get(pos) {
+        None => Err(BencodeError::at(BencodeErrorKind::UnexpectedEof, pos, path)),
+        Some(b'l' | b'd') if path.len() >= MAX_DEPTH => {
+            Err(BencodeError::at(BencodeErrorKind::TooDeep, pos, path))
+        }
+        Some(b'i') => {
+            let end = find(data, pos + 1, b'e').map_err(|error| error.with_path(path))?;
+            if !is_valid_integer(&data[pos + 1..end]) {
+                return Err(BencodeError::at(
+                    BencodeErrorKind::InvalidInteger,
+                    pos,
+                    path,
+                ));
+            }
+            Ok(end + 1)
+        }
+        Some(b'l') => {
+            let mut pos = pos + 1;
+            let mut index = 0;
+            loop {
+                if data.get(pos) == Some(&b'e') {
+                    return Ok(pos + 1);
+                }
+                path.push(PathSegment::Index(index));
+                pos = check_value(data, pos, path)?;
+                path.pop();
+                index += 1;
+            }
+        }
+        Some(b'd') => {
+            let mut pos = pos + 1;
+            loop {
+                match data.get(pos) {
+                    None => {
+                        return Err(BencodeError::at(BencodeErrorKind::UnexpectedEof, pos, path))
+                    }
+                    Some(b'e') => return Ok(pos + 1),
+                    Some(b'0'..=b'9') => {}
+                    Some(&byte) => {
+                        return Err(BencodeError::at(
+                            BencodeErrorKind::UnexpectedByte(byte),
+                            pos,
+                            path,
+                        ))
+                    }
+                }
+
+                let (key, value_start) =
+                    read_bytes(data, pos).map_err(|error| error.with_path(path))?;
+                path.push(PathSegment::Key(String::from_utf8_lossy(key).into_owned()));
+                pos = check_value(data, value_start, path)?;
+                path.pop();
+            }
+        }
+        Some(b'0'..=b'9') => {
+            let (_, end) = read_bytes(data, pos).map_err(|error| error.with_path(path))?;
+            Ok(end)
+        }
+        Some(&byte) => Err(BencodeError::at(
+            BencodeErrorKind::UnexpectedByte(byte),
+            pos,
+            path,
+        )),
+    }
+}
+
+/// `-?[1-9][0-9]*` or `0`, as the format allows no leading zeros or `-0`.
+fn is_valid_integer(digits: &[u8]) -> bool {
+    let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
+    match unsigned {
+        [] => false,
+        [b'0'] => unsigned.len() == digits.len(),
+        [b'0', ..] => false,
+        _ => unsigned.iter().all(u8::is_ascii_digit),
+    }
+}
+
+/// Finds the offset of the value at `path` within `data`, or `None` if the
+/// path does not exist.
+pub fn locate(data: &[u8], path: &[PathSegment]) -> Option<usize> {
+    let mut pos = 0;
+    for segment in path {
+        pos = match (data.get(pos)?, segment) {
+            (b'd', PathSegment::Key(key)) => {
+                dict_entries_at(data, pos)
+                    .ok()?
+                    .into_iter()
+                    .find(|(entry_key, _)| *entry_key == key.as_bytes())?
+                    .1
+                    .start
+            }
+            (b'l', PathSegment::Index(index)) => {
+                let mut item = pos + 1;
+                for _ in 0..*index {
+                    if data.get(item) == Some(&b'e') {
+                        return None;
+                    }
+                    item = skip_value(data, item).ok()?;
+                }
+                if data.get(item) == Some(&b'e') {
+                    return None;
+                }
+                item
+            }
+            _ => return None,
+        };
+    }
+    Some(pos)
+}
+
+/// Returns the byte range of the value stored under `key` in the top-level
+/// dictionary of `data`, without re-encoding it.
+pub fn dict_value_span(data: &[u8], key: &[u8]) -> Result<Range<usize>, BencodeError> {
+    dict_entries(data)?
+        .into_iter()
+        .find(|(entry_key, _)| *entry_key == key)
+        .map(|(_, span)| span)
+        .ok_or_else(|| {
+            BencodeError::new(
+                BencodeErrorKind::MissingKey(String::from_utf8_lossy(key).into_owned()),
+                0,
+            )
+        })
+}
+
+/// Dictionary keys paired with the byte range of their values.
+pub type DictEntries<'a> = Vec<(&'a [u8], Range<usize>)>;
+
+/// Lists the keys of the top-level dictionary of `data` together with the
+/// byte range of each value.
+pub fn dict_entries(data: &[u8]) -> Result<DictEntries<'_>, BencodeError> {
+    dict_entries_at(data, 0)
+}
+
+fn dict_entries_at(data: &[u8], pos: usize) -> Result<DictEntries<'_>, BencodeError> {
+    if data.get(pos) != Some(&b'd') {
+        return Err(BencodeError::new(BencodeErrorKind::NotADictionary, pos));
+    }
+
+    let mut entries = Vec::new();
+    let mut pos = pos + 1;
+    loop {
+        match data.get(pos) {
+            None => return Err(BencodeError::new(BencodeErrorKind::UnexpectedEof, pos)),
+            Some(b'e') => break,
+            Some(_) => {}
+        }
+
+        let (entry_key, value_start) = read_bytes(data, pos)?;
+        let value_end = skip_value(data, value_start)?;
+        entries.push((entry_key, value_start..value_end));
+        pos = value_end;
+    }
+
+    Ok(entries)
+}
+
+/// Writes a dictionary whose values are already bencoded. Keys are emitted
+/// in sorted order as the format requires.
+pub fn encode_dict(entries: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+    let mut encoded = vec![b'd'];
+    for (key, value) in entries {
+        encoded.extend_from_slice(key.len().to_string().as_bytes());
+        encoded.push(b':');
+        encoded.extend_from_slice(key);
+        encoded.extend_from_slice(value);
+    }
+    encoded.push(b'e');
+    encoded
+}
+
+/// Returns the offset just past the value starting at `pos`.
+pub fn skip_value(data: &[u8], pos: usize) -> Result<usize, BencodeError> {
+    skip_nested(data, pos, 0)
+}
+
+fn skip_nested(data: &[u8], pos: usize, depth: usize) -> Result<usize, BencodeError> {
+    match data.get(pos) {
+        None => Err(BencodeError::new(BencodeErrorKind::UnexpectedEof, pos)),
+        Some(b'l' | b'd') if depth >= MAX_DEPTH => {
+            Err(BencodeError::new(BencodeErrorKind::TooDeep, pos))
+        }
+        Some(b'i') => {
+            let end = find(data, pos + 1, b'e')?;
+            Ok(end + 1)
+        }
+        Some(b'l') | Some(b'd') => {
+            let mut pos = pos + 1;
+            loop {
+                match data.get(pos) {
+                    None => return Err(BencodeError::new(BencodeErrorKind::UnexpectedEof, pos)),
+                    Some(b'e') => return Ok(pos + 1),
+                    Some(_) => pos = skip_nested(data, pos, depth + 1)?,
+                }
+            }
+        }
+        Some(b'0'..=b'9') => {
+            let (_, end) = read_bytes(data, pos)?;
+            Ok(end)
+        }
+        Some(&byte) => Err(BencodeError::new(
+            BencodeErrorKind::UnexpectedByte(byte),
+            pos,
+        )),
+    }
+}
+
+/// Reads a byte string starting at `pos`, returning its contents and the
+/// offset just past it.
+fn read_bytes(data: &[u8], pos: usize) -> Result<(&[u8], usize), BencodeError> {
+    let colon = find(data, pos, b':')?;
+    let length: usize = std::str::from_utf8(&data[pos..colon])
+        .ok()
+        .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|digits| digits.parse().ok())
+        .ok_or(BencodeError::new(BencodeErrorKind::InvalidLength, pos))?;
+
+    let start = colon + 1;
+    let end = start
+        .checked_add(length)
+        .filter(|end| *end <= data.len())
+        .ok_or(BencodeError::new(
+            BencodeErrorKind::UnexpectedEof,
+            data.len(),
+        ))?;
+
+    Ok((&data[start..end], end))
+}
+
+fn find(data: &[u8], from: usize, needle: u8) -> Result<usize, BencodeError> {
+    data[from.min(data.len())..]
+        .iter()
+        .position(|b| *b == needle)
+        .map(|index| from + index)
+        .ok_or(BencodeError::new(
+            BencodeErrorKind::UnexpectedEof,
+            data.len(),
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(data: &[u8]) -> (BencodeErrorKind, usize, String) {
+        let error = check(data).unwrap_err();
+        (error.kind, error.offset, error.path)
+    }
+
+    #[test]
+    fn reports_the_kind_offset_and_path_of_malformed_input() {
+        assert_eq!(
+            error(b"d4:infod5:filesld6:lengthi01eeeee"),
+            (
+                BencodeErrorKind::InvalidInteger,
+                25,
+                "info.files[0].length".to_string()
+            )
+        );
+        assert_eq!(
+            error(b"d4:listli1ex"),
+            (
+                BencodeErrorKind::UnexpectedByte(b'x'),
+                11,
+                "list[1]".to_string()
+            )
+        );
+        assert_eq!(
+            error(b"d4:name5:ab"),
+            (BencodeErrorKind::UnexpectedEof, 11, "name".to_string())
+        );
+        assert_eq!(
+            error(b"i-0e"),
+            (BencodeErrorKind::InvalidInteger, 0, String::new())
+        );
+        assert_eq!(
+            error(b"le0:"),
+            (BencodeErrorKind::TrailingData, 2, String::new())
+        );
+        assert_eq!(
+            check(b"d1:ai1eee").unwrap_err().to_string(),
+            "trailing data after the value at offset 8"
+        )
+    }
+
+    #[test]
+    fn refuses_nesting_past_the_limit() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert_eq!(check(&nested(MAX_DEPTH)), Ok(()));
+        assert_eq!(skip_value(&nested(MAX_DEPTH), 0), Ok(2 * MAX_DEPTH));
+
+        let deep = nested(100_000);
+        let (kind, offset, path) = error(&deep);
+        assert_eq!((kind, offset), (BencodeErrorKind::TooDeep, MAX_DEPTH));
+        assert!(path.starts_with("[0][0]"));
+        assert_eq!(
+            skip_value(&deep, 0).unwrap_err().kind,
+            BencodeErrorKind::TooDeep
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+
+/// One bit per piece, most significant bit of the first byte for piece 0,
+/// as in the peer wire `bitfield` message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+/// A `bitfield` message that doesn't fit the torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitfieldError {
+    /// The message is not `ceil(piece_count / 8)` bytes long.
+    WrongLength { expected: usize, actual: usize },
+    /// A bit past the last piece is set.
+    SpareBitsSet,
+}
+
+impl fmt::Display for BitfieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BitfieldError::WrongLength { expected, actual } => {
+                write!(f, "bitfield is {} bytes, expected {}", actual, expected)
+            }
+            BitfieldError::SpareBitsSet => write!(f, "bitfield sets bits past the last piece"),
+        }
+    }
+}
+
+impl std::error::Error for BitfieldError {}
+
+impl Bitfield {
+    /// An empty bitfield for `len` pieces.
+    pub fn new(len: usize) -> Self {
+        Bitfield {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Parses a peer's `bitfield` message for a torrent of `len` pieces.
+    /// Peers sending spare bits must be disconnected, per BEP 3.
+    pub fn from_bytes(bytes: Vec<u8>, len: usize) -> Result<Self, BitfieldError> {
+        let expected = len.div_ceil(8);
+        if bytes.len() != expected {
+            return Err(BitfieldError::WrongLength {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+        let spare = expected * 8 - len;
+        if spare > 0 && bytes[expected - 1] & ((1 << spare) - 1) != 0 {
+            return Err(BitfieldError::SpareBitsSet);
+        }
+        Ok(Bitfield { bytes, len })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Number of pieces, not bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether piece `index` is set. Out-of-range pieces never are.
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & mask(index) != 0
+    }
+
+    /// Sets or clears piece `index`. Out-of-range indexes are ignored.
+    pub fn set(&mut self, index: usize, value: bool) {
+        if index >= self.len {
+            return;
+        }
+        if value {
+            self.bytes[index / 8] |= mask(index);
+        } else {
+            self.bytes[index / 8] &= !mask(index);
+        }
+    }
+
+    /// Number of pieces set.
+    pub fn count_ones(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    /// Indexes of the pieces not set, skipping full bytes at a time.
+    pub fn missing(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bytes
+            .iter()
+            .enumerate()
+            .filter(|(_, byte)| **byte != 0xff)
+            .flat_map(|(byte_index, _)| byte_index * 8..(byte_index * 8 + 8).min(self.len))
+            .filter(|index| !self.has(*index))
+    }
+}
+
+impl FromIterator<bool> for Bitfield {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        let mut bitfield = Bitfield::new(0);
+        for (index, value) in iter.into_iter().enumerate() {
+            if index % 8 == 0 {
+                bitfield.bytes.push(0);
+            }
+            bitfield.len += 1;
+            bitfield.set(index, value);
+        }
+        bitfield
+    }
+}
+
+fn mask(index: usize) -> u8 {
+    0x80 >> (index % 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_spare_bits_and_wrong_lengths() {
+        assert_eq!(
+            Bitfield::from_bytes(vec![0xff, 0xc1], 10),
+            Err(BitfieldError::SpareBitsSet)
+        );
+        assert_eq!(
+            Bitfield::from_bytes(vec![0xff], 10),
+            Err(BitfieldError::WrongLength {
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert!(Bitfield::from_bytes(vec![0xff, 0xc0], 10).is_ok());
+    }
+
+    #[test]
+    fn tracks_set_and_missing_pieces() {
+        let mut bitfield = Bitfield::new(10);
+        for index in [0, 1, 2, 3, 4, 5, 6, 7, 9] {
+            bitfield.set(index, true);
+        }
+
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert_eq!(bitfield.count_ones(), 9);
+        assert_eq!(bitfield.missing().collect::<Vec<_>>(), vec![8]);
+
+        bitfield.set(8, true);
+        assert!(bitfield.is_complete());
+        assert_eq!(bitfield.as_bytes(), &[0xff, 0xc0]);
+    }
+}
@@ -0,0 +1,823 @@
+use crate::bencode::{self, PathSegment};
+use crate::torrent::{TorrentFile, TorrentInfo};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_PIECE_LENGTH: u64 = 256 * 1024;
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+/// The BEP 52 merkle tree leaf size.
+const BLOCK_SIZE: u64 = 16 * 1024;
+/// Piece length and total size, each a big-endian `u64`.
+const CHECKPOINT_HEADER_LENGTH: u64 = 16;
+
+/// Which metainfo formats the builder emits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaVersion {
+    /// SHA-1 piece hashes only.
+    V1,
+    /// BEP 52 only: a SHA-256 merkle tree per file.
+    V2,
+    /// Both, so v1 and v2 clients share one swarm. Files are padded to
+    /// piece boundaries, as the two layouts must line up.
+    Hybrid,
+}
+
+/// Creates a `.torrent` from a file or directory on disk.
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: u64,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: String,
+    creation_date: Option<i64>,
+    private: bool,
+    source: Option<String>,
+    align_to_pieces: bool,
+    checkpoint: Option<PathBuf>,
+    meta_version: MetaVersion,
+}
+
+#[derive(Serialize)]
+struct Metainfo<'a> {
+    announce: &'a str,
+    #[serde(rename = "announce-list", skip_serializing_if = "Option::is_none")]
+    announce_list: Option<&'a Vec<Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<&'a str>,
+    #[serde(rename = "created by")]
+    created_by: &'a str,
+    #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
+    creation_date: Option<i64>,
+    info: &'a TorrentInfo,
+    #[serde(rename = "piece layers", skip_serializing_if = "Option::is_none")]
+    piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+}
+
+struct InputFile {
+    /// `None` for padding files, which hash as zeros.
+    disk_path: Option<PathBuf>,
+    path: Vec<String>,
+    length: u64,
+}
+
+impl TorrentBuilder {
+    /// Starts a builder stamped with this crate's name and version and the
+    /// current time.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TorrentBuilder {
+            path: path.into(),
+            piece_length: DEFAULT_PIECE_LENGTH,
+            announce_list: Vec::new(),
+            comment: None,
+            created_by: format!("crab_torrent/{}", env!("CARGO_PKG_VERSION")),
+            creation_date: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|elapsed| elapsed.as_secs() as i64),
+            private: false,
+            source: None,
+            align_to_pieces: false,
+            checkpoint: None,
+            meta_version: MetaVersion::V1,
+        }
+    }
+
+    /// Sets the piece length in bytes; it must be a power of two of at
+    /// least 16 KiB.
+    pub fn piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = piece_length;
+        self
+    }
+
+    /// Adds a tracker as its own tier. The first tracker added becomes
+    /// `announce`.
+    pub fn announce(mut self, tracker: impl Into<String>) -> Self {
+        self.announce_list.push(vec![tracker.into()]);
+        self
+    }
+
+    /// Adds a tier of interchangeable trackers.
+    pub fn announce_tier(mut self, tier: Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.announce_list.push(tier);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: impl Into<String>) -> Self {
+        self.created_by = created_by.into();
+        self
+    }
+
+    /// Sets the creation date as a Unix timestamp, or leaves it out with
+    /// `None` so identical input always produces an identical torrent.
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Tags the info dictionary with `source`, which private trackers use to
+    /// give their torrents a distinct infohash.
+    pub fn source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Inserts BEP 47 padding files so every file starts on a piece
+    /// boundary, letting files be cross-seeded or reused independently.
+    pub fn align_to_pieces(mut self, align_to_pieces: bool) -> Self {
+        self.align_to_pieces = align_to_pieces;
+        self
+    }
+
+    pub fn meta_version(mut self, meta_version: MetaVersion) -> Self {
+        self.meta_version = meta_version;
+        self
+    }
+
+    /// Appends v1 piece hashes to `path` as they are computed instead of
+    /// keeping them in memory. A build interrupted part way resumes from the
+    /// last hash written, provided the input and piece length are unchanged.
+    /// The file is removed once the torrent has been written.
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// Hashes the input and returns the bencoded `.torrent` contents.
+    pub fn build(self) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.build_to(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Hashes the input and writes the bencoded `.torrent` to `output`. With
+    /// a checkpoint the piece hashes are streamed from it.
+    pub fn build_to(self, mut output: impl Write) -> Result<()> {
+        if self.piece_length < MIN_PIECE_LENGTH || !self.piece_length.is_power_of_two() {
+            return Err(anyhow!(
+                "piece length {} must be a power of two of at least {}",
+                self.piece_length,
+                MIN_PIECE_LENGTH
+            ));
+        }
+        let announce = self
+            .announce_list
+            .first()
+            .and_then(|tier| tier.first())
+            .ok_or_else(|| anyhow!("a torrent needs at least one tracker"))?;
+
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("{} has no usable file name", self.path.display()))?
+            .to_string();
+
+        let is_dir = fs::metadata(&self.path)?.is_dir();
+        let files = if is_dir {
+            let mut files = Vec::new();
+            collect_files(&self.path, &mut Vec::new(), &mut files)?;
+            files
+        } else {
+            vec![InputFile {
+                disk_path: Some(self.path.clone()),
+                path: vec![name.clone()],
+                length: fs::metadata(&self.path)?.len(),
+            }]
+        };
+
+        if files.iter().all(|file| file.length == 0) {
+            return Err(anyhow!("{} contains no data", self.path.display()));
+        }
+
+        let has_v1 = self.meta_version != MetaVersion::V2;
+        let has_v2 = self.meta_version != MetaVersion::V1;
+
+        let (file_tree, piece_layers) = if has_v2 {
+            let (tree, layers) = hash_files_v2(&files, self.piece_length)?;
+            (Some(tree), Some(layers))
+        } else {
+            (None, None)
+        };
+
+        let align = self.align_to_pieces || self.meta_version == MetaVersion::Hybrid;
+        let files = if is_dir && align {
+            pad_files(files, self.piece_length)
+        } else {
+            files
+        };
+
+        let total_length: u64 = files.iter().map(|file| file.length).sum();
+        let pieces_length = total_length.div_ceil(self.piece_length) * 20;
+
+        let info = TorrentInfo {
+            name,
+            piece_length: self.piece_length as i64,
+            length: if is_dir || !has_v1 {
+                None
+            } else {
+                Some(files[0].length as i64)
+            },
+            files: if is_dir && has_v1 {
+                files
+                    .iter()
+                    .map(|file| TorrentFile {
+                        length: file.length as i64,
+                        path: file.path.clone(),
+                        attr: file.disk_path.is_none().then(|| "p".to_string()),
+                        symlink_path: None,
+                        md5sum: None,
+                        sha1: None,
+                    })
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            // A one-byte placeholder for the hashes spliced in below, as
+            // they may be too large to hold in memory.
+            pieces: ByteBuf::from(if has_v1 { vec![0] } else { Vec::new() }),
+            private: self.private.then_some(1),
+            md5sum: None,
+            sha1: None,
+            source: self.source.clone(),
+            similar: None,
+            collections: None,
+            meta_version: has_v2.then_some(2),
+            file_tree,
+            extra: BTreeMap::new(),
+        };
+
+        let metainfo = Metainfo {
+            announce,
+            announce_list: (self.announce_list.len() > 1).then_some(&self.announce_list),
+            comment: self.comment.as_deref(),
+            created_by: &self.created_by,
+            creation_date: self.creation_date,
+            info: &info,
+            piece_layers,
+        };
+
+        let skeleton = serde_bencode::to_bytes(&metainfo)?;
+        if !has_v1 {
+            output.write_all(&skeleton)?;
+            output.flush()?;
+            return Ok(());
+        }
+
+        let pieces_offset = bencode::locate(
+            &skeleton,
+            &[
+                PathSegment::Key("info".to_string()),
+                PathSegment::Key("pieces".to_string()),
+            ],
+        )
+        .ok_or_else(|| anyhow!("info dictionary has no pieces"))?;
+
+        // Hash before writing anything so an interrupted build leaves no
+        // partial output behind.
+        let mut hashes = match &self.checkpoint {
+            Some(checkpoint) => {
+                let mut hashes = open_checkpoint(checkpoint, self.piece_length, total_length)?;
+                let done = (hashes.metadata()?.len() - CHECKPOINT_HEADER_LENGTH) / 20;
+                hash_pieces(&files, self.piece_length as usize, done, &mut hashes)?;
+                hashes.seek(SeekFrom::Start(CHECKPOINT_HEADER_LENGTH))?;
+                Box::new(hashes) as Box<dyn Read>
+            }
+            None => {
+                let mut hashes = Vec::new();
+                hash_pieces(&files, self.piece_length as usize, 0, &mut hashes)?;
+                Box::new(io::Cursor::new(hashes))
+            }
+        };
+
+        output.write_all(&skeleton[..pieces_offset])?;
+        write!(output, "{}:", pieces_length)?;
+        let copied = io::copy(&mut hashes, &mut output)?;
+        if copied != pieces_length {
+            return Err(anyhow!(
+                "hashed {} bytes of pieces, expected {}",
+                copied,
+                pieces_length
+            ));
+        }
+        // Skip the `1:\0` placeholder.
+        output.write_all(&skeleton[pieces_offset + 3..])?;
+        output.flush()?;
+
+        if let Some(checkpoint) = &self.checkpoint {
+            fs::remove_file(checkpoint)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Opens the checkpoint at `path`, starting it afresh unless it was written
+/// for the same piece length and total size. Any partly written hash is
+/// dropped, leaving the file positioned after the last complete one.
+fn open_checkpoint(path: &Path, piece_length: u64, total_length: u64) -> Result<File> {
+    let mut header = Vec::with_capacity(CHECKPOINT_HEADER_LENGTH as usize);
+    header.extend_from_slice(&piece_length.to_be_bytes());
+    header.extend_from_slice(&total_length.to_be_bytes());
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+
+    let mut existing = Vec::new();
+    (&mut file)
+        .take(CHECKPOINT_HEADER_LENGTH)
+        .read_to_end(&mut existing)?;
+
+    if existing == header {
+        let hashes = file.metadata()?.len() - CHECKPOINT_HEADER_LENGTH;
+        file.set_len(CHECKPOINT_HEADER_LENGTH + hashes - hashes % 20)?;
+    } else {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+    }
+    file.seek(SeekFrom::End(0))?;
+
+    Ok(file)
+}
+
+/// Collects every regular file below `dir`, sorted by path so the piece
+/// layout is deterministic.
+fn collect_files(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<InputFile>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow!("{:?} is not valid UTF-8", name))?;
+        let file_type = entry.file_type()?;
+
+        prefix.push(name);
+        if file_type.is_dir() {
+            collect_files(&entry.path(), prefix, files)?;
+        } else if file_type.is_file() {
+            files.push(InputFile {
+                disk_path: Some(entry.path()),
+                path: prefix.clone(),
+                length: entry.metadata()?.len(),
+            });
+        }
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+/// Hashes every file per BEP 52, returning the `file tree` and the
+/// `piece layers` of files longer than a piece.
+fn hash_files_v2(
+    files: &[InputFile],
+    piece_length: u64,
+) -> Result<(Value, BTreeMap<ByteBuf, ByteBuf>)> {
+    let mut tree = HashMap::new();
+    let mut piece_layers = BTreeMap::new();
+
+    for file in files {
+        let Some(disk_path) = &file.disk_path else {
+            continue;
+        };
+
+        let mut attributes = HashMap::new();
+        attributes.insert(b"length".to_vec(), Value::Int(file.length as i64));
+        if file.length > 0 {
+            let (root, layer) = hash_file_v2(disk_path, file.length, piece_length)?;
+            attributes.insert(b"pieces root".to_vec(), Value::Bytes(root.to_vec()));
+            if let Some(layer) = layer {
+                piece_layers.insert(ByteBuf::from(root.to_vec()), ByteBuf::from(layer));
+            }
+        }
+
+        insert_tree_file(&mut tree, &file.path, Value::Dict(attributes));
+    }
+
+    Ok((Value::Dict(tree), piece_layers))
+}
+
+/// Adds a file under `path`, keeping its attributes under the empty key
+/// as BEP 52 lays them out.
+fn insert_tree_file(tree: &mut HashMap<Vec<u8>, Value>, path: &[String], attributes: Value) {
+    let Some((first, rest)) = path.split_first() else {
+        return;
+    };
+
+    let node = tree
+        .entry(first.as_bytes().to_vec())
+        .or_insert_with(|| Value::Dict(HashMap::new()));
+    if let Value::Dict(node) = node {
+        if rest.is_empty() {
+            node.insert(Vec::new(), attributes);
+        } else {
+            insert_tree_file(node, rest, attributes);
+        }
+    }
+}
+
+/// Returns a non-empty file's pieces root and, if it is longer than a
+/// piece, its piece layer.
+fn hash_file_v2(
+    path: &Path,
+    length: u64,
+    piece_length: u64,
+) -> Result<([u8; 32], Option<Vec<u8>>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let blocks_per_piece = (piece_length / BLOCK_SIZE) as usize;
+
+    if length <= piece_length {
+        let leaves = hash_blocks(&mut reader, length)?;
+        let width = leaves.len().next_power_of_two();
+        return Ok((merkle_root(leaves, width, [0; 32]), None));
+    }
+
+    let mut layer = Vec::new();
+    let mut remaining = length;
+    while remaining > 0 {
+        let piece = remaining.min(piece_length);
+        let leaves = hash_blocks(&mut reader, piece)?;
+        layer.push(merkle_root(leaves, blocks_per_piece, [0; 32]));
+        remaining -= piece;
+    }
+
+    // Pieces past the end of the file are whole subtrees of zero leaves.
+    let pad = merkle_root(Vec::new(), blocks_per_piece, [0; 32]);
+    let root = merkle_root(layer.clone(), layer.len().next_power_of_two(), pad);
+
+    Ok((root, Some(layer.concat())))
+}
+
+/// SHA-256s the next `length` bytes of `reader` in 16 KiB blocks.
+fn hash_blocks(reader: &mut impl Read, length: u64) -> Result<Vec<[u8; 32]>> {
+    let mut leaves = Vec::new();
+    let mut block = vec![0; BLOCK_SIZE as usize];
+    let mut remaining = length;
+
+    while remaining > 0 {
+        let size = remaining.min(BLOCK_SIZE) as usize;
+        reader.read_exact(&mut block[..size])?;
+        leaves.push(sha256(&[&block[..size]]));
+        remaining -= size as u64;
+    }
+
+    Ok(leaves)
+}
+
+/// Reduces `layer`, padded with `pad` to `width` nodes, to its root.
+fn merkle_root(mut layer: Vec<[u8; 32]>, width: usize, pad: [u8; 32]) -> [u8; 32] {
+    layer.resize(width, pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| sha256(&[&pair[0], &pair[1]]))
+            .collect();
+    }
+    layer[0]
+}
+
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+/// Follows every file but the last with a padding file up to the next
+/// piece boundary, named `.pad/<length>` as other clients do.
+fn pad_files(files: Vec<InputFile>, piece_length: u64) -> Vec<InputFile> {
+    let count = files.len();
+    let mut padded = Vec::with_capacity(count * 2);
+
+    for (index, file) in files.into_iter().enumerate() {
+        let remainder = file.length % piece_length;
+        padded.push(file);
+        if index + 1 < count && remainder != 0 {
+            let length = piece_length - remainder;
+            padded.push(InputFile {
+                disk_path: None,
+                path: vec![".pad".to_string(), length.to_string()],
+                length,
+            });
+        }
+    }
+
+    padded
+}
+
+/// Hashes the concatenation of `files` into 20-byte SHA-1 piece hashes,
+/// writing each to `pieces` as it completes. The first `skip` pieces are
+/// assumed to be hashed already.
+fn hash_pieces(
+    files: &[InputFile],
+    piece_length: usize,
+    skip: u64,
+    pieces: &mut impl Write,
+) -> Result<()> {
+    let mut buffer = Vec::with_capacity(piece_length);
+    let mut to_skip = skip * piece_length as u64;
+
+    for file in files {
+        if to_skip >= file.length {
+            to_skip -= file.length;
+            continue;
+        }
+        let start = to_skip;
+        to_skip = 0;
+
+        let mut reader: Box<dyn Read> = match &file.disk_path {
+            Some(disk_path) => {
+                let mut disk_file = File::open(disk_path)?;
+                disk_file.seek(SeekFrom::Start(start))?;
+                Box::new(BufReader::new(disk_file))
+            }
+            None => Box::new(io::repeat(0).take(file.length - start)),
+        };
+        loop {
+            let wanted = piece_length - buffer.len();
+            let read = (&mut reader).take(wanted as u64).read_to_end(&mut buffer)?;
+            if buffer.len() == piece_length {
+                pieces.write_all(&Sha1::digest(&buffer))?;
+                buffer.clear();
+            }
+            if read < wanted {
+                break;
+            }
+        }
+    }
+
+    if !buffer.is_empty() {
+        pieces.write_all(&Sha1::digest(&buffer))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{hex, Torrent};
+
+    /// A fresh directory under the system temp dir, removed on drop.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "crab_torrent-builder-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        /// Writes `length` bytes of a repeating pattern, returning them.
+        fn file(&self, path: &str, length: usize, seed: u8) -> Vec<u8> {
+            let data: Vec<u8> = (0..length)
+                .map(|index| (index % 251) as u8 ^ seed)
+                .collect();
+            let path = self.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, &data).unwrap();
+            data
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn build(builder: TorrentBuilder) -> Torrent {
+        let contents = builder
+            .announce("http://t.invalid/announce")
+            .creation_date(None)
+            .build()
+            .unwrap();
+        Torrent::new(contents).unwrap()
+    }
+
+    /// SHA-1 of each `piece_length` chunk of `data`.
+    fn sha1_pieces(data: &[u8], piece_length: usize) -> Vec<u8> {
+        data.chunks(piece_length)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn hashes_a_file_into_pieces_with_a_short_last_one() {
+        let scratch = Scratch::new("single");
+        let data = scratch.file("data.bin", 40000, 0);
+        let torrent = build(TorrentBuilder::new(scratch.0.join("data.bin")).piece_length(16384));
+
+        assert_eq!(torrent.info.length, Some(40000));
+        assert_eq!(torrent.piece_count(), 3);
+        assert_eq!(torrent.info.pieces.as_slice(), sha1_pieces(&data, 16384));
+    }
+
+    #[test]
+    fn hashes_the_files_of_a_directory_as_one_stream() {
+        let scratch = Scratch::new("multi");
+        // Read back sorted by path, whatever order they were written in.
+        let b = scratch.file("dir/sub/b", 30000, 1);
+        let a = scratch.file("dir/a", 20000, 2);
+        let torrent = build(TorrentBuilder::new(scratch.0.join("dir")).piece_length(16384));
+
+        let paths: Vec<(Vec<String>, u64)> = torrent.file_paths();
+        assert_eq!(
+            paths,
+            [
+                (vec!["a".to_string()], 20000),
+                (vec!["sub".to_string(), "b".to_string()], 30000)
+            ]
+        );
+        // Piece 1 spans both files; piece 3 is the last 848 bytes.
+        let data = [a, b].concat();
+        assert_eq!(torrent.piece_count(), 4);
+        assert_eq!(torrent.info.pieces.as_slice(), sha1_pieces(&data, 16384));
+    }
+
+    #[test]
+    fn builds_v2_merkle_roots_and_piece_layers() {
+        let scratch = Scratch::new("v2");
+        scratch.file("dir/small", 1000, 3);
+        scratch.file("dir/big", 2 * 32768 + 10000, 4);
+        let torrent = build(
+            TorrentBuilder::new(scratch.0.join("dir"))
+                .piece_length(32768)
+                .meta_version(MetaVersion::V2),
+        );
+        assert!(torrent.info.pieces.is_empty());
+        let roots: BTreeMap<String, String> = torrent
+            .tree_files()
+            .into_iter()
+            .map(|file| (file.path.join("/"), hex(&file.pieces_root.unwrap())))
+            .collect();
+
+        // Under one block, the root is the SHA-256 of the data as is.
+        assert_eq!(
+            roots["small"],
+            "029994c7e4e371146827d4f80ef0aa8aa652faf54d972d047968bc237f30d5f8"
+        );
+        // Five blocks in 32 KiB pieces: the third piece pairs its one block
+        // with a zero leaf, and the root pairs it with a subtree of zero
+        // leaves. Both computed independently with Python's hashlib.
+        let big = "4ab43b82d371b75a762530cd1c2ab8a4299cb4a4cea66f7151a77cfcef29777a";
+        assert_eq!(roots["big"], big);
+        let layers = torrent.piece_layers.unwrap();
+        assert_eq!(layers.len(), 1);
+        let (root, layer) = layers.iter().next().unwrap();
+        assert_eq!(hex(root), big);
+        assert_eq!(
+            hex(layer),
+            "dd999d3792c04f9e5bd0832d2a90bb765cbbfcb3093e2b8e84fc0779c25a70f6\
+             bebd44728165f87a58f5d3aaebbd923b20705f3e08750de1d45ec0430dc432a0\
+             17a101a44d6d1a63a0c40387fa7eedeaebf091b4edc9fcbea93fc89483c18dcc"
+        );
+    }
+
+    /// A checkpoint header for `piece_length` and `total_length`,
+    /// followed by `hashes`.
+    fn checkpoint(piece_length: u64, total_length: u64, hashes: &[u8]) -> Vec<u8> {
+        [
+            &piece_length.to_be_bytes()[..],
+            &total_length.to_be_bytes(),
+            hashes,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn resumes_an_interrupted_build_from_its_checkpoint() {
+        let scratch = Scratch::new("resume");
+        scratch.file("data.bin", 100000, 5);
+        let builder = || {
+            TorrentBuilder::new(scratch.0.join("data.bin"))
+                .piece_length(16384)
+                .announce("http://t.invalid/announce")
+                .creation_date(None)
+        };
+        let uninterrupted = builder().build().unwrap();
+        let pieces = Torrent::new(uninterrupted.clone()).unwrap().info.pieces;
+
+        // Interrupted in the middle of writing the third hash.
+        let path = scratch.0.join("checkpoint");
+        fs::write(&path, checkpoint(16384, 100000, &pieces[..47])).unwrap();
+        let resumed = builder().checkpoint(&path).build().unwrap();
+        assert_eq!(resumed, uninterrupted);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn trusts_only_a_checkpoint_for_the_same_layout() {
+        let scratch = Scratch::new("mismatch");
+        scratch.file("data.bin", 50000, 6);
+        let build_from = |contents: Vec<u8>| {
+            let path = scratch.0.join("checkpoint");
+            fs::write(&path, contents).unwrap();
+            build(
+                TorrentBuilder::new(scratch.0.join("data.bin"))
+                    .piece_length(16384)
+                    .checkpoint(&path),
+            )
+            .info
+            .pieces
+        };
+        let genuine = build(TorrentBuilder::new(scratch.0.join("data.bin")).piece_length(16384))
+            .info
+            .pieces;
+        let bogus = [0xab; 40];
+
+        // A matching header is trusted: its hashes are kept, not redone.
+        let kept = build_from(checkpoint(16384, 50000, &bogus));
+        assert_eq!(kept[..40], bogus);
+        assert_eq!(kept[40..], genuine[40..]);
+
+        // Another piece length or size starts over.
+        for (piece_length, total_length) in [(32768, 50000), (16384, 50001)] {
+            let redone = build_from(checkpoint(piece_length, total_length, &bogus));
+            assert_eq!(redone, genuine);
+        }
+    }
+
+    #[test]
+    fn pads_every_file_after_the_first_to_a_piece_boundary() {
+        let scratch = Scratch::new("pad");
+        let a = scratch.file("dir/a", 20000, 7);
+        let b = scratch.file("dir/b", 32768, 8);
+        let c = scratch.file("dir/c", 100, 9);
+        let aligned = build(
+            TorrentBuilder::new(scratch.0.join("dir"))
+                .piece_length(16384)
+                .align_to_pieces(true),
+        );
+        // Hybrid torrents are aligned whether asked or not.
+        let hybrid = build(
+            TorrentBuilder::new(scratch.0.join("dir"))
+                .piece_length(16384)
+                .meta_version(MetaVersion::Hybrid),
+        );
+
+        for torrent in [aligned, hybrid] {
+            let files: Vec<(String, i64, Option<&str>)> = torrent
+                .info
+                .files
+                .iter()
+                .map(|file| (file.path.join("/"), file.length, file.attr.as_deref()))
+                .collect();
+            // `b` ends on a boundary, so needs no padding.
+            assert_eq!(
+                files,
+                [
+                    ("a".to_string(), 20000, None),
+                    (".pad/12768".to_string(), 12768, Some("p")),
+                    ("b".to_string(), 32768, None),
+                    ("c".to_string(), 100, None),
+                ]
+            );
+            let mut offset = 0;
+            for file in &torrent.info.files {
+                if !file.is_padding() {
+                    assert_eq!(offset % 16384, 0, "{:?}", file.path);
+                }
+                offset += file.length;
+            }
+            // Padding hashes as zeros.
+            let data = [a.as_slice(), &[0; 12768], &b, &c].concat();
+            assert_eq!(torrent.info.pieces.as_slice(), sha1_pieces(&data, 16384));
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+/// One of the 64 reserved bits of the handshake, numbered from the least
+/// significant bit of the last byte, so DHT is bit 0 and the extension
+/// protocol bit 20.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Capability(u8);
+
+impl Capability {
+    /// A DHT node (BEP 5), whose port follows in a `port` message.
+    pub const DHT: Capability = Capability(0);
+    /// The fast extension (BEP 6).
+    pub const FAST: Capability = Capability(2);
+    /// Willing to upgrade to the v2 info hash (BEP 52).
+    pub const V2_UPGRADE: Capability = Capability(4);
+    /// The extension protocol (BEP 10).
+    pub const EXTENSIONS: Capability = Capability(20);
+
+    const NAMES: &'static [(Capability, &'static str)] = &[
+        (Capability::DHT, "dht"),
+        (Capability::FAST, "fast"),
+        (Capability::V2_UPGRADE, "v2"),
+        (Capability::EXTENSIONS, "extensions"),
+    ];
+
+    /// Bit `bit` of the reserved bytes, for extensions not named here.
+    ///
+    /// # Panics
+    ///
+    /// If `bit` is 64 or more.
+    pub const fn new(bit: u8) -> Self {
+        assert!(bit < 64, "the handshake has 64 reserved bits");
+        Capability(bit)
+    }
+
+    pub fn bit(self) -> u8 {
+        self.0
+    }
+
+    pub fn name(self) -> Option<&'static str> {
+        Capability::NAMES
+            .iter()
+            .find(|(capability, _)| *capability == self)
+            .map(|(_, name)| *name)
+    }
+
+    fn mask(self) -> u64 {
+        1 << self.0
+    }
+}
+
+/// The reserved bytes of a handshake as the set of capabilities they
+/// announce. Bits we have no name for are kept as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub fn from_bytes(bytes: [u8; 8]) -> Self {
+        Capabilities(u64::from_be_bytes(bytes))
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        self.0.to_be_bytes()
+    }
+
+    pub fn with(mut self, capability: Capability) -> Self {
+        self.insert(capability);
+        self
+    }
+
+    pub fn insert(&mut self, capability: Capability) {
+        self.0 |= capability.mask();
+    }
+
+    pub fn remove(&mut self, capability: Capability) {
+        self.0 &= !capability.mask();
+    }
+
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.mask() != 0
+    }
+
+    /// The capabilities both sides announce, the ones a connection can use.
+    pub fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The bits set, lowest first.
+    pub fn iter(self) -> impl Iterator<Item = Capability> {
+        (0..64)
+            .map(Capability)
+            .filter(move |capability| self.contains(*capability))
+    }
+}
+
+/// Names such as `dht, extensions`, with unnamed bits as `bit 43`.
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        for (index, capability) in self.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            match capability.name() {
+                Some(name) => write!(f, "{}", name)?,
+                None => write!(f, "bit {}", capability.bit())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_bits_to_their_reserved_bytes() {
+        let capabilities = Capabilities::default()
+            .with(Capability::DHT)
+            .with(Capability::EXTENSIONS)
+            .with(Capability::new(63));
+        assert_eq!(capabilities.to_bytes(), [0x80, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert_eq!(
+            Capabilities::from_bytes(capabilities.to_bytes()),
+            capabilities
+        );
+        assert_eq!(capabilities.to_string(), "dht, extensions, bit 63");
+
+        let theirs = Capabilities::default().with(Capability::EXTENSIONS);
+        assert_eq!(capabilities.intersection(theirs), theirs);
+        assert!(!theirs.contains(Capability::FAST));
+    }
+}
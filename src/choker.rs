@@ -0,0 +1,161 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeSet;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the regular unchokes are recomputed.
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the optimistic unchoke moves to another peer.
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Peers unchoked for their rates, besides the optimistic unchoke.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// A peer interested in our data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub address: SocketAddr,
+    /// Our download rate from the peer while leeching, or our upload rate
+    /// to it while seeding.
+    pub rate: f64,
+    /// Snubbed peers are unchoked only when nobody else wants a slot.
+    pub snubbed: bool,
+}
+
+/// Tit-for-tat: the peers giving us the most get our upload slots, and
+/// one more, picked at random, gets a chance to prove itself.
+#[derive(Debug, Clone)]
+pub struct Choker {
+    slots: usize,
+    unchoked: BTreeSet<SocketAddr>,
+    optimistic: Option<SocketAddr>,
+    last_run: Option<Instant>,
+    last_optimistic: Option<Instant>,
+}
+
+impl Default for Choker {
+    fn default() -> Self {
+        Choker::new(DEFAULT_UPLOAD_SLOTS)
+    }
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker {
+            slots,
+            unchoked: BTreeSet::new(),
+            optimistic: None,
+            last_run: None,
+            last_optimistic: None,
+        }
+    }
+
+    /// Whether `CHOKE_INTERVAL` has passed since the last `run`.
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.last_run
+            .is_none_or(|last_run| now.duration_since(last_run) >= CHOKE_INTERVAL)
+    }
+
+    /// Unchokes the `slots` interested `candidates` with the best rates,
+    /// snubbed peers last, and every `OPTIMISTIC_INTERVAL` (or when the
+    /// current one left or earned a regular slot) picks a new optimistic
+    /// unchoke among the rest.
+    pub fn run(&mut self, candidates: &[Candidate], now: Instant) {
+        let mut ranked: Vec<&Candidate> = candidates.iter().collect();
+        ranked.sort_by(|a, b| a.snubbed.cmp(&b.snubbed).then(b.rate.total_cmp(&a.rate)));
+        self.unchoked = ranked
+            .iter()
+            .take(self.slots)
+            .map(|candidate| candidate.address)
+            .collect();
+
+        let rotate = self
+            .last_optimistic
+            .is_none_or(|last| now.duration_since(last) >= OPTIMISTIC_INTERVAL)
+            || self.optimistic.is_none_or(|optimistic| {
+                self.unchoked.contains(&optimistic)
+                    || !candidates
+                        .iter()
+                        .any(|candidate| candidate.address == optimistic)
+            });
+        if rotate {
+            let choked: Vec<&Candidate> = ranked.iter().skip(self.slots).copied().collect();
+            let eager: Vec<&Candidate> = choked
+                .iter()
+                .filter(|candidate| !candidate.snubbed)
+                .copied()
+                .collect();
+            let pool = if eager.is_empty() { choked } else { eager };
+            self.optimistic = (!pool.is_empty()).then(|| {
+                let pick = RandomState::new().hash_one(SystemTime::now()) as usize % pool.len();
+                pool[pick].address
+            });
+            self.last_optimistic = Some(now);
+        }
+        self.last_run = Some(now);
+    }
+
+    /// Unchokes a newly interested peer straight away while a regular slot
+    /// is free, rather than at the next `run`.
+    pub fn unchoke_if_free(&mut self, address: SocketAddr) {
+        if self.unchoked.len() < self.slots {
+            self.unchoked.insert(address);
+        }
+    }
+
+    /// Forgets a peer that disconnected, freeing its slot.
+    pub fn remove(&mut self, address: &SocketAddr) {
+        self.unchoked.remove(address);
+        if self.optimistic == Some(*address) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Peers holding a regular upload slot.
+    pub fn unchoked(&self) -> &BTreeSet<SocketAddr> {
+        &self.unchoked
+    }
+
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    pub fn is_unchoked(&self, address: &SocketAddr) -> bool {
+        self.unchoked.contains(address) || self.optimistic == Some(*address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchokes_the_fastest_and_one_optimistic_peer() {
+        let candidate = |port, rate, snubbed| Candidate {
+            address: SocketAddr::from(([10, 0, 0, 1], port)),
+            rate,
+            snubbed,
+        };
+        let candidates = [
+            candidate(1, 10.0, false),
+            candidate(2, 500.0, true),
+            candidate(3, 30.0, false),
+            candidate(4, 20.0, false),
+        ];
+        let mut choker = Choker::new(2);
+        let start = Instant::now();
+        assert!(choker.is_due(start));
+        choker.run(&candidates, start);
+        assert!(!choker.is_due(start + Duration::from_secs(5)));
+
+        let unchoked: Vec<u16> = candidates
+            .iter()
+            .filter(|candidate| choker.is_unchoked(&candidate.address))
+            .map(|candidate| candidate.address.port())
+            .collect();
+        // The snubbed peer only gets a slot if nobody else wants one.
+        assert_eq!(unchoked, [1, 3, 4]);
+    }
+}
@@ -0,0 +1,510 @@
+use super::{
+    announce_request, is_transient, load_torrent, read_input, runtime, send_announce,
+    torrent_options, tracker_clients, Announced, Options, TrackerClients,
+};
+use anyhow::{anyhow, Result};
+use crab_torrent::error_log::ErrorLog;
+use crab_torrent::event_log::EventLog;
+use crab_torrent::info_hash::InfoHash;
+use crab_torrent::peer_id::SessionIdentity;
+use crab_torrent::peer_priority::canonical_priority;
+use crab_torrent::reliability::TrackerReliability;
+use crab_torrent::resume::{ResumeData, ResumeStore};
+use crab_torrent::schedule::{random_jitter, Backoff, BackoffPolicy, Clock, SystemClock};
+use crab_torrent::torrent::Torrent;
+use crab_torrent::tracker::{
+    AnnounceEvent, AnnounceLifecycle, AnnounceResponse, Peer, PeerListOptions, TrackerTiers,
+};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::task::JoinSet;
+
+/// How long to wait before retrying after a round in which every announce
+/// failed.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Every tracker that was not skipped for other reasons asked us to wait:
+/// the soonest, `tracker`, allows an announce at `retry_at`.
+#[derive(Debug)]
+pub struct TooSoon {
+    pub tracker: String,
+    pub wait: u64,
+    pub retry_at: u64,
+}
+
+impl fmt::Display for TooSoon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} allows the next announce in {} s",
+            self.tracker, self.wait
+        )
+    }
+}
+
+impl std::error::Error for TooSoon {}
+
+/// What a line typed while announcing asks for.
+enum ConsoleCommand {
+    /// Re-announce now, or as soon as the trackers allow.
+    Reannounce,
+    Stop,
+}
+
+/// Per-session announce state shared by every round.
+pub struct Announcer {
+    clients: TrackerClients,
+    identity: SessionIdentity,
+    peer_list: PeerListOptions,
+    tiers: TrackerTiers,
+    reliability: TrackerReliability,
+    /// Events reported so far, per infohash and tracker.
+    lifecycles: HashMap<(InfoHash, String), AnnounceLifecycle>,
+    clock: SystemClock,
+    events: EventLog,
+    /// The last warning message from each tracker, so a repeated warning
+    /// is logged once.
+    warnings: HashMap<String, String>,
+    backoff_policy: BackoffPolicy,
+    /// Trackers retried later after timeouts or server errors.
+    backoffs: HashMap<String, Backoff>,
+    /// Our address as trackers last reported it (BEP 24).
+    external_ip: Option<IpAddr>,
+}
+
+/// Announces the torrent, then keeps re-announcing on the tracker's
+/// interval until Enter is pressed or stdin closes, and finally tells the
+/// trackers we stopped. Typing `r` re-announces early, though never sooner
+/// than a tracker's `min interval` allows. Trackers are tried tier by tier
+/// per BEP 12.
+pub fn run(torrent_name: &str, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+    runtime()?.block_on(announce_until_stopped(&torrent, options))
+}
+
+/// Like `run`, for a bare bencoded info dictionary and the trackers to
+/// announce it to, each tracker in its own tier.
+pub fn run_info(info_name: &str, trackers: &[String], options: &Options) -> Result<()> {
+    let info_bytes = read_input(info_name, options)?;
+    let tiers = trackers
+        .iter()
+        .map(|tracker| vec![tracker.clone()])
+        .collect();
+    let torrent = Torrent::from_info_bytes(info_bytes, tiers)?;
+    runtime()?.block_on(announce_until_stopped(&torrent, options))
+}
+
+async fn announce_until_stopped(torrent: &Torrent, options: &Options) -> Result<()> {
+    let options = &torrent_options(options, &torrent.info_hash())?;
+    let store = ResumeStore::default_location();
+    let mut announcer = Announcer::new(
+        options,
+        torrent.tracker_tiers(),
+        &torrent.info_hash(),
+        &store.load(&torrent.info_hash())?,
+    )
+    .await?;
+    announcer.events.record("added")?;
+    let mut errors = ErrorLog::default();
+    let mut known_peers = BTreeSet::new();
+    let mut commands = console_commands();
+    let mut first_round = true;
+    // When each infohash is next due; missing means now.
+    let mut due: HashMap<InfoHash, u64> = HashMap::new();
+
+    loop {
+        // Reloaded every round so the reported totals stay current.
+        let mut totals = store.load(&torrent.info_hash())?;
+        let left = totals.bytes_left(torrent.total_size());
+
+        for info_hash in torrent.announce_hashes() {
+            if due
+                .get(&info_hash)
+                .is_some_and(|&at| at > announcer.clock.now())
+            {
+                continue;
+            }
+
+            let (tracker, response) = match announcer.announce(&info_hash, &mut totals, left).await
+            {
+                Ok(answered) => answered,
+                Err(error) => match error.downcast::<TooSoon>() {
+                    // Restarted or asked to re-announce too early: wait
+                    // rather than risk a ban for hammering the tracker.
+                    Ok(too_soon) => {
+                        println!("{}: {}, waiting", info_hash, too_soon);
+                        due.insert(info_hash, too_soon.retry_at);
+                        continue;
+                    }
+                    Err(error) if first_round && !is_transient(&error) => return Err(error),
+                    Err(error) => {
+                        errors.error(&info_hash.to_string(), &format!("{:#}", error));
+                        let retry = announcer
+                            .next_retry()
+                            .unwrap_or_else(|| announcer.clock.now() + RETRY_INTERVAL.as_secs());
+                        due.insert(info_hash, retry);
+                        continue;
+                    }
+                },
+            };
+
+            let next = totals
+                .schedule(&info_hash, &tracker)
+                .next_announce(random_jitter())
+                .unwrap_or_else(|| announcer.clock.now() + RETRY_INTERVAL.as_secs());
+            due.insert(info_hash, next);
+            totals.tracker_stats_mut(&info_hash, &tracker).next_announce = Some(next);
+
+            if first_round {
+                println!("tracker:   {}", tracker);
+                print_response(
+                    &info_hash,
+                    &response,
+                    announcer.external_ip,
+                    announcer.identity.port,
+                );
+            } else {
+                let new_peers = response
+                    .peers
+                    .iter()
+                    .filter(|peer| !known_peers.contains(&peer_address(peer)))
+                    .count();
+                println!(
+                    "{}: re-announced to {}, {} peers ({} new)",
+                    info_hash,
+                    tracker,
+                    response.peers.len(),
+                    new_peers
+                );
+                if let Some(warning) = &response.warning_message {
+                    eprintln!("{}: warning from {}: {}", info_hash, tracker, warning);
+                }
+            }
+            known_peers.extend(response.peers.iter().map(peer_address));
+        }
+
+        store.save(&torrent.info_hash(), &totals)?;
+        if first_round {
+            println!("re-announcing until Enter is pressed; type r and Enter to re-announce now");
+            first_round = false;
+        }
+
+        let next_due = due.values().min().copied().unwrap_or(0);
+        let wait = Duration::from_secs(next_due.saturating_sub(announcer.clock.now()));
+        // Stdin closing ends the wait just like Enter does.
+        match tokio::time::timeout(wait, commands.recv()).await {
+            Ok(Some(ConsoleCommand::Reannounce)) => due.clear(),
+            Ok(_) => break,
+            Err(_) => {}
+        }
+    }
+
+    for line in errors.flush() {
+        eprintln!("{}", line);
+    }
+
+    let totals = store.load(&torrent.info_hash())?;
+    let left = totals.bytes_left(torrent.total_size());
+    announcer.stop(&totals, left).await;
+    announcer.events.record("stopped")?;
+
+    Ok(())
+}
+
+impl Announcer {
+    /// An announcer for the trackers in `tiers`, logging to the event log
+    /// of `info_hash`. Trackers `totals` records as moved get clients too.
+    pub async fn new(
+        options: &Options,
+        mut tiers: Vec<Vec<String>>,
+        info_hash: &InfoHash,
+        totals: &ResumeData,
+    ) -> Result<Self> {
+        // A torrent without trackers has an empty `announce`.
+        for tier in &mut tiers {
+            tier.retain(|tracker| !tracker.is_empty());
+        }
+        let mut tiers = TrackerTiers::new(tiers);
+        let reliability = TrackerReliability::load()?;
+        if options.prefer_reliable_trackers {
+            reliability.reorder_tiers(tiers.tiers_mut());
+        }
+
+        let mut trackers = tiers.tiers().concat();
+        trackers.extend(totals.redirects.values().cloned());
+
+        Ok(Announcer {
+            clients: tracker_clients(options, &trackers, None).await?,
+            identity: options.identity,
+            peer_list: options.peer_list,
+            tiers,
+            reliability,
+            lifecycles: HashMap::new(),
+            clock: SystemClock,
+            events: EventLog::for_torrent(info_hash),
+            warnings: HashMap::new(),
+            backoff_policy: options.backoff,
+            backoffs: HashMap::new(),
+            external_ip: None,
+        })
+    }
+
+    /// Announces `port` rather than the `--port` option, e.g. the one we
+    /// ended up listening on.
+    pub fn port(mut self, port: u16) -> Self {
+        self.identity.port = port;
+        self
+    }
+
+    pub fn has_trackers(&self) -> bool {
+        !self.tiers.tiers().is_empty()
+    }
+
+    /// Announces `info_hash` to the first tracker that answers, skipping
+    /// any that asked us to wait longer. The one that answers is promoted
+    /// within its tier and its schedule recorded in `totals`.
+    pub async fn announce(
+        &mut self,
+        info_hash: &InfoHash,
+        totals: &mut ResumeData,
+        left: u64,
+    ) -> Result<(String, AnnounceResponse)> {
+        let mut last_error = None;
+        let mut too_soon: Option<TooSoon> = None;
+
+        for (tier, index, tracker) in self.tiers.candidates() {
+            let backoff = self.backoffs.get(&tracker).copied().unwrap_or_default();
+            if backoff.remaining(&self.clock) > 0 {
+                last_error = Some(anyhow!(
+                    "{} is retried in {} s after {} failures",
+                    tracker,
+                    backoff.remaining(&self.clock),
+                    backoff.failures
+                ));
+                continue;
+            }
+
+            let schedule = totals.schedule(info_hash, &tracker);
+            if !schedule.may_announce(&self.clock) {
+                let wait = schedule.remaining(&self.clock);
+                if too_soon.as_ref().is_none_or(|soonest| wait < soonest.wait) {
+                    too_soon = Some(TooSoon {
+                        tracker: tracker.clone(),
+                        wait,
+                        retry_at: self.clock.now() + wait,
+                    });
+                }
+                continue;
+            }
+
+            let lifecycle = self
+                .lifecycles
+                .entry((*info_hash, tracker.clone()))
+                .or_default();
+            // Only commit the event once the tracker has acknowledged it.
+            let mut attempt = *lifecycle;
+            let event = attempt.next_event(left);
+            let announced_at = self.clock.now();
+            let target = totals.announce_target(&tracker).to_string();
+            let request = announce_request(
+                &target,
+                info_hash,
+                &self.identity,
+                &self.peer_list,
+                totals,
+                left,
+                event,
+            );
+
+            let response = send_announce(&self.clients, &target, &request).await;
+            self.reliability.record(&tracker, response.is_ok());
+            self.reliability.save()?;
+
+            match response {
+                Ok(Announced { response, moved_to }) => {
+                    *lifecycle = attempt;
+                    self.backoffs.remove(&tracker);
+                    if let Some(moved_to) = moved_to.filter(|moved_to| *moved_to != target) {
+                        self.events.record(format!(
+                            "tracker {} moved permanently to {}",
+                            tracker, moved_to
+                        ))?;
+                        println!(
+                            "{}: moved permanently to {}, consider updating the torrent",
+                            tracker, moved_to
+                        );
+                        totals.redirects.insert(tracker.clone(), moved_to);
+                    }
+                    if event == AnnounceEvent::Completed {
+                        self.events
+                            .record(format!("completed, reported to {}", tracker))?;
+                    }
+                    if let Some(warning) = &response.warning_message {
+                        if self.warnings.get(&tracker) != Some(warning) {
+                            self.events
+                                .record(format!("tracker {} warning: {}", tracker, warning))?;
+                            self.warnings.insert(tracker.clone(), warning.clone());
+                        }
+                    }
+                    if let Some(external_ip) = response.external_ip {
+                        if self.external_ip != Some(external_ip) {
+                            self.events.record(format!(
+                                "tracker {} reports our address as {}",
+                                tracker, external_ip
+                            ))?;
+                            self.external_ip = Some(external_ip);
+                        }
+                    }
+                    if let Some(tracker_id) = response.tracker_id.clone() {
+                        totals.set_tracker_id(info_hash, &target, tracker_id);
+                    }
+                    totals
+                        .tracker_stats_mut(info_hash, &tracker)
+                        .record_success(announced_at, &response);
+                    totals.schedule_mut(info_hash, &tracker).record(
+                        announced_at,
+                        response.interval,
+                        response.min_interval,
+                    );
+                    self.tiers.promote(tier, index);
+                    return Ok((tracker, response));
+                }
+                Err(error) => {
+                    if is_transient(&error) {
+                        self.backoffs.entry(tracker.clone()).or_default().failed(
+                            announced_at,
+                            &self.backoff_policy,
+                            random_jitter(),
+                        );
+                    }
+                    totals
+                        .tracker_stats_mut(info_hash, &tracker)
+                        .record_failure(announced_at, format!("{:#}", error));
+                    self.events
+                        .record(format!("tracker {}: {}", tracker, error))?;
+                    last_error = Some(error.context(tracker));
+                }
+            }
+        }
+
+        Err(last_error
+            .or_else(|| too_soon.map(anyhow::Error::new))
+            .unwrap_or_else(|| anyhow!("the torrent has no trackers")))
+    }
+
+    /// The earliest time a backed-off tracker may be retried.
+    pub fn next_retry(&self) -> Option<u64> {
+        self.backoffs
+            .values()
+            .filter_map(|backoff| backoff.retry_at)
+            .min()
+    }
+
+    /// Sends `stopped` to every tracker told we started, so none hands out
+    /// an address that won't answer. The trackers are told concurrently.
+    pub async fn stop(&mut self, totals: &ResumeData, left: u64) {
+        let mut stopping = JoinSet::new();
+        for ((info_hash, tracker), lifecycle) in &mut self.lifecycles {
+            let Some(event) = lifecycle.stop() else {
+                continue;
+            };
+            let target = totals.announce_target(tracker).to_string();
+            let request = announce_request(
+                &target,
+                info_hash,
+                &self.identity,
+                &self.peer_list,
+                totals,
+                left,
+                event,
+            );
+            let clients = self.clients.clone();
+            let tracker = tracker.clone();
+            stopping.spawn(async move {
+                let sent = send_announce(&clients, &target, &request).await;
+                (tracker, sent)
+            });
+        }
+
+        while let Some(Ok((tracker, sent))) = stopping.join_next().await {
+            if let Err(error) = sent {
+                eprintln!("{}: stopped announce failed: {}", tracker, error);
+            }
+        }
+    }
+}
+
+/// Reads commands from stdin: `r` re-announces, any other line stops, as
+/// does stdin closing.
+fn console_commands() -> UnboundedReceiver<ConsoleCommand> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            match line {
+                Ok(line) if line.trim() == "r" => {
+                    if sender.send(ConsoleCommand::Reannounce).is_err() {
+                        return;
+                    }
+                }
+                _ => break,
+            }
+        }
+        let _ = sender.send(ConsoleCommand::Stop);
+    });
+    receiver
+}
+
+/// Prints the response, with the peers in BEP 40 canonical priority order
+/// once we know our external address.
+fn print_response(
+    info_hash: &InfoHash,
+    response: &AnnounceResponse,
+    external_ip: Option<IpAddr>,
+    port: u16,
+) {
+    println!("infohash:  {}", info_hash);
+    println!("interval:  {} s", response.interval);
+    if let Some(seeders) = response.complete {
+        println!("seeders:   {}", seeders);
+    }
+    if let Some(leechers) = response.incomplete {
+        println!("leechers:  {}", leechers);
+    }
+    if let Some(warning) = &response.warning_message {
+        println!("warning:   {}", warning);
+    }
+    if let Some(external_ip) = external_ip {
+        println!("external:  {}", external_ip);
+    }
+
+    let mut peers: Vec<&Peer> = response.peers.iter().collect();
+    if let Some(external_ip) = external_ip {
+        let ours = SocketAddr::new(external_ip, port);
+        // Peers given by host name have no priority and go last.
+        peers.sort_by_key(|peer| {
+            Reverse(
+                peer.ip
+                    .parse()
+                    .map(|ip| canonical_priority(ours, SocketAddr::new(ip, peer.port)))
+                    .ok(),
+            )
+        });
+    }
+    for peer in peers {
+        println!("peer:      {}", peer_address(peer));
+    }
+}
+
+fn peer_address(peer: &Peer) -> String {
+    if peer.ip.contains(':') {
+        format!("[{}]:{}", peer.ip, peer.port)
+    } else {
+        format!("{}:{}", peer.ip, peer.port)
+    }
+}
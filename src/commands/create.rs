@@ -0,0 +1,83 @@
+use anyhow::{anyhow, Result};
+use crab_torrent::builder::{MetaVersion, TorrentBuilder};
+use crab_torrent::units;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Creates `<name>.torrent` from `[flags] <path> <announce_url>`. Hashing
+/// progress is kept in `<output>.part`, so rerunning an interrupted create
+/// picks up where it stopped.
+pub fn run(args: &[String]) -> Result<()> {
+    let mut align = false;
+    let mut meta_version = MetaVersion::V1;
+    let mut comment = None;
+    let mut created_by = None;
+    let mut date = true;
+    let mut piece_length = None;
+    let mut private = false;
+    let mut source = None;
+    let mut positional = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| anyhow!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--align" => align = true,
+            "--v2" => meta_version = MetaVersion::V2,
+            "--hybrid" => meta_version = MetaVersion::Hybrid,
+            "--comment" => comment = Some(value()?),
+            "--created-by" => created_by = Some(value()?),
+            "--no-date" => date = false,
+            "--piece-length" => piece_length = Some(units::parse_size(&value()?)?),
+            "--private" => private = true,
+            "--source" => source = Some(value()?),
+            flag if flag.starts_with("--") => return Err(anyhow!("unknown flag {}", flag)),
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    let [path, announce] = positional[..] else {
+        return Err(anyhow!("create needs a path and an announce URL"));
+    };
+
+    let mut builder = TorrentBuilder::new(path)
+        .announce(announce)
+        .align_to_pieces(align)
+        .meta_version(meta_version)
+        .private(private);
+    if let Some(comment) = comment {
+        builder = builder.comment(comment);
+    }
+    if let Some(created_by) = created_by {
+        builder = builder.created_by(created_by);
+    }
+    if !date {
+        builder = builder.creation_date(None);
+    }
+    if let Some(piece_length) = piece_length {
+        builder = builder.piece_length(piece_length);
+    }
+    if let Some(source) = source {
+        builder = builder.source(source);
+    }
+
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "output".to_string());
+    let output = format!("{}.torrent", name);
+    let partial = format!("{}.tmp", output);
+
+    builder
+        .checkpoint(format!("{}.part", output))
+        .build_to(BufWriter::new(File::create(&partial)?))?;
+    fs::rename(&partial, &output)?;
+
+    println!("wrote {}", output);
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetches the internal state of a download running with `--status-port`
+/// and writes it to `file` as JSON, for attaching to bug reports. Tracker
+/// passkeys are already redacted by the download.
+pub fn run_dump(status_port: &str, file: &str) -> Result<()> {
+    let dump = fetch(status_port, "/debug")?;
+    fs::write(file, &dump).with_context(|| format!("writing {}", file))?;
+    println!("wrote {} bytes to {}", dump.len(), file);
+    Ok(())
+}
+
+/// Prints what a download running with `--status-port` knows about its
+/// connection to `address`: negotiated extensions, choke states, timers,
+/// both request queues and the last messages each way.
+pub fn run_peer(status_port: &str, address: &str) -> Result<()> {
+    let address: SocketAddr = address.parse()?;
+    let detail = fetch(status_port, &format!("/peers/{}", address))
+        .with_context(|| format!("no connection to {}", address))?;
+    println!("{}", String::from_utf8_lossy(&detail));
+    Ok(())
+}
+
+fn fetch(status_port: &str, path: &str) -> Result<Vec<u8>> {
+    let port: u16 = status_port
+        .parse()
+        .with_context(|| format!("{} is not a port", status_port))?;
+    // The status server only listens on localhost, never behind a proxy.
+    let client = reqwest::blocking::Client::builder()
+        .no_proxy()
+        .timeout(STATUS_TIMEOUT)
+        .build()?;
+    let body = client
+        .get(format!("http://127.0.0.1:{}{}", port, path))
+        .send()
+        .with_context(|| format!("no download serving its status on port {}", port))?
+        .error_for_status()?
+        .bytes()?;
+    Ok(body.to_vec())
+}
@@ -0,0 +1,32 @@
+use super::{read_input, Options};
+use anyhow::Result;
+use crab_torrent::bencode;
+use crab_torrent::torrent::hex;
+use serde_bencode::value::Value;
+use serde_json::json;
+
+/// Prints any bencoded value as JSON. Byte strings that are not UTF-8, such
+/// as `pieces`, are shown as `{"hex": "..."}`.
+pub fn run(input: &str, options: &Options) -> Result<()> {
+    let value: Value = bencode::decode(&read_input(input, options)?)?;
+    println!("{}", serde_json::to_string_pretty(&to_json(value))?);
+    Ok(())
+}
+
+fn to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Int(int) => json!(int),
+        Value::Bytes(bytes) => match String::from_utf8(bytes) {
+            Ok(string) => json!(string),
+            Err(error) => json!({ "hex": hex(error.as_bytes()) }),
+        },
+        Value::List(list) => list.into_iter().map(to_json).collect(),
+        Value::Dict(dict) => {
+            let object: serde_json::Map<_, _> = dict
+                .into_iter()
+                .map(|(key, value)| (String::from_utf8_lossy(&key).into_owned(), to_json(value)))
+                .collect();
+            serde_json::Value::Object(object)
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use super::announce::{Announcer, TooSoon, RETRY_INTERVAL};
+use super::{load_torrent, runtime, torrent_options, Options};
+use anyhow::{anyhow, Context, Result};
+use crab_torrent::bitfield::Bitfield;
+use crab_torrent::capabilities::Capability;
+use crab_torrent::connections::{ConnectionManager, Source, Strike};
+use crab_torrent::download::{run_peer, Download, PeerOptions, PeerStats};
+use crab_torrent::event_log::EventLog;
+use crab_torrent::info_hash::InfoHash;
+use crab_torrent::magnet::MagnetLink;
+use crab_torrent::metadata::fetch_metadata;
+use crab_torrent::peer::{Handshake, PeerConnection};
+use crab_torrent::rate_history::RatesReport;
+use crab_torrent::resume::{ResumeData, ResumeStore};
+use crab_torrent::schedule::{random_jitter, Clock, SystemClock};
+use crab_torrent::storage::Storage;
+use crab_torrent::torrent::Torrent;
+use crab_torrent::verify;
+use futures_util::future::{self, Either};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to look for peers discovered through peer exchange and for
+/// free connection slots.
+const DIAL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest request the status page reads, and how long it waits for it.
+const MAX_STATUS_REQUEST: usize = 8 * 1024;
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Downloads the torrent into `download_dir` from the peers its trackers
+/// return, any at `addresses` and any that connect to our port, keeping
+/// whatever is already there and verified. For a magnet link the metadata
+/// is fetched from the peers first. With `--seed`, peers are served until
+/// interrupted.
+pub fn run(
+    torrent_name: &str,
+    download_dir: &str,
+    addresses: &[String],
+    options: &Options,
+) -> Result<()> {
+    let addresses = addresses
+        .iter()
+        .map(|address| address.parse())
+        .collect::<Result<Vec<SocketAddr>, _>>()?;
+    let runtime = runtime()?;
+    let listener = runtime.block_on(listen(options))?;
+    let port = listener.local_addr()?.port();
+    let status_listener = match options.status_port {
+        Some(status_port) => {
+            let status_listener = runtime
+                .block_on(TcpListener::bind((Ipv4Addr::LOCALHOST, status_port)))
+                .with_context(|| format!("serving the status page on port {}", status_port))?;
+            println!("status at http://{}/", status_listener.local_addr()?);
+            Some(status_listener)
+        }
+        None => None,
+    };
+    let store = ResumeStore::default_location();
+    // Everything but the listener uses the torrent's own network settings.
+    let (torrent, mut announcer, totals, tracker_peers, options) =
+        if torrent_name.starts_with("magnet:") {
+            let magnet: MagnetLink = torrent_name.parse()?;
+            let mut totals = store.load(&magnet.info_hash)?;
+            let options = torrent_options(options, &magnet.info_hash)?;
+            let tiers = magnet
+                .trackers
+                .iter()
+                .map(|tracker| vec![tracker.clone()])
+                .collect();
+            let mut announcer = runtime
+                .block_on(Announcer::new(&options, tiers, &magnet.info_hash, &totals))?
+                .port(port);
+            let (torrent, tracker_peers) = runtime.block_on(fetch_torrent(
+                &magnet,
+                &addresses,
+                &mut announcer,
+                &mut totals,
+                &options,
+            ))?;
+            (torrent, announcer, totals, tracker_peers, options)
+        } else {
+            let torrent = load_torrent(torrent_name, options)?;
+            let totals = store.load(&torrent.info_hash())?;
+            let options = torrent_options(options, &torrent.info_hash())?;
+            let announcer = runtime
+                .block_on(Announcer::new(
+                    &options,
+                    torrent.tracker_tiers(),
+                    &torrent.info_hash(),
+                    &totals,
+                ))?
+                .port(port);
+            (torrent, announcer, totals, Vec::new(), options)
+        };
+    let options = &options;
+    let torrent = Arc::new(torrent);
+    if port != options.identity.port {
+        println!(
+            "{}: port {} in use, listening on {}",
+            torrent.name(),
+            options.identity.port,
+            port
+        );
+    }
+    EventLog::for_torrent(&torrent.info_hash()).record(format!("listening on port {}", port))?;
+
+    let have: Bitfield = verify::recheck(
+        &torrent,
+        Path::new(download_dir),
+        options.root_folder,
+        options.max_read_rate,
+    )?
+    .into_iter()
+    .collect();
+    let storage = Storage::new(torrent.clone(), download_dir, options.root_folder)?;
+    let download = Mutex::new(
+        Download::new(&torrent, storage, have)
+            .upload_slots(options.peer.upload_slots)
+            .max_buffer(options.peer.max_buffer),
+    );
+
+    let peer_options = &PeerOptions {
+        listen_port: Some(port),
+        ..options.peer
+    };
+
+    let info_hash = torrent.info_hash();
+    let handshake = handshake(info_hash, options);
+    // Dials `address`, or with `stream` answers a peer that connected.
+    let serve = |address: SocketAddr, stream: Option<TcpStream>| {
+        let download = &download;
+        async move {
+            let mut connected = false;
+            let result = async {
+                let mut connection = match stream {
+                    Some(stream) => {
+                        PeerConnection::accept(
+                            stream,
+                            address,
+                            &[handshake],
+                            options.encryption,
+                            HANDSHAKE_TIMEOUT,
+                        )
+                        .await?
+                    }
+                    None => {
+                        PeerConnection::dial(
+                            address,
+                            handshake,
+                            HANDSHAKE_TIMEOUT,
+                            options.encryption,
+                            &options.network,
+                        )
+                        .await?
+                    }
+                };
+                connected = true;
+                run_peer(&mut connection, download, peer_options).await
+            }
+            .await;
+            (address, connected, result)
+        }
+    };
+    let mut manager = ConnectionManager::new(options.connections)
+        .ip_filter(options.ip_filter.clone())
+        .bans(options.bans);
+    for address in addresses {
+        manager.add(info_hash, address, Source::Manual);
+    }
+    for address in tracker_peers {
+        manager.add(info_hash, address, Source::Tracker);
+    }
+    if io::stdin().is_terminal() {
+        println!("type p and Enter to list the connected peers");
+    }
+    let mut peers_requests = peers_requests();
+    let (found_sender, mut found) = mpsc::unbounded_channel();
+    let first_announces = Cell::new(announcer.has_trackers());
+    let done = Notify::new();
+    let downloading = async {
+        let mut peers = FuturesUnordered::new();
+        loop {
+            while peers_requests.try_recv().is_ok() {
+                print_peers(download.lock().unwrap().peers());
+            }
+            {
+                let mut download = download.lock().unwrap();
+                download.record_rates(Instant::now());
+                for address in download.take_holepunches() {
+                    manager.holepunch(info_hash, address);
+                }
+                for address in download.take_blamed() {
+                    if manager.strike(&info_hash, &address, Strike::HashFailure, Instant::now()) {
+                        download.drop_peer(address);
+                    }
+                }
+                if download.is_complete() {
+                    manager.finish_torrent(&info_hash);
+                } else {
+                    for address in download.take_discovered() {
+                        manager.add(info_hash, address, Source::Pex);
+                    }
+                    while let Ok(address) = found.try_recv() {
+                        manager.add(info_hash, address, Source::Tracker);
+                    }
+                }
+            }
+            peers.extend(
+                manager
+                    .next_dials(Instant::now())
+                    .into_iter()
+                    .map(|(_, address)| serve(address, None)),
+            );
+            // A seed waits for peers to connect; otherwise we stop once
+            // nobody is left to download from, after hearing back from
+            // the trackers.
+            if peers.is_empty()
+                && manager.next_retry().is_none()
+                && !first_announces.get()
+                && !peer_options.seed
+            {
+                break;
+            }
+            // With no connection open, wait out the tick for retries and
+            // incoming peers instead.
+            let finished = async {
+                if peers.is_empty() {
+                    tokio::time::sleep(DIAL_INTERVAL).await;
+                    return None;
+                }
+                tokio::time::timeout(DIAL_INTERVAL, peers.next())
+                    .await
+                    .ok()
+                    .flatten()
+            };
+            let event = match future::select(pin!(finished), pin!(listener.accept())).await {
+                Either::Left((finished, _)) => Either::Left(finished),
+                Either::Right((accepted, _)) => Either::Right(accepted),
+            };
+            if let Either::Right(accepted) = event {
+                match accepted {
+                    Ok((stream, address)) if manager.accept(info_hash, address) => {
+                        peers.push(serve(address, Some(stream)));
+                    }
+                    Ok(_) => {}
+                    Err(error) => eprintln!("accepting a peer: {}", error),
+                }
+            } else if let Either::Left(Some((address, connected, result))) = event {
+                if let Err(error) = &result {
+                    eprintln!("{}: {}", address, error);
+                }
+                if connected {
+                    manager.connected(&info_hash, &address);
+                }
+                if result.as_ref().is_err_and(|error| error.is_violation()) {
+                    manager.strike(&info_hash, &address, Strike::Violation, Instant::now());
+                }
+                manager.closed(&info_hash, &address, result.is_err(), Instant::now());
+            }
+        }
+        done.notify_one();
+    };
+    let announcing = announce_while_running(
+        &mut announcer,
+        &torrent,
+        &download,
+        totals,
+        found_sender,
+        &first_announces,
+        &done,
+    );
+    let downloading = async {
+        match status_listener {
+            Some(status_listener) => {
+                let serving = serve_status(status_listener, &torrent, &download);
+                future::select(pin!(downloading), pin!(serving)).await;
+            }
+            None => downloading.await,
+        }
+    };
+    let ((), totals) = runtime.block_on(future::join(downloading, announcing));
+
+    let blocked = manager.blocked();
+    if blocked.dials + blocked.accepts > 0 {
+        println!(
+            "{}: IP filter blocked {} dials and {} incoming peers",
+            torrent.name(),
+            blocked.dials,
+            blocked.accepts
+        );
+    }
+
+    let download = download.into_inner().unwrap();
+    store.save(&torrent.info_hash(), &totals)?;
+
+    let have = download.have();
+    if !download.dht_nodes().is_empty() {
+        println!(
+            "{}: {} DHT nodes learned from peers",
+            torrent.name(),
+            download.dht_nodes().len()
+        );
+    }
+    EventLog::for_torrent(&torrent.info_hash()).record(format!(
+        "download into {}: {}/{} pieces, {} failed hash checks",
+        download_dir,
+        have.count_ones(),
+        have.len(),
+        download.hash_failures()
+    ))?;
+    if let Some(path) = &options.piece_times {
+        let file = File::create(path).with_context(|| format!("writing {}", path.display()))?;
+        download.piece_timings().write_csv(BufWriter::new(file))?;
+    }
+
+    if !download.is_complete() {
+        return Err(anyhow!(
+            "{}: {}/{} pieces, no peer left to download the rest from",
+            torrent.name(),
+            have.count_ones(),
+            have.len()
+        ));
+    }
+    println!("{}: complete, {} pieces", torrent.name(), have.len());
+    Ok(())
+}
+
+/// Announces to the torrent's trackers until `done` is notified, sending
+/// the peers they return to `found`, then tells them we stopped.
+/// `first_announces` is cleared once every infohash has been announced,
+/// or at once without trackers. Returns `totals` brought up to date with
+/// the session and what the trackers said.
+async fn announce_while_running(
+    announcer: &mut Announcer,
+    torrent: &Torrent,
+    download: &Mutex<Download>,
+    mut totals: ResumeData,
+    found: UnboundedSender<SocketAddr>,
+    first_announces: &Cell<bool>,
+    done: &Notify,
+) -> ResumeData {
+    let (downloaded, uploaded) = (totals.downloaded, totals.uploaded);
+    // The bytes left, after updating the totals announced.
+    let update = |totals: &mut ResumeData| {
+        let download = download.lock().unwrap();
+        totals.downloaded = downloaded + download.downloaded();
+        totals.uploaded = uploaded + download.uploaded();
+        totals.left = Some(download.bytes_left());
+        download.bytes_left()
+    };
+    let clock = SystemClock;
+    // When each infohash is next due; missing means now.
+    let mut due: HashMap<InfoHash, u64> = HashMap::new();
+
+    loop {
+        let left = update(&mut totals);
+        if announcer.has_trackers() {
+            for info_hash in torrent.announce_hashes() {
+                if due.get(&info_hash).is_some_and(|&at| at > clock.now()) {
+                    continue;
+                }
+                let next = match announcer.announce(&info_hash, &mut totals, left).await {
+                    Ok((tracker, response)) => {
+                        let peers = response.peer_addrs();
+                        println!("{}: {} peers from {}", torrent.name(), peers.len(), tracker);
+                        for address in peers {
+                            let _ = found.send(address);
+                        }
+                        totals
+                            .schedule(&info_hash, &tracker)
+                            .next_announce(random_jitter())
+                    }
+                    Err(error) => match error.downcast::<TooSoon>() {
+                        Ok(too_soon) => Some(too_soon.retry_at),
+                        Err(error) => {
+                            eprintln!("{}: {:#}", info_hash, error);
+                            announcer.next_retry()
+                        }
+                    },
+                };
+                due.insert(
+                    info_hash,
+                    next.unwrap_or_else(|| clock.now() + RETRY_INTERVAL.as_secs()),
+                );
+            }
+        }
+        first_announces.set(false);
+
+        let tick = tokio::time::sleep(DIAL_INTERVAL);
+        if let Either::Left(_) = future::select(pin!(done.notified()), pin!(tick)).await {
+            break;
+        }
+    }
+
+    let left = update(&mut totals);
+    announcer.stop(&totals, left).await;
+    totals
+}
+
+/// Answers `GET /` on `listener` with the download's summary as JSON,
+/// `GET /peers/<ip:port>` with everything about one connection,
+/// `GET /rates` with its rate history, and `GET /debug` with a dump of its
+/// internal state, one client at a time.
+/// Never returns; drop it to stop serving.
+async fn serve_status(listener: TcpListener, torrent: &Torrent, download: &Mutex<Download>) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                eprintln!("status page: {}", error);
+                tokio::time::sleep(DIAL_INTERVAL).await;
+                continue;
+            }
+        };
+        let request = tokio::time::timeout(STATUS_TIMEOUT, read_request(&mut stream)).await;
+        let (status, body) = match request {
+            Ok(Ok(request)) if request.starts_with(b"GET / ") => {
+                let summary = download.lock().unwrap().to_summary(torrent);
+                let body = serde_json::to_string_pretty(&summary).expect("summaries serialize");
+                ("200 OK", body)
+            }
+            Ok(Ok(request)) if request.starts_with(b"GET /peers/") => {
+                let detail = requested_peer(&request)
+                    .and_then(|address| download.lock().unwrap().peer_detail(&address));
+                match detail {
+                    Some(detail) => (
+                        "200 OK",
+                        serde_json::to_string_pretty(&detail).expect("details serialize"),
+                    ),
+                    None => ("404 Not Found", "{}".to_string()),
+                }
+            }
+            Ok(Ok(request)) if request.starts_with(b"GET /rates ") => {
+                let history = download.lock().unwrap().rate_history().clone();
+                let report = RatesReport::new(BTreeMap::from([(torrent.info_hash(), history)]));
+                let body = serde_json::to_string(&report).expect("rates serialize");
+                ("200 OK", body)
+            }
+            Ok(Ok(request)) if request.starts_with(b"GET /debug ") => {
+                let dump = download.lock().unwrap().to_debug_dump(torrent);
+                let body = serde_json::to_string_pretty(&dump).expect("dumps serialize");
+                ("200 OK", body)
+            }
+            Ok(Ok(_)) => ("404 Not Found", "{}".to_string()),
+            _ => continue,
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+}
+
+/// The peer address in a `GET /peers/<ip:port>` request line, with the
+/// brackets of an IPv6 address percent-encoded or not.
+fn requested_peer(request: &[u8]) -> Option<SocketAddr> {
+    let request = String::from_utf8_lossy(request);
+    let path = request.split_whitespace().nth(1)?;
+    let address = urlencoding::decode(path.strip_prefix("/peers/")?).ok()?;
+    address.parse().ok()
+}
+
+/// Reads an HTTP request up to the blank line ending its headers.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 || request.len() + read > MAX_STATUS_REQUEST {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+    Ok(request)
+}
+
+/// Signals each `p` line typed on stdin.
+fn peers_requests() -> UnboundedReceiver<()> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines().map_while(Result::ok) {
+            if line.trim() == "p" && sender.send(()).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// One line per connection: totals in KiB, rates in KiB/s, then the
+/// requests in flight, pieces and failed hash checks the peer completed,
+/// and flags: `i`ncoming, `e`ncrypted, `I`nterested in us, unchoked by
+/// `u`s, unchoking `U`s and `s`nubbed.
+fn print_peers(peers: &BTreeMap<SocketAddr, PeerStats>) {
+    println!(
+        "{:<22} {:<8} {:>6} {:>9} {:>9} {:>8} {:>8} {:>5} {:>6} {:>5} flags",
+        "peer", "client", "age", "down", "up", "down/s", "up/s", "queue", "pieces", "fails"
+    );
+    for (address, stats) in peers {
+        let flags: String = [
+            (stats.incoming, 'i'),
+            (stats.encrypted, 'e'),
+            (stats.interested, 'I'),
+            (!stats.am_choking, 'u'),
+            (!stats.peer_choking, 'U'),
+            (stats.snubbed, 's'),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| *flag)
+        .collect();
+        println!(
+            "{:<22} {:<8} {:>5}s {:>9} {:>9} {:>8.1} {:>8.1} {:>5} {:>6} {:>5} {}",
+            address.to_string(),
+            String::from_utf8_lossy(&stats.peer_id.0[..8]),
+            stats.age().as_secs(),
+            stats.downloaded / 1024,
+            stats.uploaded / 1024,
+            stats.download_rate / 1024.0,
+            stats.upload_rate / 1024.0,
+            stats.outstanding,
+            stats.pieces_received,
+            stats.hash_failures,
+            flags
+        );
+    }
+}
+
+/// Listens on the first free port from `--port` to the end of its range,
+/// or once every one is taken, on any free port.
+async fn listen(options: &Options) -> Result<TcpListener> {
+    let ip = options
+        .network
+        .bind_address
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let last = options.last_port.unwrap_or(options.identity.port);
+    let mut port = options.identity.port;
+    loop {
+        match TcpListener::bind(SocketAddr::new(ip, port)).await {
+            Err(error) if error.kind() == io::ErrorKind::AddrInUse && port != 0 => {
+                port = if port < last { port + 1 } else { 0 };
+            }
+            bound => {
+                return bound.with_context(|| format!("listening on {}", SocketAddr::new(ip, port)))
+            }
+        }
+    }
+}
+
+/// Our handshake, offering the extension protocol for ut_metadata and,
+/// with `--dht-port`, our DHT node.
+fn handshake(info_hash: InfoHash, options: &Options) -> Handshake {
+    let mut handshake = Handshake::new(info_hash, options.identity.peer_id);
+    handshake.capabilities.insert(Capability::EXTENSIONS);
+    if options.peer.dht_port.is_some() {
+        handshake.capabilities.insert(Capability::DHT);
+    }
+    handshake
+}
+
+/// Fetches the info dictionary from the first of `addresses`, or of the
+/// peers the magnet link's trackers return, that has it and builds a
+/// torrent from it and the magnet link's trackers. The trackers' peers are
+/// returned too, as they won't be announced to again for a while.
+async fn fetch_torrent(
+    magnet: &MagnetLink,
+    addresses: &[SocketAddr],
+    announcer: &mut Announcer,
+    totals: &mut ResumeData,
+    options: &Options,
+) -> Result<(Torrent, Vec<SocketAddr>)> {
+    let mut tracker_peers = Vec::new();
+    if announcer.has_trackers() {
+        // The size is unknown until the metadata arrives; any `left` above
+        // zero keeps us a leecher.
+        match announcer.announce(&magnet.info_hash, totals, 1).await {
+            Ok((tracker, response)) => {
+                println!(
+                    "{}: {} peers from {}",
+                    magnet.info_hash,
+                    response.peers.len(),
+                    tracker
+                );
+                tracker_peers = response.peer_addrs();
+            }
+            Err(error) => eprintln!("{}: {:#}", magnet.info_hash, error),
+        }
+    }
+
+    let handshake = handshake(magnet.info_hash, options);
+    let mut fetched = None;
+    for &address in addresses.iter().chain(&tracker_peers) {
+        let fetching = async {
+            let mut connection = PeerConnection::dial(
+                address,
+                handshake,
+                HANDSHAKE_TIMEOUT,
+                options.encryption,
+                &options.network,
+            )
+            .await?;
+            fetch_metadata(&mut connection, &magnet.info_hash, METADATA_TIMEOUT).await
+        };
+        match fetching.await {
+            Ok(info_bytes) => {
+                fetched = Some((address, info_bytes));
+                break;
+            }
+            Err(error) => eprintln!("{}: {}", address, error),
+        }
+    }
+    let Some((address, info_bytes)) = fetched else {
+        return Err(anyhow!(
+            "no peer sent the metadata for {}",
+            magnet.info_hash
+        ));
+    };
+
+    let trackers = magnet
+        .trackers
+        .iter()
+        .map(|tracker| vec![tracker.clone()])
+        .collect();
+    let torrent = Torrent::from_info_bytes(info_bytes, trackers)?;
+    println!("{}: metadata from {}", torrent.name(), address);
+    Ok((torrent, tracker_peers))
+}
@@ -0,0 +1,49 @@
+use super::{load_torrent, runtime, torrent_options, Options};
+use anyhow::Result;
+use crab_torrent::mse::{CRYPTO_PLAINTEXT, CRYPTO_RC4};
+use crab_torrent::peer::{Handshake, PeerConnection};
+use crab_torrent::torrent::hex;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to one peer and prints what its handshake says about it.
+/// With `encrypt`, the MSE handshake comes first, offering both modes.
+pub fn run(torrent_name: &str, address: &str, encrypt: bool, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+    let options = &torrent_options(options, &torrent.info_hash())?;
+    let address: SocketAddr = address.parse()?;
+    let handshake = Handshake::new(torrent.info_hash(), options.identity.peer_id);
+
+    let connection = if encrypt {
+        runtime()?.block_on(PeerConnection::connect_encrypted(
+            address,
+            handshake,
+            HANDSHAKE_TIMEOUT,
+            CRYPTO_PLAINTEXT | CRYPTO_RC4,
+            &options.network,
+        ))?
+    } else {
+        runtime()?.block_on(PeerConnection::connect(
+            address,
+            handshake,
+            HANDSHAKE_TIMEOUT,
+            &options.network,
+        ))?
+    };
+    println!("peer:      {}", connection.address());
+    println!("peer id:   {}", connection.peer_id());
+    let reserved = hex(&connection.capabilities().to_bytes());
+    println!("reserved:  {} ({})", reserved, connection.capabilities());
+    if encrypt {
+        let mode = if connection.is_encrypted() {
+            "rc4"
+        } else {
+            "plaintext"
+        };
+        println!("encryption: {}", mode);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use super::{load_torrent, Options};
+use anyhow::Result;
+
+pub fn run(torrent_name: &str, json: bool, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+    let summary = torrent.to_summary();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+        return Ok(());
+    }
+
+    println!("name:          {}", summary.name);
+    println!("infohash:      {}", summary.info_hash);
+    if let Some(info_hash_v2) = &summary.info_hash_v2 {
+        println!("infohash v2:   {}", info_hash_v2);
+    }
+    println!("total size:    {}", summary.total_size);
+    println!(
+        "pieces:        {} x {}",
+        summary.piece_count, summary.piece_length
+    );
+    println!("private:       {}", summary.private);
+    if let Some(comment) = &summary.comment {
+        println!("comment:       {}", comment);
+    }
+    if let Some(source) = &summary.source {
+        println!("source:        {}", source);
+    }
+    for similar in &summary.similar {
+        println!("similar:       {}", similar);
+    }
+    for collection in &summary.collections {
+        println!("collection:    {}", collection);
+    }
+    if let Some(created_by) = &summary.created_by {
+        println!("created by:    {}", created_by);
+    }
+    if let Some(creation_date) = summary.creation_date {
+        println!("creation date: {}", creation_date);
+    }
+    for (tier_index, tier) in summary.trackers.iter().enumerate() {
+        println!("tier {}:        {}", tier_index, tier.join(" "));
+    }
+    for file in &summary.files {
+        println!("{:>14} {}", file.length, file.path);
+    }
+
+    Ok(())
+}
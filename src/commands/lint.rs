@@ -0,0 +1,18 @@
+use super::{load_torrent, Options};
+use anyhow::{anyhow, Result};
+
+pub fn run(torrent_name: &str, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+    let violations = torrent.validate();
+
+    if violations.is_empty() {
+        println!("{}: ok", torrent_name);
+        return Ok(());
+    }
+
+    for violation in &violations {
+        println!("{}: {}", torrent_name, violation);
+    }
+
+    Err(anyhow!("{} problem(s) found", violations.len()))
+}
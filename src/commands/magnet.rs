@@ -0,0 +1,10 @@
+use super::{load_torrent, Options};
+use anyhow::Result;
+
+pub fn run(torrent_name: &str, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+
+    println!("{}", torrent.magnet_uri());
+
+    Ok(())
+}
@@ -0,0 +1,631 @@
+pub mod announce;
+pub mod create;
+pub mod debug;
+pub mod decode;
+pub mod download;
+pub mod handshake;
+pub mod info;
+pub mod lint;
+pub mod magnet;
+pub mod network;
+pub mod probe;
+pub mod recheck;
+pub mod scrape;
+pub mod status;
+
+use anyhow::{anyhow, Context, Result};
+use crab_torrent::connections::{BanPolicy, ConnectionLimits};
+use crab_torrent::download::PeerOptions;
+use crab_torrent::info_hash::InfoHash;
+use crab_torrent::ip_filter::IpFilter;
+use crab_torrent::mse::EncryptionPolicy;
+use crab_torrent::net::{
+    authorize, Credential, NetworkSettings, Proxy, TlsBackend, TrackerCredential,
+};
+use crab_torrent::peer_id::{PeerId, SessionIdentity};
+use crab_torrent::resume::{ResumeData, ResumeStore};
+use crab_torrent::sanitize::RootFolder;
+use crab_torrent::schedule::BackoffPolicy;
+use crab_torrent::torrent::Torrent;
+use crab_torrent::tracker::{
+    parse_scrape_response, scrape_url, AnnounceError, AnnounceEvent, AnnounceRequest,
+    AnnounceResponse, PeerListOptions, ScrapeStats,
+};
+use crab_torrent::udp_tracker::{UdpTracker, UdpTrackerError};
+use crab_torrent::units;
+use futures_util::future::join_all;
+use reqwest::header::{HeaderName, HeaderValue, LOCATION};
+use reqwest::StatusCode;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use url::Url;
+
+/// Redirects followed on a single announce before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+const OPTIONS: &[&str] = &[
+    "--proxy",
+    "--bind",
+    "--doh",
+    "--tls",
+    "--ca-cert",
+    "--pin-cert",
+    "--cookie",
+    "--max-read-rate",
+    "--root-folder",
+    "--peer-id-prefix",
+    "--user-agent",
+    "--numwant",
+    "--retry-initial",
+    "--retry-max",
+    "--tracker-auth",
+    "--tracker-cookie",
+    "--tracker-header",
+    "--request-queue",
+    "--peer-timeout",
+    "--snub-timeout",
+    "--upload-slots",
+    "--max-buffer",
+    "--dht-port",
+    "--max-connections",
+    "--max-peers",
+    "--max-hash-failures",
+    "--max-violations",
+    "--max-dial-failures",
+    "--forgive-after",
+    "--port",
+    "--status-port",
+    "--piece-times",
+    "--ip-filter",
+    "--encryption",
+];
+
+/// Global options that take no value.
+const FLAGS: &[&str] = &[
+    "--prefer-reliable",
+    "--no-compact",
+    "--peer-ids",
+    "--dual-stack",
+    "--seed",
+    "--super-seed",
+];
+
+/// Options accepted before any subcommand.
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    pub network: NetworkSettings,
+    /// Cookie header sent when fetching a `.torrent` from a URL.
+    pub cookie: Option<String>,
+    /// Disk read limit for rechecks, in bytes per second.
+    pub max_read_rate: Option<u64>,
+    /// Where a torrent's files are expected under the download directory.
+    pub root_folder: RootFolder,
+    /// Try trackers with the best announce history first instead of in
+    /// the torrent's order.
+    pub prefer_reliable_trackers: bool,
+    /// The peer id and key announced to trackers, generated once per
+    /// session.
+    pub identity: SessionIdentity,
+    /// The last port of a `--port` range: while `identity.port` is in
+    /// use, the ports after it are tried up to this one.
+    pub last_port: Option<u16>,
+    /// Localhost port to serve a running download's status on.
+    pub status_port: Option<u16>,
+    /// CSV file to write each piece's timing to once a download ends.
+    pub piece_times: Option<PathBuf>,
+    /// `compact`, `numwant` and `no_peer_id` for every announce.
+    pub peer_list: PeerListOptions,
+    /// How quickly to retry trackers that time out or fail with 5xx.
+    pub backoff: BackoffPolicy,
+    /// Request queue depth and idle timeout for peer connections.
+    pub peer: PeerOptions,
+    /// Caps on open peer connections, overall and per torrent.
+    pub connections: ConnectionLimits,
+    /// When misbehaving or unreachable peers are banned, and for how long.
+    pub bans: BanPolicy,
+    /// Ranges of every `--ip-filter` list, never connected to.
+    pub ip_filter: IpFilter,
+    /// Whether peer connections, dialed and accepted, are encrypted.
+    pub encryption: EncryptionPolicy,
+}
+
+/// Parses `<url_prefix>=<user>:<password>`, `<url_prefix>=<cookie>` or
+/// `<url_prefix>=<Name>: <value>` for the matching `--tracker-*` flag.
+fn tracker_credential(flag: &str, value: &str) -> Result<TrackerCredential> {
+    let (prefix, rest) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("{} needs <tracker_url_prefix>=<value>", flag))?;
+    let credential = match flag {
+        "--tracker-auth" => {
+            let (username, password) = match rest.split_once(':') {
+                Some((username, password)) => (username, Some(password.to_string())),
+                None => (rest, None),
+            };
+            Credential::Basic {
+                username: username.to_string(),
+                password,
+            }
+        }
+        "--tracker-cookie" => Credential::Cookie(rest.to_string()),
+        _ => {
+            let (name, value) = rest
+                .split_once(':')
+                .ok_or_else(|| anyhow!("--tracker-header needs <url_prefix>=<Name>: <value>"))?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes())?;
+            let value = HeaderValue::from_str(value.trim())?;
+            Credential::Header {
+                name: name.to_string(),
+                value: value.to_str()?.to_string(),
+            }
+        }
+    };
+    Ok(TrackerCredential {
+        prefix: prefix.to_string(),
+        credential,
+    })
+}
+
+/// A runtime for the async tracker client. Torrents must be loaded before
+/// entering it: fetching one from a URL uses a blocking client, which can't
+/// run inside a runtime.
+pub fn runtime() -> Result<Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?)
+}
+
+/// Loads a torrent from a file path, an HTTP(S) URL, or `-` for stdin.
+pub fn load_torrent(torrent_name: &str, options: &Options) -> Result<Torrent> {
+    Torrent::new(read_input(torrent_name, options)?)
+}
+
+/// Reads raw bytes from a file path, an HTTP(S) URL, or `-` for stdin.
+pub fn read_input(name: &str, options: &Options) -> Result<Vec<u8>> {
+    if name == "-" {
+        let mut contents = Vec::new();
+        io::stdin().lock().read_to_end(&mut contents)?;
+        Ok(contents)
+    } else if name.starts_with("http://") || name.starts_with("https://") {
+        options
+            .network
+            .fetch_torrent_file(name, options.cookie.as_deref())
+    } else {
+        fs::read(name).with_context(|| format!("reading {}", name))
+    }
+}
+
+/// `options` with the network overrides saved for `info_hash` by the
+/// `network` command taking precedence over the session's.
+pub fn torrent_options(options: &Options, info_hash: &InfoHash) -> Result<Options> {
+    let saved = ResumeStore::default_location().load(info_hash)?;
+    Ok(Options {
+        network: options.network.merged_with(&saved.network_overrides()?),
+        ..options.clone()
+    })
+}
+
+/// Removes the global options listed in `OPTIONS` and `FLAGS`, and their
+/// values, from `args`, returning the options they describe.
+pub fn take_options(args: &mut Vec<String>) -> Result<Options> {
+    let mut options = Options::default();
+    let mut fingerprint_flags = Vec::new();
+
+    while let Some(index) = args.iter().position(|arg| FLAGS.contains(&arg.as_str())) {
+        match args.remove(index).as_str() {
+            "--prefer-reliable" => options.prefer_reliable_trackers = true,
+            "--no-compact" => options.peer_list.compact = false,
+            "--dual-stack" => options.network.dual_stack = true,
+            "--seed" => options.peer.seed = true,
+            "--super-seed" => {
+                options.peer.seed = true;
+                options.peer.super_seed = true;
+            }
+            _ => options.peer_list.no_peer_id = false,
+        }
+    }
+
+    while let Some(index) = args.iter().position(|arg| OPTIONS.contains(&arg.as_str())) {
+        if index + 1 >= args.len() {
+            return Err(anyhow!("{} needs a value", args[index]));
+        }
+        let value = args.remove(index + 1);
+        let flag = args.remove(index);
+
+        match flag.as_str() {
+            "--proxy" => options.network.proxy = Some(Proxy::from(value)),
+            "--bind" => options.network.bind_address = Some(value.parse()?),
+            "--doh" => options.network.dns_over_https = Some(value),
+            "--tls" => {
+                options.network.tls_backend = Some(match value.as_str() {
+                    "native" => TlsBackend::Native,
+                    "rustls" => TlsBackend::Rustls,
+                    _ => return Err(anyhow!("--tls must be native or rustls")),
+                })
+            }
+            "--encryption" => {
+                options.encryption = match value.as_str() {
+                    "required" => EncryptionPolicy::Required,
+                    "preferred" => EncryptionPolicy::Preferred,
+                    "disabled" => EncryptionPolicy::Disabled,
+                    _ => {
+                        return Err(anyhow!(
+                            "--encryption must be required, preferred or disabled"
+                        ))
+                    }
+                }
+            }
+            "--ca-cert" => options.network.ca_certificates.push(value.into()),
+            "--pin-cert" => options.network.pinned_certificate = Some(value.into()),
+            "--cookie" => options.cookie = Some(value),
+            "--max-read-rate" => options.max_read_rate = Some(units::parse_rate(&value)?),
+            "--numwant" => options.peer_list.numwant = value.parse()?,
+            "--retry-initial" => {
+                options.backoff.initial = units::parse_duration(&value)?.as_secs().max(1);
+            }
+            "--dht-port" => options.peer.dht_port = Some(value.parse()?),
+            "--max-connections" => options.connections.global = value.parse()?,
+            "--max-peers" => options.connections.per_torrent = value.parse()?,
+            "--max-hash-failures" | "--max-violations" | "--max-dial-failures" => {
+                let limit: u32 = value.parse()?;
+                if limit == 0 {
+                    return Err(anyhow!("{} must be at least 1", flag));
+                }
+                match flag.as_str() {
+                    "--max-hash-failures" => options.bans.hash_failures = limit,
+                    "--max-violations" => options.bans.violations = limit,
+                    _ => options.bans.connection_failures = limit,
+                }
+            }
+            "--forgive-after" => options.bans.forgive_after = units::parse_duration(&value)?,
+            "--port" => {
+                let (first, last) = match value.split_once('-') {
+                    Some((first, last)) => (first.parse()?, Some(last.parse()?)),
+                    None => (value.parse()?, None),
+                };
+                if last.is_some_and(|last| last < first) {
+                    return Err(anyhow!("--port range must not end below its start"));
+                }
+                options.identity.port = first;
+                options.last_port = last;
+            }
+            "--status-port" => options.status_port = Some(value.parse()?),
+            "--piece-times" => options.piece_times = Some(value.into()),
+            "--ip-filter" => options.ip_filter.extend(IpFilter::load(value.as_ref())?),
+            "--peer-timeout" => options.peer.idle_timeout = units::parse_duration(&value)?,
+            "--upload-slots" => options.peer.upload_slots = value.parse()?,
+            "--max-buffer" => options.peer.max_buffer = units::parse_size(&value)?,
+            "--snub-timeout" => options.peer.snub_timeout = units::parse_duration(&value)?,
+            "--retry-max" => options.backoff.max = units::parse_duration(&value)?.as_secs(),
+            "--peer-id-prefix" => {
+                options.identity.peer_id = PeerId::with_prefix(&value)?;
+                fingerprint_flags.push(flag);
+            }
+            "--user-agent" => {
+                options.network.user_agent = Some(value);
+                fingerprint_flags.push(flag);
+            }
+            "--request-queue" => {
+                let (min, max) = match value.split_once('-') {
+                    Some((min, max)) => (min.parse()?, max.parse()?),
+                    None => {
+                        let depth = value.parse()?;
+                        (depth, depth)
+                    }
+                };
+                // An empty queue would never request anything.
+                if min == 0 || min > max {
+                    return Err(anyhow!(
+                        "--request-queue depths must be at least 1, with min no more than max"
+                    ));
+                }
+                options.peer.request_queue = (min, max);
+            }
+            "--tracker-auth" | "--tracker-cookie" | "--tracker-header" => {
+                let credential = tracker_credential(&flag, &value)?;
+                options.network.tracker_credentials.push(credential);
+            }
+            _ => {
+                options.root_folder = match value.as_str() {
+                    "original" => RootFolder::Original,
+                    "strip" => RootFolder::Strip,
+                    "always" => RootFolder::Always,
+                    _ => return Err(anyhow!("--root-folder must be original, strip or always")),
+                }
+            }
+        }
+    }
+
+    // Trackers that check the client compare the two, and an announce
+    // from "qBittorrent" with a crab_torrent peer id stands out.
+    if let [flag] = fingerprint_flags.as_slice() {
+        eprintln!(
+            "warning: {} given without {}; trackers may flag the mismatched client",
+            flag,
+            if flag == "--user-agent" {
+                "--peer-id-prefix"
+            } else {
+                "--user-agent"
+            }
+        );
+    }
+
+    Ok(options)
+}
+
+pub fn announce_request(
+    tracker: &str,
+    info_hash: &InfoHash,
+    identity: &SessionIdentity,
+    peer_list: &PeerListOptions,
+    totals: &ResumeData,
+    left: u64,
+    event: AnnounceEvent,
+) -> AnnounceRequest {
+    AnnounceRequest::new(*info_hash, identity.peer_id, identity.port)
+        .uploaded(totals.uploaded)
+        .downloaded(totals.downloaded)
+        .left(left)
+        .event(event)
+        .key(identity.key_param())
+        .tracker_id(totals.tracker_id(info_hash, tracker).map(str::to_string))
+        .peer_list(*peer_list)
+}
+
+/// A tracker's reply to an announce.
+pub struct Announced {
+    pub response: AnnounceResponse,
+    /// Where the tracker moved if every redirect on the way was permanent
+    /// (301 or 308), without the announce query.
+    pub moved_to: Option<String>,
+}
+
+/// The clients announces go through: HTTP ones, one per address family
+/// with `--dual-stack`, and a UDP one shared so its connection ids are
+/// reused across announces.
+#[derive(Clone)]
+pub struct TrackerClients {
+    http: Vec<reqwest::Client>,
+    udp: Arc<UdpTracker>,
+    credentials: Arc<[TrackerCredential]>,
+}
+
+/// Builds the tracker clients for `trackers`. With a `timeout`, UDP
+/// requests are sent once and wait at most that long instead of following
+/// the BEP 15 retransmission schedule.
+pub async fn tracker_clients(
+    options: &Options,
+    trackers: &[String],
+    timeout: Option<Duration>,
+) -> Result<TrackerClients> {
+    let mut http = Vec::new();
+    for mut builder in options.network.tracker_client_builders(trackers).await? {
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        http.push(builder.build()?);
+    }
+
+    let mut udp = UdpTracker::new(options.network.bind_address);
+    if timeout.is_some() {
+        udp = udp.max_retransmits(0);
+    }
+    Ok(TrackerClients {
+        http,
+        udp: Arc::new(udp),
+        credentials: options.network.tracker_credentials.clone().into(),
+    })
+}
+
+/// Sends `request` to `tracker` over UDP or HTTP depending on its scheme.
+pub async fn send_announce(
+    clients: &TrackerClients,
+    tracker: &str,
+    request: &AnnounceRequest,
+) -> Result<Announced> {
+    let url = request.url(tracker)?;
+    match url.scheme() {
+        "udp" => {
+            let response = clients
+                .udp
+                .announce(&url, request)
+                .await
+                .map_err(udp_error)?;
+            Ok(Announced {
+                response,
+                moved_to: None,
+            })
+        }
+        "http" | "https" => send_http_announce(clients, url).await,
+        scheme => Err(anyhow!("unsupported scheme {}", scheme)),
+    }
+}
+
+/// Scrapes `info_hashes` from `tracker` in as few requests as its protocol
+/// allows: one for HTTP, one per `MAX_SCRAPE_HASHES` for UDP.
+pub async fn send_scrape(
+    clients: &TrackerClients,
+    tracker: &str,
+    info_hashes: &[InfoHash],
+) -> Result<BTreeMap<InfoHash, ScrapeStats>> {
+    let url = Url::parse(tracker)?;
+    match url.scheme() {
+        "udp" => Ok(clients
+            .udp
+            .scrape(&url, info_hashes)
+            .await
+            .map_err(udp_error)?),
+        "http" | "https" => {
+            let url = scrape_url(&url, info_hashes)
+                .ok_or_else(|| anyhow!("{} does not support scraping", tracker))?;
+            let client = clients
+                .http
+                .first()
+                .ok_or_else(|| anyhow!("no tracker client"))?;
+            let (response, _) = get_following_redirects(client, &clients.credentials, url).await?;
+            let status = response.status();
+            let body = response.bytes().await?;
+            match parse_scrape_response(&body) {
+                Err(AnnounceError::Bencode(_)) if !status.is_success() => {
+                    Err(StatusError(status).into())
+                }
+                scraped => Ok(scraped?),
+            }
+        }
+        scheme => Err(anyhow!("unsupported scheme {}", scheme)),
+    }
+}
+
+/// Reports a UDP tracker's error message like an HTTP tracker's `failure
+/// reason`.
+fn udp_error(error: UdpTrackerError) -> anyhow::Error {
+    match error {
+        UdpTrackerError::Failure(reason) => AnnounceError::Failure(reason).into(),
+        error => error.into(),
+    }
+}
+
+/// Sends the announce through every client, so with dual-stack the tracker
+/// learns each of our addresses, and merges the peers they get back. It
+/// fails only if every client fails, with the first client's error.
+async fn send_http_announce(clients: &TrackerClients, url: Url) -> Result<Announced> {
+    let results = join_all(
+        clients
+            .http
+            .iter()
+            .map(|client| send_announce_with(client, &clients.credentials, url.clone())),
+    )
+    .await;
+
+    let mut merged: Option<Announced> = None;
+    let mut first_error = None;
+    for result in results {
+        match (result, &mut merged) {
+            (Ok(announced), None) => merged = Some(announced),
+            (Ok(announced), Some(merged)) => {
+                for peer in announced.response.peers {
+                    let known = merged
+                        .response
+                        .peers
+                        .iter()
+                        .any(|known| known.ip == peer.ip && known.port == peer.port);
+                    if !known {
+                        merged.response.peers.push(peer);
+                    }
+                }
+            }
+            (Err(error), _) => {
+                first_error.get_or_insert(error);
+            }
+        }
+    }
+
+    merged.ok_or_else(|| first_error.unwrap_or_else(|| anyhow!("no tracker client")))
+}
+
+/// Sends one announce and decodes the tracker's reply.
+async fn send_announce_with(
+    client: &reqwest::Client,
+    credentials: &[TrackerCredential],
+    url: Url,
+) -> Result<Announced> {
+    let (response, moved_to) = get_following_redirects(client, credentials, url).await?;
+    let status = response.status();
+    let body = response.bytes().await?;
+    // Private trackers often send their failure reason with an error status,
+    // so only fall back to the status when the body doesn't explain it.
+    let response = match AnnounceResponse::from_bytes(&body) {
+        Err(AnnounceError::Bencode(_)) if !status.is_success() => {
+            return Err(StatusError(status).into());
+        }
+        response => response?,
+    };
+
+    Ok(Announced { response, moved_to })
+}
+
+/// Sends a tracker request, following up to `MAX_REDIRECTS` redirects and
+/// adding the `credentials` configured for each URL on the way. Also
+/// returns where the tracker moved if every redirect was permanent.
+/// `client` must not follow redirects itself.
+async fn get_following_redirects(
+    client: &reqwest::Client,
+    credentials: &[TrackerCredential],
+    mut url: Url,
+) -> Result<(reqwest::Response, Option<String>)> {
+    let mut permanent = true;
+    let mut redirects = 0;
+
+    let response = loop {
+        let response = authorize(client.get(url.clone()), credentials, &url)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_redirection() {
+            break response;
+        }
+
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            return Err(anyhow!(
+                "more than {} redirects from {}",
+                MAX_REDIRECTS,
+                url
+            ));
+        }
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .ok_or_else(|| anyhow!("{} redirect from {} has no location", status, url))?
+            .to_str()?;
+        url = url.join(location)?;
+        permanent &= matches!(
+            status,
+            StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT
+        );
+    };
+
+    let moved_to = (redirects > 0 && permanent).then(|| {
+        let mut target = url.clone();
+        target.set_query(None);
+        target.to_string()
+    });
+    Ok((response, moved_to))
+}
+
+/// A tracker answered with an HTTP error status and no failure reason.
+#[derive(Debug)]
+pub struct StatusError(pub StatusCode);
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tracker answered {}", self.0)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Whether an announce failed in a way worth retrying soon: it timed out,
+/// couldn't connect, or the tracker answered with a server error.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(StatusError(status)) = cause.downcast_ref() {
+            return status.is_server_error();
+        }
+        if let Some(error) = cause.downcast_ref::<UdpTrackerError>() {
+            return matches!(error, UdpTrackerError::TimedOut | UdpTrackerError::Io(_));
+        }
+        cause.downcast_ref::<reqwest::Error>().is_some_and(|error| {
+            error.is_timeout()
+                || error.is_connect()
+                || error
+                    .status()
+                    .is_some_and(|status| status.is_server_error())
+        })
+    })
+}
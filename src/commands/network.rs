@@ -0,0 +1,39 @@
+use super::{load_torrent, Options};
+use anyhow::Result;
+use crab_torrent::resume::ResumeStore;
+
+/// Saves `--proxy` and `--bind` as the torrent's own network settings,
+/// which its downloads, announces and handshakes use instead of the
+/// session's, and prints what is saved. With `clear`, the saved settings
+/// are forgotten first.
+pub fn run(torrent_name: &str, clear: bool, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+    let store = ResumeStore::default_location();
+    let mut saved = store.load(&torrent.info_hash())?;
+
+    let before = (saved.proxy.clone(), saved.bind_address.clone());
+    if clear {
+        saved.proxy = None;
+        saved.bind_address = None;
+    }
+    if let Some(proxy) = &options.network.proxy {
+        saved.proxy = Some(proxy.to_string());
+    }
+    if let Some(bind_address) = options.network.bind_address {
+        saved.bind_address = Some(bind_address.to_string());
+    }
+    if (saved.proxy.clone(), saved.bind_address.clone()) != before {
+        store.save(&torrent.info_hash(), &saved)?;
+    }
+
+    println!("torrent: {}", torrent.name());
+    println!(
+        "proxy:   {}",
+        saved.proxy.as_deref().unwrap_or("session default")
+    );
+    println!(
+        "bind:    {}",
+        saved.bind_address.as_deref().unwrap_or("session default")
+    );
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use super::{
+    announce_request, load_torrent, runtime, send_announce, tracker_clients, Options,
+    TrackerClients,
+};
+use anyhow::Result;
+use crab_torrent::info_hash::InfoHash;
+use crab_torrent::reliability::TrackerReliability;
+use crab_torrent::resume::{ResumeData, ResumeStore};
+use crab_torrent::torrent::Torrent;
+use crab_torrent::tracker::{AnnounceError, AnnounceEvent};
+use futures_util::future::join_all;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+
+enum ProbeOutcome {
+    Peers {
+        count: usize,
+        /// Set when the tracker redirected permanently.
+        moved_to: Option<String>,
+        warning: Option<String>,
+        /// Our address as the tracker saw it.
+        external_ip: Option<IpAddr>,
+    },
+    Failure(String),
+    Error(String),
+}
+
+/// Announces to every tracker in every tier at once, timing each response,
+/// and prints a summary suitable for pasting into a bug report.
+pub fn run(torrent_name: &str, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+    runtime()?.block_on(probe(&torrent, options))
+}
+
+async fn probe(torrent: &Torrent, options: &Options) -> Result<()> {
+    let tiers = torrent.tracker_tiers();
+    let clients = tracker_clients(options, &tiers.concat(), Some(TRACKER_TIMEOUT)).await?;
+    let info_hash = torrent.info_hash();
+    let totals = ResumeStore::default_location().load(&info_hash)?;
+    let left = totals.bytes_left(torrent.total_size());
+    let mut reliability = TrackerReliability::load()?;
+
+    println!("crab_torrent {} probe", env!("CARGO_PKG_VERSION"));
+    println!("torrent:   {}", torrent.name());
+    println!("infohash:  {}", info_hash);
+    println!("peer id:   {}", options.identity.peer_id);
+
+    // All requests start together, so the time since `started` is each
+    // tracker's response time.
+    let started = Instant::now();
+    let outcomes = join_all(tiers.iter().map(|tier| {
+        join_all(tier.iter().map(|tracker| async {
+            let outcome =
+                probe_tracker(&clients, tracker, &info_hash, options, &totals, left).await;
+            (outcome, started.elapsed())
+        }))
+    }))
+    .await;
+
+    // Best effort: the probe never serves data, so leave the swarms joined.
+    let answered = tiers
+        .iter()
+        .flatten()
+        .zip(outcomes.iter().flatten())
+        .filter(|(_, (outcome, _))| matches!(outcome, ProbeOutcome::Peers { .. }));
+    join_all(answered.map(|(tracker, _)| async {
+        let stopped = announce_request(
+            tracker,
+            &info_hash,
+            &options.identity,
+            &options.peer_list,
+            &totals,
+            left,
+            AnnounceEvent::Stopped,
+        );
+        let _ = send_announce(&clients, tracker, &stopped).await;
+    }))
+    .await;
+
+    let mut first_peer: Option<Duration> = None;
+    let mut external_ips = BTreeSet::new();
+    for (tier_index, (tier, outcomes)) in tiers.iter().zip(outcomes).enumerate() {
+        println!("tier {}:", tier_index);
+        for (tracker, (outcome, elapsed)) in tier.iter().zip(outcomes) {
+            reliability.record(tracker, matches!(outcome, ProbeOutcome::Peers { .. }));
+
+            match outcome {
+                ProbeOutcome::Peers {
+                    count,
+                    moved_to,
+                    warning,
+                    external_ip,
+                } => {
+                    if count > 0 {
+                        first_peer = first_peer.min(Some(elapsed)).or(Some(elapsed));
+                    }
+                    println!(
+                        "  {:<50} {:>6} ms  ok ({} peers)",
+                        tracker,
+                        elapsed.as_millis(),
+                        count
+                    );
+                    if let Some(moved_to) = moved_to {
+                        println!("    moved permanently to {}", moved_to);
+                    }
+                    if let Some(warning) = warning {
+                        println!("    warning: {}", warning);
+                    }
+                    if let Some(external_ip) = external_ip {
+                        println!("    external ip: {}", external_ip);
+                        external_ips.insert(external_ip);
+                    }
+                }
+                ProbeOutcome::Failure(reason) => println!(
+                    "  {:<50} {:>6} ms  failure: {}",
+                    tracker,
+                    elapsed.as_millis(),
+                    reason
+                ),
+                ProbeOutcome::Error(error) => println!(
+                    "  {:<50} {:>6} ms  error: {}",
+                    tracker,
+                    elapsed.as_millis(),
+                    error
+                ),
+            }
+        }
+    }
+
+    reliability.save()?;
+
+    print_external_ips(&external_ips, options);
+    println!("dht lookup:          not available (DHT is not supported yet)");
+    match first_peer {
+        Some(elapsed) => println!("time to first peer:  {} ms", elapsed.as_millis()),
+        None => println!("time to first peer:  no peers returned"),
+    }
+
+    Ok(())
+}
+
+async fn probe_tracker(
+    clients: &TrackerClients,
+    tracker: &str,
+    info_hash: &InfoHash,
+    options: &Options,
+    totals: &ResumeData,
+    left: u64,
+) -> ProbeOutcome {
+    match announce(clients, tracker, info_hash, options, totals, left).await {
+        Ok(outcome) => outcome,
+        Err(error) => ProbeOutcome::Error(error.to_string()),
+    }
+}
+
+async fn announce(
+    clients: &TrackerClients,
+    tracker: &str,
+    info_hash: &InfoHash,
+    options: &Options,
+    totals: &ResumeData,
+    left: u64,
+) -> Result<ProbeOutcome> {
+    let request = announce_request(
+        tracker,
+        info_hash,
+        &options.identity,
+        &options.peer_list,
+        totals,
+        left,
+        AnnounceEvent::Started,
+    );
+
+    match send_announce(clients, tracker, &request).await {
+        Ok(announced) => Ok(ProbeOutcome::Peers {
+            count: announced.response.peers.len(),
+            moved_to: announced.moved_to,
+            warning: announced.response.warning_message,
+            external_ip: announced.response.external_ip,
+        }),
+        Err(error) => match error.downcast::<AnnounceError>() {
+            Ok(AnnounceError::Failure(reason)) => Ok(ProbeOutcome::Failure(reason)),
+            Ok(error) => Err(error.into()),
+            Err(error) => Err(error),
+        },
+    }
+}
+
+/// Summarises the addresses trackers saw us at: trackers that disagree
+/// point at a proxy or VPN used for some connections only, and one that
+/// differs from the bound address means a NAT sits in between.
+fn print_external_ips(external_ips: &BTreeSet<IpAddr>, options: &Options) {
+    let listed: Vec<String> = external_ips.iter().map(IpAddr::to_string).collect();
+    match listed.as_slice() {
+        [] => println!("external ip:         not reported by any tracker"),
+        [external_ip] => println!("external ip:         {}", external_ip),
+        _ => println!(
+            "external ip:         trackers disagree ({}), some connections take another route",
+            listed.join(", ")
+        ),
+    }
+
+    if let Some(bind_address) = options.network.bind_address {
+        if !bind_address.is_unspecified()
+            && !external_ips.is_empty()
+            && !external_ips.contains(&bind_address)
+        {
+            println!(
+                "nat:                 bound to {}, so port {} must be forwarded to be reachable",
+                bind_address, options.identity.port
+            );
+        }
+    }
+}
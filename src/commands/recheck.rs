@@ -0,0 +1,58 @@
+use super::{load_torrent, Options};
+use anyhow::Result;
+use crab_torrent::bitfield::Bitfield;
+use crab_torrent::event_log::EventLog;
+use crab_torrent::resume::ResumeStore;
+use crab_torrent::verify::{self, FileCheck};
+use std::path::Path;
+
+pub fn run(torrent_name: &str, download_dir: &str, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+    let verified: Bitfield = verify::recheck(
+        &torrent,
+        Path::new(download_dir),
+        options.root_folder,
+        options.max_read_rate,
+    )?
+    .into_iter()
+    .collect();
+
+    let complete = verified.count_ones();
+    // Announces report what is still missing, so a resumed download
+    // doesn't claim to start from nothing.
+    let left = torrent
+        .pieces()
+        .filter(|piece| !verified.has(piece.index))
+        .map(|piece| piece.length)
+        .sum();
+    let store = ResumeStore::default_location();
+    let mut totals = store.load(&torrent.info_hash())?;
+    totals.left = Some(left);
+    store.save(&torrent.info_hash(), &totals)?;
+
+    EventLog::for_torrent(&torrent.info_hash()).record(format!(
+        "recheck of {}: {}/{} pieces verified",
+        download_dir,
+        complete,
+        verified.len()
+    ))?;
+    println!(
+        "{}: {}/{} pieces verified",
+        torrent.name(),
+        complete,
+        verified.len()
+    );
+
+    for (_, path, check) in
+        verify::verify_files(&torrent, Path::new(download_dir), options.root_folder)?
+    {
+        match check {
+            FileCheck::Match => println!("  {}: checksum ok", path.display()),
+            FileCheck::Mismatch => println!("  {}: checksum MISMATCH", path.display()),
+            FileCheck::Missing => println!("  {}: missing", path.display()),
+            FileCheck::NoChecksum => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use super::{load_torrent, runtime, send_scrape, tracker_clients, Options};
+use anyhow::Result;
+use crab_torrent::info_hash::InfoHash;
+use std::time::Duration;
+
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Scrapes many torrents from one tracker at once. Each of `targets` is an
+/// infohash in hex or base32, or a torrent file or URL.
+pub fn run(tracker: &str, targets: &[String], options: &Options) -> Result<()> {
+    let mut info_hashes = Vec::new();
+    for target in targets {
+        match target.parse::<InfoHash>() {
+            Ok(info_hash) => info_hashes.push(info_hash),
+            Err(_) => info_hashes.push(load_torrent(target, options)?.info_hash()),
+        }
+    }
+    info_hashes.sort();
+    info_hashes.dedup();
+
+    runtime()?.block_on(async {
+        let trackers = [tracker.to_string()];
+        let clients = tracker_clients(options, &trackers, Some(TRACKER_TIMEOUT)).await?;
+        let scraped = send_scrape(&clients, tracker, &info_hashes).await?;
+
+        for info_hash in &info_hashes {
+            match scraped.get(info_hash) {
+                Some(stats) => println!(
+                    "{}  {} seeders, {} leechers, {} completed",
+                    info_hash, stats.complete, stats.incomplete, stats.downloaded
+                ),
+                None => println!("{}  not tracked", info_hash),
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,97 @@
+use super::{load_torrent, Options};
+use anyhow::Result;
+use crab_torrent::event_log::EventLog;
+use crab_torrent::info_hash::InfoHash;
+use crab_torrent::reliability::TrackerReliability;
+use crab_torrent::resume::{ResumeData, ResumeStore};
+use crab_torrent::schedule::SystemClock;
+
+/// Prints each tracker's announce history, what it last reported and when
+/// it is next announced to, and any permanent redirect the torrent file
+/// should be updated with.
+pub fn run(torrent_name: &str, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+    let totals = ResumeStore::default_location().load(&torrent.info_hash())?;
+    let reliability = TrackerReliability::load()?;
+
+    println!("torrent:    {}", torrent.name());
+    println!("uploaded:   {}", totals.uploaded);
+    println!("downloaded: {}", totals.downloaded);
+    println!("left:       {}", totals.bytes_left(torrent.total_size()));
+
+    for (tier_index, tier) in torrent.tracker_tiers().iter().enumerate() {
+        println!("tier {}:", tier_index);
+        for tracker in tier {
+            let record = reliability.get(tracker);
+            println!(
+                "  {}  ({} ok, {} failed)",
+                tracker, record.successes, record.failures
+            );
+            if let Some(moved_to) = totals.redirects.get(tracker) {
+                println!("    moved permanently to {}", moved_to);
+            }
+            for info_hash in torrent.announce_hashes() {
+                print_tracker_stats(&info_hash, &totals, tracker);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn print_tracker_stats(info_hash: &InfoHash, totals: &ResumeData, tracker: &str) {
+    let schedule = totals.schedule(info_hash, tracker);
+    let stats = totals.tracker_stats(info_hash, tracker);
+    if schedule.last_announce.is_none() && stats.last_failure.is_none() {
+        return;
+    }
+
+    println!("    {}:", info_hash);
+    if let Some(last) = stats.last_success.or(schedule.last_announce) {
+        println!(
+            "      last success:  {} ({} peers, {} seeders, {} leechers)",
+            last,
+            stats.peers,
+            count(stats.seeders),
+            count(stats.leechers)
+        );
+    }
+    if let (Some(at), Some(error)) = (stats.last_failure, &stats.last_error) {
+        println!(
+            "      last failure:  {} ({} in a row): {}",
+            at, stats.consecutive_failures, error
+        );
+    }
+    if let Some(external_ip) = stats.external_ip {
+        println!("      external ip:   {}", external_ip);
+    }
+    if let Some(next) = stats.next_announce {
+        println!("      next announce: {}", next);
+    }
+    if schedule.last_announce.is_some() {
+        println!(
+            "      interval:      {} s, next allowed in {} s",
+            schedule.interval,
+            schedule.remaining(&SystemClock)
+        );
+    }
+}
+
+fn count(value: Option<u64>) -> String {
+    value.map_or_else(|| "?".to_string(), |value| value.to_string())
+}
+
+/// Prints the torrent's persisted event log, oldest first.
+pub fn run_log(torrent_name: &str, options: &Options) -> Result<()> {
+    let torrent = load_torrent(torrent_name, options)?;
+    let events = EventLog::for_torrent(&torrent.info_hash()).events()?;
+
+    if events.is_empty() {
+        println!("{}: no events recorded", torrent.name());
+    }
+    for event in events {
+        println!("{:>10}  {}", event.time, event.message);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,529 @@
+use crate::info_hash::InfoHash;
+use crate::ip_filter::IpFilter;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Connections open at once, across all torrents.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+/// Connections open at once for one torrent.
+pub const DEFAULT_MAX_TORRENT_CONNECTIONS: usize = 50;
+
+/// Wait before redialing a peer whose connection failed, doubling with
+/// each further failure.
+pub const RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Failures in a row after which a peer is given up on.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// Pieces a peer may send that fail their hash check before it is banned.
+pub const DEFAULT_MAX_HASH_FAILURES: u32 = 3;
+
+/// Protocol violations a peer may commit before it is banned.
+pub const DEFAULT_MAX_VIOLATIONS: u32 = 2;
+
+/// How often one strike of each kind is forgiven.
+pub const DEFAULT_FORGIVE_AFTER: Duration = Duration::from_secs(30 * 60);
+
+/// Something a peer did that counts against it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strike {
+    /// A piece it sent a block of failed its hash check.
+    HashFailure,
+    /// It broke the protocol, as with a malformed message or a block
+    /// that isn't part of any piece.
+    Violation,
+}
+
+/// When peers are banned: neither dialed nor accepted. Strikes wear off
+/// one of each kind every `forgive_after`, so a peer that had a bad spell
+/// on a long session is tried again once it falls back under the limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanPolicy {
+    pub hash_failures: u32,
+    pub violations: u32,
+    /// Failed connections in a row, after each of which the peer is
+    /// retried with a growing delay.
+    pub connection_failures: u32,
+    /// Zero keeps strikes for the whole session.
+    pub forgive_after: Duration,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        BanPolicy {
+            hash_failures: DEFAULT_MAX_HASH_FAILURES,
+            violations: DEFAULT_MAX_VIOLATIONS,
+            connection_failures: MAX_ATTEMPTS,
+            forgive_after: DEFAULT_FORGIVE_AFTER,
+        }
+    }
+}
+
+/// Where we heard of a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Source {
+    /// Given on the command line.
+    Manual,
+    Tracker,
+    Dht,
+    Pex,
+    /// The peer connected to us.
+    Incoming,
+    /// Named in a ut_holepunch connect message.
+    Holepunch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub global: usize,
+    pub per_torrent: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            global: DEFAULT_MAX_CONNECTIONS,
+            per_torrent: DEFAULT_MAX_TORRENT_CONNECTIONS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    /// Waiting for a free slot, not before `retry_at` if set.
+    Candidate {
+        retry_at: Option<Instant>,
+    },
+    Connecting,
+    Connected,
+    /// Over a `BanPolicy` limit until enough strikes are forgiven.
+    Banned,
+    /// Done with: we no longer need the peer.
+    Finished,
+}
+
+/// Connection attempts the IP filter stopped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Blocked {
+    pub dials: u64,
+    pub accepts: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Peer {
+    source: Source,
+    status: Status,
+    /// Failures since the peer last connected.
+    failures: u32,
+    hash_failures: u32,
+    violations: u32,
+    /// When strikes were last forgiven, or first given.
+    forgiven_at: Instant,
+}
+
+impl Peer {
+    fn new(source: Source, status: Status) -> Self {
+        Peer {
+            source,
+            status,
+            failures: 0,
+            hash_failures: 0,
+            violations: 0,
+            forgiven_at: Instant::now(),
+        }
+    }
+
+    fn has_strikes(&self) -> bool {
+        self.failures + self.hash_failures + self.violations > 0
+    }
+
+    fn is_over(&self, policy: &BanPolicy) -> bool {
+        self.failures >= policy.connection_failures
+            || self.hash_failures >= policy.hash_failures
+            || self.violations >= policy.violations
+    }
+
+    /// Forgives a strike of each kind for every `forgive_after` since the
+    /// last time.
+    fn forgive(&mut self, policy: &BanPolicy, now: Instant) {
+        if !self.has_strikes() || policy.forgive_after.is_zero() {
+            self.forgiven_at = now;
+            return;
+        }
+        let periods = now
+            .saturating_duration_since(self.forgiven_at)
+            .as_secs_f64()
+            / policy.forgive_after.as_secs_f64();
+        let periods = periods as u32;
+        if periods > 0 {
+            self.failures = self.failures.saturating_sub(periods);
+            self.hash_failures = self.hash_failures.saturating_sub(periods);
+            self.violations = self.violations.saturating_sub(periods);
+            self.forgiven_at += policy.forgive_after * periods;
+        }
+    }
+}
+
+/// Decides which of the peers discovered for each torrent to dial,
+/// keeping within the connection limits and replacing connections that
+/// end with candidates still waiting. Each peer, keyed by ip and port, is
+/// tracked once per torrent whichever source reports it.
+#[derive(Debug, Default)]
+pub struct ConnectionManager {
+    limits: ConnectionLimits,
+    /// Peers in the order they were discovered, per torrent.
+    peers: BTreeMap<InfoHash, Vec<(SocketAddr, Peer)>>,
+    filter: IpFilter,
+    blocked: Blocked,
+    bans: BanPolicy,
+}
+
+impl ConnectionManager {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        ConnectionManager {
+            limits,
+            peers: BTreeMap::new(),
+            filter: IpFilter::default(),
+            blocked: Blocked::default(),
+            bans: BanPolicy::default(),
+        }
+    }
+
+    pub fn bans(mut self, policy: BanPolicy) -> Self {
+        self.bans = policy;
+        self
+    }
+
+    /// Neither dials nor accepts peers in `filter`'s ranges.
+    pub fn ip_filter(mut self, filter: IpFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn blocked(&self) -> Blocked {
+        self.blocked
+    }
+
+    /// Records a peer from `source`, returning whether it was new.
+    pub fn add(&mut self, info_hash: InfoHash, address: SocketAddr, source: Source) -> bool {
+        let peers = self.peers.entry(info_hash).or_default();
+        if peers.iter().any(|(known, _)| *known == address) {
+            return false;
+        }
+        peers.push((
+            address,
+            Peer::new(source, Status::Candidate { retry_at: None }),
+        ));
+        true
+    }
+
+    /// Where `address` was first heard of for the torrent.
+    pub fn source(&self, info_hash: &InfoHash, address: &SocketAddr) -> Option<Source> {
+        self.find(info_hash, address).map(|peer| peer.source)
+    }
+
+    /// Open and opening connections, across all torrents.
+    pub fn connections(&self) -> usize {
+        self.peers
+            .keys()
+            .map(|info_hash| self.torrent_connections(info_hash))
+            .sum()
+    }
+
+    pub fn torrent_connections(&self, info_hash: &InfoHash) -> usize {
+        self.peers.get(info_hash).map_or(0, |peers| {
+            peers
+                .iter()
+                .filter(|(_, peer)| matches!(peer.status, Status::Connecting | Status::Connected))
+                .count()
+        })
+    }
+
+    /// The peers to dial now, oldest discoveries first, as far as the
+    /// limits allow. They count as connections until `closed`.
+    pub fn next_dials(&mut self, now: Instant) -> Vec<(InfoHash, SocketAddr)> {
+        let mut free = self.limits.global.saturating_sub(self.connections());
+        let mut dials = Vec::new();
+        let info_hashes: Vec<InfoHash> = self.peers.keys().copied().collect();
+        for info_hash in info_hashes {
+            let mut torrent_free = self
+                .limits
+                .per_torrent
+                .saturating_sub(self.torrent_connections(&info_hash));
+            for (address, peer) in self.peers.get_mut(&info_hash).into_iter().flatten() {
+                if free == 0 || torrent_free == 0 {
+                    break;
+                }
+                if peer.status == Status::Banned {
+                    peer.forgive(&self.bans, now);
+                    if !peer.is_over(&self.bans) {
+                        peer.status = Status::Candidate { retry_at: None };
+                    }
+                }
+                if let Status::Candidate { retry_at } = peer.status {
+                    if self.filter.is_blocked(address.ip()) {
+                        peer.status = Status::Finished;
+                        self.blocked.dials += 1;
+                    } else if retry_at.is_none_or(|retry_at| retry_at <= now) {
+                        peer.status = Status::Connecting;
+                        dials.push((info_hash, *address));
+                        free -= 1;
+                        torrent_free -= 1;
+                    }
+                }
+            }
+        }
+        dials
+    }
+
+    /// Takes a connection the peer opened to us, unless it is filtered,
+    /// banned, duplicates one we have or the limits are reached. As it
+    /// comes from an ephemeral port, a ban on any port of its address
+    /// applies.
+    pub fn accept(&mut self, info_hash: InfoHash, address: SocketAddr) -> bool {
+        if self.filter.is_blocked(address.ip()) {
+            self.blocked.accepts += 1;
+            return false;
+        }
+        let (bans, now) = (self.bans, Instant::now());
+        let banned = self
+            .peers
+            .get_mut(&info_hash)
+            .into_iter()
+            .flatten()
+            .filter(|(known, peer)| known.ip() == address.ip() && peer.status == Status::Banned)
+            .any(|(_, peer)| {
+                peer.forgive(&bans, now);
+                peer.is_over(&bans)
+            });
+        if banned {
+            return false;
+        }
+        if self.connections() >= self.limits.global
+            || self.torrent_connections(&info_hash) >= self.limits.per_torrent
+        {
+            return false;
+        }
+        self.add(info_hash, address, Source::Incoming);
+        let peer = self.find_mut(&info_hash, &address).expect("just added");
+        if matches!(peer.status, Status::Connecting | Status::Connected) {
+            return false;
+        }
+        peer.status = Status::Connected;
+        peer.failures = 0;
+        true
+    }
+
+    /// The handshake with a dialed peer succeeded.
+    pub fn connected(&mut self, info_hash: &InfoHash, address: &SocketAddr) {
+        if let Some(peer) = self.find_mut(info_hash, address) {
+            peer.status = Status::Connected;
+            peer.failures = 0;
+        }
+    }
+
+    /// Counts `strike` against a peer, banning it once over the
+    /// `BanPolicy` limit, and returns whether it is banned. Its open
+    /// connection is left for the caller to close.
+    pub fn strike(
+        &mut self,
+        info_hash: &InfoHash,
+        address: &SocketAddr,
+        strike: Strike,
+        now: Instant,
+    ) -> bool {
+        let bans = self.bans;
+        let Some(peer) = self.find_mut(info_hash, address) else {
+            return false;
+        };
+        peer.forgive(&bans, now);
+        match strike {
+            Strike::HashFailure => peer.hash_failures += 1,
+            Strike::Violation => peer.violations += 1,
+        }
+        let banned = peer.is_over(&bans);
+        if banned && peer.status != Status::Connected && peer.status != Status::Connecting {
+            peer.status = Status::Banned;
+        }
+        banned
+    }
+
+    /// A connection ended, freeing its slot. A peer we dialed that
+    /// `failed` is retried after a delay until it has failed the
+    /// `BanPolicy`'s connection failures in a row, and banned then, as
+    /// are peers over its other limits; others are not dialed again.
+    pub fn closed(
+        &mut self,
+        info_hash: &InfoHash,
+        address: &SocketAddr,
+        failed: bool,
+        now: Instant,
+    ) {
+        let bans = self.bans;
+        let Some(peer) = self.find_mut(info_hash, address) else {
+            return;
+        };
+        peer.forgive(&bans, now);
+        if failed {
+            peer.failures += 1;
+        }
+        if peer.is_over(&bans) {
+            peer.status = Status::Banned;
+            return;
+        }
+        // An incoming peer's address has an ephemeral port, not worth
+        // dialing.
+        if !failed || peer.source == Source::Incoming {
+            peer.status = Status::Finished;
+            return;
+        }
+        peer.status = Status::Candidate {
+            retry_at: Some(now + RETRY_DELAY * 2u32.pow(peer.failures - 1)),
+        };
+    }
+
+    /// When the next waiting peer may be dialed, if any is left to try.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .flatten()
+            .filter_map(|(_, peer)| match peer.status {
+                Status::Candidate { retry_at } => Some(retry_at),
+                _ => None,
+            })
+            .map(|retry_at| retry_at.unwrap_or_else(Instant::now))
+            .min()
+    }
+
+    /// Dials `address` at the next `next_dials`, whatever its failures,
+    /// as a holepunch relay asks: the peer is dialing us at the same time.
+    /// Banned peers stay banned.
+    pub fn holepunch(&mut self, info_hash: InfoHash, address: SocketAddr) {
+        self.add(info_hash, address, Source::Holepunch);
+        let peer = self.find_mut(&info_hash, &address).expect("just added");
+        if !matches!(
+            peer.status,
+            Status::Connecting | Status::Connected | Status::Banned
+        ) {
+            peer.status = Status::Candidate { retry_at: None };
+            peer.failures = 0;
+        }
+    }
+
+    /// Stops dialing for a torrent, once complete or removed. Connections
+    /// still open stay counted until they are closed.
+    pub fn finish_torrent(&mut self, info_hash: &InfoHash) {
+        for (_, peer) in self.peers.get_mut(info_hash).into_iter().flatten() {
+            if let Status::Candidate { .. } = peer.status {
+                peer.status = Status::Finished;
+            }
+        }
+    }
+
+    fn find(&self, info_hash: &InfoHash, address: &SocketAddr) -> Option<&Peer> {
+        self.peers
+            .get(info_hash)?
+            .iter()
+            .find(|(known, _)| known == address)
+            .map(|(_, peer)| peer)
+    }
+
+    fn find_mut(&mut self, info_hash: &InfoHash, address: &SocketAddr) -> Option<&mut Peer> {
+        self.peers
+            .get_mut(info_hash)?
+            .iter_mut()
+            .find(|(known, _)| known == address)
+            .map(|(_, peer)| peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dials_within_the_limits_and_replaces_failed_peers() {
+        let mut manager = ConnectionManager::new(ConnectionLimits {
+            global: 3,
+            per_torrent: 2,
+        });
+        let (one, two) = (InfoHash([1; 20]), InfoHash([2; 20]));
+        let address = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        for port in 1..=3 {
+            assert!(manager.add(one, address(port), Source::Tracker));
+        }
+        for port in 1..=2 {
+            assert!(manager.add(two, address(port), Source::Pex));
+        }
+        assert!(!manager.add(one, address(1), Source::Dht));
+
+        let now = Instant::now();
+        let dials = manager.next_dials(now);
+        assert_eq!(
+            dials,
+            vec![(one, address(1)), (one, address(2)), (two, address(1))]
+        );
+        assert!(manager.next_dials(now).is_empty());
+
+        manager.closed(&one, &address(1), true, now);
+        assert_eq!(manager.next_dials(now), vec![(one, address(3))]);
+        manager.closed(&one, &address(3), false, now);
+        assert_eq!(manager.next_dials(now), vec![(two, address(2))]);
+        manager.closed(&two, &address(1), false, now);
+        assert!(manager.next_dials(now).is_empty());
+        assert_eq!(
+            manager.next_dials(now + RETRY_DELAY),
+            vec![(one, address(1))]
+        );
+        assert!(!manager.accept(two, address(9)));
+    }
+
+    #[test]
+    fn bans_peers_over_the_limits_until_strikes_wear_off() {
+        let policy = BanPolicy {
+            hash_failures: 2,
+            violations: 1,
+            connection_failures: 2,
+            forgive_after: Duration::from_secs(600),
+        };
+        let mut manager = ConnectionManager::new(ConnectionLimits::default()).bans(policy);
+        let torrent = InfoHash([1; 20]);
+        let (cheat, flaky) = (
+            SocketAddr::from(([10, 0, 0, 1], 1)),
+            SocketAddr::from(([10, 0, 0, 2], 2)),
+        );
+        manager.add(torrent, cheat, Source::Tracker);
+        manager.add(torrent, flaky, Source::Tracker);
+        let now = Instant::now();
+        assert_eq!(manager.next_dials(now).len(), 2);
+        manager.connected(&torrent, &cheat);
+
+        assert!(!manager.strike(&torrent, &cheat, Strike::HashFailure, now));
+        assert!(manager.strike(&torrent, &cheat, Strike::HashFailure, now));
+        manager.closed(&torrent, &cheat, false, now);
+        manager.closed(&torrent, &flaky, true, now);
+        let retry = now + RETRY_DELAY;
+        assert_eq!(manager.next_dials(retry), vec![(torrent, flaky)]);
+        manager.closed(&torrent, &flaky, true, retry);
+        // Both are banned, from any port.
+        assert!(manager.next_dials(retry + RETRY_DELAY * 4).is_empty());
+        assert!(manager.next_retry().is_none());
+        assert!(!manager.accept(torrent, SocketAddr::from(([10, 0, 0, 1], 50000))));
+
+        // A strike of each kind is forgiven per period, counted from the
+        // first.
+        assert!(manager
+            .next_dials(now + policy.forgive_after / 2)
+            .is_empty());
+        let later = now + policy.forgive_after;
+        assert_eq!(
+            manager.next_dials(later),
+            vec![(torrent, cheat), (torrent, flaky)]
+        );
+        manager.connected(&torrent, &cheat);
+        // One violation is enough.
+        assert!(manager.strike(&torrent, &cheat, Strike::Violation, later));
+    }
+}
@@ -0,0 +1,1289 @@
+use crate::assembler::{Assembled, BadBlock, PieceAssembler};
+use crate::bitfield::Bitfield;
+use crate::capabilities::{Capabilities, Capability};
+use crate::choker::{Candidate, Choker, DEFAULT_UPLOAD_SLOTS};
+use crate::debug::{DownloadState, PartialPiece, RequestedBlock};
+use crate::extension::{self, ExtensionHandshake, UT_HOLEPUNCH_ID, UT_METADATA_ID, UT_PEX_ID};
+use crate::holepunch::{ErrorCode, HolepunchMessage};
+use crate::metadata::{our_handshake, MetadataMessage};
+use crate::peer::{MessageRecord, PeerConnection, PeerError};
+use crate::peer_id::PeerId;
+use crate::pex::{PexMessage, PexState, REACHABLE, SUPPORTS_HOLEPUNCH};
+use crate::piece_timing::PieceTimings;
+use crate::pipeline::{
+    piece_blocks, Block, RequestPipeline, BLOCK_LEN, DEFAULT_MAX_DEPTH, DEFAULT_MIN_DEPTH,
+};
+use crate::rate::RateMeter;
+use crate::rate_history::{RateHistory, RateSample};
+use crate::storage::Storage;
+use crate::super_seed::SuperSeed;
+use crate::torrent::{hex, Piece, Torrent};
+use crate::wire::Message;
+use sha1::{Digest, Sha1};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Peers are dropped after this long without sending anything.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// How long a connection waits for a message before checking for newly
+/// verified pieces and whether the download finished.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Peers that leave requests unanswered this long are snubbed.
+pub const DEFAULT_SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Requests a peer may have queued with us; more are dropped.
+pub const MAX_PEER_REQUESTS: usize = 250;
+
+/// Blocks sent to a peer before checking for its messages again.
+const SERVE_BATCH: usize = 4;
+
+/// Bytes of pieces being downloaded at once, across all peers.
+pub const DEFAULT_MAX_BUFFER: u64 = 64 * 1024 * 1024;
+
+/// Settings for each peer connection of a download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerOptions {
+    /// Bounds on the block requests kept outstanding (see
+    /// `RequestPipeline`).
+    pub request_queue: (usize, usize),
+    /// Peers silent for longer are dropped.
+    pub idle_timeout: Duration,
+    /// The longest a peer may deliver none of our requests before it is
+    /// snubbed: its requests go to other peers, and it gets one at a
+    /// time. Peers that have delivered get less, by their usual delay
+    /// (see `RequestPipeline::request_timeout`).
+    pub snub_timeout: Duration,
+    /// UDP port of our DHT node, sent to peers that run one too.
+    pub dht_port: Option<u16>,
+    /// TCP port we take connections on, told to peers in the extension
+    /// handshake.
+    pub listen_port: Option<u16>,
+    /// Keep serving peers once the download is complete, rather than
+    /// closing the connection.
+    pub seed: bool,
+    /// Peers unchoked for their rates (see `Choker`).
+    pub upload_slots: usize,
+    /// Bytes of pieces requested or partly received at once, each held
+    /// in memory until verified (see `Download::max_buffer`).
+    pub max_buffer: u64,
+    /// On connections opened once the download is complete, reveal
+    /// pieces one at a time (see `SuperSeed`) instead of sending our
+    /// bitfield.
+    pub super_seed: bool,
+}
+
+impl Default for PeerOptions {
+    fn default() -> Self {
+        PeerOptions {
+            request_queue: (DEFAULT_MIN_DEPTH, DEFAULT_MAX_DEPTH),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            snub_timeout: DEFAULT_SNUB_TIMEOUT,
+            dht_port: None,
+            listen_port: None,
+            seed: false,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            max_buffer: DEFAULT_MAX_BUFFER,
+            super_seed: false,
+        }
+    }
+}
+
+/// Statistics of one connection, kept current by its exchange loop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub peer_id: PeerId,
+    pub connected_at: Instant,
+    /// The peer connected to us.
+    pub incoming: bool,
+    /// The connection is RC4-encrypted.
+    pub encrypted: bool,
+    /// Reserved bits both handshakes set.
+    pub capabilities: Capabilities,
+    /// Extensions both sides offered, with the ids the peer wants.
+    pub extensions: BTreeMap<String, u8>,
+    /// Client name and version from the peer's extension handshake.
+    pub client_version: Option<String>,
+    /// Payload bytes of blocks we asked the peer for and received.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Smoothed rates, in bytes per second.
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// Our requests the peer hasn't answered yet.
+    pub outstanding: usize,
+    /// Those requests, oldest first.
+    pub requested: Vec<Block>,
+    /// The peer's requests we haven't served yet, oldest first.
+    pub peer_requests: Vec<Block>,
+    /// Pieces the peer sent the last block of that passed their hash
+    /// check, and those that failed it.
+    pub pieces_received: u32,
+    pub hash_failures: u32,
+    /// The peer wants our data.
+    pub interested: bool,
+    pub am_interested: bool,
+    pub am_choking: bool,
+    pub peer_choking: bool,
+    pub snubbed: bool,
+    pub last_sent: Instant,
+    pub last_received: Instant,
+    /// How long our requests may go unanswered before the peer is
+    /// snubbed, from its delivery delay.
+    pub request_timeout: Duration,
+    /// The last messages both ways, oldest first.
+    pub history: Vec<MessageRecord>,
+}
+
+impl PeerStats {
+    fn new(connection: &PeerConnection) -> Self {
+        PeerStats {
+            peer_id: connection.peer_id(),
+            connected_at: Instant::now(),
+            incoming: connection.is_incoming(),
+            encrypted: connection.is_encrypted(),
+            capabilities: connection.negotiated(),
+            extensions: BTreeMap::new(),
+            client_version: None,
+            downloaded: 0,
+            uploaded: 0,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            outstanding: 0,
+            requested: Vec::new(),
+            peer_requests: Vec::new(),
+            pieces_received: 0,
+            hash_failures: 0,
+            interested: false,
+            am_interested: false,
+            am_choking: true,
+            peer_choking: true,
+            snubbed: false,
+            last_sent: connection.last_sent(),
+            last_received: connection.last_received(),
+            request_timeout: Duration::ZERO,
+            history: Vec::new(),
+        }
+    }
+
+    /// How long the connection has been up.
+    pub fn age(&self) -> Duration {
+        self.connected_at.elapsed()
+    }
+}
+
+/// What a received block did to the download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// Stored until the rest of its piece arrives.
+    Stored,
+    /// Already received, or its piece is already complete.
+    Duplicate,
+    /// The block completed a piece that passed its hash check and was
+    /// written out.
+    PieceVerified(u32),
+    /// The block completed a piece that failed its hash check. The piece
+    /// will be requested again; the peers to blame are handed out by
+    /// `Download::take_blamed`.
+    HashFailed(u32),
+    /// The block doesn't fit its piece.
+    Bad(BadBlock),
+}
+
+/// The state of one torrent's download shared by all of its peer
+/// connections: which pieces we have, which blocks are requested, and the
+/// pieces being put together.
+#[derive(Debug)]
+pub struct Download {
+    pieces: Vec<Piece>,
+    /// The bencoded info dictionary, served to peers over ut_metadata.
+    info_bytes: Vec<u8>,
+    /// Private torrents get peers from their trackers only, so peer
+    /// exchange is off.
+    private: bool,
+    storage: Storage,
+    have: Bitfield,
+    assembler: PieceAssembler,
+    /// Blocks requested but not arrived, with how many peers they were
+    /// requested from. More than one only in endgame.
+    requested: HashMap<Block, usize>,
+    /// Bytes of pieces in flight beyond which no new piece is started.
+    max_buffer: u64,
+    /// Pieces verified since the download started, in order, so each
+    /// connection can announce the ones it hasn't yet.
+    verified: Vec<u32>,
+    /// Payload bytes of verified pieces.
+    downloaded: u64,
+    /// Payload bytes sent to peers.
+    uploaded: u64,
+    hash_failures: u64,
+    /// Peers with a live connection and their ut_pex flags.
+    connected: BTreeMap<SocketAddr, u8>,
+    /// Every peer address heard of, so each is dialed once.
+    known: HashSet<SocketAddr>,
+    /// Peers heard of but not yet handed out to dial.
+    discovered: Vec<SocketAddr>,
+    /// DHT nodes peers told us about with `port`, to bootstrap from.
+    dht_nodes: BTreeSet<SocketAddr>,
+    /// Statistics of each connection, by the address it came from.
+    peers: BTreeMap<SocketAddr, PeerStats>,
+    choker: Choker,
+    /// ut_holepunch connects to relay, by the listed address of the peer
+    /// to send them to.
+    relayed: BTreeMap<SocketAddr, Vec<HolepunchMessage>>,
+    /// Peers a relay asked us to dial, not yet handed out.
+    holepunches: Vec<SocketAddr>,
+    /// Who sent each block of the pieces being put together.
+    senders: HashMap<Block, SocketAddr>,
+    /// Blocks of pieces that failed their hash check with blocks from
+    /// several peers, with their senders and digests: once the piece
+    /// verifies, the peers whose blocks differ sent the bad data.
+    suspects: HashMap<u32, Vec<(Block, SocketAddr, [u8; 20])>>,
+    /// Peers found to have sent bad data, not yet handed out.
+    blamed: Vec<SocketAddr>,
+    timings: PieceTimings,
+    rates: RateHistory,
+    /// Connections to close at their next turn, as of banned peers.
+    dropped: HashSet<SocketAddr>,
+    super_seed: SuperSeed,
+}
+
+impl Download {
+    /// A download of `torrent` into `storage`, starting from the pieces
+    /// already verified on disk in `have`.
+    pub fn new(torrent: &Torrent, storage: Storage, have: Bitfield) -> Self {
+        Download {
+            pieces: torrent.pieces().collect(),
+            info_bytes: torrent.info_bytes().to_vec(),
+            private: torrent.info.private == Some(1),
+            storage,
+            have,
+            assembler: PieceAssembler::new(),
+            requested: HashMap::new(),
+            max_buffer: DEFAULT_MAX_BUFFER,
+            verified: Vec::new(),
+            downloaded: 0,
+            uploaded: 0,
+            hash_failures: 0,
+            connected: BTreeMap::new(),
+            known: HashSet::new(),
+            discovered: Vec::new(),
+            dht_nodes: BTreeSet::new(),
+            peers: BTreeMap::new(),
+            choker: Choker::default(),
+            relayed: BTreeMap::new(),
+            holepunches: Vec::new(),
+            senders: HashMap::new(),
+            suspects: HashMap::new(),
+            blamed: Vec::new(),
+            timings: PieceTimings::new(Instant::now()),
+            rates: RateHistory::new(Instant::now()),
+            dropped: HashSet::new(),
+            super_seed: SuperSeed::new(torrent.pieces().count()),
+        }
+    }
+
+    /// Sets how many peers are unchoked for their rates, besides the
+    /// optimistic unchoke.
+    pub fn upload_slots(mut self, slots: usize) -> Self {
+        self.choker = Choker::new(slots);
+        self
+    }
+
+    /// Caps the pieces in flight, those with blocks requested or held
+    /// until the rest arrive, at `bytes` in all, so a fast download
+    /// doesn't buffer hundreds of partial pieces. Peers are asked for no
+    /// new piece until one finishes; a piece larger than `bytes` is still
+    /// fetched alone.
+    pub fn max_buffer(mut self, bytes: u64) -> Self {
+        self.max_buffer = bytes;
+        self
+    }
+
+    /// Whether the peer at `address` should be unchoked now, rerunning
+    /// the choker when it is due. Peers are ranked by what they give us
+    /// while we download, and by what we give them once we seed.
+    fn should_unchoke(&mut self, address: &SocketAddr, now: Instant) -> bool {
+        if self.choker.is_due(now) {
+            let seeding = self.is_complete();
+            let candidates: Vec<Candidate> = self
+                .peers
+                .iter()
+                .filter(|(_, stats)| stats.interested)
+                .map(|(address, stats)| Candidate {
+                    address: *address,
+                    rate: if seeding {
+                        stats.upload_rate
+                    } else {
+                        stats.download_rate
+                    },
+                    snubbed: stats.snubbed,
+                })
+                .collect();
+            self.choker.run(&candidates, now);
+        }
+        if self
+            .peers
+            .get(address)
+            .is_some_and(|stats| stats.interested)
+        {
+            self.choker.unchoke_if_free(*address);
+        }
+        self.choker.is_unchoked(address)
+    }
+
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info_bytes
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private
+    }
+
+    /// Records peers from any source. Ones not heard of before are queued
+    /// for `take_discovered`.
+    pub fn discover(&mut self, addresses: impl IntoIterator<Item = SocketAddr>) {
+        for address in addresses {
+            if self.known.insert(address) {
+                self.discovered.push(address);
+            }
+        }
+    }
+
+    /// Peers discovered since the last call, to dial.
+    pub fn take_discovered(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.discovered)
+    }
+
+    /// Peers a holepunch relay asked us to dial since the last call. They
+    /// dial us at the same time, so they should be dialed straight away.
+    pub fn take_holepunches(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.holepunches)
+    }
+
+    /// Peers found to have sent bad data since the last call, once per
+    /// piece: the peer that sent every block of a piece that failed its
+    /// hash check, or, when several did, those whose blocks differ from
+    /// the piece once it verifies.
+    pub fn take_blamed(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.blamed)
+    }
+
+    /// Closes the connection to the peer at `address`, if any, which then
+    /// ends with `PeerError::Banned`.
+    pub fn drop_peer(&mut self, address: SocketAddr) {
+        if self.peers.contains_key(&address) {
+            self.dropped.insert(address);
+        }
+    }
+
+    /// Forwards a rendezvous from the peer at `from`, listed as `listed`,
+    /// to the connected `target`, or says why it can't. The target is
+    /// sent the address the initiator listens on when we know it, since
+    /// `from` is then just the port it dialed us from.
+    fn relay(
+        &mut self,
+        from: SocketAddr,
+        listed: Option<SocketAddr>,
+        target: SocketAddr,
+    ) -> Result<(), ErrorCode> {
+        if target == from || Some(target) == listed {
+            return Err(ErrorCode::NoSelf);
+        }
+        let Some(flags) = self.connected.get(&target) else {
+            return Err(if self.known.contains(&target) {
+                ErrorCode::NotConnected
+            } else {
+                ErrorCode::NoSuchPeer
+            });
+        };
+        if flags & SUPPORTS_HOLEPUNCH == 0 {
+            return Err(ErrorCode::NoSupport);
+        }
+        self.relayed
+            .entry(target)
+            .or_default()
+            .push(HolepunchMessage::Connect(listed.unwrap_or(from)));
+        Ok(())
+    }
+
+    pub fn dht_nodes(&self) -> &BTreeSet<SocketAddr> {
+        &self.dht_nodes
+    }
+
+    pub fn is_snubbed(&self, address: &SocketAddr) -> bool {
+        self.peers.get(address).is_some_and(|stats| stats.snubbed)
+    }
+
+    /// Statistics of each live connection.
+    pub fn peers(&self) -> &BTreeMap<SocketAddr, PeerStats> {
+        &self.peers
+    }
+
+    /// Peers with a live connection, with their ut_pex flags.
+    pub fn connected(&self) -> &BTreeMap<SocketAddr, u8> {
+        &self.connected
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.is_complete()
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    /// Whether we can answer a peer's request for `block`: we have its
+    /// piece, and it fits in the piece and isn't larger than a block.
+    pub fn can_serve(&self, block: &Block) -> bool {
+        let index = block.piece as usize;
+        self.pieces.get(index).is_some_and(|piece| {
+            block.length > 0
+                && block.length <= BLOCK_LEN
+                && block.begin as u64 + block.length as u64 <= piece.length
+                && self.have.has(index)
+        })
+    }
+
+    /// Reads a block we `can_serve` from disk.
+    pub fn read_block(&self, block: &Block) -> io::Result<Vec<u8>> {
+        self.storage
+            .read_block(block.piece as usize, block.begin, block.length)
+    }
+
+    pub fn hash_failures(&self) -> u64 {
+        self.hash_failures
+    }
+
+    /// When each piece was first requested and verified.
+    pub fn piece_timings(&self) -> &PieceTimings {
+        &self.timings
+    }
+
+    /// Adds the peers' current rates to the rate history, once a second
+    /// however often it is called.
+    pub fn record_rates(&mut self, now: Instant) {
+        let sample = self
+            .peers
+            .values()
+            .fold(RateSample::default(), |sample, stats| RateSample {
+                download: sample.download + stats.download_rate,
+                upload: sample.upload + stats.upload_rate,
+            });
+        self.rates.record(now, sample);
+    }
+
+    /// The download's rates over the last hour and day.
+    pub fn rate_history(&self) -> &RateHistory {
+        &self.rates
+    }
+
+    /// Bytes of the pieces not yet verified.
+    pub fn bytes_left(&self) -> u64 {
+        self.have
+            .missing()
+            .map(|index| self.pieces[index].length)
+            .sum()
+    }
+
+    /// Pieces verified since the download started, oldest first.
+    pub fn verified(&self) -> &[u32] {
+        &self.verified
+    }
+
+    /// The picker's, choker's and peer discovery's state, for debugging.
+    pub fn debug_state(&self) -> DownloadState {
+        let mut requested: Vec<RequestedBlock> = self
+            .requested
+            .iter()
+            .map(|(block, peers)| RequestedBlock {
+                block: *block,
+                peers: *peers,
+            })
+            .collect();
+        requested.sort_by_key(|requested| requested.block);
+        let mut partial_pieces: Vec<PartialPiece> = self
+            .assembler
+            .progress()
+            .map(|(piece, blocks_received, bytes)| PartialPiece {
+                piece,
+                blocks_received,
+                blocks: self.pieces[piece as usize]
+                    .length
+                    .div_ceil(BLOCK_LEN as u64) as usize,
+                bytes,
+            })
+            .collect();
+        partial_pieces.sort_by_key(|partial| partial.piece);
+        DownloadState {
+            have: hex(self.have.as_bytes()),
+            max_buffer: self.max_buffer,
+            requested,
+            partial_pieces,
+            suspect_pieces: self.suspects.keys().copied().collect::<BTreeSet<_>>(),
+            unchoked: self.choker.unchoked().clone(),
+            optimistic: self.choker.optimistic(),
+            known_peers: self.known.len(),
+            undialed: self.discovered.clone(),
+            holepunches: self.holepunches.clone(),
+            dht_nodes: self.dht_nodes.clone(),
+            dropping: self.dropped.iter().copied().collect(),
+        }
+    }
+
+    /// Whether a peer with `peer_has` has any piece we lack.
+    pub fn wants_from(&self, peer_has: &Bitfield) -> bool {
+        self.have.missing().any(|index| peer_has.has(index))
+    }
+
+    /// Picks up to `count` blocks to request from a peer with `peer_has`,
+    /// finishing pieces already started before starting new ones, as far
+    /// as `max_buffer` allows, and marks them requested. In endgame, when every missing block is
+    /// already requested, blocks requested from other peers are picked
+    /// again unless `pending` says this peer has them outstanding too.
+    pub fn pick_blocks(
+        &mut self,
+        peer_has: &Bitfield,
+        count: usize,
+        pending: impl Fn(&Block) -> bool,
+    ) -> Vec<Block> {
+        let mut started: Vec<usize> = self
+            .assembler
+            .in_progress()
+            .map(|index| index as usize)
+            .collect();
+        started.sort_unstable();
+        let fresh = self.have.missing().filter(|index| !started.contains(index));
+        let candidates: Vec<usize> = started.iter().copied().chain(fresh).collect();
+        let mut in_flight: HashSet<usize> = started
+            .iter()
+            .copied()
+            .chain(self.requested.keys().map(|block| block.piece as usize))
+            .collect();
+        let mut buffered: u64 = in_flight
+            .iter()
+            .map(|&index| self.pieces[index].length)
+            .sum();
+
+        let mut blocks = Vec::new();
+        for index in candidates {
+            if blocks.len() >= count {
+                break;
+            }
+            if !peer_has.has(index) {
+                continue;
+            }
+            if !in_flight.contains(&index) {
+                let length = self.pieces[index].length;
+                if !in_flight.is_empty() && buffered + length > self.max_buffer {
+                    continue;
+                }
+                in_flight.insert(index);
+                buffered += length;
+            }
+            for block in self.assembler.missing_blocks(&self.pieces[index]) {
+                if blocks.len() >= count {
+                    break;
+                }
+                if let Entry::Vacant(entry) = self.requested.entry(block) {
+                    entry.insert(1);
+                    blocks.push(block);
+                    self.timings.requested(block.piece, Instant::now());
+                }
+            }
+        }
+
+        if blocks.len() < count && self.in_endgame() {
+            let mut duplicates: Vec<Block> = self
+                .requested
+                .keys()
+                .filter(|block| peer_has.has(block.piece as usize) && !pending(block))
+                .copied()
+                .collect();
+            // Blocks requested from the fewest peers first.
+            duplicates.sort_by_key(|block| (self.requested[block], *block));
+            for block in duplicates.into_iter().take(count - blocks.len()) {
+                *self.requested.entry(block).or_default() += 1;
+                blocks.push(block);
+            }
+        }
+        blocks
+    }
+
+    /// Whether every missing block has been requested, so the last ones
+    /// may be requested from several peers at once.
+    pub fn in_endgame(&self) -> bool {
+        self.have.missing().all(|index| {
+            self.assembler
+                .missing_blocks(&self.pieces[index])
+                .all(|block| self.requested.contains_key(&block))
+        })
+    }
+
+    /// Whether `block` still has to arrive. Outstanding requests for
+    /// blocks that don't are cancelled.
+    pub fn is_needed(&self, block: &Block) -> bool {
+        !self.have.has(block.piece as usize) && !self.assembler.has_block(block)
+    }
+
+    /// Returns requests that will never be answered, such as those a
+    /// peer dropped by choking us, so other peers can pick them.
+    pub fn release(&mut self, blocks: impl IntoIterator<Item = Block>) {
+        for block in blocks {
+            if let Some(count) = self.requested.get_mut(&block) {
+                *count -= 1;
+                if *count == 0 {
+                    self.requested.remove(&block);
+                }
+            }
+        }
+    }
+
+    /// Adds a block received `from` a peer, writing out its piece once
+    /// complete and verified. Fails only if the piece can't be written.
+    pub fn on_block(
+        &mut self,
+        block: Block,
+        data: &[u8],
+        from: SocketAddr,
+    ) -> io::Result<BlockOutcome> {
+        self.requested.remove(&block);
+        let index = block.piece as usize;
+        if self.have.has(index) {
+            return Ok(BlockOutcome::Duplicate);
+        }
+        let Some(piece) = self.pieces.get(index) else {
+            return Ok(BlockOutcome::Bad(BadBlock(block)));
+        };
+
+        match self.assembler.add_block(piece, block.begin, data) {
+            Err(bad) => Ok(BlockOutcome::Bad(bad)),
+            Ok(Assembled::Incomplete) => {
+                self.senders.insert(block, from);
+                Ok(BlockOutcome::Stored)
+            }
+            Ok(Assembled::Duplicate) => Ok(BlockOutcome::Duplicate),
+            Ok(Assembled::Verified(data)) => {
+                for block in piece_blocks(block.piece, piece.length) {
+                    self.senders.remove(&block);
+                }
+                let mut culprits = BTreeSet::new();
+                for (block, sender, digest) in
+                    self.suspects.remove(&block.piece).into_iter().flatten()
+                {
+                    if block_digest(&data, &block) != digest {
+                        culprits.insert(sender);
+                    }
+                }
+                self.blamed.extend(culprits);
+                self.timings.completed(block.piece, Instant::now());
+                self.storage.write_piece(index, &data)?;
+                self.have.set(index, true);
+                self.verified.push(block.piece);
+                self.downloaded += data.len() as u64;
+                Ok(BlockOutcome::PieceVerified(block.piece))
+            }
+            Ok(Assembled::HashMismatch(data)) => {
+                self.hash_failures += 1;
+                self.timings.failed(block.piece);
+                self.senders.insert(block, from);
+                let sent: Vec<(Block, SocketAddr)> = piece_blocks(block.piece, piece.length)
+                    .filter_map(|block| Some((block, self.senders.remove(&block)?)))
+                    .collect();
+                if sent.iter().all(|(_, sender)| *sender == from) {
+                    self.blamed.push(from);
+                } else {
+                    let suspects = self.suspects.entry(block.piece).or_default();
+                    for (block, sender) in sent {
+                        suspects.push((block, sender, block_digest(&data, &block)));
+                    }
+                }
+                Ok(BlockOutcome::HashFailed(block.piece))
+            }
+        }
+    }
+}
+
+/// The SHA-1 of `block` within its piece's `data`.
+fn block_digest(data: &[u8], block: &Block) -> [u8; 20] {
+    let begin = block.begin as usize;
+    Sha1::digest(&data[begin..begin + block.length as usize]).into()
+}
+
+/// Downloads from one connected peer until the download completes or the
+/// connection fails. Requests still outstanding at the end are released
+/// for other peers.
+pub async fn run_peer(
+    connection: &mut PeerConnection,
+    download: &Mutex<Download>,
+    options: &PeerOptions,
+) -> Result<(), PeerError> {
+    let (min_depth, max_depth) = options.request_queue;
+    let mut pipeline = RequestPipeline::new(min_depth, max_depth);
+    // Peers that connected to us are listed for peer exchange once their
+    // extension handshake gives the port they listen on.
+    let mut listed = (!connection.is_incoming()).then(|| connection.address());
+    if let Some(address) = listed {
+        let mut download = download.lock().unwrap();
+        download.known.insert(address);
+        download.connected.insert(address, REACHABLE);
+    }
+    let result = exchange(connection, download, &mut pipeline, &mut listed, options).await;
+    let mut download = download.lock().unwrap();
+    download.release(pipeline.clear());
+    if let Some(address) = listed {
+        download.connected.remove(&address);
+        download.relayed.remove(&address);
+    }
+    download.peers.remove(&connection.address());
+    download.dropped.remove(&connection.address());
+    download.choker.remove(&connection.address());
+    download.super_seed.disconnect(&connection.address());
+    result
+}
+
+async fn exchange(
+    connection: &mut PeerConnection,
+    download: &Mutex<Download>,
+    pipeline: &mut RequestPipeline,
+    listed: &mut Option<SocketAddr>,
+    options: &PeerOptions,
+) -> Result<(), PeerError> {
+    let (have, mut announced, mut handshake, private) = {
+        let download = download.lock().unwrap();
+        (
+            download.have().clone(),
+            download.verified().len(),
+            our_handshake(Some(download.info_bytes())),
+            download.is_private(),
+        )
+    };
+    if !private {
+        handshake.m.insert("ut_pex".to_string(), UT_PEX_ID);
+    }
+    handshake
+        .m
+        .insert("ut_holepunch".to_string(), UT_HOLEPUNCH_ID);
+    handshake.p = options.listen_port;
+    let mut pex = PexState::default();
+    // Blocks the peer asked us for, oldest first.
+    let mut requests: VecDeque<Block> = VecDeque::new();
+    let mut upload = RateMeter::new(Instant::now());
+    let mut stats = PeerStats::new(connection);
+    let mut peer_has = Bitfield::new(have.len());
+    let mut peer_extensions = ExtensionHandshake::default();
+    let super_seeding = options.seed && options.super_seed && have.is_complete();
+    if super_seeding {
+        download
+            .lock()
+            .unwrap()
+            .super_seed
+            .connect(connection.address());
+    } else if have.count_ones() > 0 {
+        connection
+            .send(&Message::Bitfield(have.as_bytes().to_vec()))
+            .await?;
+    }
+    if connection.supports(Capability::EXTENSIONS) {
+        connection
+            .send(&Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload: handshake.to_bytes(),
+            })
+            .await?;
+    }
+    if let Some(port) = options.dht_port {
+        if connection.supports(Capability::DHT) {
+            connection.send(&Message::Port(port)).await?;
+        }
+    }
+
+    loop {
+        let (haves, complete, wanted, mut connected) = {
+            let download = download.lock().unwrap();
+            if download.dropped.contains(&connection.address()) {
+                return Err(PeerError::Banned);
+            }
+            (
+                download.verified()[announced..].to_vec(),
+                download.is_complete(),
+                download.wants_from(&peer_has),
+                download.connected().clone(),
+            )
+        };
+        announced += haves.len();
+        for index in haves {
+            connection.send(&Message::Have(index)).await?;
+        }
+        if super_seeding {
+            let offer = download
+                .lock()
+                .unwrap()
+                .super_seed
+                .next_offer(&connection.address());
+            if let Some(index) = offer {
+                connection.send(&Message::Have(index as u32)).await?;
+            }
+        }
+        if let Some(id) = peer_extensions.id_of("ut_pex").filter(|_| !private) {
+            if let Some(address) = listed {
+                connected.remove(address);
+            }
+            if let Some(message) = pex.next_message(&connected, Instant::now()) {
+                connection
+                    .send(&Message::Extended {
+                        id,
+                        payload: message.to_bytes(),
+                    })
+                    .await?;
+            }
+        }
+        if let (Some(id), Some(address)) = (peer_extensions.id_of("ut_holepunch"), *listed) {
+            let relayed = download.lock().unwrap().relayed.remove(&address);
+            for message in relayed.into_iter().flatten() {
+                connection
+                    .send(&Message::Extended {
+                        id,
+                        payload: message.to_bytes(),
+                    })
+                    .await?;
+            }
+        }
+        connection.keep_alive().await?;
+
+        // In endgame the same block goes to several peers; once one
+        // delivers it, the others are told not to bother.
+        let stale: Vec<Block> = {
+            let download = download.lock().unwrap();
+            pipeline
+                .outstanding()
+                .map(|(block, _)| *block)
+                .filter(|block| !download.is_needed(block))
+                .collect()
+        };
+        for block in stale {
+            pipeline.remove(&block);
+            connection.send(&block.cancel()).await?;
+        }
+
+        let timeout = pipeline.request_timeout(options.snub_timeout);
+        if !pipeline.is_snubbed() && pipeline.is_stalled(Instant::now(), timeout) {
+            let blocks = pipeline.snub();
+            for block in &blocks {
+                connection.send(&block.cancel()).await?;
+            }
+            download.lock().unwrap().release(blocks);
+        }
+
+        if complete {
+            if connection.state().am_interested {
+                connection.send(&Message::NotInterested).await?;
+            }
+            // Two seeds have nothing to trade.
+            if !options.seed || peer_has.is_complete() {
+                return Ok(());
+            }
+        }
+        if wanted != connection.state().am_interested {
+            let message = if wanted {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            };
+            connection.send(&message).await?;
+        }
+
+        if connection.state().can_request() && pipeline.free_slots() > 0 {
+            let blocks =
+                download
+                    .lock()
+                    .unwrap()
+                    .pick_blocks(&peer_has, pipeline.free_slots(), |block| {
+                        pipeline.contains(block)
+                    });
+            for block in blocks {
+                connection.send(&block.request()).await?;
+                pipeline.push(block, Instant::now());
+            }
+        }
+
+        let now = Instant::now();
+        upload.update(now);
+        let unchoke = {
+            let mut download = download.lock().unwrap();
+            let state = connection.state();
+            stats.download_rate = pipeline.rate();
+            stats.upload_rate = upload.rate();
+            stats.outstanding = pipeline.outstanding().count();
+            stats.requested = pipeline.outstanding().map(|(block, _)| *block).collect();
+            stats.peer_requests = requests.iter().copied().collect();
+            stats.interested = state.peer_interested;
+            stats.am_interested = state.am_interested;
+            stats.am_choking = state.am_choking;
+            stats.peer_choking = state.peer_choking;
+            stats.snubbed = pipeline.is_snubbed();
+            stats.last_sent = connection.last_sent();
+            stats.last_received = connection.last_received();
+            stats.request_timeout = timeout;
+            stats.history = connection.history().iter().cloned().collect();
+            download.peers.insert(connection.address(), stats.clone());
+            download.should_unchoke(&connection.address(), now)
+        };
+        if unchoke == connection.state().am_choking {
+            let message = if unchoke {
+                Message::Unchoke
+            } else {
+                // A choked peer's requests are discarded.
+                requests.clear();
+                Message::Choke
+            };
+            connection.send(&message).await?;
+        }
+
+        for _ in 0..SERVE_BATCH {
+            let Some(block) = requests.pop_front() else {
+                break;
+            };
+            let data = download
+                .lock()
+                .unwrap()
+                .read_block(&block)
+                .map_err(PeerError::Storage)?;
+            let length = data.len() as u64;
+            connection
+                .send(&Message::Piece {
+                    index: block.piece,
+                    begin: block.begin,
+                    block: data,
+                })
+                .await?;
+            download.lock().unwrap().uploaded += length;
+            upload.record(length);
+            stats.uploaded += length;
+        }
+
+        // Only look for messages already here while requests wait.
+        let wait = if requests.is_empty() {
+            TICK
+        } else {
+            Duration::ZERO
+        };
+        let message = match tokio::time::timeout(wait, connection.receive()).await {
+            Ok(message) => message?,
+            Err(_) => {
+                if connection.silent_for() >= options.idle_timeout {
+                    return Err(PeerError::Idle(connection.silent_for()));
+                }
+                pipeline.update_rate(Instant::now());
+                continue;
+            }
+        };
+        match message {
+            Message::Bitfield(bytes) => {
+                peer_has = Bitfield::from_bytes(bytes, peer_has.len())?;
+                if super_seeding {
+                    let mut download = download.lock().unwrap();
+                    download
+                        .super_seed
+                        .peer_has(&connection.address(), &peer_has);
+                }
+            }
+            Message::Have(index) => {
+                peer_has.set(index as usize, true);
+                if super_seeding {
+                    let mut download = download.lock().unwrap();
+                    download
+                        .super_seed
+                        .peer_has(&connection.address(), &peer_has);
+                }
+            }
+            Message::Choke => download.lock().unwrap().release(pipeline.clear()),
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length,
+                };
+                // Requests we can't or won't answer are dropped, as are
+                // any sent while we choke the peer.
+                if !connection.state().am_choking
+                    && requests.len() < MAX_PEER_REQUESTS
+                    && !requests.contains(&block)
+                    && download.lock().unwrap().can_serve(&block)
+                    && (!super_seeding
+                        || download
+                            .lock()
+                            .unwrap()
+                            .super_seed
+                            .is_offered(&connection.address(), index as usize))
+                {
+                    requests.push_back(block);
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => requests.retain(|queued| {
+                *queued
+                    != Block {
+                        piece: index,
+                        begin,
+                        length,
+                    }
+            }),
+            Message::Port(port)
+                if port != 0 && connection.capabilities().contains(Capability::DHT) =>
+            {
+                let node = SocketAddr::new(connection.address().ip(), port);
+                download.lock().unwrap().dht_nodes.insert(node);
+            }
+            Message::Piece {
+                index,
+                begin,
+                block: data,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length: data.len() as u32,
+                };
+                // Blocks we didn't ask this peer for are dropped unread.
+                if !pipeline.on_received(block, Instant::now()) {
+                    continue;
+                }
+                stats.downloaded += block.length as u64;
+                let outcome = download
+                    .lock()
+                    .unwrap()
+                    .on_block(block, &data, connection.address())
+                    .map_err(PeerError::Storage)?;
+                match outcome {
+                    BlockOutcome::PieceVerified(_) => stats.pieces_received += 1,
+                    BlockOutcome::HashFailed(_) => stats.hash_failures += 1,
+                    BlockOutcome::Bad(bad) => return Err(PeerError::BadBlock(bad)),
+                    BlockOutcome::Stored | BlockOutcome::Duplicate => {}
+                }
+            }
+            Message::Extended {
+                id: extension::HANDSHAKE_ID,
+                payload,
+            } => {
+                // A malformed handshake just leaves extensions off.
+                peer_extensions = ExtensionHandshake::from_bytes(&payload).unwrap_or_default();
+                stats.extensions = peer_extensions
+                    .m
+                    .iter()
+                    .filter(|(name, id)| **id != 0 && handshake.m.contains_key(*name))
+                    .map(|(name, id)| (name.clone(), *id))
+                    .collect();
+                stats.client_version = peer_extensions.v.clone();
+                let flags = if peer_extensions.id_of("ut_holepunch").is_some() {
+                    SUPPORTS_HOLEPUNCH
+                } else {
+                    0
+                };
+                let mut download = download.lock().unwrap();
+                if let (None, Some(port)) = (*listed, peer_extensions.p) {
+                    let address = SocketAddr::new(connection.address().ip(), port);
+                    download.known.insert(address);
+                    download.connected.insert(address, 0);
+                    *listed = Some(address);
+                }
+                if let Some(address) = listed {
+                    *download.connected.entry(*address).or_default() |= flags;
+                }
+            }
+            Message::Extended {
+                id: UT_PEX_ID,
+                payload,
+            } if !private && pex.accept(Instant::now()) => {
+                let Ok(message) = PexMessage::parse(&payload) else {
+                    continue;
+                };
+                // Peers that can't be dialed but take holepunches are
+                // reached through the peer that told us of them.
+                let rendezvous: Vec<SocketAddr> = {
+                    let mut download = download.lock().unwrap();
+                    let unreachable = message
+                        .added
+                        .iter()
+                        .filter(|(address, flags)| {
+                            flags & (REACHABLE | SUPPORTS_HOLEPUNCH) == SUPPORTS_HOLEPUNCH
+                                && !download.known.contains(address)
+                        })
+                        .map(|(address, _)| *address)
+                        .collect();
+                    download.discover(message.added.into_iter().map(|(address, _)| address));
+                    unreachable
+                };
+                if let Some(id) = peer_extensions.id_of("ut_holepunch") {
+                    for address in rendezvous {
+                        connection
+                            .send(&Message::Extended {
+                                id,
+                                payload: HolepunchMessage::Rendezvous(address).to_bytes(),
+                            })
+                            .await?;
+                    }
+                }
+            }
+            Message::Extended {
+                id: UT_HOLEPUNCH_ID,
+                payload,
+            } => match HolepunchMessage::parse(&payload) {
+                Some(HolepunchMessage::Rendezvous(target)) => {
+                    let relayed =
+                        download
+                            .lock()
+                            .unwrap()
+                            .relay(connection.address(), *listed, target);
+                    let answer = match relayed {
+                        Ok(()) => HolepunchMessage::Connect(target),
+                        Err(code) => HolepunchMessage::Error(target, code),
+                    };
+                    if let Some(id) = peer_extensions.id_of("ut_holepunch") {
+                        connection
+                            .send(&Message::Extended {
+                                id,
+                                payload: answer.to_bytes(),
+                            })
+                            .await?;
+                    }
+                }
+                Some(HolepunchMessage::Connect(address)) => {
+                    download.lock().unwrap().holepunches.push(address);
+                }
+                // Nothing to retry: the target stays undialable.
+                Some(HolepunchMessage::Error(..)) | None => {}
+            },
+            Message::Extended {
+                id: UT_METADATA_ID,
+                payload,
+            } => {
+                let (Ok(MetadataMessage::Request(piece)), Some(id)) = (
+                    MetadataMessage::parse(&payload),
+                    peer_extensions.id_of("ut_metadata"),
+                ) else {
+                    continue;
+                };
+                let answer = MetadataMessage::answer(download.lock().unwrap().info_bytes(), piece);
+                connection
+                    .send(&Message::Extended {
+                        id,
+                        payload: answer.to_bytes(),
+                    })
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::RootFolder;
+    use std::fs;
+    use std::sync::Arc;
+
+    const PIECE_LEN: usize = 2 * BLOCK_LEN as usize;
+
+    /// Piece `index` is all `index + 1` bytes.
+    fn piece_data(index: u8) -> Vec<u8> {
+        vec![index + 1; PIECE_LEN]
+    }
+
+    /// A download of four two-block pieces into `dir`, none of them had.
+    fn four_pieces(dir: &str) -> Download {
+        let mut info = b"d6:lengthi131072e4:name1:a12:piece lengthi32768e6:pieces80:".to_vec();
+        for index in 0..4 {
+            info.extend(Sha1::digest(piece_data(index)));
+        }
+        info.push(b'e');
+        let torrent = Arc::new(Torrent::from_info_bytes(info, Vec::new()).unwrap());
+        let storage = Storage::new(torrent.clone(), dir, RootFolder::default()).unwrap();
+        Download::new(&torrent, storage, Bitfield::new(4))
+    }
+
+    #[test]
+    fn starts_no_piece_past_the_buffer_limit() {
+        let mut download = four_pieces("unused").max_buffer(2 * PIECE_LEN as u64);
+        let mut all = Bitfield::new(4);
+        (0..4).for_each(|index| all.set(index, true));
+
+        let blocks = download.pick_blocks(&all, 10, |_| false);
+        let pieces: BTreeSet<u32> = blocks.iter().map(|block| block.piece).collect();
+        assert_eq!(pieces, BTreeSet::from([0, 1]));
+        assert!(download.pick_blocks(&all, 10, |_| false).is_empty());
+
+        // Once a piece is no longer in flight, the next one starts.
+        download.release(blocks.iter().filter(|block| block.piece == 0).copied());
+        let blocks = download.pick_blocks(&all, 10, |_| false);
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|block| block.piece == 0));
+
+        // A piece larger than the limit is still fetched, alone.
+        let mut download = four_pieces("unused").max_buffer(1024);
+        let blocks = download.pick_blocks(&all, 10, |_| false);
+        assert!(blocks.len() == 2 && blocks.iter().all(|block| block.piece == 0));
+    }
+
+    #[test]
+    fn blames_the_peers_that_sent_bad_blocks() {
+        let dir = std::env::temp_dir().join(format!("crab_torrent-blame-{}", std::process::id()));
+        let mut download = four_pieces(dir.to_str().unwrap());
+        let peer = |last| SocketAddr::from(([10, 0, 0, last], 6881));
+        let good = piece_data(0);
+        let bad = vec![0; BLOCK_LEN as usize];
+        let [first, second] = [0, 1].map(|n| Block {
+            piece: 0,
+            begin: n * BLOCK_LEN,
+            length: BLOCK_LEN,
+        });
+        let (head, tail) = good.split_at(BLOCK_LEN as usize);
+
+        // With two senders, either could be at fault.
+        assert_eq!(
+            download.on_block(first, head, peer(1)).unwrap(),
+            BlockOutcome::Stored
+        );
+        assert_eq!(
+            download.on_block(second, &bad, peer(2)).unwrap(),
+            BlockOutcome::HashFailed(0)
+        );
+        assert_eq!(download.take_blamed(), []);
+
+        // The piece verifying shows which.
+        download.on_block(first, head, peer(1)).unwrap();
+        assert_eq!(
+            download.on_block(second, tail, peer(3)).unwrap(),
+            BlockOutcome::PieceVerified(0)
+        );
+        assert_eq!(download.take_blamed(), [peer(2)]);
+
+        // A peer that sent all of a bad piece is blamed at once.
+        let [first, second] = [first, second].map(|block| Block { piece: 1, ..block });
+        download.on_block(first, &bad, peer(2)).unwrap();
+        download.on_block(second, &bad, peer(2)).unwrap();
+        assert_eq!(download.take_blamed(), [peer(2)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
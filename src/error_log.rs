@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Collapses repeated errors from the same source so a flapping tracker or
+/// misbehaving peer doesn't flood the log. The first occurrence of an error
+/// is logged in full; repeats are counted and summarized at most once per
+/// interval.
+#[derive(Debug)]
+pub struct ErrorLog {
+    interval: Duration,
+    repeats: HashMap<(String, String), Repeats>,
+}
+
+#[derive(Debug)]
+struct Repeats {
+    count: u64,
+    since: Instant,
+}
+
+impl ErrorLog {
+    pub fn new(interval: Duration) -> Self {
+        ErrorLog {
+            interval,
+            repeats: HashMap::new(),
+        }
+    }
+
+    /// Records `message` from `source` (a tracker URL, a peer address...)
+    /// and returns the line to log now, if any.
+    pub fn report(&mut self, source: &str, message: &str) -> Option<String> {
+        let now = Instant::now();
+        let key = (source.to_string(), message.to_string());
+
+        let Some(repeats) = self.repeats.get_mut(&key) else {
+            self.repeats.insert(
+                key,
+                Repeats {
+                    count: 0,
+                    since: now,
+                },
+            );
+            return Some(format!("{}: {}", source, message));
+        };
+
+        repeats.count += 1;
+        if now.duration_since(repeats.since) < self.interval {
+            return None;
+        }
+
+        let line = summary(source, message, repeats, now);
+        repeats.count = 0;
+        repeats.since = now;
+        Some(line)
+    }
+
+    /// Records and prints to stderr.
+    pub fn error(&mut self, source: &str, message: &str) {
+        if let Some(line) = self.report(source, message) {
+            eprintln!("{}", line);
+        }
+    }
+
+    /// Summaries of repeats not yet logged, e.g. before exiting.
+    pub fn flush(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let mut lines: Vec<String> = self
+            .repeats
+            .iter()
+            .filter(|(_, repeats)| repeats.count > 0)
+            .map(|((source, message), repeats)| summary(source, message, repeats, now))
+            .collect();
+        lines.sort();
+
+        for repeats in self.repeats.values_mut() {
+            repeats.count = 0;
+            repeats.since = now;
+        }
+        lines
+    }
+}
+
+impl Default for ErrorLog {
+    fn default() -> Self {
+        ErrorLog::new(DEFAULT_SUMMARY_INTERVAL)
+    }
+}
+
+fn summary(source: &str, message: &str, repeats: &Repeats, now: Instant) -> String {
+    format!(
+        "{}: {} (repeated {} times in {} s)",
+        source,
+        message,
+        repeats.count,
+        now.duration_since(repeats.since).as_secs()
+    )
+}
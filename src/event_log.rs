@@ -0,0 +1,68 @@
+use crate::info_hash::InfoHash;
+use crate::resume::data_dir;
+use crate::schedule::{Clock, SystemClock};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Oldest events are dropped beyond this many per torrent.
+pub const MAX_EVENTS: usize = 500;
+
+/// Something notable that happened to a torrent.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Event {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub message: String,
+}
+
+/// A bounded, persistent log of a torrent's events, kept next to its
+/// resume data so it survives restarts.
+pub struct EventLog {
+    path: PathBuf,
+}
+
+impl EventLog {
+    pub fn new(dir: impl Into<PathBuf>, info_hash: &InfoHash) -> Self {
+        EventLog {
+            path: dir.into().join(format!("{}.log", info_hash)),
+        }
+    }
+
+    /// The log for `info_hash` in `data_dir()`.
+    pub fn for_torrent(info_hash: &InfoHash) -> Self {
+        EventLog::new(data_dir(), info_hash)
+    }
+
+    /// Every event recorded, oldest first.
+    pub fn events(&self) -> Result<Vec<Event>> {
+        match fs::read(&self.path) {
+            Ok(contents) => Ok(serde_bencode::from_bytes(&contents)?),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Appends an event stamped with the current time.
+    pub fn record(&self, message: impl Into<String>) -> Result<()> {
+        let mut events = self.events()?;
+        events.push(Event {
+            time: SystemClock.now(),
+            message: message.into(),
+        });
+        if events.len() > MAX_EVENTS {
+            events.drain(..events.len() - MAX_EVENTS);
+        }
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = self.path.with_extension("log.tmp");
+        fs::write(&temporary, serde_bencode::to_bytes(&events)?)?;
+        fs::rename(temporary, &self.path)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use crate::bencode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Extended message id of the extension protocol handshake (BEP 10).
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The ids we ask peers to use for extension messages sent to us. Peers
+/// pick their own ids, which their handshake tells us.
+pub const UT_METADATA_ID: u8 = 1;
+pub const UT_PEX_ID: u8 = 2;
+pub const UT_HOLEPUNCH_ID: u8 = 3;
+
+/// The dictionary both sides send after the BitTorrent handshake to agree
+/// on which extensions they speak.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExtensionHandshake {
+    /// Extension names mapped to the message id the sender wants for
+    /// them. An id of 0 turns an extension off.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// Size of the info dictionary, for ut_metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+    /// Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// The sender's listening port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+}
+
+impl ExtensionHandshake {
+    /// The id the sender wants for extension `name`, if it speaks it.
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).expect("handshake encodes")
+    }
+
+    /// Parses a peer's handshake. Fields we don't know are ignored.
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, bencode::BencodeError> {
+        bencode::decode(payload)
+    }
+}
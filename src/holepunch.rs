@@ -0,0 +1,142 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Why a relay couldn't forward a rendezvous.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The relay doesn't know the target.
+    NoSuchPeer,
+    /// The relay isn't connected to the target.
+    NotConnected,
+    /// The target doesn't support ut_holepunch.
+    NoSupport,
+    /// The initiator named itself as the target.
+    NoSelf,
+    Other(u32),
+}
+
+impl ErrorCode {
+    fn from_u32(code: u32) -> Self {
+        match code {
+            1 => ErrorCode::NoSuchPeer,
+            2 => ErrorCode::NotConnected,
+            3 => ErrorCode::NoSupport,
+            4 => ErrorCode::NoSelf,
+            code => ErrorCode::Other(code),
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            ErrorCode::NoSuchPeer => 1,
+            ErrorCode::NotConnected => 2,
+            ErrorCode::NoSupport => 3,
+            ErrorCode::NoSelf => 4,
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::NoSuchPeer => write!(f, "no such peer"),
+            ErrorCode::NotConnected => write!(f, "not connected to the peer"),
+            ErrorCode::NoSupport => write!(f, "peer does not support holepunching"),
+            ErrorCode::NoSelf => write!(f, "cannot holepunch to oneself"),
+            ErrorCode::Other(code) => write!(f, "holepunch error {}", code),
+        }
+    }
+}
+
+/// A ut_holepunch message (BEP 55). Two peers that can't reach each
+/// other ask a peer both are connected to to relay: the initiator sends
+/// it `Rendezvous` naming the target, and it sends each side `Connect`
+/// with the other's address, at which both dial at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchMessage {
+    Rendezvous(SocketAddr),
+    Connect(SocketAddr),
+    /// The relay's answer to a rendezvous for the address it couldn't
+    /// forward.
+    Error(SocketAddr, ErrorCode),
+}
+
+impl HolepunchMessage {
+    /// The extended message payload: type, address family, address, port
+    /// and, for errors, the code.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (kind, address, code) = match *self {
+            HolepunchMessage::Rendezvous(address) => (0, address, 0),
+            HolepunchMessage::Connect(address) => (1, address, 0),
+            HolepunchMessage::Error(address, code) => (2, address, code.to_u32()),
+        };
+        let mut bytes = vec![kind];
+        match address.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(0);
+                bytes.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(1);
+                bytes.extend(ip.octets());
+            }
+        }
+        bytes.extend(address.port().to_be_bytes());
+        bytes.extend(code.to_be_bytes());
+        bytes
+    }
+
+    /// Reads a payload, or `None` if it is malformed.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let (&kind, rest) = payload.split_first()?;
+        let (&family, rest) = rest.split_first()?;
+        let (ip, rest): (IpAddr, _) = match family {
+            0 => {
+                let (ip, rest) = rest.split_first_chunk::<4>()?;
+                (Ipv4Addr::from(*ip).into(), rest)
+            }
+            1 => {
+                let (ip, rest) = rest.split_first_chunk::<16>()?;
+                (Ipv6Addr::from(*ip).into(), rest)
+            }
+            _ => return None,
+        };
+        let (port, rest) = rest.split_first_chunk::<2>()?;
+        let address = SocketAddr::new(ip, u16::from_be_bytes(*port));
+        match kind {
+            0 => Some(HolepunchMessage::Rendezvous(address)),
+            1 => Some(HolepunchMessage::Connect(address)),
+            2 => {
+                let (code, _) = rest.split_first_chunk::<4>()?;
+                let code = ErrorCode::from_u32(u32::from_be_bytes(*code));
+                Some(HolepunchMessage::Error(address, code))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_each_message() {
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+        let rendezvous = HolepunchMessage::Rendezvous(v4);
+        assert_eq!(
+            rendezvous.to_bytes(),
+            [0, 0, 10, 0, 0, 1, 0x1a, 0xe1, 0, 0, 0, 0]
+        );
+        for message in [
+            rendezvous,
+            HolepunchMessage::Connect(v6),
+            HolepunchMessage::Error(v4, ErrorCode::NotConnected),
+        ] {
+            assert_eq!(HolepunchMessage::parse(&message.to_bytes()), Some(message));
+        }
+        assert_eq!(HolepunchMessage::parse(&[1, 0, 10, 0]), None);
+    }
+}
@@ -0,0 +1,172 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use urlencoding::encode_binary;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A 20-byte torrent infohash: the SHA-1 of a v1 info dictionary, or the
+/// truncated SHA-256 of a v2 one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct InfoHash(pub [u8; 20]);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseInfoHashError(String);
+
+impl fmt::Display for ParseInfoHashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "\"{}\" is not a 40-character hex or 32-character base32 infohash",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseInfoHashError {}
+
+impl InfoHash {
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Percent-encodes the raw bytes for the `info_hash` tracker parameter.
+    pub fn url_encoded(&self) -> String {
+        encode_binary(&self.0).into_owned()
+    }
+
+    /// RFC 4648 base32 without padding, as found in older magnet links.
+    pub fn to_base32(&self) -> String {
+        let mut encoded = String::with_capacity(32);
+        let mut buffer: u32 = 0;
+        let mut bits = 0;
+
+        for byte in self.0 {
+            buffer = (buffer << 8) | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+            }
+        }
+
+        encoded
+    }
+
+    fn from_hex(s: &str) -> Option<InfoHash> {
+        if !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let mut bytes = [0u8; 20];
+        for (byte, pair) in bytes.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+            let pair = std::str::from_utf8(pair).ok()?;
+            *byte = u8::from_str_radix(pair, 16).ok()?;
+        }
+        Some(InfoHash(bytes))
+    }
+
+    fn from_base32(s: &str) -> Option<InfoHash> {
+        let mut bytes = Vec::with_capacity(20);
+        let mut buffer: u32 = 0;
+        let mut bits = 0;
+
+        for c in s.bytes() {
+            let value = BASE32_ALPHABET
+                .iter()
+                .position(|a| *a == c.to_ascii_uppercase())?;
+            buffer = (buffer << 5) | value as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+            }
+        }
+
+        bytes.try_into().ok().map(InfoHash)
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for InfoHash {
+    type Err = ParseInfoHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = match s.len() {
+            40 => InfoHash::from_hex(s),
+            32 => InfoHash::from_base32(s),
+            _ => None,
+        };
+        parsed.ok_or_else(|| ParseInfoHashError(s.to_string()))
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(bytes: [u8; 20]) -> Self {
+        InfoHash(bytes)
+    }
+}
+
+impl AsRef<[u8]> for InfoHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Serializes as the lowercase hex string.
+impl Serialize for InfoHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "0123456789abcdef0123456789abcdef01234567";
+    const BASE32: &str = "AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH";
+
+    #[test]
+    fn parses_and_prints_hex_and_base32() {
+        let info_hash: InfoHash = HEX.parse().unwrap();
+        assert_eq!(info_hash.0[..4], [0x01, 0x23, 0x45, 0x67]);
+        assert_eq!(info_hash.to_string(), HEX);
+        assert_eq!(info_hash.to_base32(), BASE32);
+        assert_eq!(BASE32.parse::<InfoHash>(), Ok(info_hash));
+        assert_eq!(BASE32.to_lowercase().parse::<InfoHash>(), Ok(info_hash));
+        assert_eq!(HEX.to_uppercase().parse::<InfoHash>(), Ok(info_hash));
+    }
+
+    #[test]
+    fn rejects_wrong_lengths_and_alphabets() {
+        for bad in [
+            "",
+            &HEX[..39],
+            &format!("{}0", HEX),
+            &BASE32[..31],
+            // Hex length, but not hex.
+            "0123456789abcdef0123456789abcdef0123456g",
+            // Base32 length, with 0, 1 and 8, which base32 lacks.
+            "AERUKZ4JVPG66AJDIVTYTK6N54ASGR01",
+            "AERUKZ4JVPG66AJDIVTYTK6N54ASGRL8",
+            // 40 bytes, but multi-byte characters.
+            "éééééééééééééééééééé",
+        ] {
+            assert_eq!(
+                bad.parse::<InfoHash>(),
+                Err(ParseInfoHashError(bad.to_string())),
+                "{:?}",
+                bad
+            );
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// eMule entries at or above this access level are allowed, not blocked.
+const EMULE_ALLOW_LEVEL: u32 = 128;
+
+#[derive(Debug)]
+pub enum IpFilterError {
+    Io(io::Error),
+    /// A line that is neither a comment nor a range in a known format.
+    BadLine {
+        line: usize,
+        text: String,
+    },
+}
+
+impl fmt::Display for IpFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IpFilterError::Io(error) => write!(f, "reading the IP filter: {}", error),
+            IpFilterError::BadLine { line, text } => {
+                write!(f, "IP filter line {}: not a range: {}", line, text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IpFilterError {}
+
+impl From<io::Error> for IpFilterError {
+    fn from(error: io::Error) -> Self {
+        IpFilterError::Io(error)
+    }
+}
+
+/// Address ranges peers are not accepted from or dialed at, loaded from
+/// eMule `ipfilter.dat` lines (`1.2.3.0 - 1.2.3.255 , 000 , name`),
+/// PeerGuardian `.p2p` lines (`name:1.2.3.0-1.2.3.255`), CIDR blocks
+/// (`1.2.3.0/24`, `2001:db8::/32`) or single addresses. Blank lines and
+/// ones starting with `#` or `//` are skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    /// Inclusive ranges, sorted and merged. IPv4 sorts before IPv6.
+    ranges: Vec<(IpAddr, IpAddr)>,
+}
+
+impl IpFilter {
+    pub fn load(path: &Path) -> Result<Self, IpFilterError> {
+        IpFilter::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, IpFilterError> {
+        let mut filter = IpFilter::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some(range)) => filter.ranges.push(range),
+                Some(None) => {}
+                None => {
+                    return Err(IpFilterError::BadLine {
+                        line: index + 1,
+                        text: line.to_string(),
+                    })
+                }
+            }
+        }
+        filter.merge();
+        Ok(filter)
+    }
+
+    /// Adds the ranges of `other`, as when several lists are given.
+    pub fn extend(&mut self, other: IpFilter) {
+        self.ranges.extend(other.ranges);
+        self.merge();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Whether `ip`, or the IPv4 address an IPv4-mapped one stands for,
+    /// lies in a listed range.
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let after = self.ranges.partition_point(|(start, _)| *start <= ip);
+        after > 0 && ip <= self.ranges[after - 1].1
+    }
+
+    fn merge(&mut self) {
+        self.ranges.sort();
+        let mut merged: Vec<(IpAddr, IpAddr)> = Vec::with_capacity(self.ranges.len());
+        for (start, end) in self.ranges.drain(..) {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 || is_next(last.1, start) => {
+                    last.1 = last.1.max(end);
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+/// The range a line blocks, `Some(None)` for an eMule entry that allows
+/// it, or `None` if the line can't be read.
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    if let Some((range, rest)) = line.split_once(',') {
+        let level = rest.split(',').next()?.trim().parse::<u32>().ok()?;
+        let range = parse_range(range)?;
+        return Some((level < EMULE_ALLOW_LEVEL).then_some(range));
+    }
+    if let Some(range) = parse_range(line) {
+        return Some(Some(range));
+    }
+    // The name may hold colons of its own, the IPv4 range can't.
+    let (_, range) = line.rsplit_once(':')?;
+    parse_range(range).map(Some)
+}
+
+/// `start - end`, `address/prefix` or a single address.
+fn parse_range(text: &str) -> Option<(IpAddr, IpAddr)> {
+    if let Some((start, end)) = text.split_once('-') {
+        let (start, end) = (parse_ip(start.trim())?, parse_ip(end.trim())?);
+        return (start.is_ipv4() == end.is_ipv4() && start <= end).then_some((start, end));
+    }
+    if let Some((address, prefix)) = text.split_once('/') {
+        let prefix: u32 = prefix.trim().parse().ok()?;
+        return match parse_ip(address.trim())? {
+            IpAddr::V4(address) if prefix <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                let start = u32::from(address) & mask;
+                Some((
+                    Ipv4Addr::from(start).into(),
+                    Ipv4Addr::from(start | !mask).into(),
+                ))
+            }
+            IpAddr::V6(address) if prefix <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                let start = u128::from(address) & mask;
+                Some((
+                    Ipv6Addr::from(start).into(),
+                    Ipv6Addr::from(start | !mask).into(),
+                ))
+            }
+            _ => None,
+        };
+    }
+    let ip = parse_ip(text.trim())?;
+    Some((ip, ip))
+}
+
+/// Parses an address, allowing the zero-padded octets eMule lists use.
+fn parse_ip(text: &str) -> Option<IpAddr> {
+    if text.contains(':') {
+        return text.parse::<Ipv6Addr>().ok().map(|ip| ip.to_canonical());
+    }
+    let mut octets = [0u8; 4];
+    let mut parts = text.split('.');
+    for octet in &mut octets {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 3 {
+            return None;
+        }
+        *octet = part.parse().ok()?;
+    }
+    parts
+        .next()
+        .is_none()
+        .then(|| Ipv4Addr::from(octets).into())
+}
+
+/// Whether `b` directly follows `a`, so their ranges can be joined.
+fn is_next(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => u32::from(a).checked_add(1) == Some(u32::from(b)),
+        (IpAddr::V6(a), IpAddr::V6(b)) => u128::from(a).checked_add(1) == Some(u128::from(b)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_each_format_and_blocks_listed_addresses() {
+        let filter = IpFilter::parse(
+            "# comment\n\
+             001.002.003.000 - 001.002.003.255 , 000 , eMule\n\
+             009.009.009.009 - 009.009.009.009 , 200 , allowed\n\
+             Some: guardian:5.6.7.0-5.6.7.9\n\
+             10.0.0.0/8\n\
+             2001:db8::/32\n\
+             192.168.1.1\n",
+        )
+        .unwrap();
+        let blocked = |ip: &str| filter.is_blocked(ip.parse().unwrap());
+        assert!(blocked("1.2.3.4"));
+        assert!(!blocked("1.2.4.0"));
+        assert!(!blocked("9.9.9.9"));
+        assert!(blocked("5.6.7.9"));
+        assert!(!blocked("5.6.7.10"));
+        assert!(blocked("10.200.0.1"));
+        assert!(blocked("::ffff:10.0.0.1"));
+        assert!(blocked("2001:db8:ffff::1"));
+        assert!(!blocked("2001:db9::"));
+        assert!(blocked("192.168.1.1"));
+        assert!(!blocked("192.168.1.2"));
+
+        assert!(matches!(
+            IpFilter::parse("1.2.3.4\nnonsense\n"),
+            Err(IpFilterError::BadLine { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,46 @@
+pub mod assembler;
+pub mod bencode;
+pub mod bitfield;
+pub mod builder;
+pub mod capabilities;
+pub mod choker;
+pub mod connections;
+pub mod debug;
+pub mod download;
+pub mod error_log;
+pub mod event_log;
+pub mod extension;
+pub mod holepunch;
+pub mod info_hash;
+pub mod ip_filter;
+pub mod magnet;
+pub mod metadata;
+pub mod mse;
+pub mod net;
+pub mod peer;
+pub mod peer_id;
+pub mod peer_priority;
+pub mod peer_state;
+pub mod pex;
+pub mod piece_timing;
+pub mod pipeline;
+pub mod priority;
+pub mod rate;
+pub mod rate_history;
+pub mod reliability;
+pub mod resume;
+pub mod sanitize;
+pub mod schedule;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod storage;
+pub mod summary;
+pub mod super_seed;
+pub mod throttle;
+pub mod torrent;
+pub mod tracker;
+pub mod udp_tracker;
+pub mod units;
+pub mod validate;
+pub mod verify;
+pub mod wire;
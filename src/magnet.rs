@@ -0,0 +1,47 @@
+use crate::info_hash::InfoHash;
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+use url::Url;
+
+/// The parts of a magnet link needed to fetch a torrent's metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    /// The `dn` display name, until the metadata gives the real one.
+    pub name: Option<String>,
+    /// `tr` trackers, in order.
+    pub trackers: Vec<String>,
+}
+
+impl FromStr for MagnetLink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let url = Url::parse(s)?;
+        if url.scheme() != "magnet" {
+            return Err(anyhow!("{} is not a magnet link", s));
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(hash.parse()?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.ok_or_else(|| anyhow!("magnet link has no urn:btih infohash"))?,
+            name,
+            trackers,
+        })
+    }
+}
@@ -1,37 +1,176 @@
-mod torrent;
+mod commands;
 
 use anyhow::{anyhow, Result};
 use std::env;
-use std::fs;
-use torrent::Torrent;
-use url::Url;
-use urlencoding::encode_binary;
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        return Err(anyhow!("Usage: ./bencode <torrent_name>"));
-    }
-
-    let torrent_name = &args[1];
-    let file_contents = fs::read(torrent_name).expect("Couldn't read torrent file");
-    let torrent = Torrent::new(file_contents)?;
+const USAGE: &str = "Usage: crab_torrent [options] [add|probe|magnet|lint] <torrent_file_or_url>
+       crab_torrent add --info <info_dict_file_or_url> <announce_url>...
+       crab_torrent info [--json] <torrent_file_or_url>
+       crab_torrent decode <bencoded_file_or_url>
+       crab_torrent recheck <torrent_file_or_url> <download_dir>
+       crab_torrent status [--log] <torrent_file_or_url>
+       crab_torrent network [--clear] <torrent_file_or_url>
+       crab_torrent scrape <tracker_url> <infohash_or_torrent>...
+       crab_torrent handshake [--encrypt] <torrent_file_or_url> <ip:port>
+       crab_torrent download <torrent_file_url_or_magnet> <download_dir> [<ip:port>...]
+       crab_torrent create [create options] <path> <announce_url>
+       crab_torrent debug dump <status_port> <file>
+       crab_torrent debug peer <status_port> <ip:port>
 
-    let client = reqwest::blocking::Client::new();
-    let mut url = Url::parse(&torrent.announce)?;
+A file argument of - reads from stdin. network saves --proxy and --bind as
+one torrent's own settings, used instead of the session's for it.
 
-    let info_hash = torrent.info_hash();
-    let info_hash_string = encode_binary(&info_hash);
-    let sum: i64 = torrent.info.files.iter().map(|b| b.length).sum();
+Options:
+  --proxy <url|direct>  proxy for tracker, .torrent and peer connections;
+                        peers need a socks5:// or http:// proxy
+  --bind <ip>           local address to connect and listen from
+  --doh <url>           resolve tracker hostnames with this DNS-over-HTTPS
+                        JSON resolver
+  --tls <backend>       rustls (default) or native (needs the native-tls
+                        feature)
+  --ca-cert <pem>       also trust this CA certificate; may be repeated
+  --pin-cert <pem>      trust only this certificate for HTTPS trackers
+  --cookie <cookie>     cookie sent when fetching a .torrent URL
+  --tracker-auth <prefix>=<user>:<password>
+                        HTTP basic auth for trackers whose URL starts with
+                        prefix; may be repeated
+  --tracker-cookie <prefix>=<cookie>
+                        Cookie header for those trackers
+  --tracker-header <prefix>=<Name>: <value>
+                        extra header for those trackers
+  --max-read-rate <n>   limit recheck disk reads to a rate like 20MiB/s
+  --dual-stack          announce over IPv4 and IPv6 separately
+  --prefer-reliable     announce to historically reliable trackers first
+  --peer-id-prefix <p>  start the announced peer id with p, e.g. -qB4630-
+  --user-agent <ua>     HTTP User-Agent, e.g. qBittorrent/4.6.3; set with
+                        --peer-id-prefix so both name the same client
+  --numwant <n>         peers to ask trackers for (default 50)
+  --no-compact          ask trackers for dictionary peer lists
+  --peer-ids            ask for peer ids in dictionary peer lists
+  --retry-initial <d>   first retry delay after a tracker times out or
+                        fails with 5xx, doubling per failure (default 15s)
+  --retry-max <d>       longest retry delay (default 30m)
+  --request-queue <n|min-max>
+                        block requests to keep outstanding per peer; a
+                        range adapts to each peer's rate (default 5-16)
+  --dht-port <port>     UDP port of our DHT node, advertised to peers
+  --peer-timeout <d>    drop peers silent for this long (default 3m)
+  --snub-timeout <d>    move requests off peers that answer none for this
+                        long (default 1m), or sooner for peers that
+                        usually answer faster
+  --port <port|first-last>
+                        TCP port to take peer connections on and announce
+                        (default 6881); with a range, the first one free,
+                        or any free port once all are taken
+  --status-port <port>  serve a download's progress, rates and peers as
+                        JSON at http://127.0.0.1:<port>/, one connection
+                        in detail at /peers/<ip:port>, rates each second
+                        for the last hour and each minute for the last
+                        day at /rates, and its internal state for bug
+                        reports at /debug
+  --piece-times <file>  once a download ends, write when each piece was
+                        first requested and verified to a CSV file
+  --seed                keep serving peers after a download completes
+  --super-seed          seed revealing one piece at a time to each peer,
+                        the next once the last has spread to others
+  --upload-slots <n>    peers unchoked for their rates, plus one
+                        optimistic unchoke (default 4)
+  --max-buffer <size>   memory for pieces being downloaded; new pieces
+                        wait once this much is in flight (default 64MiB)
+  --max-connections <n> peer connections open at once (default 200)
+  --max-peers <n>       peer connections per torrent (default 50)
+  --max-hash-failures <n>
+                        ban peers after sending this many pieces that
+                        fail their hash check (default 3)
+  --max-violations <n>  ban peers after this many protocol violations
+                        (default 2)
+  --max-dial-failures <n>
+                        ban peers after this many failed connections in
+                        a row (default 3)
+  --forgive-after <d>   forgive each peer one strike of each kind this
+                        often, lifting bans (default 30m, 0 never)
+  --encryption <mode>   required, preferred (RC4 when the peer supports
+                        it, the default) or disabled
+  --ip-filter <file>    never connect to peers in the ranges of an eMule
+                        .dat, PeerGuardian .p2p or CIDR list; may be
+                        repeated
+  --root-folder <mode>  original, strip (no folder for multi-file torrents)
+                        or always (a folder even for single files)
 
-    url.set_query(Some(&format!(
-        "info_hash={}&peer_id={}&downloaded={}&uploaded={}&left={}&event={}&port={}",
-        info_hash_string, "-PC0001-W6R0LID6jXMs", 0, 0, sum, "started", 6881,
-    )));
+Create options:
+  --align               pad files to start on piece boundaries
+  --v2 | --hybrid       emit v2-only or hybrid v1+v2 metainfo
+  --comment <text>      set the comment
+  --created-by <text>   replace the created by string
+  --no-date             leave out the creation date
+  --piece-length <size> piece length, e.g. 1MiB
+  --private             set the private flag
+  --source <tag>        set the source tag";
 
-    let response = client.get(url).send()?;
-
-    println!("{}", response.text()?);
+fn main() -> Result<()> {
+    let mut args: Vec<String> = env::args().collect();
+    let options = commands::take_options(&mut args)?;
 
-    Ok(())
+    match args.as_slice() {
+        [_, command, torrent_name] if command == "probe" => {
+            commands::probe::run(torrent_name, &options)
+        }
+        [_, command, torrent_name] if command == "magnet" => {
+            commands::magnet::run(torrent_name, &options)
+        }
+        [_, command, torrent_name] if command == "lint" => {
+            commands::lint::run(torrent_name, &options)
+        }
+        [_, command, input] if command == "decode" => commands::decode::run(input, &options),
+        [_, command, torrent_name] if command == "info" => {
+            commands::info::run(torrent_name, false, &options)
+        }
+        [_, command, flag, torrent_name] if command == "info" && flag == "--json" => {
+            commands::info::run(torrent_name, true, &options)
+        }
+        [_, command, torrent_name, download_dir] if command == "recheck" => {
+            commands::recheck::run(torrent_name, download_dir, &options)
+        }
+        [_, command, action, status_port, file] if command == "debug" && action == "dump" => {
+            commands::debug::run_dump(status_port, file)
+        }
+        [_, command, action, status_port, address] if command == "debug" && action == "peer" => {
+            commands::debug::run_peer(status_port, address)
+        }
+        [_, command, create_args @ ..] if command == "create" => commands::create::run(create_args),
+        [_, command, torrent_name] if command == "status" => {
+            commands::status::run(torrent_name, &options)
+        }
+        [_, command, flag, torrent_name] if command == "status" && flag == "--log" => {
+            commands::status::run_log(torrent_name, &options)
+        }
+        [_, command, torrent_name] if command == "network" => {
+            commands::network::run(torrent_name, false, &options)
+        }
+        [_, command, flag, torrent_name] if command == "network" && flag == "--clear" => {
+            commands::network::run(torrent_name, true, &options)
+        }
+        [_, command, tracker, targets @ ..] if command == "scrape" && !targets.is_empty() => {
+            commands::scrape::run(tracker, targets, &options)
+        }
+        [_, command, torrent_name, address] if command == "handshake" => {
+            commands::handshake::run(torrent_name, address, false, &options)
+        }
+        [_, command, flag, torrent_name, address]
+            if command == "handshake" && flag == "--encrypt" =>
+        {
+            commands::handshake::run(torrent_name, address, true, &options)
+        }
+        [_, command, torrent_name, download_dir, addresses @ ..] if command == "download" => {
+            commands::download::run(torrent_name, download_dir, addresses, &options)
+        }
+        [_, command, flag, info_name, trackers @ ..] if command == "add" && flag == "--info" => {
+            commands::announce::run_info(info_name, trackers, &options)
+        }
+        [_, command, torrent_name] if command == "add" => {
+            commands::announce::run(torrent_name, &options)
+        }
+        [_, torrent_name] => commands::announce::run(torrent_name, &options),
+        _ => Err(anyhow!(USAGE)),
+    }
 }
@@ -0,0 +1,266 @@
+use crate::bencode;
+use crate::capabilities::Capability;
+use crate::extension::{self, ExtensionHandshake, UT_METADATA_ID};
+use crate::info_hash::InfoHash;
+use crate::net::DEFAULT_USER_AGENT;
+use crate::peer::{PeerConnection, PeerError};
+use crate::wire::Message;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::time::Duration;
+
+/// The info dictionary is exchanged in pieces of this size, the last one
+/// possibly shorter.
+pub const METADATA_PIECE_LEN: usize = 16 * 1024;
+
+/// Largest info dictionary we'll fetch; real ones are rarely over a few
+/// MiB.
+pub const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A ut_metadata message (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data {
+        piece: u32,
+        total_size: u64,
+        data: Vec<u8>,
+    },
+    Reject(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataError {
+    /// The peer doesn't speak ut_metadata or didn't say how big the info
+    /// dictionary is.
+    Unsupported,
+    Rejected(u32),
+    Malformed,
+    TooLarge(u64),
+    /// The assembled dictionary doesn't hash to the infohash.
+    HashMismatch,
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::Unsupported => write!(f, "peer does not offer the metadata"),
+            MetadataError::Rejected(piece) => {
+                write!(f, "peer rejected the request for metadata piece {}", piece)
+            }
+            MetadataError::Malformed => write!(f, "malformed ut_metadata message"),
+            MetadataError::TooLarge(size) => {
+                write!(f, "{}-byte metadata exceeds {}", size, MAX_METADATA_SIZE)
+            }
+            MetadataError::HashMismatch => write!(f, "metadata does not match the infohash"),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+/// The bencoded dictionary at the start of every ut_metadata message.
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    msg_type: u8,
+    piece: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<u64>,
+}
+
+impl MetadataMessage {
+    /// The extended message payload: a bencoded header, followed by the
+    /// piece itself for `Data`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (header, data) = match self {
+            MetadataMessage::Request(piece) => (
+                Header {
+                    msg_type: 0,
+                    piece: *piece,
+                    total_size: None,
+                },
+                &[][..],
+            ),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                Header {
+                    msg_type: 1,
+                    piece: *piece,
+                    total_size: Some(*total_size),
+                },
+                &data[..],
+            ),
+            MetadataMessage::Reject(piece) => (
+                Header {
+                    msg_type: 2,
+                    piece: *piece,
+                    total_size: None,
+                },
+                &[][..],
+            ),
+        };
+        let mut bytes = serde_bencode::to_bytes(&header).expect("header encodes");
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    pub fn parse(payload: &[u8]) -> Result<Self, MetadataError> {
+        let end = bencode::skip_value(payload, 0).map_err(|_| MetadataError::Malformed)?;
+        let header: Header =
+            bencode::decode(&payload[..end]).map_err(|_| MetadataError::Malformed)?;
+        match header.msg_type {
+            0 => Ok(MetadataMessage::Request(header.piece)),
+            1 => Ok(MetadataMessage::Data {
+                piece: header.piece,
+                total_size: header.total_size.ok_or(MetadataError::Malformed)?,
+                data: payload[end..].to_vec(),
+            }),
+            2 => Ok(MetadataMessage::Reject(header.piece)),
+            _ => Err(MetadataError::Malformed),
+        }
+    }
+
+    /// The reply to a request for `piece` of our `info_bytes`.
+    pub fn answer(info_bytes: &[u8], piece: u32) -> Self {
+        let start = piece as usize * METADATA_PIECE_LEN;
+        if start >= info_bytes.len() {
+            return MetadataMessage::Reject(piece);
+        }
+        let end = (start + METADATA_PIECE_LEN).min(info_bytes.len());
+        MetadataMessage::Data {
+            piece,
+            total_size: info_bytes.len() as u64,
+            data: info_bytes[start..end].to_vec(),
+        }
+    }
+}
+
+/// Our extension handshake, offering ut_metadata with the size of
+/// `info_bytes` when we have them.
+pub fn our_handshake(info_bytes: Option<&[u8]>) -> ExtensionHandshake {
+    let mut handshake = ExtensionHandshake {
+        metadata_size: info_bytes.map(|bytes| bytes.len() as u64),
+        v: Some(DEFAULT_USER_AGENT.to_string()),
+        ..ExtensionHandshake::default()
+    };
+    handshake
+        .m
+        .insert("ut_metadata".to_string(), UT_METADATA_ID);
+    handshake
+}
+
+/// Fetches the info dictionary for `info_hash` from a connected peer,
+/// checking that it hashes to the infohash.
+pub async fn fetch_metadata(
+    connection: &mut PeerConnection,
+    info_hash: &InfoHash,
+    timeout: Duration,
+) -> Result<Vec<u8>, PeerError> {
+    tokio::time::timeout(timeout, fetch(connection, info_hash))
+        .await
+        .map_err(|_| PeerError::TimedOut)?
+}
+
+async fn fetch(
+    connection: &mut PeerConnection,
+    info_hash: &InfoHash,
+) -> Result<Vec<u8>, PeerError> {
+    if !connection.supports(Capability::EXTENSIONS) {
+        return Err(MetadataError::Unsupported.into());
+    }
+    connection
+        .send(&Message::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload: our_handshake(None).to_bytes(),
+        })
+        .await?;
+
+    let (peer_id, size) = loop {
+        if let Message::Extended {
+            id: extension::HANDSHAKE_ID,
+            payload,
+        } = connection.receive().await?
+        {
+            let handshake =
+                ExtensionHandshake::from_bytes(&payload).map_err(|_| MetadataError::Malformed)?;
+            match (handshake.id_of("ut_metadata"), handshake.metadata_size) {
+                (Some(id), Some(size)) if size > 0 => break (id, size),
+                _ => return Err(MetadataError::Unsupported.into()),
+            }
+        }
+    };
+    if size > MAX_METADATA_SIZE {
+        return Err(MetadataError::TooLarge(size).into());
+    }
+
+    let piece_count = (size as usize).div_ceil(METADATA_PIECE_LEN);
+    for piece in 0..piece_count {
+        connection
+            .send(&Message::Extended {
+                id: peer_id,
+                payload: MetadataMessage::Request(piece as u32).to_bytes(),
+            })
+            .await?;
+    }
+
+    let mut info_bytes = vec![0; size as usize];
+    let mut received = vec![false; piece_count];
+    while received.contains(&false) {
+        let Message::Extended {
+            id: UT_METADATA_ID,
+            payload,
+        } = connection.receive().await?
+        else {
+            continue;
+        };
+        match MetadataMessage::parse(&payload)? {
+            MetadataMessage::Data { piece, data, .. } => {
+                let start = piece as usize * METADATA_PIECE_LEN;
+                let expected = (size as usize)
+                    .saturating_sub(start)
+                    .min(METADATA_PIECE_LEN);
+                if piece as usize >= piece_count || data.len() != expected {
+                    return Err(MetadataError::Malformed.into());
+                }
+                info_bytes[start..start + expected].copy_from_slice(&data);
+                received[piece as usize] = true;
+            }
+            MetadataMessage::Reject(piece) => {
+                return Err(MetadataError::Rejected(piece).into());
+            }
+            MetadataMessage::Request(piece) => {
+                connection
+                    .send(&Message::Extended {
+                        id: peer_id,
+                        payload: MetadataMessage::Reject(piece).to_bytes(),
+                    })
+                    .await?;
+            }
+        }
+    }
+
+    if Sha1::digest(&info_bytes)[..] != info_hash.as_bytes()[..] {
+        return Err(MetadataError::HashMismatch.into());
+    }
+    Ok(info_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_round_trip_with_trailing_data() {
+        let info_bytes = vec![b'x'; METADATA_PIECE_LEN + 10];
+        let answer = MetadataMessage::answer(&info_bytes, 1);
+        assert_eq!(MetadataMessage::parse(&answer.to_bytes()), Ok(answer));
+        assert_eq!(
+            MetadataMessage::answer(&info_bytes, 2),
+            MetadataMessage::Reject(2)
+        );
+    }
+}
@@ -0,0 +1,550 @@
+use crate::info_hash::InfoHash;
+use sha1::{Digest, Sha1};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// `crypto_provide` and `crypto_select` bits: after the handshake the
+/// stream is either left in the clear or stays RC4-encrypted.
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// Whether peer connections are encrypted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Only RC4: plaintext peers are neither dialed nor accepted.
+    Required,
+    /// Dial with RC4 and fall back to a plaintext connection when the
+    /// peer doesn't speak MSE; accept either.
+    #[default]
+    Preferred,
+    /// Only plaintext, though an MSE handshake that settles on it is
+    /// accepted.
+    Disabled,
+}
+
+impl EncryptionPolicy {
+    /// The `crypto_provide` modes offered or accepted in an MSE handshake.
+    pub fn modes(self) -> u32 {
+        match self {
+            EncryptionPolicy::Required => CRYPTO_RC4,
+            EncryptionPolicy::Preferred => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Disabled => CRYPTO_PLAINTEXT,
+        }
+    }
+
+    /// Whether a peer may skip MSE and start with the BitTorrent
+    /// handshake.
+    pub fn allows_plaintext(self) -> bool {
+        self != EncryptionPolicy::Required
+    }
+}
+
+/// The 768-bit safe prime of the Diffie-Hellman exchange; the generator
+/// is 2.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+                     020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+                     4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+
+/// Bytes in a public key.
+const KEY_LEN: usize = 96;
+
+/// Random padding after public keys and in the handshake runs up to this
+/// long, so the two markers we scan for must turn up within it.
+const MAX_PAD: usize = 512;
+
+/// How a plain BitTorrent handshake starts: the protocol string and its
+/// length byte.
+const PLAINTEXT_START: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// The verification constant both sides encrypt to prove they derived
+/// the same keys.
+const VC: [u8; 8] = [0; 8];
+
+#[derive(Debug)]
+pub enum MseError {
+    Io(io::Error),
+    /// The other side's marker never turned up where the padding ends.
+    NoSync,
+    /// The initiator asked for a torrent we don't have.
+    UnknownTorrent,
+    /// The decrypted verification constant or a length is wrong, so the
+    /// keys don't agree.
+    BadHandshake,
+    /// No encryption mode both sides accept.
+    NoCommonMethod,
+    /// The peer connected in plaintext, but we require encryption.
+    PlaintextRefused,
+    /// The peer answered our key with a plaintext handshake.
+    Unsupported,
+}
+
+impl fmt::Display for MseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MseError::Io(error) => write!(f, "{}", error),
+            MseError::NoSync => write!(f, "encryption handshake never synchronized"),
+            MseError::UnknownTorrent => write!(f, "encrypted connection for an unknown torrent"),
+            MseError::BadHandshake => write!(f, "malformed encryption handshake"),
+            MseError::NoCommonMethod => write!(f, "peer offers no acceptable encryption mode"),
+            MseError::PlaintextRefused => write!(f, "peer connected without encryption"),
+            MseError::Unsupported => write!(f, "peer does not support encryption"),
+        }
+    }
+}
+
+impl std::error::Error for MseError {}
+
+impl From<io::Error> for MseError {
+    fn from(error: io::Error) -> Self {
+        MseError::Io(error)
+    }
+}
+
+/// The RC4 stream cipher. MSE uses it to obfuscate traffic, not to keep
+/// it secret.
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Rc4")
+    }
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, byte) in state.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /// A cipher keyed from the shared secret, with the first 1 KiB of
+    /// keystream thrown away as MSE requires.
+    fn keyed(label: &[u8], secret: &[u8], skey: &InfoHash) -> Self {
+        let mut rc4 = Rc4::new(&hash(&[label, secret, skey.as_bytes()]));
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    /// Encrypts or decrypts `data` in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+/// The two RC4 streams of an encrypted connection.
+#[derive(Debug, Clone)]
+pub struct Cipher {
+    pub outgoing: Rc4,
+    pub incoming: Rc4,
+}
+
+/// What the handshake settled on.
+#[derive(Debug)]
+pub struct Negotiated {
+    /// The ciphers for the rest of the connection, or `None` when only
+    /// the handshake was obfuscated.
+    pub cipher: Option<Cipher>,
+    /// Bytes that followed the handshake, already decrypted.
+    pub leftover: Vec<u8>,
+}
+
+/// Runs the handshake as the connecting side for `info_hash`, offering
+/// the `provide` modes. The initial payload is left empty; the BitTorrent
+/// handshake follows over the negotiated stream.
+pub async fn initiate<S>(
+    stream: &mut S,
+    info_hash: &InfoHash,
+    provide: u32,
+) -> Result<Negotiated, MseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let keys = KeyPair::generate();
+    stream
+        .write_all(&[&keys.public[..], &pad()].concat())
+        .await?;
+
+    let mut buffer = Vec::new();
+    // Some peers without MSE answer our key with their own handshake
+    // instead of hanging up.
+    fill(stream, &mut buffer, PLAINTEXT_START.len()).await?;
+    if buffer.starts_with(PLAINTEXT_START) {
+        return Err(MseError::Unsupported);
+    }
+    fill(stream, &mut buffer, KEY_LEN).await?;
+    let secret = keys.shared_secret(&buffer[..KEY_LEN]);
+    buffer.drain(..KEY_LEN);
+    let mut outgoing = Rc4::keyed(b"keyA", &secret, info_hash);
+    let mut incoming = Rc4::keyed(b"keyB", &secret, info_hash);
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    message.extend(xor(
+        hash(&[b"req2", info_hash.as_bytes()]),
+        hash(&[b"req3", &secret]),
+    ));
+    let pad_c = pad();
+    let mut encrypted = VC.to_vec();
+    encrypted.extend(provide.to_be_bytes());
+    encrypted.extend((pad_c.len() as u16).to_be_bytes());
+    encrypted.extend(pad_c);
+    encrypted.extend(0u16.to_be_bytes());
+    outgoing.apply(&mut encrypted);
+    message.extend(encrypted);
+    stream.write_all(&message).await?;
+
+    let mut marker = VC;
+    incoming.apply(&mut marker);
+    synchronize(stream, &mut buffer, &marker).await?;
+    let mut header = take(stream, &mut buffer, 6).await?;
+    incoming.apply(&mut header);
+    let select = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let pad_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err(MseError::BadHandshake);
+    }
+    let mut pad_d = take(stream, &mut buffer, pad_len).await?;
+    incoming.apply(&mut pad_d);
+
+    let cipher = match select & provide {
+        CRYPTO_RC4 => Some(Cipher { outgoing, incoming }),
+        CRYPTO_PLAINTEXT => None,
+        _ => return Err(MseError::NoCommonMethod),
+    };
+    Ok(finish(cipher, Vec::new(), buffer))
+}
+
+/// Runs the handshake as the accepting side, once the first bytes read,
+/// `received`, turned out not to be a plain BitTorrent handshake. The
+/// initiator must name one of `info_hashes` and offer one of the
+/// `allowed` modes; RC4 wins when both sides accept it.
+pub async fn accept<S>(
+    stream: &mut S,
+    received: Vec<u8>,
+    info_hashes: &[InfoHash],
+    allowed: u32,
+) -> Result<(InfoHash, Negotiated), MseError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer = received;
+    fill(stream, &mut buffer, KEY_LEN).await?;
+    let keys = KeyPair::generate();
+    let secret = keys.shared_secret(&buffer[..KEY_LEN]);
+    buffer.drain(..KEY_LEN);
+    stream
+        .write_all(&[&keys.public[..], &pad()].concat())
+        .await?;
+
+    synchronize(stream, &mut buffer, &hash(&[b"req1", &secret])).await?;
+    let obfuscated = take(stream, &mut buffer, 20).await?;
+    let wanted = xor(
+        obfuscated.try_into().expect("20 bytes"),
+        hash(&[b"req3", &secret]),
+    );
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_bytes()]) == wanted)
+        .ok_or(MseError::UnknownTorrent)?;
+    let mut incoming = Rc4::keyed(b"keyA", &secret, &info_hash);
+    let mut outgoing = Rc4::keyed(b"keyB", &secret, &info_hash);
+
+    let mut header = take(stream, &mut buffer, 14).await?;
+    incoming.apply(&mut header);
+    if header[..8] != VC {
+        return Err(MseError::BadHandshake);
+    }
+    let provide = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let pad_len = u16::from_be_bytes([header[12], header[13]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err(MseError::BadHandshake);
+    }
+    let mut rest = take(stream, &mut buffer, pad_len + 2).await?;
+    incoming.apply(&mut rest);
+    let payload_len = u16::from_be_bytes([rest[pad_len], rest[pad_len + 1]]) as usize;
+    let mut payload = take(stream, &mut buffer, payload_len).await?;
+    incoming.apply(&mut payload);
+
+    let select = if provide & allowed & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & allowed & CRYPTO_PLAINTEXT != 0 {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(MseError::NoCommonMethod);
+    };
+    let mut reply = VC.to_vec();
+    reply.extend(select.to_be_bytes());
+    reply.extend(0u16.to_be_bytes());
+    outgoing.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    let cipher = (select == CRYPTO_RC4).then_some(Cipher { outgoing, incoming });
+    Ok((info_hash, finish(cipher, payload, buffer)))
+}
+
+/// The outcome, with whatever was read past the handshake decrypted as
+/// the stream will be from now on.
+fn finish(mut cipher: Option<Cipher>, mut leftover: Vec<u8>, mut rest: Vec<u8>) -> Negotiated {
+    if let Some(cipher) = &mut cipher {
+        cipher.incoming.apply(&mut rest);
+    }
+    leftover.extend(rest);
+    Negotiated { cipher, leftover }
+}
+
+/// Reads until `buffer` holds at least `len` bytes.
+async fn fill<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    len: usize,
+) -> Result<(), MseError> {
+    while buffer.len() < len {
+        buffer.reserve(MAX_PAD);
+        if stream.read_buf(buffer).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+    Ok(())
+}
+
+async fn take<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    len: usize,
+) -> Result<Vec<u8>, MseError> {
+    fill(stream, buffer, len).await?;
+    Ok(buffer.drain(..len).collect())
+}
+
+/// Skips the other side's padding by reading up to and past `marker`,
+/// which must start within `MAX_PAD` bytes.
+async fn synchronize<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    marker: &[u8],
+) -> Result<(), MseError> {
+    loop {
+        if let Some(position) = buffer
+            .windows(marker.len())
+            .position(|window| window == marker)
+        {
+            buffer.drain(..position + marker.len());
+            return Ok(());
+        }
+        if buffer.len() >= MAX_PAD + marker.len() {
+            return Err(MseError::NoSync);
+        }
+        let len = buffer.len() + 1;
+        fill(stream, buffer, len).await?;
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(mut a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    for (x, y) in a.iter_mut().zip(b) {
+        *x ^= y;
+    }
+    a
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let random = RandomState::new();
+    (0..len)
+        .map(|index| random.hash_one((index, SystemTime::now())) as u8)
+        .collect()
+}
+
+/// Random padding of random length, up to `MAX_PAD`.
+fn pad() -> Vec<u8> {
+    let len = RandomState::new().hash_one(SystemTime::now()) as usize % (MAX_PAD + 1);
+    random_bytes(len)
+}
+
+/// A fresh Diffie-Hellman key pair.
+struct KeyPair {
+    private: Vec<u8>,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let private = random_bytes(20);
+        let public = to_bytes(&pow_mod(&from_bytes(&[2]), &private));
+        KeyPair { private, public }
+    }
+
+    /// The secret shared with the owner of `their_public`.
+    fn shared_secret(&self, their_public: &[u8]) -> [u8; KEY_LEN] {
+        to_bytes(&pow_mod(&from_bytes(their_public), &self.private))
+    }
+}
+
+/// A 768-bit number as little-endian 64-bit limbs.
+const LIMBS: usize = KEY_LEN / 8;
+type Number = [u64; LIMBS];
+
+fn from_bytes(bytes: &[u8]) -> Number {
+    let mut number = [0; LIMBS];
+    for (index, byte) in bytes.iter().rev().enumerate() {
+        number[index / 8] |= (*byte as u64) << (8 * (index % 8));
+    }
+    number
+}
+
+fn to_bytes(number: &Number) -> [u8; KEY_LEN] {
+    let mut bytes = [0; KEY_LEN];
+    for (index, limb) in number.iter().rev().enumerate() {
+        bytes[index * 8..index * 8 + 8].copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+fn prime() -> Number {
+    let bytes: Vec<u8> = (0..PRIME.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&PRIME[index..index + 2], 16).expect("hex prime"))
+        .collect();
+    from_bytes(&bytes)
+}
+
+fn at_least(a: &Number, b: &Number) -> bool {
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x > y;
+        }
+    }
+    true
+}
+
+fn subtract(a: &mut Number, b: &Number) {
+    let mut borrow = false;
+    for (x, y) in a.iter_mut().zip(b) {
+        let (difference, under) = x.overflowing_sub(*y);
+        let (difference, under_again) = difference.overflowing_sub(borrow as u64);
+        *x = difference;
+        borrow = under || under_again;
+    }
+}
+
+/// `a + b mod p`, for `a` and `b` below `p`.
+fn add_mod(a: &mut Number, b: &Number, p: &Number) {
+    let mut carry = false;
+    for (x, y) in a.iter_mut().zip(b) {
+        let (sum, over) = x.overflowing_add(*y);
+        let (sum, over_again) = sum.overflowing_add(carry as u64);
+        *x = sum;
+        carry = over || over_again;
+    }
+    if carry || at_least(a, p) {
+        subtract(a, p);
+    }
+}
+
+/// `a * b mod p` by shifting and adding, which is slow but plenty for a
+/// handful of exponentiations per connection.
+fn mul_mod(a: &Number, b: &Number, p: &Number) -> Number {
+    let mut result = [0; LIMBS];
+    for bit in (0..LIMBS * 64).rev() {
+        let doubled = result;
+        add_mod(&mut result, &doubled, p);
+        if b[bit / 64] >> (bit % 64) & 1 == 1 {
+            add_mod(&mut result, a, p);
+        }
+    }
+    result
+}
+
+fn pow_mod(base: &Number, exponent: &[u8]) -> Number {
+    let p = prime();
+    let mut base = *base;
+    while at_least(&base, &p) {
+        subtract(&mut base, &p);
+    }
+    let mut result = from_bytes(&[1]);
+    for byte in exponent {
+        for shift in (0..8).rev() {
+            result = mul_mod(&result, &result, &p);
+            if byte >> shift & 1 == 1 {
+                result = mul_mod(&result, &base, &p);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rc4_matches_the_reference_keystream() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+
+    #[test]
+    fn both_sides_agree_on_each_mode() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let info_hash = InfoHash([7; 20]);
+        for (provide, expected) in [
+            (CRYPTO_PLAINTEXT | CRYPTO_RC4, true),
+            (CRYPTO_PLAINTEXT, false),
+        ] {
+            let (mut ours, mut theirs) = tokio::io::duplex(4096);
+            let (initiated, accepted) = runtime.block_on(async {
+                let accepting = async {
+                    accept(
+                        &mut theirs,
+                        Vec::new(),
+                        &[InfoHash([1; 20]), info_hash],
+                        CRYPTO_PLAINTEXT | CRYPTO_RC4,
+                    )
+                    .await
+                };
+                futures_util::future::join(initiate(&mut ours, &info_hash, provide), accepting)
+                    .await
+            });
+            let mut initiated = initiated.unwrap();
+            let (found, mut accepted) = accepted.unwrap();
+            assert_eq!(found, info_hash);
+            assert_eq!(initiated.cipher.is_some(), expected);
+            if let (Some(a), Some(b)) = (&mut initiated.cipher, &mut accepted.cipher) {
+                let mut message = *b"hello";
+                a.outgoing.apply(&mut message);
+                b.incoming.apply(&mut message);
+                assert_eq!(&message, b"hello");
+            }
+        }
+    }
+}
@@ -0,0 +1,707 @@
+use anyhow::{anyhow, Context, Result};
+use reqwest::header::{ACCEPT, COOKIE};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use url::Url;
+
+/// The User-Agent sent unless overridden, matching the default peer id
+/// prefix.
+pub const DEFAULT_USER_AGENT: &str = concat!("crab_torrent/", env!("CARGO_PKG_VERSION"));
+
+/// Largest `.torrent` file accepted when fetching one over HTTP.
+pub const MAX_TORRENT_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Longest reply accepted from an HTTP proxy to `CONNECT`.
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+/// How outgoing connections reach the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Proxy {
+    /// Connect directly, ignoring any proxy configured elsewhere.
+    Direct,
+    /// Route through the given proxy URL (`http://`, `https://` or `socks5://`).
+    Url(String),
+}
+
+impl From<String> for Proxy {
+    /// `direct` or a proxy URL, as `--proxy` takes them.
+    fn from(value: String) -> Self {
+        if value == "direct" {
+            Proxy::Direct
+        } else {
+            Proxy::Url(value)
+        }
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Proxy::Direct => write!(f, "direct"),
+            Proxy::Url(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// Which TLS implementation HTTPS connections use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsBackend {
+    /// The platform's TLS library: OpenSSL, Secure Transport or SChannel.
+    /// Only available when built with the `native-tls` feature.
+    Native,
+    /// rustls, the default: pure Rust, so the binary cross-compiles without
+    /// a C toolchain for the target.
+    Rustls,
+}
+
+/// Something a private tracker expects with every request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credential {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    /// A `Cookie` header value, e.g. `uid=1; pass=abc`.
+    Cookie(String),
+    Header {
+        name: String,
+        value: String,
+    },
+}
+
+/// A credential sent to every tracker whose URL starts with `prefix`, so
+/// it never reaches other trackers, even through a redirect.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerCredential {
+    pub prefix: String,
+    pub credential: Credential,
+}
+
+/// Proxy and interface settings. A session holds the defaults and each
+/// torrent may carry its own overrides, e.g. private trackers direct while
+/// public torrents go through a VPN proxy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkSettings {
+    pub proxy: Option<Proxy>,
+    pub bind_address: Option<IpAddr>,
+    /// DNS-over-HTTPS resolver URL (JSON API, e.g.
+    /// `https://1.1.1.1/dns-query`) used for tracker hostnames only.
+    pub dns_over_https: Option<String>,
+    /// TLS implementation; reqwest's default when unset.
+    pub tls_backend: Option<TlsBackend>,
+    /// PEM CA certificates trusted in addition to the system ones, for
+    /// trackers behind an internal CA.
+    pub ca_certificates: Vec<PathBuf>,
+    /// A PEM certificate that tracker connections trust instead of any CA,
+    /// for trackers with a self-signed certificate.
+    pub pinned_certificate: Option<PathBuf>,
+    /// Announce over IPv4 and IPv6 separately so peers on either stack can
+    /// find us. Ignored when `bind_address` pins one family.
+    pub dual_stack: bool,
+    /// User-Agent for every HTTP request; `DEFAULT_USER_AGENT` when unset.
+    /// Should name the same client as the peer id prefix.
+    pub user_agent: Option<String>,
+    /// Authentication for HTTP trackers. UDP trackers have no equivalent.
+    pub tracker_credentials: Vec<TrackerCredential>,
+}
+
+/// The parts of a DNS JSON API reply used here.
+#[derive(Deserialize)]
+struct DnsResponse {
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DnsAnswer {
+    data: String,
+}
+
+impl NetworkSettings {
+    /// Returns these settings with every value set in `overrides` taking
+    /// precedence.
+    pub fn merged_with(&self, overrides: &NetworkSettings) -> NetworkSettings {
+        NetworkSettings {
+            proxy: overrides.proxy.clone().or_else(|| self.proxy.clone()),
+            bind_address: overrides.bind_address.or(self.bind_address),
+            dns_over_https: overrides
+                .dns_over_https
+                .clone()
+                .or_else(|| self.dns_over_https.clone()),
+            tls_backend: overrides.tls_backend.or(self.tls_backend),
+            ca_certificates: if overrides.ca_certificates.is_empty() {
+                self.ca_certificates.clone()
+            } else {
+                overrides.ca_certificates.clone()
+            },
+            pinned_certificate: overrides
+                .pinned_certificate
+                .clone()
+                .or_else(|| self.pinned_certificate.clone()),
+            dual_stack: overrides.dual_stack || self.dual_stack,
+            user_agent: overrides
+                .user_agent
+                .clone()
+                .or_else(|| self.user_agent.clone()),
+            tracker_credentials: overrides
+                .tracker_credentials
+                .iter()
+                .chain(&self.tracker_credentials)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Builds a blocking HTTP client honouring these settings.
+    pub fn http_client(&self) -> Result<reqwest::blocking::Client> {
+        Ok(self.http_client_builder()?.build()?)
+    }
+
+    pub fn http_client_builder(&self) -> Result<reqwest::blocking::ClientBuilder> {
+        self.configure(reqwest::blocking::Client::builder())
+    }
+
+    /// Builds an async client for tracker requests, with the hostnames of
+    /// `trackers` resolved through DNS-over-HTTPS when a resolver is
+    /// configured. Other lookups still go through the system resolver.
+    /// Redirects are not followed, so callers can tell a tracker that moved
+    /// permanently from one that redirected once. A pinned certificate
+    /// applies here only.
+    pub async fn tracker_client_builder(
+        &self,
+        trackers: &[String],
+    ) -> Result<reqwest::ClientBuilder> {
+        let mut builder = self
+            .configure(reqwest::Client::builder())?
+            .redirect(reqwest::redirect::Policy::none());
+        if let Some(path) = &self.pinned_certificate {
+            builder = builder
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(read_certificate(path)?);
+        }
+        let Some(resolver) = &self.dns_over_https else {
+            return Ok(builder);
+        };
+
+        let client = self.configure(reqwest::Client::builder())?.build()?;
+        let mut hosts: Vec<String> = trackers
+            .iter()
+            .filter_map(|tracker| Url::parse(tracker).ok())
+            .filter_map(|url| url.domain().map(str::to_string))
+            .collect();
+        hosts.sort();
+        hosts.dedup();
+
+        for host in hosts {
+            let addrs: Vec<SocketAddr> = resolve_over_https(&client, resolver, &host)
+                .await?
+                .into_iter()
+                // Port 0 keeps the port from the tracker URL.
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect();
+            if addrs.is_empty() {
+                return Err(anyhow!("{} did not resolve {}", resolver, host));
+            }
+            builder = builder.resolve_to_addrs(&host, &addrs);
+        }
+
+        Ok(builder)
+    }
+
+    /// Tracker client builders: one per address family when `dual_stack`
+    /// is set, each bound to that family's unspecified address so the
+    /// tracker sees both of ours, or a single one otherwise.
+    pub async fn tracker_client_builders(
+        &self,
+        trackers: &[String],
+    ) -> Result<Vec<reqwest::ClientBuilder>> {
+        if !self.dual_stack || self.bind_address.is_some() {
+            return Ok(vec![self.tracker_client_builder(trackers).await?]);
+        }
+
+        let mut builders = Vec::new();
+        for address in [
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        ] {
+            builders.push(
+                self.tracker_client_builder(trackers)
+                    .await?
+                    .local_address(address),
+            );
+        }
+        Ok(builders)
+    }
+
+    /// Applies the proxy, bind address, TLS settings and User-Agent to
+    /// either kind of client builder.
+    fn configure<B: ClientSettings>(&self, mut builder: B) -> Result<B> {
+        builder = builder.user_agent(self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT));
+
+        match &self.proxy {
+            Some(Proxy::Direct) => builder = builder.no_proxy(),
+            Some(Proxy::Url(url)) => builder = builder.proxy(reqwest::Proxy::all(url)?),
+            None => {}
+        }
+
+        if let Some(address) = self.bind_address {
+            builder = builder.local_address(address);
+        }
+
+        match self.tls_backend {
+            Some(TlsBackend::Native) => builder = builder.use_native_tls()?,
+            Some(TlsBackend::Rustls) => builder = builder.use_rustls_tls(),
+            None => {}
+        }
+        for path in &self.ca_certificates {
+            builder = builder.add_root_certificate(read_certificate(path)?);
+        }
+
+        Ok(builder)
+    }
+
+    /// Opens a TCP connection for a peer, from `bind_address` when set and
+    /// through the proxy when one is set. SOCKS5 and HTTP proxies are asked
+    /// to `CONNECT`; other proxies can't carry peer traffic, so dialing
+    /// fails rather than going around them.
+    pub async fn connect(&self, address: SocketAddr) -> io::Result<TcpStream> {
+        let Some(Proxy::Url(proxy)) = &self.proxy else {
+            return self.connect_from(address).await;
+        };
+        let url = Url::parse(proxy).map_err(|error| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", proxy, error))
+        })?;
+        let default_port = match url.scheme() {
+            "socks5" | "socks5h" => 1080,
+            "http" => 80,
+            scheme => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("peer connections can't go through a {} proxy", scheme),
+                ))
+            }
+        };
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port().unwrap_or(default_port);
+
+        let mut last_error = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("proxy {} did not resolve", host),
+        );
+        for proxy_address in tokio::net::lookup_host((host, port)).await? {
+            match self.connect_from(proxy_address).await {
+                Ok(mut stream) => {
+                    if url.scheme() == "http" {
+                        http_connect(&mut stream, &url, address).await?;
+                    } else {
+                        socks5_connect(&mut stream, &url, address).await?;
+                    }
+                    return Ok(stream);
+                }
+                Err(error) => last_error = error,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn connect_from(&self, address: SocketAddr) -> io::Result<TcpStream> {
+        let Some(ip) = self.bind_address else {
+            return TcpStream::connect(address).await;
+        };
+        let socket = match ip {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(ip, 0))?;
+        socket.connect(address).await
+    }
+
+    /// Downloads a `.torrent` file, sending `cookie` as the `Cookie` header
+    /// for private trackers whose download links need a login session.
+    pub fn fetch_torrent_file(&self, url: &str, cookie: Option<&str>) -> Result<Vec<u8>> {
+        let client = self.http_client_builder()?.cookie_store(true).build()?;
+
+        let mut request = client.get(url);
+        if let Some(cookie) = cookie {
+            request = request.header(COOKIE, cookie);
+        }
+
+        let response = request.send()?.error_for_status()?;
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_TORRENT_FILE_SIZE)
+        {
+            return Err(anyhow!(
+                "{} is larger than {} bytes",
+                url,
+                MAX_TORRENT_FILE_SIZE
+            ));
+        }
+
+        let mut contents = Vec::new();
+        response
+            .take(MAX_TORRENT_FILE_SIZE + 1)
+            .read_to_end(&mut contents)?;
+        if contents.len() as u64 > MAX_TORRENT_FILE_SIZE {
+            return Err(anyhow!(
+                "{} is larger than {} bytes",
+                url,
+                MAX_TORRENT_FILE_SIZE
+            ));
+        }
+
+        Ok(contents)
+    }
+}
+
+/// Adds the credentials configured for `url` to a tracker request.
+pub fn authorize(
+    mut request: reqwest::RequestBuilder,
+    credentials: &[TrackerCredential],
+    url: &Url,
+) -> reqwest::RequestBuilder {
+    for tracker in credentials {
+        if !url.as_str().starts_with(&tracker.prefix) {
+            continue;
+        }
+        request = match &tracker.credential {
+            Credential::Basic { username, password } => {
+                request.basic_auth(username, password.as_ref())
+            }
+            Credential::Cookie(cookie) => request.header(COOKIE, cookie),
+            Credential::Header { name, value } => request.header(name, value),
+        };
+    }
+    request
+}
+
+/// The user and password in a proxy URL, percent-decoded.
+fn proxy_credentials(url: &Url) -> Option<(String, String)> {
+    if url.username().is_empty() {
+        return None;
+    }
+    let decode = |part: &str| {
+        urlencoding::decode(part)
+            .map(|part| part.into_owned())
+            .unwrap_or_else(|_| part.to_string())
+    };
+    Some((
+        decode(url.username()),
+        decode(url.password().unwrap_or_default()),
+    ))
+}
+
+fn proxy_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message)
+}
+
+/// Asks a SOCKS5 proxy (RFC 1928) to connect to `address`, logging in
+/// (RFC 1929) with the URL's credentials if it has any.
+async fn socks5_connect(stream: &mut TcpStream, url: &Url, address: SocketAddr) -> io::Result<()> {
+    const NO_AUTH: u8 = 0;
+    const PASSWORD: u8 = 2;
+
+    let credentials = proxy_credentials(url);
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[5, 2, NO_AUTH, PASSWORD],
+        None => &[5, 1, NO_AUTH],
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    match (choice, &credentials) {
+        ([5, NO_AUTH], _) => {}
+        ([5, PASSWORD], Some((user, password))) => {
+            let mut login = vec![1, user.len() as u8];
+            login.extend(user.as_bytes());
+            login.push(password.len() as u8);
+            login.extend(password.as_bytes());
+            stream.write_all(&login).await?;
+            let mut status = [0; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                return Err(proxy_error("SOCKS5 proxy rejected the login".to_string()));
+            }
+        }
+        _ => {
+            return Err(proxy_error(
+                "SOCKS5 proxy refused every login method".to_string(),
+            ))
+        }
+    }
+
+    let mut request = vec![5, 1, 0];
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            request.push(1);
+            request.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(4);
+            request.extend(ip.octets());
+        }
+    }
+    request.extend(address.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(proxy_error(format!(
+            "SOCKS5 proxy could not connect to {} (reply {})",
+            address, reply[1]
+        )));
+    }
+    // The address the proxy connected from, which we have no use for.
+    let bound_length = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(proxy_error("SOCKS5 proxy sent a garbled reply".to_string())),
+    };
+    let mut bound = vec![0; bound_length + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Asks an HTTP proxy to tunnel to `address` with `CONNECT`.
+async fn http_connect(stream: &mut TcpStream, url: &Url, address: SocketAddr) -> io::Result<()> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", address);
+    if let Some((user, password)) = proxy_credentials(url) {
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(format!("{}:{}", user, password).as_bytes())
+        );
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+
+    // Byte by byte, so nothing past the headers is taken from the peer.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() == MAX_CONNECT_RESPONSE {
+            return Err(proxy_error(
+                "HTTP proxy sent an oversized reply".to_string(),
+            ));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(proxy_error(format!(
+            "HTTP proxy could not connect to {}: {}",
+            address, status_line
+        ))),
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn read_certificate(path: &Path) -> Result<reqwest::Certificate> {
+    let pem = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    reqwest::Certificate::from_pem(&pem)
+        .with_context(|| format!("{} is not a PEM certificate", path.display()))
+}
+
+/// The settings `NetworkSettings` applies, which reqwest's blocking and
+/// async client builders both have but share no trait for.
+trait ClientSettings: Sized {
+    fn user_agent(self, user_agent: &str) -> Self;
+    fn no_proxy(self) -> Self;
+    fn proxy(self, proxy: reqwest::Proxy) -> Self;
+    fn local_address(self, address: IpAddr) -> Self;
+    fn use_native_tls(self) -> Result<Self>;
+    fn use_rustls_tls(self) -> Self;
+    fn add_root_certificate(self, certificate: reqwest::Certificate) -> Self;
+}
+
+macro_rules! impl_client_settings {
+    ($builder:ty) => {
+        impl ClientSettings for $builder {
+            fn user_agent(self, user_agent: &str) -> Self {
+                self.user_agent(user_agent)
+            }
+
+            fn no_proxy(self) -> Self {
+                self.no_proxy()
+            }
+
+            fn proxy(self, proxy: reqwest::Proxy) -> Self {
+                self.proxy(proxy)
+            }
+
+            fn local_address(self, address: IpAddr) -> Self {
+                self.local_address(address)
+            }
+
+            #[cfg(feature = "native-tls")]
+            fn use_native_tls(self) -> Result<Self> {
+                Ok(self.use_native_tls())
+            }
+
+            #[cfg(not(feature = "native-tls"))]
+            fn use_native_tls(self) -> Result<Self> {
+                Err(anyhow!(
+                    "this build has no native TLS support; rebuild with --features native-tls"
+                ))
+            }
+
+            fn use_rustls_tls(self) -> Self {
+                self.use_rustls_tls()
+            }
+
+            fn add_root_certificate(self, certificate: reqwest::Certificate) -> Self {
+                self.add_root_certificate(certificate)
+            }
+        }
+    };
+}
+
+impl_client_settings!(reqwest::ClientBuilder);
+impl_client_settings!(reqwest::blocking::ClientBuilder);
+
+/// Looks up the A and AAAA records of `host` with a DNS JSON API resolver.
+async fn resolve_over_https(
+    client: &reqwest::Client,
+    resolver: &str,
+    host: &str,
+) -> Result<Vec<IpAddr>> {
+    let mut addresses = Vec::new();
+    for record_type in ["A", "AAAA"] {
+        let body = client
+            .get(resolver)
+            .query(&[("name", host), ("type", record_type)])
+            .header(ACCEPT, "application/dns-json")
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let response: DnsResponse = serde_json::from_slice(&body)?;
+        // Answers may include CNAMEs, whose data is not an address.
+        addresses.extend(
+            response
+                .answer
+                .iter()
+                .filter_map(|answer| answer.data.parse::<IpAddr>().ok()),
+        );
+    }
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn through(proxy: String) -> NetworkSettings {
+        NetworkSettings {
+            proxy: Some(Proxy::Url(proxy)),
+            bind_address: Some(Ipv4Addr::LOCALHOST.into()),
+            ..NetworkSettings::default()
+        }
+    }
+
+    #[test]
+    fn dials_peers_through_a_socks5_proxy() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = format!("socks5://user:p%40ss@{}", listener.local_addr().unwrap());
+            let peer: SocketAddr = "10.1.2.3:6881".parse().unwrap();
+            let proxying = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut greeting = [0; 4];
+                stream.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [5, 2, 0, 2]);
+                stream.write_all(&[5, 2]).await.unwrap();
+                let mut login = [0; 11];
+                stream.read_exact(&mut login).await.unwrap();
+                assert_eq!(&login, b"\x01\x04user\x04p@ss");
+                stream.write_all(&[1, 0]).await.unwrap();
+                let mut request = [0; 10];
+                stream.read_exact(&mut request).await.unwrap();
+                assert_eq!(request, [5, 1, 0, 1, 10, 1, 2, 3, 0x1a, 0xe1]);
+                stream
+                    .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80, b'!'])
+                    .await
+                    .unwrap();
+            };
+            let settings = through(proxy);
+            let (_, stream) = futures_util::future::join(proxying, settings.connect(peer)).await;
+            // The tunnel starts right after the reply.
+            assert_eq!(stream.unwrap().read_u8().await.unwrap(), b'!');
+        });
+    }
+
+    #[test]
+    fn dials_peers_through_an_http_proxy_and_refuses_others() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = format!("http://{}", listener.local_addr().unwrap());
+            let peer: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+            let proxying = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
+                }
+                assert_eq!(
+                    request,
+                    b"CONNECT [2001:db8::1]:51413 HTTP/1.1\r\n\
+                      Host: [2001:db8::1]:51413\r\n\r\n"
+                );
+                stream
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n!")
+                    .await
+                    .unwrap();
+            };
+            let settings = through(proxy);
+            let (_, stream) = futures_util::future::join(proxying, settings.connect(peer)).await;
+            assert_eq!(stream.unwrap().read_u8().await.unwrap(), b'!');
+
+            let https = through("https://127.0.0.1:1".to_string());
+            let refused = https.connect(peer).await.unwrap_err();
+            assert_eq!(refused.kind(), io::ErrorKind::Unsupported);
+        });
+    }
+
+    #[test]
+    fn encodes_basic_credentials_in_base64() {
+        assert_eq!(base64(b"user:pass"), "dXNlcjpwYXNz");
+        assert_eq!(base64(b"a:b"), "YTpi");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+    }
+}
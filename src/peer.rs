@@ -0,0 +1,700 @@
+use crate::assembler::BadBlock;
+use crate::bitfield::BitfieldError;
+use crate::capabilities::{Capabilities, Capability};
+use crate::info_hash::InfoHash;
+use crate::metadata::MetadataError;
+use crate::mse::{self, Cipher, EncryptionPolicy, MseError};
+use crate::net::NetworkSettings;
+use crate::peer_id::PeerId;
+use crate::peer_state::{PeerState, ProtocolViolation};
+use crate::wire::{Message, WireError, MAX_MESSAGE_LEN};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The protocol string every BitTorrent handshake starts with, after its
+/// length byte.
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Length byte, protocol string, reserved bytes, infohash and peer id.
+pub const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+
+/// Longest a connection goes without sending anything before a
+/// keep-alive, well under the two minutes peers and NATs allow.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// How much to read from the socket at a time.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Messages each connection remembers, both ways, for inspecting it.
+pub const MESSAGE_HISTORY: usize = 64;
+
+/// A message sent or received, kept in a connection's history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageRecord {
+    pub at: Instant,
+    pub sent: bool,
+    /// The message with its bitfield, block or extension payload left
+    /// out, so keeping it costs no more than its fields.
+    pub message: Message,
+    /// Bytes of payload left out.
+    pub payload: usize,
+}
+
+impl MessageRecord {
+    fn new(message: &Message, sent: bool) -> Self {
+        let (message, payload) = match message {
+            Message::Bitfield(bytes) => (Message::Bitfield(Vec::new()), bytes.len()),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => (
+                Message::Piece {
+                    index: *index,
+                    begin: *begin,
+                    block: Vec::new(),
+                },
+                block.len(),
+            ),
+            Message::Extended { id, payload } => (
+                Message::Extended {
+                    id: *id,
+                    payload: Vec::new(),
+                },
+                payload.len(),
+            ),
+            Message::Unknown { id, payload } => (
+                Message::Unknown {
+                    id: *id,
+                    payload: Vec::new(),
+                },
+                payload.len(),
+            ),
+            message => (message.clone(), 0),
+        };
+        MessageRecord {
+            at: Instant::now(),
+            sent,
+            message,
+            payload,
+        }
+    }
+}
+
+/// The opening message of a peer connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    /// What the reserved bytes announce, such as a DHT node (BEP 5) or the
+    /// extension protocol (BEP 10).
+    pub capabilities: Capabilities,
+    pub info_hash: InfoHash,
+    pub peer_id: PeerId,
+}
+
+#[derive(Debug)]
+pub enum PeerError {
+    Io(io::Error),
+    /// The peer didn't answer within the timeout.
+    TimedOut,
+    /// The reply doesn't start with the BitTorrent protocol string.
+    NotBitTorrent,
+    /// The peer answered for a different torrent.
+    InfoHashMismatch(InfoHash),
+    /// The peer sent bytes that aren't a valid message.
+    Wire(WireError),
+    /// A message out of order for the connection's state.
+    Protocol(ProtocolViolation),
+    /// The peer's bitfield doesn't fit the torrent.
+    BadBitfield(BitfieldError),
+    /// The peer sent a block that isn't part of any piece.
+    BadBlock(BadBlock),
+    /// A piece from the peer couldn't be written to disk, or one it asked
+    /// for couldn't be read.
+    Storage(io::Error),
+    /// The peer sent nothing, not even a keep-alive, for this long.
+    Idle(Duration),
+    /// Fetching the info dictionary from the peer failed.
+    Metadata(MetadataError),
+    /// The encryption handshake failed.
+    Encryption(MseError),
+    /// We dropped the peer for misbehaving.
+    Banned,
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::Io(error) => write!(f, "{}", error),
+            PeerError::TimedOut => write!(f, "peer did not answer the handshake in time"),
+            PeerError::NotBitTorrent => write!(f, "peer does not speak the BitTorrent protocol"),
+            PeerError::InfoHashMismatch(info_hash) => {
+                write!(f, "peer answered for another torrent, {}", info_hash)
+            }
+            PeerError::Wire(error) => write!(f, "{}", error),
+            PeerError::Protocol(violation) => write!(f, "protocol violation: {}", violation),
+            PeerError::BadBitfield(error) => write!(f, "{}", error),
+            PeerError::BadBlock(bad) => write!(f, "{}", bad),
+            PeerError::Storage(error) => write!(f, "piece storage failed: {}", error),
+            PeerError::Metadata(error) => write!(f, "{}", error),
+            PeerError::Encryption(error) => write!(f, "{}", error),
+            PeerError::Idle(silence) => {
+                write!(f, "peer was silent for {} s", silence.as_secs())
+            }
+            PeerError::Banned => write!(f, "peer banned for misbehaving"),
+        }
+    }
+}
+
+impl PeerError {
+    /// Whether the peer broke the protocol, rather than the connection
+    /// failing or timing out.
+    pub fn is_violation(&self) -> bool {
+        matches!(
+            self,
+            PeerError::Wire(_)
+                | PeerError::Protocol(_)
+                | PeerError::BadBitfield(_)
+                | PeerError::BadBlock(_)
+        )
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+impl From<io::Error> for PeerError {
+    fn from(error: io::Error) -> Self {
+        PeerError::Io(error)
+    }
+}
+
+impl From<WireError> for PeerError {
+    fn from(error: WireError) -> Self {
+        PeerError::Wire(error)
+    }
+}
+
+impl From<ProtocolViolation> for PeerError {
+    fn from(violation: ProtocolViolation) -> Self {
+        PeerError::Protocol(violation)
+    }
+}
+
+impl From<BitfieldError> for PeerError {
+    fn from(error: BitfieldError) -> Self {
+        PeerError::BadBitfield(error)
+    }
+}
+
+impl From<MetadataError> for PeerError {
+    fn from(error: MetadataError) -> Self {
+        PeerError::Metadata(error)
+    }
+}
+
+impl From<MseError> for PeerError {
+    fn from(error: MseError) -> Self {
+        PeerError::Encryption(error)
+    }
+}
+
+impl Handshake {
+    pub fn new(info_hash: InfoHash, peer_id: PeerId) -> Self {
+        Handshake {
+            capabilities: Capabilities::default(),
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.capabilities.to_bytes());
+        bytes[28..48].copy_from_slice(self.info_hash.as_bytes());
+        bytes[48..68].copy_from_slice(self.peer_id.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HANDSHAKE_LEN]) -> Result<Self, PeerError> {
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(PeerError::NotBitTorrent);
+        }
+
+        let mut reserved = [0; 8];
+        reserved.copy_from_slice(&bytes[20..28]);
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&bytes[28..48]);
+        let mut peer_id = [0; 20];
+        peer_id.copy_from_slice(&bytes[48..68]);
+
+        Ok(Handshake {
+            capabilities: Capabilities::from_bytes(reserved),
+            info_hash: InfoHash(info_hash),
+            peer_id: PeerId(peer_id),
+        })
+    }
+}
+
+/// The socket under a connection, encrypting and decrypting when MSE
+/// settled on RC4.
+#[derive(Debug)]
+struct Transport {
+    stream: TcpStream,
+    cipher: Option<Cipher>,
+    /// Bytes read and decrypted but not yet consumed.
+    buffer: Vec<u8>,
+}
+
+impl Transport {
+    async fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match &mut self.cipher {
+            Some(cipher) => {
+                let mut bytes = bytes.to_vec();
+                cipher.outgoing.apply(&mut bytes);
+                self.stream.write_all(&bytes).await
+            }
+            None => self.stream.write_all(bytes).await,
+        }
+    }
+
+    /// Reads what the socket has into the buffer. Safe to cancel: bytes
+    /// are decrypted as soon as they arrive.
+    async fn read_more(&mut self) -> io::Result<()> {
+        let start = self.buffer.len();
+        self.buffer.reserve(READ_CHUNK);
+        if self.stream.read_buf(&mut self.buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(cipher) = &mut self.cipher {
+            cipher.incoming.apply(&mut self.buffer[start..]);
+        }
+        Ok(())
+    }
+
+    async fn read_exact(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        while self.buffer.len() < len {
+            self.read_more().await?;
+        }
+        Ok(self.buffer.drain(..len).collect())
+    }
+}
+
+/// A TCP connection to a peer that has completed the handshake for our
+/// torrent.
+#[derive(Debug)]
+pub struct PeerConnection {
+    transport: Transport,
+    address: SocketAddr,
+    /// What our handshake announced.
+    local: Capabilities,
+    /// What the peer sent in its handshake.
+    remote: Handshake,
+    state: PeerState,
+    /// Whether the peer connected to us rather than we to it.
+    incoming: bool,
+    last_sent: Instant,
+    last_received: Instant,
+    /// The last `MESSAGE_HISTORY` messages, oldest first.
+    history: VecDeque<MessageRecord>,
+}
+
+impl PeerConnection {
+    /// Connects to `address` as `network` says and exchanges handshakes,
+    /// failing if the peer takes longer than `timeout` or answers for
+    /// another torrent.
+    pub async fn connect(
+        address: SocketAddr,
+        handshake: Handshake,
+        timeout: Duration,
+        network: &NetworkSettings,
+    ) -> Result<PeerConnection, PeerError> {
+        tokio::time::timeout(
+            timeout,
+            PeerConnection::open(address, handshake, None, network),
+        )
+        .await
+        .map_err(|_| PeerError::TimedOut)?
+    }
+
+    /// Like `connect`, but runs the MSE handshake first, offering the
+    /// `provide` modes (`mse::CRYPTO_PLAINTEXT`, `mse::CRYPTO_RC4`).
+    pub async fn connect_encrypted(
+        address: SocketAddr,
+        handshake: Handshake,
+        timeout: Duration,
+        provide: u32,
+        network: &NetworkSettings,
+    ) -> Result<PeerConnection, PeerError> {
+        tokio::time::timeout(
+            timeout,
+            PeerConnection::open(address, handshake, Some(provide), network),
+        )
+        .await
+        .map_err(|_| PeerError::TimedOut)?
+    }
+
+    /// Connects as `policy` asks: plaintext, over MSE, or over MSE and
+    /// then, if that fails, plaintext again for peers without it.
+    pub async fn dial(
+        address: SocketAddr,
+        handshake: Handshake,
+        timeout: Duration,
+        policy: EncryptionPolicy,
+        network: &NetworkSettings,
+    ) -> Result<PeerConnection, PeerError> {
+        if policy == EncryptionPolicy::Disabled {
+            return PeerConnection::connect(address, handshake, timeout, network).await;
+        }
+        let encrypted =
+            PeerConnection::connect_encrypted(address, handshake, timeout, policy.modes(), network)
+                .await;
+        match encrypted {
+            // A peer without MSE hangs up on what looks to it like a
+            // garbled handshake, or waits for the rest of one until we
+            // give up.
+            Err(PeerError::Encryption(_) | PeerError::Io(_) | PeerError::TimedOut)
+                if policy == EncryptionPolicy::Preferred =>
+            {
+                PeerConnection::connect(address, handshake, timeout, network).await
+            }
+            encrypted => encrypted,
+        }
+    }
+
+    async fn open(
+        address: SocketAddr,
+        handshake: Handshake,
+        provide: Option<u32>,
+        network: &NetworkSettings,
+    ) -> Result<PeerConnection, PeerError> {
+        let mut stream = network.connect(address).await?;
+        let mut transport = match provide {
+            Some(provide) => {
+                let negotiated = mse::initiate(&mut stream, &handshake.info_hash, provide).await?;
+                Transport {
+                    stream,
+                    cipher: negotiated.cipher,
+                    buffer: negotiated.leftover,
+                }
+            }
+            None => Transport {
+                stream,
+                cipher: None,
+                buffer: Vec::new(),
+            },
+        };
+        transport.write_all(&handshake.to_bytes()).await?;
+
+        let remote = read_handshake(&mut transport).await?;
+        if remote.info_hash != handshake.info_hash {
+            return Err(PeerError::InfoHashMismatch(remote.info_hash));
+        }
+        Ok(PeerConnection::established(
+            transport,
+            address,
+            handshake.capabilities,
+            remote,
+            false,
+        ))
+    }
+
+    /// Takes over a connection a peer opened to us, for whichever of our
+    /// torrents it asks for: `handshakes` holds our handshake for each.
+    /// Plaintext and MSE handshakes are taken as far as `policy` allows.
+    pub async fn accept(
+        stream: TcpStream,
+        address: SocketAddr,
+        handshakes: &[Handshake],
+        policy: EncryptionPolicy,
+        timeout: Duration,
+    ) -> Result<PeerConnection, PeerError> {
+        tokio::time::timeout(
+            timeout,
+            PeerConnection::answer(stream, address, handshakes, policy),
+        )
+        .await
+        .map_err(|_| PeerError::TimedOut)?
+    }
+
+    async fn answer(
+        mut stream: TcpStream,
+        address: SocketAddr,
+        handshakes: &[Handshake],
+        policy: EncryptionPolicy,
+    ) -> Result<PeerConnection, PeerError> {
+        let mut start = [0; 20];
+        stream.read_exact(&mut start).await?;
+        let mut transport = if start[0] as usize == PROTOCOL.len() && &start[1..] == PROTOCOL {
+            if !policy.allows_plaintext() {
+                return Err(MseError::PlaintextRefused.into());
+            }
+            Transport {
+                stream,
+                cipher: None,
+                buffer: start.to_vec(),
+            }
+        } else {
+            let info_hashes: Vec<InfoHash> = handshakes.iter().map(|ours| ours.info_hash).collect();
+            let (_, negotiated) =
+                mse::accept(&mut stream, start.to_vec(), &info_hashes, policy.modes()).await?;
+            Transport {
+                stream,
+                cipher: negotiated.cipher,
+                buffer: negotiated.leftover,
+            }
+        };
+
+        let remote = read_handshake(&mut transport).await?;
+        let ours = handshakes
+            .iter()
+            .find(|ours| ours.info_hash == remote.info_hash)
+            .ok_or(PeerError::InfoHashMismatch(remote.info_hash))?;
+        transport.write_all(&ours.to_bytes()).await?;
+        Ok(PeerConnection::established(
+            transport,
+            address,
+            ours.capabilities,
+            remote,
+            true,
+        ))
+    }
+
+    fn established(
+        transport: Transport,
+        address: SocketAddr,
+        local: Capabilities,
+        remote: Handshake,
+        incoming: bool,
+    ) -> Self {
+        PeerConnection {
+            transport,
+            address,
+            local,
+            remote,
+            state: PeerState::default(),
+            incoming,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            history: VecDeque::with_capacity(MESSAGE_HISTORY),
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.remote.peer_id
+    }
+
+    /// What the peer's handshake announced.
+    pub fn capabilities(&self) -> Capabilities {
+        self.remote.capabilities
+    }
+
+    /// What both handshakes announced, so the connection may use.
+    pub fn negotiated(&self) -> Capabilities {
+        self.local.intersection(self.remote.capabilities)
+    }
+
+    /// Whether both handshakes announced `capability`, so the connection
+    /// may use it.
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.negotiated().contains(capability)
+    }
+
+    pub fn is_incoming(&self) -> bool {
+        self.incoming
+    }
+
+    /// Whether the connection is RC4-encrypted, as opposed to plaintext
+    /// after an obfuscated handshake or no MSE at all.
+    pub fn is_encrypted(&self) -> bool {
+        self.transport.cipher.is_some()
+    }
+
+    pub fn state(&self) -> &PeerState {
+        &self.state
+    }
+
+    /// Sends `message` if the connection's state allows it.
+    pub async fn send(&mut self, message: &Message) -> Result<(), PeerError> {
+        self.state.on_send(message)?;
+        self.transport.write_all(&message.to_bytes()).await?;
+        self.last_sent = Instant::now();
+        self.remember(MessageRecord::new(message, true));
+        Ok(())
+    }
+
+    fn remember(&mut self, record: MessageRecord) {
+        if self.history.len() == MESSAGE_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(record);
+    }
+
+    /// The last messages sent and received, oldest first.
+    pub fn history(&self) -> &VecDeque<MessageRecord> {
+        &self.history
+    }
+
+    /// When we last sent the peer anything.
+    pub fn last_sent(&self) -> Instant {
+        self.last_sent
+    }
+
+    /// When the peer last sent anything.
+    pub fn last_received(&self) -> Instant {
+        self.last_received
+    }
+
+    /// Sends a keep-alive if nothing else has been sent for
+    /// `KEEP_ALIVE_INTERVAL`.
+    pub async fn keep_alive(&mut self) -> Result<(), PeerError> {
+        if self.last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+            self.send(&Message::KeepAlive).await?;
+        }
+        Ok(())
+    }
+
+    /// How long since the peer last sent anything, keep-alives included.
+    pub fn silent_for(&self) -> Duration {
+        self.last_received.elapsed()
+    }
+
+    /// Reads the next message, failing if it breaks the protocol. Safe to
+    /// cancel, as under a timeout: a partly read message is kept for the
+    /// next call.
+    pub async fn receive(&mut self) -> Result<Message, PeerError> {
+        loop {
+            if let Some(body) = self.take_frame()? {
+                self.last_received = Instant::now();
+                let message = Message::parse(&body)?;
+                self.remember(MessageRecord::new(&message, false));
+                self.state.on_receive(&message)?;
+                return Ok(message);
+            }
+            self.transport.read_more().await?;
+        }
+    }
+
+    /// Removes the first complete message body from the buffer.
+    fn take_frame(&mut self) -> Result<Option<Vec<u8>>, WireError> {
+        let buffer = &mut self.transport.buffer;
+        let Some(prefix) = buffer.first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(*prefix) as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(WireError::TooLong(len));
+        }
+        if buffer.len() < 4 + len {
+            return Ok(None);
+        }
+        let body = buffer[4..4 + len].to_vec();
+        buffer.drain(..4 + len);
+        Ok(Some(body))
+    }
+}
+
+async fn read_handshake(transport: &mut Transport) -> Result<Handshake, PeerError> {
+    let bytes = transport.read_exact(HANDSHAKE_LEN).await?;
+    Handshake::from_bytes(&bytes.try_into().expect("read a whole handshake"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn preferred_encryption_falls_back_when_a_peer_stays_silent() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let ours = Handshake::new(InfoHash([7; 20]), PeerId([1; 20]));
+
+            // A plaintext-only peer that waits for a handshake which never
+            // comes, then answers the plaintext retry.
+            let listening = async {
+                let (mut silent, _) = listener.accept().await.unwrap();
+                let mut key = [0; 96];
+                silent.read_exact(&mut key).await.unwrap();
+                let retry = tokio::time::timeout(Duration::from_secs(5), listener.accept());
+                let (mut plain, _) = retry.await.expect("no plaintext retry").unwrap();
+                let mut theirs = [0; HANDSHAKE_LEN];
+                plain.read_exact(&mut theirs).await.unwrap();
+                assert_eq!(theirs, ours.to_bytes());
+                let reply = Handshake::new(InfoHash([7; 20]), PeerId([2; 20]));
+                plain.write_all(&reply.to_bytes()).await.unwrap();
+                (silent, plain)
+            };
+            let network = NetworkSettings::default();
+            let dialing = PeerConnection::dial(
+                address,
+                ours,
+                Duration::from_millis(200),
+                EncryptionPolicy::Preferred,
+                &network,
+            );
+            let (_, connection) = futures_util::future::join(listening, dialing).await;
+            assert_eq!(connection.unwrap().peer_id(), PeerId([2; 20]));
+        });
+    }
+
+    #[test]
+    fn remembers_the_last_messages_without_their_payloads() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let ours = Handshake::new(InfoHash([7; 20]), PeerId([1; 20]));
+            let network = NetworkSettings::default();
+            let dialing = PeerConnection::connect(address, ours, Duration::from_secs(5), &network);
+            let accepting = async {
+                let (stream, from) = listener.accept().await.unwrap();
+                let timeout = Duration::from_secs(5);
+                PeerConnection::accept(stream, from, &[ours], EncryptionPolicy::Disabled, timeout)
+                    .await
+            };
+            let (dialed, accepted) = futures_util::future::join(dialing, accepting).await;
+            let (mut sender, mut receiver) = (dialed.unwrap(), accepted.unwrap());
+
+            sender
+                .send(&Message::Bitfield(vec![0xff; 2]))
+                .await
+                .unwrap();
+            sender.send(&Message::Have(0)).await.unwrap();
+            for _ in 0..2 {
+                receiver.receive().await.unwrap();
+            }
+            let first = &receiver.history()[0];
+            assert_eq!(
+                (&first.message, first.payload),
+                (&Message::Bitfield(Vec::new()), 2)
+            );
+            assert!(!first.sent && sender.history()[0].sent);
+
+            for index in 1..MESSAGE_HISTORY as u32 {
+                sender.send(&Message::Have(index)).await.unwrap();
+                receiver.receive().await.unwrap();
+            }
+            // The bitfield fell out of the history; the haves remain.
+            assert_eq!(receiver.history().len(), MESSAGE_HISTORY);
+            assert_eq!(receiver.history()[0].message, Message::Have(0));
+        });
+    }
+}
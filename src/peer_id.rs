@@ -0,0 +1,130 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::time::SystemTime;
+use urlencoding::encode_binary;
+
+/// Azureus-style client code: `-CT0100-` for crab_torrent 0.1.0.
+const CLIENT_CODE: &str = "CT";
+
+/// Prefixes longer than this would leave too few random bytes to tell
+/// peers apart.
+pub const MAX_PREFIX_LEN: usize = 12;
+
+const SUFFIX_ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// The 20-byte id this client announces as: a client prefix followed by
+/// random characters, fresh for every session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdPrefixError(String);
+
+impl fmt::Display for PeerIdPrefixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "peer id prefix \"{}\" must be at most {} printable ASCII characters",
+            self.0, MAX_PREFIX_LEN
+        )
+    }
+}
+
+impl std::error::Error for PeerIdPrefixError {}
+
+impl PeerId {
+    /// A random id with this client's own prefix.
+    pub fn random() -> Self {
+        PeerId::with_prefix(&default_prefix()).expect("the default prefix is valid")
+    }
+
+    /// A random id starting with `prefix`, e.g. `-qB4630-` to identify as
+    /// another client.
+    pub fn with_prefix(prefix: &str) -> Result<Self, PeerIdPrefixError> {
+        if prefix.len() > MAX_PREFIX_LEN || !prefix.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(PeerIdPrefixError(prefix.to_string()));
+        }
+
+        let mut id = [0; 20];
+        id[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        let random = RandomState::new();
+        for (index, byte) in id.iter_mut().enumerate().skip(prefix.len()) {
+            let value = random.hash_one((index, SystemTime::now()));
+            *byte = SUFFIX_ALPHABET[(value % SUFFIX_ALPHABET.len() as u64) as usize];
+        }
+
+        Ok(PeerId(id))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Percent-encodes the raw bytes for the `peer_id` tracker parameter.
+    pub fn url_encoded(&self) -> String {
+        encode_binary(&self.0).into_owned()
+    }
+}
+
+impl Default for PeerId {
+    fn default() -> Self {
+        PeerId::random()
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+/// TCP port peers connect to unless `--port` says otherwise.
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// How this client identifies itself to trackers for a whole session: its
+/// peer id, the `key` trackers use to recognise it after its IP changes,
+/// and the port it takes peer connections on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionIdentity {
+    pub peer_id: PeerId,
+    pub key: u32,
+    pub port: u16,
+}
+
+impl SessionIdentity {
+    pub fn new(peer_id: PeerId) -> Self {
+        SessionIdentity {
+            peer_id,
+            key: RandomState::new().hash_one(SystemTime::now()) as u32,
+            port: DEFAULT_PORT,
+        }
+    }
+
+    /// The `key` tracker parameter: eight hex digits.
+    pub fn key_param(&self) -> String {
+        format!("{:08X}", self.key)
+    }
+}
+
+impl Default for SessionIdentity {
+    fn default() -> Self {
+        SessionIdentity::new(PeerId::random())
+    }
+}
+
+/// `-CT` followed by one digit each of the major, minor and patch version
+/// and a trailing `0`, as in `-CT0100-`.
+fn default_prefix() -> String {
+    let digit = |part: &str| {
+        part.parse::<u32>()
+            .ok()
+            .and_then(|n| char::from_digit(n % 10, 10))
+            .unwrap_or('0')
+    };
+    let version: String = env!("CARGO_PKG_VERSION")
+        .splitn(3, '.')
+        .map(|part| digit(part.split('-').next().unwrap_or(part)))
+        .collect();
+    format!("-{}{:0<4}-", CLIENT_CODE, version)
+}
@@ -0,0 +1,96 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// BEP 40 canonical peer priority between our address and a peer's. Both
+/// ends of a connection compute the same value, so when a swarm is too big
+/// to connect to everyone, clients agree on which connections to keep
+/// instead of churning. Higher is preferred. Addresses of different
+/// families have no defined priority and get 0.
+pub fn canonical_priority(ours: SocketAddr, theirs: SocketAddr) -> u32 {
+    if ours.ip() == theirs.ip() {
+        let (low, high) = sorted(ours.port(), theirs.port());
+        let mut bytes = low.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&high.to_be_bytes());
+        return crc32c(&bytes);
+    }
+
+    let (ours, theirs) = match (ours.ip(), theirs.ip()) {
+        (IpAddr::V4(ours), IpAddr::V4(theirs)) => {
+            (ours.octets().to_vec(), theirs.octets().to_vec())
+        }
+        (IpAddr::V6(ours), IpAddr::V6(theirs)) => {
+            (ours.octets().to_vec(), theirs.octets().to_vec())
+        }
+        _ => return 0,
+    };
+    // The leading /16 of IPv4 and /48 of IPv6 always count in full, plus
+    // up to two more bytes while the addresses share them. The rest is
+    // masked with 0x55 so neighbouring addresses can't game the order.
+    let mut full = if ours.len() == 4 { 2 } else { 6 };
+    for _ in 0..2 {
+        if ours[..full] == theirs[..full] {
+            full += 1;
+        }
+    }
+    let mask = |address: Vec<u8>| -> Vec<u8> {
+        address
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| if index < full { *byte } else { byte & 0x55 })
+            .collect()
+    };
+
+    let (low, high) = sorted(mask(ours), mask(theirs));
+    crc32c(&[low, high].concat())
+}
+
+fn sorted<T: Ord>(a: T, b: T) -> (T, T) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// CRC-32C (Castagnoli), the checksum BEP 40 specifies.
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_bep_40_examples() {
+        assert_eq!(
+            canonical_priority(addr("123.213.32.10:6881"), addr("98.76.54.32:6881")),
+            0xec2d_7224
+        );
+        assert_eq!(
+            canonical_priority(addr("123.213.32.10:6881"), addr("123.213.32.234:6881")),
+            0x9956_8189
+        );
+    }
+
+    #[test]
+    fn is_symmetric() {
+        let a = addr("[2001:db8::1]:6881");
+        let b = addr("[2001:db8:1::2]:51413");
+        assert_eq!(canonical_priority(a, b), canonical_priority(b, a));
+    }
+}
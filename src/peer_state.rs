@@ -0,0 +1,141 @@
+use crate::wire::Message;
+use std::fmt;
+
+/// The choke and interest state of one peer connection, with the message
+/// ordering rules that depend on it. Every message sent or received goes
+/// through `on_send` or `on_receive`, so no caller tracks these flags
+/// itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// A bitfield may only be the first message after the handshake.
+    sent_any: bool,
+    received_any: bool,
+}
+
+impl Default for PeerState {
+    /// Connections start with both sides choked and not interested.
+    fn default() -> Self {
+        PeerState {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            sent_any: false,
+            received_any: false,
+        }
+    }
+}
+
+/// A message that is not allowed in the connection's current state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolViolation {
+    /// A `bitfield` after other messages.
+    LateBitfield,
+    /// Our `request` while the peer chokes us or we said we aren't
+    /// interested.
+    RequestWhileChoked,
+    /// Our `piece` while we choke the peer.
+    PieceWhileChoking,
+}
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolViolation::LateBitfield => {
+                write!(f, "bitfield must be the first message")
+            }
+            ProtocolViolation::RequestWhileChoked => {
+                write!(f, "request sent while choked or not interested")
+            }
+            ProtocolViolation::PieceWhileChoking => {
+                write!(f, "piece sent to a choked peer")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolViolation {}
+
+impl PeerState {
+    /// Checks a message we are about to send and applies its transition.
+    /// Nothing changes if it is not allowed.
+    pub fn on_send(&mut self, message: &Message) -> Result<(), ProtocolViolation> {
+        match message {
+            Message::Bitfield(_) if self.sent_any => return Err(ProtocolViolation::LateBitfield),
+            Message::Request { .. } if self.peer_choking || !self.am_interested => {
+                return Err(ProtocolViolation::RequestWhileChoked);
+            }
+            Message::Piece { .. } if self.am_choking => {
+                return Err(ProtocolViolation::PieceWhileChoking);
+            }
+            Message::Choke => self.am_choking = true,
+            Message::Unchoke => self.am_choking = false,
+            Message::Interested => self.am_interested = true,
+            Message::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+        if *message != Message::KeepAlive {
+            self.sent_any = true;
+        }
+        Ok(())
+    }
+
+    /// Checks a message from the peer and applies its transition.
+    /// Requests that arrive while we choke the peer are not violations:
+    /// they may have crossed our `choke` on the wire. Callers drop them.
+    pub fn on_receive(&mut self, message: &Message) -> Result<(), ProtocolViolation> {
+        match message {
+            Message::Bitfield(_) if self.received_any => {
+                return Err(ProtocolViolation::LateBitfield);
+            }
+            Message::Choke => self.peer_choking = true,
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => self.peer_interested = true,
+            Message::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+        if *message != Message::KeepAlive {
+            self.received_any = true;
+        }
+        Ok(())
+    }
+
+    /// Whether we may request blocks from the peer.
+    pub fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_need_interest_and_an_unchoke() {
+        let mut state = PeerState::default();
+        let request = Message::Request {
+            index: 0,
+            begin: 0,
+            length: 16384,
+        };
+
+        assert_eq!(
+            state.on_send(&request),
+            Err(ProtocolViolation::RequestWhileChoked)
+        );
+        state.on_send(&Message::Interested).unwrap();
+        state.on_receive(&Message::Unchoke).unwrap();
+        assert_eq!(state.on_send(&request), Ok(()));
+
+        state.on_receive(&Message::Choke).unwrap();
+        assert!(!state.can_request());
+        assert_eq!(
+            state.on_receive(&Message::Bitfield(vec![0])),
+            Err(ProtocolViolation::LateBitfield)
+        );
+    }
+}
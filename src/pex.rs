@@ -0,0 +1,233 @@
+use crate::bencode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Peers are told about changes at most this often.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most peers added, and separately dropped, in one message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// Per-peer flags of the `added.f` lists.
+pub const PREFERS_ENCRYPTION: u8 = 0x01;
+pub const SEED: u8 = 0x02;
+pub const SUPPORTS_UTP: u8 = 0x04;
+pub const SUPPORTS_HOLEPUNCH: u8 = 0x08;
+/// We reached the peer ourselves, so it accepts connections.
+pub const REACHABLE: u8 = 0x10;
+
+/// A ut_pex message (BEP 11): peers the sender connected to or dropped
+/// since its last message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    /// New peers with their flags.
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+/// The wire form, with IPv4 and IPv6 peers in separate compact lists.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct RawPex {
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    added: Vec<u8>,
+    #[serde(
+        default,
+        rename = "added.f",
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_bytes"
+    )]
+    added_flags: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    dropped: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    added6: Vec<u8>,
+    #[serde(
+        default,
+        rename = "added6.f",
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_bytes"
+    )]
+    added6_flags: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    dropped6: Vec<u8>,
+}
+
+impl PexMessage {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = RawPex::default();
+        for (address, flags) in &self.added {
+            match address {
+                SocketAddr::V4(_) => {
+                    raw.added.extend(compact(address));
+                    raw.added_flags.push(*flags);
+                }
+                SocketAddr::V6(_) => {
+                    raw.added6.extend(compact(address));
+                    raw.added6_flags.push(*flags);
+                }
+            }
+        }
+        for address in &self.dropped {
+            match address {
+                SocketAddr::V4(_) => raw.dropped.extend(compact(address)),
+                SocketAddr::V6(_) => raw.dropped6.extend(compact(address)),
+            }
+        }
+        serde_bencode::to_bytes(&raw).expect("pex message encodes")
+    }
+
+    /// Parses a peer's message, keeping at most `MAX_PEX_PEERS` of each
+    /// list so a hostile peer can't flood us. Missing flags count as none.
+    pub fn parse(payload: &[u8]) -> Result<Self, bencode::BencodeError> {
+        let raw: RawPex = bencode::decode(payload)?;
+        let mut added = Vec::new();
+        for (chunk, flags, len) in [
+            (&raw.added, &raw.added_flags, 6),
+            (&raw.added6, &raw.added6_flags, 18),
+        ] {
+            for (index, address) in raw_addresses(chunk, len).enumerate() {
+                added.push((address, flags.get(index).copied().unwrap_or(0)));
+            }
+        }
+        let dropped = raw_addresses(&raw.dropped, 6)
+            .chain(raw_addresses(&raw.dropped6, 18))
+            .take(MAX_PEX_PEERS)
+            .collect();
+        added.truncate(MAX_PEX_PEERS);
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+fn compact(address: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match address.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&address.port().to_be_bytes());
+    bytes
+}
+
+fn raw_addresses(bytes: &[u8], len: usize) -> impl Iterator<Item = SocketAddr> + '_ {
+    bytes.chunks_exact(len).map(move |chunk| {
+        let port = u16::from_be_bytes([chunk[len - 2], chunk[len - 1]]);
+        let ip = match <[u8; 4]>::try_from(&chunk[..len - 2]) {
+            Ok(octets) => IpAddr::V4(Ipv4Addr::from(octets)),
+            Err(_) => {
+                let octets: [u8; 16] = chunk[..16].try_into().expect("chunk is 18 bytes");
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        };
+        SocketAddr::new(ip, port)
+    })
+}
+
+/// What one connection has told its peer over ut_pex, and when messages
+/// last went each way, to keep both directions to one per interval.
+#[derive(Debug, Default)]
+pub struct PexState {
+    /// The peers the other side has been told we're connected to.
+    advertised: BTreeMap<SocketAddr, u8>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexState {
+    /// The message to send now, if one is due and anything changed, given
+    /// the peers we're `connected` to. The other side's own address must
+    /// not be among them.
+    pub fn next_message(
+        &mut self,
+        connected: &BTreeMap<SocketAddr, u8>,
+        now: Instant,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|sent| now.duration_since(sent) < PEX_INTERVAL)
+        {
+            return None;
+        }
+
+        let added: Vec<(SocketAddr, u8)> = connected
+            .iter()
+            .filter(|(address, _)| !self.advertised.contains_key(address))
+            .take(MAX_PEX_PEERS)
+            .map(|(address, flags)| (*address, *flags))
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .keys()
+            .filter(|address| !connected.contains_key(address))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let message = PexMessage { added, dropped };
+        if message.is_empty() {
+            return None;
+        }
+
+        for (address, flags) in &message.added {
+            self.advertised.insert(*address, *flags);
+        }
+        for address in &message.dropped {
+            self.advertised.remove(address);
+        }
+        self.last_sent = Some(now);
+        Some(message)
+    }
+
+    /// Whether to act on a message arriving now. Peers sending more often
+    /// than the interval allows (with some slack) are ignored.
+    pub fn accept(&mut self, now: Instant) -> bool {
+        if self
+            .last_received
+            .is_some_and(|received| now.duration_since(received) < PEX_INTERVAL / 2)
+        {
+            return false;
+        }
+        self.last_received = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_both_families_and_limits_the_rate() {
+        let message = PexMessage {
+            added: vec![
+                ("10.0.0.1:6881".parse().unwrap(), REACHABLE),
+                ("[2001:db8::1]:51413".parse().unwrap(), SEED),
+            ],
+            dropped: vec!["10.0.0.2:6881".parse().unwrap()],
+        };
+        assert_eq!(PexMessage::parse(&message.to_bytes()).unwrap(), message);
+
+        let start = Instant::now();
+        let mut state = PexState::default();
+        let connected: BTreeMap<_, _> = message.added.iter().copied().collect();
+        assert_eq!(
+            state.next_message(&connected, start).unwrap().added.len(),
+            2
+        );
+        assert!(state.next_message(&BTreeMap::new(), start).is_none());
+        let later = start + PEX_INTERVAL;
+        assert_eq!(
+            state
+                .next_message(&BTreeMap::new(), later)
+                .unwrap()
+                .dropped
+                .len(),
+            2
+        );
+        assert!(state.accept(start));
+        assert!(!state.accept(start + Duration::from_secs(5)));
+    }
+}
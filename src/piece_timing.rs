@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// When a piece was first requested and when it verified, both from the
+/// start of the download.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceTiming {
+    pub first_request: Duration,
+    pub completed: Option<Duration>,
+    /// Times the piece failed its hash check before it verified.
+    pub hash_failures: u32,
+}
+
+impl PieceTiming {
+    /// From the first request to verifying.
+    pub fn download_time(&self) -> Option<Duration> {
+        self.completed
+            .map(|completed| completed.saturating_sub(self.first_request))
+    }
+}
+
+/// The timing of each piece requested during a download, for studying
+/// how the picker orders pieces and how quickly the swarm serves them.
+#[derive(Debug, Clone)]
+pub struct PieceTimings {
+    start: Instant,
+    pieces: BTreeMap<u32, PieceTiming>,
+}
+
+impl PieceTimings {
+    pub fn new(start: Instant) -> Self {
+        PieceTimings {
+            start,
+            pieces: BTreeMap::new(),
+        }
+    }
+
+    /// A block of `piece` was requested; only the first request counts.
+    pub fn requested(&mut self, piece: u32, now: Instant) {
+        let since_start = now.saturating_duration_since(self.start);
+        self.pieces.entry(piece).or_insert(PieceTiming {
+            first_request: since_start,
+            completed: None,
+            hash_failures: 0,
+        });
+    }
+
+    pub fn completed(&mut self, piece: u32, now: Instant) {
+        let since_start = now.saturating_duration_since(self.start);
+        if let Some(timing) = self.pieces.get_mut(&piece) {
+            timing.completed = Some(since_start);
+        }
+    }
+
+    pub fn failed(&mut self, piece: u32) {
+        if let Some(timing) = self.pieces.get_mut(&piece) {
+            timing.hash_failures += 1;
+        }
+    }
+
+    pub fn get(&self, piece: u32) -> Option<&PieceTiming> {
+        self.pieces.get(&piece)
+    }
+
+    /// Requested pieces in index order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &PieceTiming)> {
+        self.pieces.iter().map(|(piece, timing)| (*piece, timing))
+    }
+
+    /// Writes one line per requested piece, with times in milliseconds
+    /// and the completion columns empty for pieces that didn't verify.
+    pub fn write_csv(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "piece,first_request_ms,completed_ms,download_ms,hash_failures"
+        )?;
+        for (piece, timing) in self.iter() {
+            let millis = |duration: Option<Duration>| {
+                duration.map_or(String::new(), |duration| duration.as_millis().to_string())
+            };
+            writeln!(
+                out,
+                "{},{},{},{},{}",
+                piece,
+                timing.first_request.as_millis(),
+                millis(timing.completed),
+                millis(timing.download_time()),
+                timing.hash_failures
+            )?;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_pieces_from_their_first_request() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut timings = PieceTimings::new(start);
+        timings.requested(3, at(10));
+        timings.requested(3, at(20));
+        timings.requested(0, at(15));
+        timings.failed(3);
+        timings.completed(3, at(250));
+
+        assert_eq!(
+            timings.get(3).unwrap().download_time(),
+            Some(Duration::from_millis(240))
+        );
+        let mut csv = Vec::new();
+        timings.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "piece,first_request_ms,completed_ms,download_ms,hash_failures\n\
+             0,15,,,0\n\
+             3,10,250,240,1\n"
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use crate::rate::RateMeter;
+use crate::wire::Message;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Size of the blocks pieces are requested in. Peers may refuse larger
+/// requests.
+pub const BLOCK_LEN: u32 = 16 * 1024;
+
+pub const DEFAULT_MIN_DEPTH: usize = 5;
+pub const DEFAULT_MAX_DEPTH: usize = 16;
+
+/// How many seconds of data to keep requested from a peer, so a fast peer
+/// never idles waiting for the next request to cross the wire.
+const QUEUE_SECONDS: f64 = 3.0;
+
+/// The shortest request timeout, so a pause of a few seconds at a fast
+/// peer doesn't snub it.
+pub const MIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One block of a piece, as named in `request`, `piece` and `cancel`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Block {
+    pub fn request(&self) -> Message {
+        Message::Request {
+            index: self.piece,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+
+    pub fn cancel(&self) -> Message {
+        Message::Cancel {
+            index: self.piece,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+}
+
+/// Splits a piece into the blocks to request, the last one possibly
+/// shorter.
+pub fn piece_blocks(piece: u32, piece_length: u64) -> impl Iterator<Item = Block> {
+    (0..piece_length)
+        .step_by(BLOCK_LEN as usize)
+        .map(move |begin| Block {
+            piece,
+            begin: begin as u32,
+            length: (piece_length - begin).min(BLOCK_LEN as u64) as u32,
+        })
+}
+
+/// The block requests outstanding to one peer. The queue depth starts at
+/// `min_depth` and follows the peer's measured rate up to `max_depth`.
+#[derive(Debug, Clone)]
+pub struct RequestPipeline {
+    min_depth: usize,
+    max_depth: usize,
+    depth: usize,
+    /// Requested blocks with when they were requested, oldest first.
+    outstanding: VecDeque<(Block, Instant)>,
+    /// When a requested block last arrived.
+    last_delivery: Instant,
+    /// Smoothed time the peer takes to deliver a block once it is due,
+    /// and its mean deviation, as TCP estimates round trips (RFC 6298).
+    /// `None` until a block arrives.
+    delay: Option<Duration>,
+    delay_variation: Duration,
+    /// Whether the peer stopped delivering, which keeps the queue at a
+    /// single request until it sends a block again.
+    snubbed: bool,
+    /// Download rate from the peer.
+    meter: RateMeter,
+}
+
+impl Default for RequestPipeline {
+    fn default() -> Self {
+        RequestPipeline::new(DEFAULT_MIN_DEPTH, DEFAULT_MAX_DEPTH)
+    }
+}
+
+impl RequestPipeline {
+    /// A pipeline keeping between `min_depth` and `max_depth` requests
+    /// outstanding. Equal bounds give a fixed depth.
+    pub fn new(min_depth: usize, max_depth: usize) -> Self {
+        let min_depth = min_depth.max(1);
+        RequestPipeline {
+            min_depth,
+            max_depth: max_depth.max(min_depth),
+            depth: min_depth,
+            outstanding: VecDeque::new(),
+            last_delivery: Instant::now(),
+            delay: None,
+            delay_variation: Duration::ZERO,
+            snubbed: false,
+            meter: RateMeter::new(Instant::now()),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Bytes per second received over the last few seconds.
+    pub fn rate(&self) -> f64 {
+        self.meter.rate()
+    }
+
+    /// How many more requests may be sent now.
+    pub fn free_slots(&self) -> usize {
+        let depth = if self.snubbed { 1 } else { self.depth };
+        depth.saturating_sub(self.outstanding.len())
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    /// How long requests may go unanswered before the peer is stalled:
+    /// like TCP's retransmission timeout, the smoothed delivery delay plus
+    /// four times its deviation, so a slow but steady peer gets longer
+    /// than one that usually answers at once. It stays between
+    /// `MIN_REQUEST_TIMEOUT` and `max`, and is `max` until the peer has
+    /// delivered a block.
+    pub fn request_timeout(&self, max: Duration) -> Duration {
+        match self.delay {
+            Some(delay) => {
+                (delay + 4 * self.delay_variation).clamp(MIN_REQUEST_TIMEOUT.min(max), max)
+            }
+            None => max,
+        }
+    }
+
+    /// Whether requests have gone unanswered for `timeout`: nothing
+    /// arrived in that time, though the oldest request is at least as old.
+    pub fn is_stalled(&self, now: Instant, timeout: Duration) -> bool {
+        self.outstanding.front().is_some_and(|(_, requested)| {
+            now.saturating_duration_since((*requested).max(self.last_delivery)) >= timeout
+        })
+    }
+
+    /// Marks the peer snubbed and returns its outstanding requests, to be
+    /// cancelled and handed to other peers.
+    pub fn snub(&mut self) -> Vec<Block> {
+        self.snubbed = true;
+        self.clear()
+    }
+
+    pub fn outstanding(&self) -> impl Iterator<Item = &(Block, Instant)> {
+        self.outstanding.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    pub fn contains(&self, block: &Block) -> bool {
+        self.outstanding.iter().any(|(queued, _)| queued == block)
+    }
+
+    /// Records that `block` was requested.
+    pub fn push(&mut self, block: Block, now: Instant) {
+        self.outstanding.push_back((block, now));
+    }
+
+    /// Records an arrived block and returns whether we had asked for it.
+    pub fn on_received(&mut self, block: Block, now: Instant) -> bool {
+        let requested = self.take(&block);
+        if let Some(requested) = requested {
+            // Blocks queue behind each other at the peer, so the delay
+            // counts from the later of the request and the last delivery.
+            self.sample_delay(now.saturating_duration_since(requested.max(self.last_delivery)));
+            self.meter.record(block.length as u64);
+            self.last_delivery = now;
+            self.snubbed = false;
+        }
+        self.update_rate(now);
+        requested.is_some()
+    }
+
+    /// Forgets a request, as when sending `cancel`.
+    pub fn remove(&mut self, block: &Block) -> bool {
+        self.take(block).is_some()
+    }
+
+    /// Removes a request, returning when it was made.
+    fn take(&mut self, block: &Block) -> Option<Instant> {
+        let position = self
+            .outstanding
+            .iter()
+            .position(|(queued, _)| queued == block)?;
+        self.outstanding
+            .remove(position)
+            .map(|(_, requested)| requested)
+    }
+
+    fn sample_delay(&mut self, sample: Duration) {
+        match self.delay {
+            None => {
+                self.delay = Some(sample);
+                self.delay_variation = sample / 2;
+            }
+            Some(delay) => {
+                let deviation = delay.abs_diff(sample);
+                self.delay_variation = (self.delay_variation * 3 + deviation) / 4;
+                self.delay = Some((delay * 7 + sample) / 8);
+            }
+        }
+    }
+
+    /// Drops every outstanding request and returns them so they can be
+    /// requested again elsewhere. A peer discards our requests when it
+    /// chokes us.
+    pub fn clear(&mut self) -> Vec<Block> {
+        self.outstanding.drain(..).map(|(block, _)| block).collect()
+    }
+
+    /// Samples the download rate once a window has passed, and resizes
+    /// the queue to match.
+    pub fn update_rate(&mut self, now: Instant) {
+        if !self.meter.update(now) {
+            return;
+        }
+        let wanted = (self.rate() * QUEUE_SECONDS / BLOCK_LEN as f64).ceil() as usize;
+        self.depth = wanted.clamp(self.min_depth, self.max_depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_pieces_into_blocks() {
+        let blocks: Vec<_> = piece_blocks(3, 40_000).collect();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2].begin, 32_768);
+        assert_eq!(blocks[2].length, 40_000 - 32_768);
+    }
+
+    #[test]
+    fn depth_follows_the_measured_rate() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(2, 16);
+        pipeline.meter = RateMeter::new(start);
+        assert_eq!(pipeline.free_slots(), 2);
+
+        let blocks: Vec<_> = piece_blocks(0, 4 * BLOCK_LEN as u64).collect();
+        for block in &blocks {
+            pipeline.push(*block, start);
+        }
+        for block in &blocks {
+            assert!(pipeline.on_received(*block, start + Duration::from_millis(500)));
+        }
+        pipeline.update_rate(start + Duration::from_secs(1));
+        // 64 KiB/s keeps 12 blocks in flight over three seconds.
+        assert_eq!(pipeline.depth(), 12);
+        assert!(!pipeline.on_received(blocks[0], start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn timeout_follows_the_delivery_delay() {
+        let start = Instant::now();
+        let max = Duration::from_secs(60);
+        let mut pipeline = RequestPipeline::new(1, 1);
+        pipeline.last_delivery = start;
+        assert_eq!(pipeline.request_timeout(max), max);
+
+        // A block every 8 s, steadily.
+        let mut now = start;
+        for block in piece_blocks(0, 20 * BLOCK_LEN as u64) {
+            pipeline.push(block, now);
+            now += Duration::from_secs(8);
+            assert!(pipeline.on_received(block, now));
+        }
+        let steady = pipeline.request_timeout(max);
+        assert!(steady > Duration::from_secs(8) && steady < Duration::from_secs(10));
+
+        // Erratic deliveries widen it.
+        for (index, block) in piece_blocks(1, 8 * BLOCK_LEN as u64).enumerate() {
+            pipeline.push(block, now);
+            now += Duration::from_secs(if index % 2 == 0 { 1 } else { 20 });
+            assert!(pipeline.on_received(block, now));
+        }
+        assert!(pipeline.request_timeout(max) > Duration::from_secs(30));
+
+        // A peer that answers at once still gets the floor.
+        let mut fast = RequestPipeline::new(1, 1);
+        let block = piece_blocks(0, BLOCK_LEN as u64).next().unwrap();
+        fast.push(block, start);
+        fast.on_received(block, start + Duration::from_millis(20));
+        assert_eq!(fast.request_timeout(max), MIN_REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn stalls_without_deliveries_and_recovers_on_one() {
+        let start = Instant::now();
+        let mut pipeline = RequestPipeline::new(4, 4);
+        pipeline.last_delivery = start;
+        let blocks: Vec<_> = piece_blocks(0, 3 * BLOCK_LEN as u64).collect();
+        pipeline.push(blocks[0], start);
+        pipeline.push(blocks[1], start + Duration::from_secs(30));
+        assert!(!pipeline.is_stalled(start + Duration::from_secs(59), Duration::from_secs(60)));
+        assert!(pipeline.is_stalled(start + Duration::from_secs(60), Duration::from_secs(60)));
+
+        assert_eq!(pipeline.snub(), blocks[..2]);
+        assert_eq!(pipeline.free_slots(), 1);
+        pipeline.push(blocks[2], start + Duration::from_secs(60));
+        assert!(pipeline.on_received(blocks[2], start + Duration::from_secs(61)));
+        assert!(!pipeline.is_snubbed());
+        assert_eq!(pipeline.free_slots(), 4);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::torrent::Torrent;
+use std::collections::BTreeSet;
+
+/// Default number of bytes at each end of a media file to fetch first.
+pub const DEFAULT_PREVIEW_WINDOW: u64 = 4 * 1024 * 1024;
+
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "avi", "flac", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "ogg", "ogv", "opus", "ts", "wav",
+    "webm", "wmv",
+];
+
+/// Boosts the pieces holding the start and end of media files, where
+/// containers keep the headers and indexes players need before they can
+/// preview anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreviewBoost {
+    /// Bytes at each end of a file to boost.
+    pub window: u64,
+}
+
+impl Default for PreviewBoost {
+    fn default() -> Self {
+        PreviewBoost {
+            window: DEFAULT_PREVIEW_WINDOW,
+        }
+    }
+}
+
+impl PreviewBoost {
+    /// Returns the pieces covering the head and tail window of every media
+    /// file in `selected_files`.
+    pub fn boosted_pieces(&self, torrent: &Torrent, selected_files: &[usize]) -> BTreeSet<usize> {
+        let paths = torrent.file_paths();
+        let mut pieces = BTreeSet::new();
+
+        for &file_index in selected_files {
+            let Some((path, length)) = paths.get(file_index) else {
+                continue;
+            };
+            if torrent.is_padding_file(file_index) || !is_media_file(path) || *length == 0 {
+                continue;
+            }
+
+            let window = self.window.min(*length);
+            if let Some(head) = torrent.file_pieces(file_index, 0, window) {
+                pieces.extend(head);
+            }
+            if let Some(tail) = torrent.file_pieces(file_index, length - window, window) {
+                pieces.extend(tail);
+            }
+        }
+
+        pieces
+    }
+}
+
+/// Whether the file's extension belongs to a common audio or video format.
+pub fn is_media_file(path: &[String]) -> bool {
+    path.last()
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, extension)| {
+            MEDIA_EXTENSIONS
+                .iter()
+                .any(|media| media.eq_ignore_ascii_case(extension))
+        })
+        .unwrap_or(false)
+}
@@ -0,0 +1,49 @@
+use std::time::{Duration, Instant};
+
+/// How often a rate is sampled.
+pub const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// A transfer rate in bytes per second, sampled every `RATE_WINDOW` and
+/// smoothed over the last few samples.
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    rate: f64,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl RateMeter {
+    pub fn new(now: Instant) -> Self {
+        RateMeter {
+            rate: 0.0,
+            window_start: now,
+            window_bytes: 0,
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn record(&mut self, bytes: u64) {
+        self.window_bytes += bytes;
+    }
+
+    /// Folds the bytes of the current window into the rate once it is
+    /// long enough, returning whether it did.
+    pub fn update(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return false;
+        }
+        let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            self.rate * 0.7 + sample * 0.3
+        };
+        self.window_start = now;
+        self.window_bytes = 0;
+        true
+    }
+}
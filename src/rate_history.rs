@@ -0,0 +1,184 @@
+use crate::info_hash::InfoHash;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Per-second samples kept: the last hour.
+pub const SECOND_SAMPLES: usize = 3600;
+
+/// Per-minute samples kept: the last day.
+pub const MINUTE_SAMPLES: usize = 1440;
+
+/// Download and upload rates, in bytes per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct RateSample {
+    pub download: f64,
+    pub upload: f64,
+}
+
+impl RateSample {
+    fn add(self, other: RateSample) -> RateSample {
+        RateSample {
+            download: self.download + other.download,
+            upload: self.upload + other.upload,
+        }
+    }
+
+    fn scale(self, factor: f64) -> RateSample {
+        RateSample {
+            download: self.download * factor,
+            upload: self.upload * factor,
+        }
+    }
+}
+
+/// Rates over the last hour at one-second resolution and the last day at
+/// one-minute resolution, oldest first. Each series is a ring: once full,
+/// every new sample pushes out the oldest.
+#[derive(Debug, Clone, Serialize)]
+pub struct RateHistory {
+    pub seconds: VecDeque<RateSample>,
+    /// Each the mean of its minute's seconds.
+    pub minutes: VecDeque<RateSample>,
+    /// When the last second was sampled.
+    #[serde(skip)]
+    last: Instant,
+    /// Sum and count of the seconds since the last minute sample.
+    #[serde(skip)]
+    minute_sum: RateSample,
+    #[serde(skip)]
+    minute_seconds: u64,
+}
+
+impl RateHistory {
+    pub fn new(now: Instant) -> Self {
+        RateHistory {
+            seconds: VecDeque::with_capacity(SECOND_SAMPLES),
+            minutes: VecDeque::with_capacity(MINUTE_SAMPLES),
+            last: now,
+            minute_sum: RateSample::default(),
+            minute_seconds: 0,
+        }
+    }
+
+    /// Records `sample` for each whole second since the last one recorded,
+    /// so a caller running late fills the gap with the rate it sees now.
+    /// Calls less than a second apart record nothing.
+    pub fn record(&mut self, now: Instant, sample: RateSample) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs();
+        if elapsed == 0 {
+            return;
+        }
+        self.last += Duration::from_secs(elapsed);
+
+        push(
+            &mut self.seconds,
+            SECOND_SAMPLES,
+            sample,
+            elapsed.min(SECOND_SAMPLES as u64) as usize,
+        );
+
+        self.minute_sum = self.minute_sum.add(sample.scale(elapsed as f64));
+        self.minute_seconds += elapsed;
+        if self.minute_seconds >= 60 {
+            let mean = self.minute_sum.scale(1.0 / self.minute_seconds as f64);
+            let minutes = (self.minute_seconds / 60).min(MINUTE_SAMPLES as u64) as usize;
+            push(&mut self.minutes, MINUTE_SAMPLES, mean, minutes);
+            // The seconds past the minute count toward the next one.
+            self.minute_seconds %= 60;
+            self.minute_sum = mean.scale(self.minute_seconds as f64);
+        }
+    }
+
+    /// The rates of several histories added up, aligned on their newest
+    /// samples.
+    pub fn combined<'a>(histories: impl IntoIterator<Item = &'a RateHistory>) -> RateHistory {
+        let mut combined = RateHistory::new(Instant::now());
+        for history in histories {
+            add_aligned(&mut combined.seconds, &history.seconds);
+            add_aligned(&mut combined.minutes, &history.minutes);
+        }
+        combined
+    }
+}
+
+fn push(series: &mut VecDeque<RateSample>, capacity: usize, sample: RateSample, count: usize) {
+    for _ in 0..count {
+        if series.len() == capacity {
+            series.pop_front();
+        }
+        series.push_back(sample);
+    }
+}
+
+fn add_aligned(total: &mut VecDeque<RateSample>, series: &VecDeque<RateSample>) {
+    while total.len() < series.len() {
+        total.push_front(RateSample::default());
+    }
+    let offset = total.len() - series.len();
+    for (index, sample) in series.iter().enumerate() {
+        total[offset + index] = total[offset + index].add(*sample);
+    }
+}
+
+/// The rate histories served by the status page: the session's, which
+/// adds up its torrents', and each torrent's.
+#[derive(Debug, Serialize)]
+pub struct RatesReport {
+    pub session: RateHistory,
+    pub torrents: BTreeMap<InfoHash, RateHistory>,
+}
+
+impl RatesReport {
+    pub fn new(torrents: BTreeMap<InfoHash, RateHistory>) -> Self {
+        RatesReport {
+            session: RateHistory::combined(torrents.values()),
+            torrents,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(download: f64) -> RateSample {
+        RateSample {
+            download,
+            upload: 0.0,
+        }
+    }
+
+    #[test]
+    fn keeps_seconds_and_minute_means_in_rings() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_millis(secs * 1000 + 300);
+        let mut history = RateHistory::new(start);
+        history.record(start + Duration::from_millis(400), rate(9.0));
+        assert!(history.seconds.is_empty());
+
+        // 30 seconds at 10 B/s, then a late call covering 30 more at 40.
+        for second in 1..=30 {
+            history.record(at(second), rate(10.0));
+        }
+        history.record(at(60), rate(40.0));
+        assert_eq!(history.seconds.len(), 60);
+        assert_eq!(history.seconds[29], rate(10.0));
+        assert_eq!(history.seconds[30], rate(40.0));
+        assert_eq!(history.minutes, [rate(25.0)]);
+
+        // A two-hour gap fills the hour of seconds and adds 120 minutes.
+        history.record(at(60 + 7200), rate(5.0));
+        assert_eq!(history.seconds.len(), SECOND_SAMPLES);
+        assert_eq!(history.minutes.len(), 121);
+        assert_eq!(history.minutes.back(), Some(&rate(5.0)));
+
+        let mut other = RateHistory::new(start);
+        other.record(at(1), rate(1.0));
+        let combined = RateHistory::combined([&history, &other]);
+        assert_eq!(combined.seconds.len(), SECOND_SAMPLES);
+        assert_eq!(combined.seconds.back(), Some(&rate(6.0)));
+        assert_eq!(combined.seconds[SECOND_SAMPLES - 2], rate(5.0));
+        assert_eq!(combined.minutes.len(), 121);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::resume::data_dir;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Announce outcomes recorded for one tracker URL.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TrackerRecord {
+    pub successes: u64,
+    pub failures: u64,
+}
+
+impl TrackerRecord {
+    /// Estimated chance of the next announce succeeding. Trackers with no
+    /// history score 0.5 so they are neither favoured nor buried.
+    pub fn score(&self) -> f64 {
+        (self.successes as f64 + 1.0) / ((self.successes + self.failures) as f64 + 2.0)
+    }
+}
+
+/// Per-tracker reliability history, persisted across sessions.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TrackerReliability {
+    trackers: BTreeMap<String, TrackerRecord>,
+}
+
+impl TrackerReliability {
+    /// Loads the history from the data directory, starting empty if none
+    /// was saved.
+    pub fn load() -> Result<Self> {
+        match fs::read(Self::path()) {
+            Ok(contents) => Ok(serde_bencode::from_bytes(&contents)?),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_bencode::to_bytes(self)?)?;
+        fs::rename(temporary, path)?;
+
+        Ok(())
+    }
+
+    pub fn record(&mut self, tracker: &str, success: bool) {
+        let record = self.trackers.entry(tracker.to_string()).or_default();
+        if success {
+            record.successes += 1;
+        } else {
+            record.failures += 1;
+        }
+    }
+
+    pub fn get(&self, tracker: &str) -> TrackerRecord {
+        self.trackers.get(tracker).copied().unwrap_or_default()
+    }
+
+    /// Reorders trackers so historically reliable ones are tried first:
+    /// within each tier by score, then tiers by their best tracker. This
+    /// overrides the order from the torrent file, so it is only meant to be
+    /// applied when the user opts in.
+    pub fn reorder_tiers(&self, tiers: &mut [Vec<String>]) {
+        let score = |tracker: &String| self.get(tracker).score();
+
+        for tier in tiers.iter_mut() {
+            tier.sort_by(|a, b| score(b).total_cmp(&score(a)));
+        }
+
+        let best = |tier: &Vec<String>| tier.first().map(score).unwrap_or(0.0);
+        tiers.sort_by(|a, b| best(b).total_cmp(&best(a)));
+    }
+
+    fn path() -> PathBuf {
+        data_dir().join("trackers.reliability")
+    }
+}
@@ -0,0 +1,209 @@
+use crate::info_hash::InfoHash;
+use crate::net::{NetworkSettings, Proxy};
+use crate::schedule::AnnounceSchedule;
+use crate::tracker::TrackerStats;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Per-torrent state that must survive restarts.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResumeData {
+    /// Payload bytes sent to peers, excluding protocol overhead.
+    pub uploaded: u64,
+    /// Payload bytes received from peers, excluding protocol overhead.
+    pub downloaded: u64,
+    /// Bytes of the torrent not yet verified on disk, as of the last
+    /// recheck. `None` until a recheck runs, meaning nothing is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub left: Option<u64>,
+    /// Announce timing per infohash and tracker URL (see `schedule`), so
+    /// restarting doesn't let the client announce sooner than a tracker
+    /// allows.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub announces: BTreeMap<String, AnnounceSchedule>,
+    /// Trackers that answered with a permanent redirect, and where to, so
+    /// later announces go straight to the new URL.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub redirects: BTreeMap<String, String>,
+    /// The `tracker id` each tracker last sent, per infohash and announce
+    /// URL, echoed back as `trackerid` on later announces.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tracker_ids: BTreeMap<String, String>,
+    /// What each tracker last reported, per infohash and tracker URL.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tracker_stats: BTreeMap<String, TrackerStats>,
+    /// This torrent's proxy, `direct` or a URL, overriding the session's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// This torrent's local address, overriding the session's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_address: Option<String>,
+}
+
+impl ResumeData {
+    pub fn record_uploaded(&mut self, payload_bytes: u64) {
+        self.uploaded += payload_bytes;
+    }
+
+    pub fn record_downloaded(&mut self, payload_bytes: u64) {
+        self.downloaded += payload_bytes;
+    }
+
+    /// The `left` value to announce for a torrent of `total_size` bytes.
+    pub fn bytes_left(&self, total_size: u64) -> u64 {
+        self.left.map_or(total_size, |left| left.min(total_size))
+    }
+
+    /// The announce schedule for `info_hash` on `tracker`. Hybrid torrents
+    /// announce both of their hashes, each on its own schedule.
+    pub fn schedule(&self, info_hash: &InfoHash, tracker: &str) -> AnnounceSchedule {
+        self.announces
+            .get(&schedule_key(info_hash, tracker))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn schedule_mut(&mut self, info_hash: &InfoHash, tracker: &str) -> &mut AnnounceSchedule {
+        self.announces
+            .entry(schedule_key(info_hash, tracker))
+            .or_default()
+    }
+
+    pub fn tracker_stats(&self, info_hash: &InfoHash, tracker: &str) -> TrackerStats {
+        self.tracker_stats
+            .get(&schedule_key(info_hash, tracker))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn tracker_stats_mut(&mut self, info_hash: &InfoHash, tracker: &str) -> &mut TrackerStats {
+        self.tracker_stats
+            .entry(schedule_key(info_hash, tracker))
+            .or_default()
+    }
+
+    pub fn tracker_id(&self, info_hash: &InfoHash, tracker: &str) -> Option<&str> {
+        self.tracker_ids
+            .get(&schedule_key(info_hash, tracker))
+            .map(String::as_str)
+    }
+
+    pub fn set_tracker_id(&mut self, info_hash: &InfoHash, tracker: &str, tracker_id: String) {
+        self.tracker_ids
+            .insert(schedule_key(info_hash, tracker), tracker_id);
+    }
+
+    /// The settings this torrent overrides, to merge over the session's.
+    pub fn network_overrides(&self) -> Result<NetworkSettings> {
+        Ok(NetworkSettings {
+            proxy: self.proxy.clone().map(Proxy::from),
+            bind_address: self.bind_address.as_deref().map(str::parse).transpose()?,
+            ..NetworkSettings::default()
+        })
+    }
+
+    /// The URL to announce to for `tracker`, following a recorded permanent
+    /// redirect.
+    pub fn announce_target<'a>(&'a self, tracker: &'a str) -> &'a str {
+        self.redirects.get(tracker).map_or(tracker, String::as_str)
+    }
+}
+
+fn schedule_key(info_hash: &InfoHash, tracker: &str) -> String {
+    format!("{} {}", info_hash, tracker)
+}
+
+/// `$XDG_DATA_HOME/crab_torrent`, falling back to
+/// `~/.local/share/crab_torrent`. Everything that persists between runs
+/// lives here.
+pub fn data_dir() -> PathBuf {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("crab_torrent")
+}
+
+/// Stores resume data as one bencoded file per infohash.
+pub struct ResumeStore {
+    dir: PathBuf,
+}
+
+impl ResumeStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ResumeStore { dir: dir.into() }
+    }
+
+    /// Stores resume data in `data_dir()`.
+    pub fn default_location() -> Self {
+        ResumeStore::new(data_dir())
+    }
+
+    /// Loads the resume data for `info_hash`, or empty data if none was saved.
+    pub fn load(&self, info_hash: &InfoHash) -> Result<ResumeData> {
+        match fs::read(self.path(info_hash)) {
+            Ok(contents) => Ok(serde_bencode::from_bytes(&contents)?),
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(ResumeData::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub fn save(&self, info_hash: &InfoHash, data: &ResumeData) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let path = self.path(info_hash);
+        let temporary = path.with_extension("resume.tmp");
+        fs::write(&temporary, serde_bencode::to_bytes(data)?)?;
+        fs::rename(temporary, path)?;
+
+        Ok(())
+    }
+
+    fn path(&self, info_hash: &InfoHash) -> PathBuf {
+        self.dir.join(format!("{}.resume", info_hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    #[test]
+    fn saved_network_overrides_win_over_the_session() {
+        let dir = env::temp_dir().join(format!("crab_torrent-resume-{}", std::process::id()));
+        let store = ResumeStore::new(&dir);
+        let info_hash = InfoHash([3; 20]);
+        let data = ResumeData {
+            proxy: Some("direct".to_string()),
+            bind_address: Some("10.0.0.2".to_string()),
+            ..ResumeData::default()
+        };
+        store.save(&info_hash, &data).unwrap();
+
+        let session = NetworkSettings {
+            proxy: Some(Proxy::Url("socks5://vpn:1080".to_string())),
+            bind_address: Some("10.8.0.5".parse().unwrap()),
+            user_agent: Some("session".to_string()),
+            ..NetworkSettings::default()
+        };
+        let overrides = store.load(&info_hash).unwrap().network_overrides().unwrap();
+        let merged = session.merged_with(&overrides);
+        assert_eq!(merged.proxy, Some(Proxy::Direct));
+        assert_eq!(
+            merged.bind_address,
+            Some("10.0.0.2".parse::<IpAddr>().unwrap())
+        );
+        assert_eq!(merged.user_agent.as_deref(), Some("session"));
+
+        // Torrents without overrides keep the session's settings.
+        let merged = session.merged_with(&ResumeData::default().network_overrides().unwrap());
+        assert_eq!(merged, session);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
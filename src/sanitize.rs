@@ -0,0 +1,231 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// What to do with path components that could escape the download directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeMode {
+    /// Refuse the path.
+    Reject,
+    /// Drop or replace the offending parts.
+    Rewrite,
+}
+
+/// Whether a torrent's files are saved inside a folder named after it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RootFolder {
+    /// Multi-file torrents get the folder, single-file torrents don't.
+    #[default]
+    Original,
+    /// Save every file directly into the target directory.
+    Strip,
+    /// Always use the folder; a single file's is named after it without
+    /// its extension.
+    Always,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// The path has no components, or none survive rewriting.
+    Empty,
+    EmptyComponent,
+    /// A `.` or `..` component.
+    RelativeComponent(String),
+    /// A component holding a path separator or drive prefix, which would
+    /// make it absolute or split it into several components.
+    Separator(String),
+    NulByte,
+    /// A device name such as `CON` or `com1.txt`, which Windows opens as
+    /// the device rather than a file.
+    Reserved(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathError::Empty => write!(f, "path is empty"),
+            PathError::EmptyComponent => write!(f, "path has an empty component"),
+            PathError::RelativeComponent(component) => {
+                write!(f, "path component \"{}\" is not allowed", component)
+            }
+            PathError::Separator(component) => {
+                write!(f, "path component \"{}\" contains a separator", component)
+            }
+            PathError::NulByte => write!(f, "path contains a NUL byte"),
+            PathError::Reserved(component) => {
+                write!(
+                    f,
+                    "path component \"{}\" is a reserved device name",
+                    component
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns the components of a torrent file path into a relative path that
+/// stays inside the directory it is joined onto. On Windows, reserved
+/// device names are refused or rewritten too.
+pub fn sanitize_path(components: &[String], mode: SanitizeMode) -> Result<PathBuf, PathError> {
+    sanitize_path_for(components, mode, cfg!(windows))
+}
+
+fn sanitize_path_for(
+    components: &[String],
+    mode: SanitizeMode,
+    windows: bool,
+) -> Result<PathBuf, PathError> {
+    let mut path = PathBuf::new();
+
+    for component in components {
+        match sanitize_component(component, mode, windows)? {
+            Some(component) => path.push(component),
+            None => continue,
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(PathError::Empty);
+    }
+
+    Ok(path)
+}
+
+/// Returns the component to use, or `None` if it should be dropped.
+fn sanitize_component(
+    component: &str,
+    mode: SanitizeMode,
+    windows: bool,
+) -> Result<Option<String>, PathError> {
+    let error = if component.is_empty() {
+        Some(PathError::EmptyComponent)
+    } else if component == "." || component == ".." {
+        Some(PathError::RelativeComponent(component.to_string()))
+    } else if component.contains('\0') {
+        Some(PathError::NulByte)
+    } else if component.contains(['/', '\\', ':']) {
+        Some(PathError::Separator(component.to_string()))
+    } else if windows && is_reserved(component) {
+        Some(PathError::Reserved(component.to_string()))
+    } else {
+        None
+    };
+
+    match (error, mode) {
+        (None, _) => Ok(Some(component.to_string())),
+        (Some(error), SanitizeMode::Reject) => Err(error),
+        (Some(_), SanitizeMode::Rewrite) => {
+            let rewritten: String = component
+                .chars()
+                .filter(|c| *c != '\0')
+                .map(|c| {
+                    if matches!(c, '/' | '\\' | ':') {
+                        '_'
+                    } else {
+                        c
+                    }
+                })
+                .collect();
+
+            if rewritten.is_empty() || rewritten == "." || rewritten == ".." {
+                Ok(None)
+            } else if windows && is_reserved(&rewritten) {
+                Ok(Some(format!("_{}", rewritten)))
+            } else {
+                Ok(Some(rewritten))
+            }
+        }
+    }
+}
+
+/// Whether Windows takes `component` for a device: a reserved name in any
+/// case, alone or before an extension, ignoring trailing spaces.
+fn is_reserved(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or(component).trim_end();
+    RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn components(path: &[&str]) -> Vec<String> {
+        path.iter().map(|component| component.to_string()).collect()
+    }
+
+    fn sanitize(path: &[&str], mode: SanitizeMode) -> Result<PathBuf, PathError> {
+        sanitize_path_for(&components(path), mode, true)
+    }
+
+    #[test]
+    fn rejects_components_that_escape_or_are_invalid() {
+        let reject = |path: &[&str]| sanitize(path, SanitizeMode::Reject);
+        assert_eq!(
+            reject(&["dir", "file.txt"]),
+            Ok(PathBuf::from("dir/file.txt"))
+        );
+        assert_eq!(
+            reject(&["..", "etc", "passwd"]),
+            Err(PathError::RelativeComponent("..".to_string()))
+        );
+        assert_eq!(
+            reject(&["dir", "."]),
+            Err(PathError::RelativeComponent(".".to_string()))
+        );
+        assert_eq!(
+            reject(&["/etc", "passwd"]),
+            Err(PathError::Separator("/etc".to_string()))
+        );
+        assert_eq!(
+            reject(&["C:", "Windows"]),
+            Err(PathError::Separator("C:".to_string()))
+        );
+        assert_eq!(
+            reject(&["a\\..\\b"]),
+            Err(PathError::Separator("a\\..\\b".to_string()))
+        );
+        assert_eq!(reject(&["dir", ""]), Err(PathError::EmptyComponent));
+        assert_eq!(reject(&["nul\0byte"]), Err(PathError::NulByte));
+        assert_eq!(reject(&[]), Err(PathError::Empty));
+        for reserved in ["CON", "nul", "Com1.txt", "lpt9 .log", "AUX.tar.gz"] {
+            assert_eq!(
+                reject(&["dir", reserved]),
+                Err(PathError::Reserved(reserved.to_string()))
+            );
+        }
+        assert_eq!(
+            reject(&["CONSOLE", "com10", "nul_"]),
+            Ok(PathBuf::from("CONSOLE/com10/nul_"))
+        );
+    }
+
+    #[test]
+    fn rewrites_components_to_stay_inside_the_directory() {
+        let rewrite = |path: &[&str]| sanitize(path, SanitizeMode::Rewrite);
+        assert_eq!(
+            rewrite(&["..", "etc", "passwd"]),
+            Ok(PathBuf::from("etc/passwd"))
+        );
+        assert_eq!(
+            rewrite(&["/etc", "", "pass\0wd"]),
+            Ok(PathBuf::from("_etc/passwd"))
+        );
+        assert_eq!(rewrite(&["a/../b"]), Ok(PathBuf::from("a_.._b")));
+        assert_eq!(rewrite(&["con.txt"]), Ok(PathBuf::from("_con.txt")));
+        assert_eq!(rewrite(&["..", "."]), Err(PathError::Empty));
+        // Elsewhere, device names are ordinary files.
+        assert_eq!(
+            sanitize_path_for(&components(&["con.txt"]), SanitizeMode::Reject, false),
+            Ok(PathBuf::from("con.txt"))
+        );
+    }
+}
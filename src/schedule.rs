@@ -0,0 +1,282 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How far either side of the tracker's interval a re-announce may land, as
+/// a fraction of the interval. Spreading announces keeps many torrents on
+/// the same tracker from firing in lockstep.
+pub const JITTER: f64 = 0.1;
+
+/// The current time in seconds since the Unix epoch. Wall-clock time is
+/// used so a schedule saved before a restart still applies after it.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// When a tracker was last announced to and how long it asked us to wait.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AnnounceSchedule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_announce: Option<u64>,
+    #[serde(default)]
+    pub interval: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_interval: Option<u64>,
+}
+
+impl AnnounceSchedule {
+    /// Records an announce made at `at` and the intervals the tracker
+    /// returned.
+    pub fn record(&mut self, at: u64, interval: u64, min_interval: Option<u64>) {
+        self.last_announce = Some(at);
+        self.interval = interval;
+        self.min_interval = min_interval;
+    }
+
+    /// Seconds until the tracker allows another announce: `min interval`
+    /// when it sent one, the full interval otherwise.
+    pub fn remaining(&self, clock: &impl Clock) -> u64 {
+        self.earliest()
+            .map_or(0, |earliest| earliest.saturating_sub(clock.now()))
+    }
+
+    pub fn may_announce(&self, clock: &impl Clock) -> bool {
+        self.remaining(clock) == 0
+    }
+
+    /// When the next regular announce is due, with `jitter` in `[-1, 1]`
+    /// moving it by up to `JITTER` of the interval. It is never earlier than
+    /// the tracker allows. `None` if there has been no announce yet.
+    pub fn next_announce(&self, jitter: f64) -> Option<u64> {
+        let last = self.last_announce?;
+        let offset = self.interval as f64 * JITTER * jitter.clamp(-1.0, 1.0);
+        let jittered = last + (self.interval as f64 + offset).round().max(0.0) as u64;
+        Some(jittered.max(self.earliest()?))
+    }
+
+    fn earliest(&self) -> Option<u64> {
+        Some(self.last_announce? + self.min_interval.unwrap_or(self.interval))
+    }
+}
+
+/// How long to wait before retrying a tracker after transient failures:
+/// `initial` seconds, doubling with each consecutive failure up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackoffPolicy {
+    pub initial: u64,
+    pub max: u64,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        BackoffPolicy {
+            initial: 15,
+            max: 30 * 60,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Seconds to wait after `failures` consecutive failures, moved by up
+    /// to `JITTER` of the delay with `jitter` in `[-1, 1]`.
+    pub fn delay(&self, failures: u32, jitter: f64) -> u64 {
+        let exponent = failures.saturating_sub(1).min(32);
+        let delay = self.initial.saturating_mul(1 << exponent).min(self.max);
+        let offset = delay as f64 * JITTER * jitter.clamp(-1.0, 1.0);
+        (delay as f64 + offset).round().max(1.0) as u64
+    }
+}
+
+/// Consecutive transient failures of one tracker and when it may be tried
+/// again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub failures: u32,
+    pub retry_at: Option<u64>,
+}
+
+impl Backoff {
+    pub fn failed(&mut self, at: u64, policy: &BackoffPolicy, jitter: f64) {
+        self.failures += 1;
+        self.retry_at = Some(at + policy.delay(self.failures, jitter));
+    }
+
+    pub fn succeeded(&mut self) {
+        *self = Backoff::default();
+    }
+
+    /// Seconds until the tracker may be retried.
+    pub fn remaining(&self, clock: &impl Clock) -> u64 {
+        self.retry_at
+            .map_or(0, |retry_at| retry_at.saturating_sub(clock.now()))
+    }
+}
+
+/// A jitter value in `[-1, 1]` for `AnnounceSchedule::next_announce`.
+pub fn random_jitter() -> f64 {
+    let random = RandomState::new().hash_one(SystemTime::now());
+    (random as f64 / u64::MAX as f64) * 2.0 - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info_hash::InfoHash;
+    use crate::resume::ResumeData;
+    use std::cell::Cell;
+
+    struct MockClock(Cell<u64>);
+
+    impl MockClock {
+        fn at(now: u64) -> Self {
+            MockClock(Cell::new(now))
+        }
+
+        fn advance(&self, seconds: u64) {
+            self.0.set(self.0.get() + seconds);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn first_announce_is_allowed() {
+        let clock = MockClock::at(1_000);
+        let schedule = AnnounceSchedule::default();
+
+        assert!(schedule.may_announce(&clock));
+        assert_eq!(schedule.next_announce(0.0), None);
+    }
+
+    #[test]
+    fn blocks_announces_until_min_interval_passes() {
+        let clock = MockClock::at(1_000);
+        let mut schedule = AnnounceSchedule::default();
+        schedule.record(clock.now(), 1_800, Some(300));
+
+        clock.advance(299);
+        assert!(!schedule.may_announce(&clock));
+        assert_eq!(schedule.remaining(&clock), 1);
+
+        clock.advance(1);
+        assert!(schedule.may_announce(&clock));
+    }
+
+    #[test]
+    fn falls_back_to_interval_without_min_interval() {
+        let clock = MockClock::at(1_000);
+        let mut schedule = AnnounceSchedule::default();
+        schedule.record(clock.now(), 1_800, None);
+
+        clock.advance(1_799);
+        assert!(!schedule.may_announce(&clock));
+        clock.advance(1);
+        assert!(schedule.may_announce(&clock));
+    }
+
+    #[test]
+    fn jitter_spreads_around_interval() {
+        let mut schedule = AnnounceSchedule::default();
+        schedule.record(1_000, 1_800, Some(60));
+
+        assert_eq!(schedule.next_announce(0.0), Some(2_800));
+        assert_eq!(schedule.next_announce(1.0), Some(2_980));
+        assert_eq!(schedule.next_announce(-1.0), Some(2_620));
+        // Out-of-range jitter is clamped.
+        assert_eq!(schedule.next_announce(5.0), Some(2_980));
+    }
+
+    #[test]
+    fn jitter_never_undercuts_min_interval() {
+        let mut schedule = AnnounceSchedule::default();
+        schedule.record(1_000, 100, Some(95));
+
+        for step in -10..=10 {
+            let next = schedule.next_announce(step as f64 / 10.0).unwrap();
+            assert!(next >= 1_095, "announce at {} undercuts min interval", next);
+        }
+
+        schedule.record(1_000, 100, None);
+        for step in -10..=10 {
+            assert!(schedule.next_announce(step as f64 / 10.0).unwrap() >= 1_100);
+        }
+    }
+
+    #[test]
+    fn random_jitter_is_in_range() {
+        for _ in 0..100 {
+            let jitter = random_jitter();
+            assert!((-1.0..=1.0).contains(&jitter));
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let policy = BackoffPolicy {
+            initial: 10,
+            max: 100,
+        };
+
+        assert_eq!(policy.delay(1, 0.0), 10);
+        assert_eq!(policy.delay(2, 0.0), 20);
+        assert_eq!(policy.delay(4, 0.0), 80);
+        assert_eq!(policy.delay(5, 0.0), 100);
+        assert_eq!(policy.delay(u32::MAX, 0.0), 100);
+        assert_eq!(policy.delay(2, 1.0), 22);
+        assert_eq!(policy.delay(2, -1.0), 18);
+    }
+
+    #[test]
+    fn backoff_resets_after_success() {
+        let clock = MockClock::at(1_000);
+        let policy = BackoffPolicy::default();
+        let mut backoff = Backoff::default();
+
+        backoff.failed(clock.now(), &policy, 0.0);
+        backoff.failed(clock.now(), &policy, 0.0);
+        assert_eq!(backoff.remaining(&clock), 30);
+
+        clock.advance(30);
+        assert_eq!(backoff.remaining(&clock), 0);
+
+        backoff.succeeded();
+        assert_eq!(backoff, Backoff::default());
+    }
+
+    #[test]
+    fn min_interval_survives_restart() {
+        let clock = MockClock::at(1_000);
+        let info_hash = InfoHash([7; 20]);
+        let tracker = "http://tracker.example/announce";
+        let mut resume = ResumeData::default();
+        resume
+            .schedule_mut(&info_hash, tracker)
+            .record(clock.now(), 1_800, Some(300));
+
+        let saved = serde_bencode::to_bytes(&resume).unwrap();
+        clock.advance(120);
+        let restored: ResumeData = serde_bencode::from_bytes(&saved).unwrap();
+
+        let schedule = restored.schedule(&info_hash, tracker);
+        assert_eq!(schedule, resume.schedule(&info_hash, tracker));
+        assert!(!schedule.may_announce(&clock));
+        assert_eq!(schedule.remaining(&clock), 180);
+    }
+}
@@ -0,0 +1,435 @@
+//! Virtual peers for reproducing swarm interactions in tests: each one
+//! listens on localhost, speaks the real wire protocol and serves a real
+//! torrent's data, but follows a script, stalling, choking, corrupting
+//! blocks or hanging up as told.
+
+use crate::bitfield::Bitfield;
+use crate::download::{run_peer, Download, PeerOptions};
+use crate::info_hash::InfoHash;
+use crate::mse::EncryptionPolicy;
+use crate::net::NetworkSettings;
+use crate::peer::{Handshake, PeerConnection, PeerError};
+use crate::peer_id::PeerId;
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+use crate::wire::Message;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::BTreeSet;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// How long a virtual peer waits for a message before checking its choke
+/// schedule again.
+const TICK: Duration = Duration::from_millis(50);
+
+/// Handshakes, both ways, that take longer fail.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `run_swarm` waits before redialing a peer that hung up.
+const REDIAL_DELAY: Duration = Duration::from_millis(100);
+
+/// What a virtual peer does besides serving every block it's asked for.
+/// Behaviors combine: a peer can be both slow and a choker.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Behavior {
+    /// Waits this long before serving each block.
+    delay: Duration,
+    /// Unchokes for the first duration, chokes for the second, and
+    /// repeats, dropping requests that arrive while choked.
+    choke_cycle: Option<(Duration, Duration)>,
+    /// Pieces whose blocks are sent with their first byte flipped.
+    corrupt: BTreeSet<u32>,
+    /// Hangs up after serving this many blocks on a connection.
+    disconnect_after: Option<usize>,
+}
+
+impl Behavior {
+    pub fn slow(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    pub fn choker(mut self, unchoked: Duration, choked: Duration) -> Self {
+        self.choke_cycle = Some((unchoked, choked));
+        self
+    }
+
+    pub fn corruptor(mut self, pieces: impl IntoIterator<Item = u32>) -> Self {
+        self.corrupt = pieces.into_iter().collect();
+        self
+    }
+
+    pub fn disconnector(mut self, blocks: usize) -> Self {
+        self.disconnect_after = Some(blocks);
+        self
+    }
+
+    /// Whether the peer is in an unchoked phase `elapsed` into a
+    /// connection.
+    fn unchoked_at(&self, elapsed: Duration) -> bool {
+        match self.choke_cycle {
+            None => true,
+            Some((unchoked, choked)) => {
+                let cycle = (unchoked + choked).as_millis().max(1);
+                elapsed.as_millis() % cycle < unchoked.as_millis()
+            }
+        }
+    }
+}
+
+/// What a virtual peer has done, across all its connections.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VirtualStats {
+    pub connections: usize,
+    pub blocks_sent: usize,
+    pub corrupted: usize,
+    pub chokes: usize,
+    /// Requests that arrived while the peer was choking.
+    pub requests_dropped: usize,
+}
+
+/// A seed running in this process. It stops when dropped.
+#[derive(Debug)]
+pub struct VirtualPeer {
+    address: SocketAddr,
+    stats: Arc<Mutex<VirtualStats>>,
+    task: JoinHandle<()>,
+}
+
+impl VirtualPeer {
+    /// Starts a seed of `torrent`, reading its data from `storage`, that
+    /// accepts connections on a localhost port one at a time.
+    pub async fn spawn(
+        torrent: &Torrent,
+        storage: Arc<Storage>,
+        behavior: Behavior,
+    ) -> io::Result<VirtualPeer> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let address = listener.local_addr()?;
+        let handshake = Handshake::new(torrent.info_hash(), PeerId::random());
+        let pieces = torrent.piece_count();
+        let stats = Arc::new(Mutex::new(VirtualStats::default()));
+        let task = tokio::spawn({
+            let stats = stats.clone();
+            async move {
+                while let Ok((stream, from)) = listener.accept().await {
+                    let Ok(mut connection) = PeerConnection::accept(
+                        stream,
+                        from,
+                        &[handshake],
+                        EncryptionPolicy::Disabled,
+                        HANDSHAKE_TIMEOUT,
+                    )
+                    .await
+                    else {
+                        continue;
+                    };
+                    stats.lock().unwrap().connections += 1;
+                    let _ = serve(&mut connection, &storage, pieces, &behavior, &stats).await;
+                }
+            }
+        });
+        Ok(VirtualPeer {
+            address,
+            stats,
+            task,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn stats(&self) -> VirtualStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+impl Drop for VirtualPeer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Serves one connection by `behavior` until it hangs up or the other side
+/// does.
+async fn serve(
+    connection: &mut PeerConnection,
+    storage: &Storage,
+    pieces: usize,
+    behavior: &Behavior,
+    stats: &Mutex<VirtualStats>,
+) -> Result<(), PeerError> {
+    let mut all = Bitfield::new(pieces);
+    (0..pieces).for_each(|index| all.set(index, true));
+    connection
+        .send(&Message::Bitfield(all.as_bytes().to_vec()))
+        .await?;
+    let connected_at = Instant::now();
+    let mut served = 0;
+
+    loop {
+        let unchoked = behavior.unchoked_at(connected_at.elapsed());
+        let state = connection.state();
+        if state.peer_interested && unchoked == state.am_choking {
+            if unchoked {
+                connection.send(&Message::Unchoke).await?;
+            } else {
+                connection.send(&Message::Choke).await?;
+                stats.lock().unwrap().chokes += 1;
+            }
+        }
+
+        let message = match tokio::time::timeout(TICK, connection.receive()).await {
+            Ok(message) => message?,
+            Err(_) => continue,
+        };
+        let Message::Request {
+            index,
+            begin,
+            length,
+        } = message
+        else {
+            continue;
+        };
+        if connection.state().am_choking {
+            stats.lock().unwrap().requests_dropped += 1;
+            continue;
+        }
+        if !behavior.delay.is_zero() {
+            tokio::time::sleep(behavior.delay).await;
+        }
+        let mut block = storage.read_block(index as usize, begin, length)?;
+        let corrupt = behavior.corrupt.contains(&index);
+        if corrupt {
+            block[0] ^= 0xff;
+        }
+        connection
+            .send(&Message::Piece {
+                index,
+                begin,
+                block,
+            })
+            .await?;
+        served += 1;
+        {
+            let mut stats = stats.lock().unwrap();
+            stats.blocks_sent += 1;
+            stats.corrupted += corrupt as usize;
+        }
+        if behavior
+            .disconnect_after
+            .is_some_and(|after| served >= after)
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Runs `download` against the peers at `peers` until it completes or
+/// `limit` passes. Peers are redialed whenever their connection ends,
+/// except those blamed for bad pieces, which are dropped for good and
+/// returned.
+pub async fn run_swarm(
+    download: &Mutex<Download>,
+    info_hash: InfoHash,
+    peers: &[SocketAddr],
+    options: &PeerOptions,
+    limit: Duration,
+) -> Vec<SocketAddr> {
+    let handshake = Handshake::new(info_hash, PeerId::random());
+    let network = NetworkSettings::default();
+    let network = &network;
+    let session = |address: SocketAddr, delay: Duration| async move {
+        tokio::time::sleep(delay).await;
+        let dialed = PeerConnection::dial(
+            address,
+            handshake,
+            HANDSHAKE_TIMEOUT,
+            EncryptionPolicy::Disabled,
+            network,
+        )
+        .await;
+        if let Ok(mut connection) = dialed {
+            let _ = run_peer(&mut connection, download, options).await;
+        }
+        address
+    };
+
+    let deadline = Instant::now() + limit;
+    let mut blamed = Vec::new();
+    let mut sessions: FuturesUnordered<_> = peers
+        .iter()
+        .map(|&address| session(address, Duration::ZERO))
+        .collect();
+    while Instant::now() < deadline && !download.lock().unwrap().is_complete() {
+        let ended = tokio::time::timeout(TICK, sessions.next()).await;
+        {
+            let mut download = download.lock().unwrap();
+            // A banned peer can fail more pieces before its connection
+            // sees the ban, and be blamed again.
+            for address in download.take_blamed() {
+                download.drop_peer(address);
+                if !blamed.contains(&address) {
+                    blamed.push(address);
+                }
+            }
+        }
+        match ended {
+            Ok(Some(address)) if !blamed.contains(&address) => {
+                sessions.push(session(address, REDIAL_DELAY));
+            }
+            Ok(None) => break,
+            _ => {}
+        }
+    }
+    // Let the connections see the download finished and clean up.
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let _ = tokio::time::timeout(remaining, sessions.collect::<Vec<_>>()).await;
+    blamed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::BLOCK_LEN;
+    use crate::sanitize::RootFolder;
+    use sha1::{Digest, Sha1};
+    use std::fs;
+    use std::path::PathBuf;
+
+    const PIECE_LEN: usize = 2 * BLOCK_LEN as usize;
+
+    const LIMIT: Duration = Duration::from_secs(20);
+
+    /// A torrent of four two-block pieces, each all `index + 1` bytes,
+    /// with its data in a seed directory and an empty download directory.
+    struct Fixture {
+        torrent: Arc<Torrent>,
+        seed: Arc<Storage>,
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "crab_torrent-sim-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("seed")).unwrap();
+            let data: Vec<u8> = (0..4)
+                .flat_map(|index| vec![index + 1; PIECE_LEN])
+                .collect();
+            fs::write(dir.join("seed/a"), &data).unwrap();
+
+            let mut info = b"d6:lengthi131072e4:name1:a12:piece lengthi32768e6:pieces80:".to_vec();
+            for piece in data.chunks(PIECE_LEN) {
+                info.extend(Sha1::digest(piece));
+            }
+            info.push(b'e');
+            let torrent = Arc::new(Torrent::from_info_bytes(info, Vec::new()).unwrap());
+            let seed = Storage::new(torrent.clone(), dir.join("seed"), RootFolder::default());
+            Fixture {
+                torrent,
+                seed: Arc::new(seed.unwrap()),
+                dir,
+            }
+        }
+
+        async fn peer(&self, behavior: Behavior) -> VirtualPeer {
+            VirtualPeer::spawn(&self.torrent, self.seed.clone(), behavior)
+                .await
+                .unwrap()
+        }
+
+        fn download(&self) -> Mutex<Download> {
+            let storage = Storage::new(
+                self.torrent.clone(),
+                self.dir.join("download"),
+                RootFolder::default(),
+            )
+            .unwrap();
+            Mutex::new(Download::new(&self.torrent, storage, Bitfield::new(4)))
+        }
+
+        /// Runs a download against `peers`, returning it and the peers
+        /// blamed for bad pieces.
+        async fn run(&self, peers: &[&VirtualPeer]) -> (Download, Vec<SocketAddr>) {
+            let download = self.download();
+            let addresses: Vec<SocketAddr> = peers.iter().map(|peer| peer.address()).collect();
+            let options = PeerOptions::default();
+            let blamed = run_swarm(
+                &download,
+                self.torrent.info_hash(),
+                &addresses,
+                &options,
+                LIMIT,
+            )
+            .await;
+            (download.into_inner().unwrap(), blamed)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn resumes_from_peers_that_keep_hanging_up() {
+        block_on(async {
+            let fixture = Fixture::new("disconnector");
+            let peer = fixture.peer(Behavior::default().disconnector(3)).await;
+            let (download, blamed) = fixture.run(&[&peer]).await;
+            assert!(download.is_complete());
+            assert!(blamed.is_empty());
+            // Eight blocks at three a connection.
+            assert!(peer.stats().connections >= 3);
+        });
+    }
+
+    #[test]
+    fn rerequests_blocks_a_choke_discarded() {
+        block_on(async {
+            let fixture = Fixture::new("choker");
+            let behavior = Behavior::default()
+                .slow(Duration::from_millis(100))
+                .choker(Duration::from_millis(250), Duration::from_millis(250));
+            let peer = fixture.peer(behavior).await;
+            let (download, blamed) = fixture.run(&[&peer]).await;
+            assert!(download.is_complete());
+            assert!(blamed.is_empty());
+            assert!(peer.stats().chokes >= 1);
+        });
+    }
+
+    #[test]
+    fn blames_a_corruptor_and_finishes_from_an_honest_peer() {
+        block_on(async {
+            let fixture = Fixture::new("corruptor");
+            let corruptor = fixture.peer(Behavior::default().corruptor(0..4)).await;
+            let honest = fixture
+                .peer(Behavior::default().slow(Duration::from_millis(20)))
+                .await;
+            let (download, blamed) = fixture.run(&[&corruptor, &honest]).await;
+            assert!(download.is_complete());
+            assert_eq!(blamed, [corruptor.address()]);
+            assert!(corruptor.stats().corrupted >= 1);
+        });
+    }
+}
@@ -0,0 +1,84 @@
+use crate::sanitize::{RootFolder, SanitizeMode};
+use crate::torrent::Torrent;
+use anyhow::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Writes a torrent's pieces into its files under a download directory,
+/// laid out the same way `verify::recheck` reads them back, and reads
+/// blocks of them back for peers.
+#[derive(Debug)]
+pub struct Storage {
+    torrent: Arc<Torrent>,
+    download_dir: PathBuf,
+    /// Sanitized path of each file, `None` for padding files, which are
+    /// never written.
+    paths: Vec<Option<PathBuf>>,
+}
+
+impl Storage {
+    pub fn new(
+        torrent: Arc<Torrent>,
+        download_dir: impl Into<PathBuf>,
+        root_folder: RootFolder,
+    ) -> Result<Self> {
+        let paths = torrent.sanitized_paths(SanitizeMode::Reject, root_folder)?;
+        Ok(Storage {
+            torrent,
+            download_dir: download_dir.into(),
+            paths,
+        })
+    }
+
+    /// Writes a verified piece, creating files and directories as needed.
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> io::Result<()> {
+        let mut position = 0;
+        for segment in self.torrent.piece_segments(index) {
+            let end = position + segment.length as usize;
+            if let Some(path) = &self.paths[segment.file_index] {
+                let path = self.download_dir.join(path);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(path)?;
+                file.seek(SeekFrom::Start(segment.offset))?;
+                file.write_all(&data[position..end])?;
+            }
+            position = end;
+        }
+        Ok(())
+    }
+
+    /// Reads `length` bytes at `begin` within a piece we have. Padding
+    /// reads as zeros.
+    pub fn read_block(&self, index: usize, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let (start, end) = (begin as u64, begin as u64 + length as u64);
+        let mut data = Vec::with_capacity(length as usize);
+        let mut position = 0;
+        for segment in self.torrent.piece_segments(index) {
+            let segment_end = position + segment.length;
+            if segment_end > start && position < end {
+                let skip = start.saturating_sub(position);
+                let len = (end.min(segment_end) - position - skip) as usize;
+                match &self.paths[segment.file_index] {
+                    Some(path) => {
+                        let mut file = File::open(self.download_dir.join(path))?;
+                        file.seek(SeekFrom::Start(segment.offset + skip))?;
+                        let mut bytes = vec![0; len];
+                        file.read_exact(&mut bytes)?;
+                        data.extend(bytes);
+                    }
+                    None => data.resize(data.len() + len, 0),
+                }
+            }
+            position = segment_end;
+        }
+        Ok(data)
+    }
+}
@@ -0,0 +1,221 @@
+use crate::download::Download;
+use crate::info_hash::InfoHash;
+use crate::torrent::{hex, Torrent};
+use serde::Serialize;
+
+/// A serializable overview of a torrent's metadata.
+#[derive(Debug, Serialize)]
+pub struct TorrentSummary {
+    pub name: String,
+    pub info_hash: InfoHash,
+    /// Full SHA-256 infohash in hex, for v2 and hybrid torrents.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info_hash_v2: Option<String>,
+    pub trackers: Vec<Vec<String>>,
+    pub files: Vec<FileSummary>,
+    pub total_size: u64,
+    pub piece_length: i64,
+    pub piece_count: usize,
+    pub private: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_date: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub similar: Vec<InfoHash>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub collections: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileSummary {
+    /// Path components joined with `/`.
+    pub path: String,
+    pub length: u64,
+}
+
+/// A serializable snapshot of a running download: progress, rates and the
+/// connected peers.
+#[derive(Debug, Serialize)]
+pub struct DownloadSummary {
+    pub name: String,
+    pub info_hash: InfoHash,
+    pub piece_count: usize,
+    /// Pieces verified on disk.
+    pub pieces: usize,
+    pub bytes_left: u64,
+    /// Payload bytes this session.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Sums of the peers' smoothed rates, in bytes per second.
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub hash_failures: u64,
+    pub peers: Vec<PeerSummary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerSummary {
+    pub address: String,
+    /// The peer id's client prefix, e.g. `-qB4630-`.
+    pub client: String,
+    /// Seconds connected.
+    pub age: u64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// Our requests the peer hasn't answered yet.
+    pub outstanding: usize,
+    pub pieces_received: u32,
+    pub hash_failures: u32,
+    pub incoming: bool,
+    pub encrypted: bool,
+    pub interested: bool,
+    pub am_choking: bool,
+    pub peer_choking: bool,
+    pub snubbed: bool,
+}
+
+impl Download {
+    pub fn to_summary(&self, torrent: &Torrent) -> DownloadSummary {
+        let peers: Vec<PeerSummary> = self
+            .peers()
+            .iter()
+            .map(|(address, stats)| PeerSummary {
+                address: address.to_string(),
+                client: String::from_utf8_lossy(&stats.peer_id.0[..8]).into_owned(),
+                age: stats.age().as_secs(),
+                downloaded: stats.downloaded,
+                uploaded: stats.uploaded,
+                download_rate: stats.download_rate,
+                upload_rate: stats.upload_rate,
+                outstanding: stats.outstanding,
+                pieces_received: stats.pieces_received,
+                hash_failures: stats.hash_failures,
+                incoming: stats.incoming,
+                encrypted: stats.encrypted,
+                interested: stats.interested,
+                am_choking: stats.am_choking,
+                peer_choking: stats.peer_choking,
+                snubbed: stats.snubbed,
+            })
+            .collect();
+        DownloadSummary {
+            name: torrent.name().to_string(),
+            info_hash: torrent.info_hash(),
+            piece_count: self.have().len(),
+            pieces: self.have().count_ones(),
+            bytes_left: self.bytes_left(),
+            downloaded: self.downloaded(),
+            uploaded: self.uploaded(),
+            download_rate: peers.iter().map(|peer| peer.download_rate).sum(),
+            upload_rate: peers.iter().map(|peer| peer.upload_rate).sum(),
+            hash_failures: self.hash_failures(),
+            peers,
+        }
+    }
+}
+
+impl Torrent {
+    pub fn to_summary(&self) -> TorrentSummary {
+        TorrentSummary {
+            name: self.name().to_string(),
+            info_hash: self.info_hash(),
+            info_hash_v2: self.info_hash_v2().map(|hash| hex(&hash)),
+            trackers: self.tracker_tiers(),
+            files: self
+                .visible_files()
+                .into_iter()
+                .map(|(_, path, length)| FileSummary {
+                    path: path.join("/"),
+                    length,
+                })
+                .collect(),
+            total_size: self.total_size(),
+            piece_length: self.info.piece_length,
+            piece_count: self.piece_count(),
+            private: self.info.private == Some(1),
+            comment: self.comment.clone(),
+            created_by: self.created_by.clone(),
+            creation_date: self.creation_date,
+            source: self.source().map(str::to_string),
+            similar: self.similar(),
+            collections: self.collections(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitfield::Bitfield;
+    use crate::sanitize::RootFolder;
+    use crate::storage::Storage;
+    use sha1::{Digest, Sha1};
+    use std::sync::Arc;
+
+    fn torrent(info: &[u8], piece_count: usize) -> Torrent {
+        let mut info = info.to_vec();
+        info.extend(format!("12:piece lengthi16384e6:pieces{}:", piece_count * 20).bytes());
+        info.extend(vec![0; piece_count * 20]);
+        info.push(b'e');
+        Torrent::from_info_bytes(info, vec![vec!["http://t.invalid/announce".into()]]).unwrap()
+    }
+
+    fn info_hash(torrent: &Torrent) -> InfoHash {
+        InfoHash(Sha1::digest(torrent.info_bytes()).into())
+    }
+
+    #[test]
+    fn summarizes_a_single_file_torrent() {
+        let torrent = torrent(b"d6:lengthi40000e4:name5:a.bin", 3);
+        let summary = torrent.to_summary();
+        assert_eq!(summary.name, "a.bin");
+        assert_eq!(summary.info_hash, info_hash(&torrent));
+        assert_eq!(summary.info_hash_v2, None);
+        assert_eq!(summary.total_size, 40000);
+        assert_eq!(summary.piece_length, 16384);
+        assert_eq!(summary.piece_count, 3);
+        let files: Vec<_> = summary
+            .files
+            .iter()
+            .map(|file| (&*file.path, file.length))
+            .collect();
+        assert_eq!(files, [("a.bin", 40000)]);
+        assert_eq!(summary.trackers, [["http://t.invalid/announce"]]);
+    }
+
+    #[test]
+    fn summarizes_a_multi_file_torrent_and_its_download() {
+        let torrent = Arc::new(torrent(
+            b"d5:filesld6:lengthi1000e4:pathl1:aeed6:lengthi20000e4:pathl3:sub1:beee4:name3:dir",
+            2,
+        ));
+        let summary = torrent.to_summary();
+        assert_eq!(summary.name, "dir");
+        assert_eq!(summary.info_hash, info_hash(&torrent));
+        assert_eq!(summary.total_size, 21000);
+        assert_eq!(summary.piece_count, 2);
+        let files: Vec<_> = summary
+            .files
+            .iter()
+            .map(|file| (&*file.path, file.length))
+            .collect();
+        assert_eq!(files, [("a", 1000), ("sub/b", 20000)]);
+
+        let mut have = Bitfield::new(2);
+        have.set(0, true);
+        let storage = Storage::new(torrent.clone(), "unused", RootFolder::default()).unwrap();
+        let summary = Download::new(&torrent, storage, have).to_summary(&torrent);
+        assert_eq!(summary.info_hash, info_hash(&torrent));
+        assert_eq!((summary.pieces, summary.piece_count), (1, 2));
+        assert_eq!(summary.bytes_left, 21000 - 16384);
+        assert_eq!(summary.downloaded, 0);
+        assert!(summary.peers.is_empty());
+    }
+}
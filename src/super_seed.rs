@@ -0,0 +1,123 @@
+use crate::bitfield::Bitfield;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone)]
+struct Leecher {
+    has: Bitfield,
+    /// The piece we last revealed to the peer.
+    offer: Option<usize>,
+}
+
+/// Super-seeding (BEP 16): rather than announcing every piece, a seed
+/// reveals one piece at a time to each peer, the rarest it lacks, and
+/// reveals the next only once the last has spread, so each upload goes
+/// to pieces the swarm doesn't have yet.
+#[derive(Debug, Clone)]
+pub struct SuperSeed {
+    pieces: usize,
+    peers: BTreeMap<SocketAddr, Leecher>,
+    /// Times each piece was revealed, to spread offers evenly.
+    offered: Vec<u32>,
+}
+
+impl SuperSeed {
+    pub fn new(pieces: usize) -> Self {
+        SuperSeed {
+            pieces,
+            peers: BTreeMap::new(),
+            offered: vec![0; pieces],
+        }
+    }
+
+    /// Starts super-seeding to a peer, which sees no pieces until offered.
+    pub fn connect(&mut self, address: SocketAddr) {
+        self.peers.insert(
+            address,
+            Leecher {
+                has: Bitfield::new(self.pieces),
+                offer: None,
+            },
+        );
+    }
+
+    pub fn disconnect(&mut self, address: &SocketAddr) {
+        self.peers.remove(address);
+    }
+
+    /// Records the pieces a peer has, from its bitfield or a have.
+    pub fn peer_has(&mut self, address: &SocketAddr, has: &Bitfield) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.has = has.clone();
+        }
+    }
+
+    /// Whether we revealed `index` to the peer, so it may request it.
+    pub fn is_offered(&self, address: &SocketAddr, index: usize) -> bool {
+        self.peers
+            .get(address)
+            .is_some_and(|peer| peer.offer == Some(index))
+    }
+
+    /// The piece to reveal to a peer next, if it has none yet or the one
+    /// it was given has spread: the peer has it, and so does another peer
+    /// or every other peer. The rarest piece the peer lacks wins.
+    pub fn next_offer(&mut self, address: &SocketAddr) -> Option<usize> {
+        let peer = self.peers.get(address)?;
+        if let Some(offer) = peer.offer {
+            let others = self.peers.iter().filter(|(other, _)| *other != address);
+            let spread = peer.has.has(offer)
+                && (others.clone().any(|(_, other)| other.has.has(offer))
+                    || others.clone().all(|(_, other)| other.has.has(offer)));
+            if !spread {
+                return None;
+            }
+        }
+        let available = |index: usize| {
+            self.peers
+                .values()
+                .filter(|other| other.has.has(index))
+                .count()
+        };
+        let index = (0..self.pieces)
+            .filter(|&index| !peer.has.has(index) && Some(index) != peer.offer)
+            .min_by_key(|&index| (available(index), self.offered[index]))?;
+        self.offered[index] += 1;
+        self.peers.get_mut(address)?.offer = Some(index);
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reveals_the_next_piece_once_the_last_spread() {
+        let (a, b) = (
+            SocketAddr::from(([10, 0, 0, 1], 1)),
+            SocketAddr::from(([10, 0, 0, 2], 2)),
+        );
+        let mut seed = SuperSeed::new(3);
+        seed.connect(a);
+        seed.connect(b);
+        let mut b_has = Bitfield::new(3);
+        b_has.set(0, true);
+        seed.peer_has(&b, &b_has);
+
+        // The piece b already has is the least rare.
+        let offer = seed.next_offer(&a).unwrap();
+        assert_eq!(offer, 1);
+        assert!(seed.is_offered(&a, 1));
+        assert_eq!(seed.next_offer(&a), None);
+
+        let mut a_has = Bitfield::new(3);
+        a_has.set(1, true);
+        seed.peer_has(&a, &a_has);
+        assert_eq!(seed.next_offer(&a), None);
+        // b got piece 1 from a rather than from us.
+        b_has.set(1, true);
+        seed.peer_has(&b, &b_has);
+        assert_eq!(seed.next_offer(&a), Some(2));
+    }
+}
@@ -0,0 +1,36 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits a byte stream to an average rate by sleeping whenever it runs
+/// ahead of schedule.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    started: Instant,
+    consumed: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Throttle {
+            bytes_per_second,
+            started: Instant::now(),
+            consumed: 0,
+        }
+    }
+
+    /// Accounts for `bytes` just processed, blocking until the average rate
+    /// is back under the limit. A limit of zero means unlimited.
+    pub fn consume(&mut self, bytes: u64) {
+        if self.bytes_per_second == 0 {
+            return;
+        }
+
+        self.consumed += bytes;
+        let due = Duration::from_secs_f64(self.consumed as f64 / self.bytes_per_second as f64);
+        let elapsed = self.started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
@@ -1,45 +1,829 @@
+use crate::bencode::{self, BencodeError, BencodeErrorKind, PathSegment};
+use crate::info_hash::InfoHash;
+use crate::sanitize::{sanitize_path, PathError, RootFolder, SanitizeMode};
 use anyhow::Result;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_bencode::de;
+use serde_bencode::value::Value;
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::PathBuf;
+use urlencoding::encode;
 
 #[derive(Debug, Deserialize)]
 pub struct Torrent {
     pub announce: String,
-    #[serde(rename = "created by")]
-    pub created_by: String,
-    #[serde(rename = "creation date")]
-    pub creation_date: i64,
+    #[serde(rename = "announce-list", default)]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(rename = "created by", default)]
+    pub created_by: Option<String>,
+    /// Unix timestamp; often left out of reproducible torrents.
+    #[serde(rename = "creation date", default)]
+    pub creation_date: Option<i64>,
     pub info: TorrentInfo,
+    #[serde(rename = "piece layers", default)]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+    #[serde(skip)]
+    info_bytes: Vec<u8>,
+    /// Top-level keys this type does not model, kept bencoded so that
+    /// re-saving does not drop them.
+    #[serde(skip)]
+    extra: BTreeMap<Vec<u8>, Vec<u8>>,
 }
 
+const KNOWN_KEYS: &[&[u8]] = &[
+    b"announce",
+    b"announce-list",
+    b"comment",
+    b"created by",
+    b"creation date",
+    b"info",
+    b"piece layers",
+];
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TorrentInfo {
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub files: Vec<TorrentFile>,
+    /// Concatenated SHA-1 piece hashes; empty for v2-only torrents.
+    #[serde(default, skip_serializing_if = "is_empty_bytes")]
     pub pieces: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+    /// Hex MD5 of the file, for single-file torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+    /// BEP 47 SHA-1 of the file, for single-file torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<ByteBuf>,
+    /// Tag identifying the tracker or group the torrent was made for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// BEP 38 infohashes of torrents sharing files with this one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similar: Option<Vec<ByteBuf>>,
+    /// BEP 38 names of collections this torrent belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collections: Option<Vec<String>>,
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<i64>,
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<Value>,
+    /// Keys not modelled above (`x_cross_seed`, ...),
+    /// kept so the dictionary survives a parse/serialize round trip.
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+fn is_empty_bytes(bytes: &ByteBuf) -> bool {
+    bytes.is_empty()
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TorrentFile {
     pub length: i64,
     pub path: Vec<String>,
+    /// BEP 47 attribute flags: `p` padding, `x` executable, `h` hidden,
+    /// `l` symlink.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+    #[serde(
+        rename = "symlink path",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<String>>,
+    /// Hex MD5 of the file's contents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+    /// BEP 47 SHA-1 of the file's contents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<ByteBuf>,
+}
+
+impl TorrentFile {
+    /// Whether this is a BEP 47 padding file. Pad files only exist to align
+    /// the next file to a piece boundary; they are never written to disk.
+    /// Files under a `.pad` directory are treated the same way, as older
+    /// creators didn't set `attr`.
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p') || self.path.first().is_some_and(|first| first == ".pad")
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.has_attr('h')
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.has_attr('l')
+    }
+
+    fn has_attr(&self, flag: char) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains(flag))
+    }
+}
+
+/// One piece of the v1 piece layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Piece {
+    pub index: usize,
+    pub hash: [u8; 20],
+    /// Offset of the piece within the concatenation of all files.
+    pub offset: u64,
+    /// Length of the piece; only the final piece may be shorter than
+    /// `piece length`.
+    pub length: u64,
+}
+
+/// The part of a file covered by a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSegment {
+    /// Index into the torrent's file list (`info.files`, or the single file).
+    pub file_index: usize,
+    /// Offset within the file.
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A file described by the v2 `file tree`.
+#[derive(Debug)]
+pub struct TreeFile {
+    pub path: Vec<String>,
+    pub length: i64,
+    pub pieces_root: Option<Vec<u8>>,
 }
 
 impl Torrent {
     pub fn new(torrent_contents: Vec<u8>) -> Result<Self> {
-        let torrent: Torrent = de::from_bytes(&torrent_contents)?;
+        let mut torrent: Torrent = bencode::decode(&torrent_contents)?;
+        check_lengths(&torrent.info, &torrent_contents, &["info"])?;
+        for (key, span) in bencode::dict_entries(&torrent_contents)? {
+            if key == b"info" {
+                torrent.info_bytes = torrent_contents[span].to_vec();
+            } else if !KNOWN_KEYS.contains(&key) {
+                torrent
+                    .extra
+                    .insert(key.to_vec(), torrent_contents[span].to_vec());
+            }
+        }
         Ok(torrent)
     }
 
-    pub fn info_hash(&self) -> [u8; 20] {
-        let info_bytes = serde_bencode::to_bytes(&self.info).expect("info serialization failed");
+    /// Builds a torrent around a bare bencoded `info` dictionary, as fetched
+    /// with ut_metadata or stored by an indexer. The dictionary is kept byte
+    /// for byte so the infohash matches. The first tracker becomes
+    /// `announce`; `announce-list` is only set when there is more than one.
+    /// v2 piece layers can't be recovered from the info dictionary alone.
+    pub fn from_info_bytes(info_bytes: Vec<u8>, trackers: Vec<Vec<String>>) -> Result<Self> {
+        let info: TorrentInfo = bencode::decode(&info_bytes)?;
+        check_lengths(&info, &info_bytes, &[])?;
+        let trackers: Vec<Vec<String>> = trackers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        let announce = trackers
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .unwrap_or_default();
+        let announce_list = (trackers.concat().len() > 1).then_some(trackers);
+
+        Ok(Torrent {
+            announce,
+            announce_list,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            info,
+            piece_layers: None,
+            info_bytes,
+            extra: BTreeMap::new(),
+        })
+    }
+
+    /// Re-encodes the torrent. The `info` dictionary is written back byte for
+    /// byte, so edits to trackers or the comment keep the infohash.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut entries = self.extra.clone();
+        entries.insert(
+            b"announce".to_vec(),
+            serde_bencode::to_bytes(&self.announce)?,
+        );
+        if let Some(announce_list) = &self.announce_list {
+            entries.insert(
+                b"announce-list".to_vec(),
+                serde_bencode::to_bytes(announce_list)?,
+            );
+        }
+        if let Some(comment) = &self.comment {
+            entries.insert(b"comment".to_vec(), serde_bencode::to_bytes(comment)?);
+        }
+        if let Some(created_by) = &self.created_by {
+            entries.insert(b"created by".to_vec(), serde_bencode::to_bytes(created_by)?);
+        }
+        if let Some(creation_date) = &self.creation_date {
+            entries.insert(
+                b"creation date".to_vec(),
+                serde_bencode::to_bytes(creation_date)?,
+            );
+        }
+        entries.insert(b"info".to_vec(), self.info_bytes.clone());
+        if let Some(piece_layers) = &self.piece_layers {
+            entries.insert(
+                b"piece layers".to_vec(),
+                serde_bencode::to_bytes(piece_layers)?,
+            );
+        }
+
+        Ok(bencode::encode_dict(&entries))
+    }
+
+    pub fn set_announce(&mut self, tracker: impl Into<String>) {
+        self.announce = tracker.into();
+    }
+
+    /// Appends a tier to `announce-list`, creating the list with `announce`
+    /// as its first tier if the torrent did not have one.
+    pub fn add_tracker_tier(&mut self, tier: Vec<String>) {
+        let announce = self.announce.clone();
+        self.announce_list
+            .get_or_insert_with(|| vec![vec![announce]])
+            .push(tier);
+    }
+
+    pub fn set_comment(&mut self, comment: Option<String>) {
+        self.comment = comment;
+    }
+
+    /// Sets or clears the private flag. The flag lives in the `info`
+    /// dictionary, so unlike the other setters this changes the infohash.
+    pub fn set_private(&mut self, private: bool) -> Result<()> {
+        self.info.private = private.then_some(1);
+        self.info_bytes = serde_bencode::to_bytes(&self.info)?;
+        Ok(())
+    }
+
+    /// The bencoded `info` dictionary exactly as it appeared in the file.
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info_bytes
+    }
+
+    pub fn info_hash(&self) -> InfoHash {
+        let result = Sha1::digest(&self.info_bytes);
+
+        InfoHash(result.into())
+    }
+
+    /// The full SHA-256 infohash, present for v2 and hybrid torrents.
+    pub fn info_hash_v2(&self) -> Option<[u8; 32]> {
+        if !self.is_v2() {
+            return None;
+        }
+
+        let result = Sha256::digest(&self.info_bytes);
+
+        Some(result.into())
+    }
+
+    /// The SHA-256 infohash truncated to 20 bytes, as used by trackers and
+    /// the peer handshake.
+    pub fn info_hash_v2_truncated(&self) -> Option<InfoHash> {
+        self.info_hash_v2().map(|hash| {
+            let mut truncated = [0u8; 20];
+            truncated.copy_from_slice(&hash[..20]);
+            InfoHash(truncated)
+        })
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.info.meta_version == Some(2) && self.info.file_tree.is_some()
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v2() && !self.info.pieces.is_empty()
+    }
+
+    /// Whether the torrent has v1 pieces, so its SHA-1 infohash names a
+    /// swarm.
+    pub fn has_v1(&self) -> bool {
+        !self.is_v2() || self.is_hybrid()
+    }
+
+    /// The 20-byte hashes to announce under: the v1 hash unless the torrent
+    /// is v2-only, followed by the truncated v2 hash for v2 torrents.
+    pub fn announce_hashes(&self) -> Vec<InfoHash> {
+        let mut hashes = Vec::new();
+        if self.has_v1() {
+            hashes.push(self.info_hash());
+        }
+        hashes.extend(self.info_hash_v2_truncated());
+        hashes
+    }
+
+    /// Tracker tiers from `announce-list`, falling back to a single tier
+    /// holding `announce`.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if !tiers.is_empty() => tiers.clone(),
+            _ => vec![vec![self.announce.clone()]],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.info.name
+    }
+
+    /// Total size of the torrent's content in bytes.
+    pub fn total_size(&self) -> u64 {
+        // Lengths were checked to fit when the torrent was read.
+        self.file_lengths()
+            .iter()
+            .fold(0, |total: u64, length| total.saturating_add(*length))
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.info.pieces.len() / 20
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        !self.info.files.is_empty()
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.info.source.as_deref()
+    }
+
+    /// BEP 38 similar torrents, from the info dictionary and the top level.
+    pub fn similar(&self) -> Vec<InfoHash> {
+        let top_level: Vec<ByteBuf> = self.extra_value(b"similar").unwrap_or_default();
+        self.info
+            .similar
+            .iter()
+            .flatten()
+            .chain(top_level.iter())
+            .filter_map(|hash| <[u8; 20]>::try_from(hash.as_slice()).ok())
+            .map(InfoHash)
+            .collect()
+    }
+
+    /// BEP 38 collections, from the info dictionary and the top level.
+    pub fn collections(&self) -> Vec<String> {
+        let top_level: Vec<String> = self.extra_value(b"collections").unwrap_or_default();
+        self.info
+            .collections
+            .iter()
+            .flatten()
+            .cloned()
+            .chain(top_level)
+            .collect()
+    }
+
+    /// Decodes an unmodelled top-level key, if present and well formed.
+    fn extra_value<T: DeserializeOwned>(&self, key: &[u8]) -> Option<T> {
+        serde_bencode::from_bytes(self.extra.get(key)?).ok()
+    }
+
+    /// Iterates over every piece with its hash and position in the torrent's
+    /// data, including the short final piece.
+    pub fn pieces(&self) -> impl Iterator<Item = Piece> + '_ {
+        let piece_length = self.info.piece_length as u64;
+        let total_size = self.total_size();
+
+        self.info
+            .pieces
+            .chunks_exact(20)
+            .enumerate()
+            .map(move |(index, hash)| {
+                let offset = index as u64 * piece_length;
+                Piece {
+                    index,
+                    hash: hash.try_into().expect("chunk is 20 bytes"),
+                    offset,
+                    length: piece_length.min(total_size.saturating_sub(offset)),
+                }
+            })
+    }
+
+    /// Maps a piece to the file segments it covers, in order. Pieces may
+    /// straddle several files in multi-file torrents.
+    pub fn piece_segments(&self, piece_index: usize) -> Vec<FileSegment> {
+        let piece_length = self.info.piece_length as u64;
+        let start = piece_index as u64 * piece_length;
+        let end = (start + piece_length).min(self.total_size());
+
+        let mut segments = Vec::new();
+        let mut file_start = 0;
+        for (file_index, file_length) in self.file_lengths().into_iter().enumerate() {
+            let file_end = file_start + file_length;
+            if file_end > start && file_start < end {
+                let segment_start = start.max(file_start);
+                segments.push(FileSegment {
+                    file_index,
+                    offset: segment_start - file_start,
+                    length: end.min(file_end) - segment_start,
+                });
+            }
+            if file_end >= end {
+                break;
+            }
+            file_start = file_end;
+        }
+
+        segments
+    }
+
+    /// Maps a byte range of a file to the range of piece indices covering it.
+    /// Returns `None` if the file does not exist.
+    pub fn file_pieces(&self, file_index: usize, offset: u64, length: u64) -> Option<Range<usize>> {
+        let file_lengths = self.file_lengths();
+        file_lengths.get(file_index)?;
+
+        let piece_length = self.info.piece_length as u64;
+        let file_start: u64 = file_lengths[..file_index].iter().sum();
+        let start = file_start + offset;
+        let end = start + length;
+
+        let first = (start / piece_length) as usize;
+        let last = end.div_ceil(piece_length) as usize;
+        Some(first..last.max(first))
+    }
+
+    /// Paths and lengths of every file in layout order, padding files
+    /// included so indices match the piece math; a single-file torrent has
+    /// one file named after the torrent.
+    pub fn file_paths(&self) -> Vec<(Vec<String>, u64)> {
+        if self.info.files.is_empty() {
+            vec![(
+                vec![self.info.name.clone()],
+                self.info.length.unwrap_or(0) as u64,
+            )]
+        } else {
+            self.info
+                .files
+                .iter()
+                .map(|file| (file.path.clone(), file.length as u64))
+                .collect()
+        }
+    }
+
+    /// Whether the file at `file_index` is a padding file.
+    pub fn is_padding_file(&self, file_index: usize) -> bool {
+        self.info
+            .files
+            .get(file_index)
+            .is_some_and(TorrentFile::is_padding)
+    }
+
+    /// Indices, paths and lengths of the files a user should see, leaving
+    /// out padding files.
+    pub fn visible_files(&self) -> Vec<(usize, Vec<String>, u64)> {
+        self.file_paths()
+            .into_iter()
+            .enumerate()
+            .filter(|(file_index, _)| !self.is_padding_file(*file_index))
+            .map(|(file_index, (path, length))| (file_index, path, length))
+            .collect()
+    }
+
+    /// Relative on-disk paths for every file, with unsafe components
+    /// handled per `mode`. `root_folder` decides whether they sit under a
+    /// folder named after the torrent. Padding files have no on-disk path
+    /// and map to `None`.
+    pub fn sanitized_paths(
+        &self,
+        mode: SanitizeMode,
+        root_folder: RootFolder,
+    ) -> Result<Vec<Option<PathBuf>>, PathError> {
+        let name = sanitize_path(std::slice::from_ref(&self.info.name), mode)?;
+
+        if !self.is_multi_file() {
+            return Ok(vec![Some(match root_folder {
+                RootFolder::Always => {
+                    PathBuf::from(name.file_stem().unwrap_or(name.as_os_str())).join(&name)
+                }
+                RootFolder::Original | RootFolder::Strip => name,
+            })]);
+        }
+
+        let root = match root_folder {
+            RootFolder::Strip => PathBuf::new(),
+            RootFolder::Original | RootFolder::Always => name,
+        };
+        self.info
+            .files
+            .iter()
+            .map(|file| -> Result<Option<PathBuf>, PathError> {
+                if file.is_padding() {
+                    return Ok(None);
+                }
+                Ok(Some(root.join(sanitize_path(&file.path, mode)?)))
+            })
+            .collect()
+    }
+
+    /// Lengths of every file in layout order; a single-file torrent has one.
+    fn file_lengths(&self) -> Vec<u64> {
+        if self.info.files.is_empty() {
+            vec![self.info.length.unwrap_or(0) as u64]
+        } else {
+            self.info
+                .files
+                .iter()
+                .map(|file| file.length as u64)
+                .collect()
+        }
+    }
+
+    /// Builds a magnet link carrying the infohash(es), display name and
+    /// every tracker.
+    pub fn magnet_uri(&self) -> String {
+        let mut topics = Vec::new();
+        if self.has_v1() {
+            topics.push(format!("xt=urn:btih:{}", self.info_hash()));
+        }
+        if let Some(hash) = self.info_hash_v2() {
+            // multihash prefix: 0x12 = sha2-256, 0x20 = 32-byte digest
+            topics.push(format!("xt=urn:btmh:1220{}", hex(&hash)));
+        }
+        let mut uri = format!("magnet:?{}", topics.join("&"));
+        uri.push_str(&format!("&dn={}", encode(&self.info.name)));
+        for tracker in self.tracker_tiers().iter().flatten() {
+            uri.push_str(&format!("&tr={}", encode(tracker)));
+        }
+        uri
+    }
+
+    /// Flattens the v2 `file tree` into a list of files in tree order.
+    pub fn tree_files(&self) -> Vec<TreeFile> {
+        let mut files = Vec::new();
+        if let Some(tree) = &self.info.file_tree {
+            collect_tree_files(tree, &mut Vec::new(), &mut files);
+        }
+        files
+    }
+}
+
+/// The piece math divides by `piece length` and sums file lengths into a
+/// `u64`, so anything but a positive piece length, a negative file length
+/// or lengths adding up past `u64::MAX` are refused when the torrent is
+/// read.
+fn check_lengths(info: &TorrentInfo, data: &[u8], parents: &[&str]) -> Result<(), BencodeError> {
+    let error = |keys: &[PathSegment], message: String| {
+        let path: Vec<PathSegment> = parents
+            .iter()
+            .map(|key| PathSegment::Key(key.to_string()))
+            .chain(keys.iter().cloned())
+            .collect();
+        Err(BencodeError {
+            kind: BencodeErrorKind::Decode(message),
+            offset: bencode::locate(data, &path).unwrap_or(0),
+            path: bencode::render_path(&path),
+        })
+    };
+    if info.piece_length <= 0 {
+        return error(
+            &[PathSegment::Key("piece length".to_string())],
+            format!("piece length must be positive, not {}", info.piece_length),
+        );
+    }
+    let lengths: Vec<(Vec<PathSegment>, i64)> = if info.files.is_empty() {
+        info.length
+            .map(|length| (vec![PathSegment::Key("length".to_string())], length))
+            .into_iter()
+            .collect()
+    } else {
+        info.files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let path = vec![
+                    PathSegment::Key("files".to_string()),
+                    PathSegment::Index(index),
+                    PathSegment::Key("length".to_string()),
+                ];
+                (path, file.length)
+            })
+            .collect()
+    };
+    let mut total: u64 = 0;
+    for (path, length) in lengths {
+        if length < 0 {
+            return error(
+                &path,
+                format!("file length must not be negative, not {}", length),
+            );
+        }
+        let Some(sum) = total.checked_add(length as u64) else {
+            return error(
+                &path,
+                "file lengths add up to more than 2^64 bytes".to_string(),
+            );
+        };
+        total = sum;
+    }
+    Ok(())
+}
+
+/// Lowercase hex of `bytes`, as used for digests in magnets and output.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn collect_tree_files(node: &Value, path: &mut Vec<String>, files: &mut Vec<TreeFile>) {
+    let Value::Dict(entries) = node else {
+        return;
+    };
+
+    let mut names: Vec<&Vec<u8>> = entries.keys().collect();
+    names.sort();
+
+    for name in names {
+        let child = &entries[name];
+        if name.is_empty() {
+            if let Value::Dict(attributes) = child {
+                let length = match attributes.get(b"length".as_slice()) {
+                    Some(Value::Int(length)) => *length,
+                    _ => 0,
+                };
+                let pieces_root = match attributes.get(b"pieces root".as_slice()) {
+                    Some(Value::Bytes(root)) => Some(root.clone()),
+                    _ => None,
+                };
+                files.push(TreeFile {
+                    path: path.clone(),
+                    length,
+                    pieces_root,
+                });
+            }
+        } else {
+            path.push(String::from_utf8_lossy(name).into_owned());
+            collect_tree_files(child, path, files);
+            path.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let result = Sha1::digest(&info_bytes);
+    /// Files `a` (6 bytes) and `b` (5 bytes) in 4-byte pieces.
+    fn two_files(piece_length: &str) -> Vec<u8> {
+        [
+            b"d8:announce16:http://t.invalid4:infod5:filesl\
+              d6:lengthi6e4:pathl1:aeed6:lengthi5e4:pathl1:beee\
+              4:name3:dir12:piece lengthi"
+                .as_slice(),
+            piece_length.as_bytes(),
+            b"e6:pieces60:",
+            &[0; 60],
+            b"ee",
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn maps_pieces_across_files() {
+        let torrent = Torrent::new(two_files("4")).unwrap();
+        let pieces: Vec<(u64, u64)> = torrent
+            .pieces()
+            .map(|piece| (piece.offset, piece.length))
+            .collect();
+        assert_eq!(pieces, [(0, 4), (4, 4), (8, 3)]);
+
+        // Piece 1 ends `a` and starts `b`.
+        assert_eq!(
+            torrent.piece_segments(1),
+            [
+                FileSegment {
+                    file_index: 0,
+                    offset: 4,
+                    length: 2
+                },
+                FileSegment {
+                    file_index: 1,
+                    offset: 0,
+                    length: 2
+                },
+            ]
+        );
+        assert_eq!(
+            torrent.piece_segments(2),
+            [FileSegment {
+                file_index: 1,
+                offset: 2,
+                length: 3
+            }]
+        );
+        assert_eq!(torrent.file_pieces(0, 0, 6), Some(0..2));
+        assert_eq!(torrent.file_pieces(1, 0, 5), Some(1..3));
+        assert_eq!(torrent.file_pieces(1, 4, 1), Some(2..3));
+        assert_eq!(torrent.file_pieces(2, 0, 1), None);
+    }
+
+    /// A one-file torrent with v1 pieces, a v2 file tree, or both.
+    fn single(v1: bool, v2: bool) -> Torrent {
+        let mut info = b"d".to_vec();
+        if v2 {
+            info.extend(b"9:file treed1:ad0:d6:lengthi6eeee");
+        }
+        if v1 {
+            info.extend(b"6:lengthi6e");
+        }
+        if v2 {
+            info.extend(b"12:meta versioni2e");
+        }
+        info.extend(b"4:name1:a12:piece lengthi16384e");
+        if v1 {
+            info.extend(b"6:pieces20:");
+            info.extend([0; 20]);
+        }
+        info.push(b'e');
+        Torrent::from_info_bytes(info, vec![vec!["http://t.invalid".to_string()]]).unwrap()
+    }
+
+    #[test]
+    fn announces_and_links_only_the_hashes_a_torrent_has() {
+        let v1 = single(true, false);
+        assert_eq!(v1.announce_hashes(), [v1.info_hash()]);
+        assert_eq!(v1.magnet_uri().matches("xt=").count(), 1);
+        assert!(v1.magnet_uri().starts_with("magnet:?xt=urn:btih:"));
+
+        let hybrid = single(true, true);
+        assert!(hybrid.is_hybrid());
+        assert_eq!(
+            hybrid.announce_hashes(),
+            [hybrid.info_hash(), hybrid.info_hash_v2_truncated().unwrap()]
+        );
+        assert!(hybrid.magnet_uri().contains("&xt=urn:btmh:1220"));
+
+        let v2 = single(false, true);
+        assert!(v2.is_v2() && !v2.is_hybrid());
+        assert_eq!(v2.announce_hashes(), [v2.info_hash_v2_truncated().unwrap()]);
+        assert!(v2.magnet_uri().starts_with("magnet:?xt=urn:btmh:1220"));
+        assert!(!v2.magnet_uri().contains("btih"));
+    }
+
+    #[test]
+    fn refuses_a_piece_length_that_is_not_positive() {
+        for piece_length in ["0", "-4"] {
+            let error = Torrent::new(two_files(piece_length)).unwrap_err();
+            let error = error.downcast_ref::<BencodeError>().unwrap();
+            assert_eq!(error.path, "info.piece length");
+            assert_eq!(error.offset, 120);
+        }
+    }
+
+    #[test]
+    fn refuses_file_lengths_that_are_negative_or_overflow() {
+        let files = |lengths: &[&str]| {
+            let mut torrent = b"d8:announce16:http://t.invalid4:infod5:filesl".to_vec();
+            for (index, length) in lengths.iter().enumerate() {
+                torrent.extend(format!("d6:lengthi{}e4:pathl1:{}ee", length, index).bytes());
+            }
+            torrent.extend(b"e4:name3:dir12:piece lengthi4e6:pieces0:ee");
+            torrent
+        };
+        let negative = files(&["6", "-1"]);
+        let error = Torrent::new(negative.clone()).unwrap_err();
+        let error = error.downcast_ref::<BencodeError>().unwrap();
+        assert_eq!(error.path, "info.files[1].length");
+        assert_eq!(error.offset, find(&negative, b"i-1e"));
+
+        // Each fits an i64; all three together don't fit a u64.
+        let max = i64::MAX.to_string();
+        let huge = files(&[&max, &max, "2"]);
+        let error = Torrent::new(huge).unwrap_err();
+        let error = error.downcast_ref::<BencodeError>().unwrap();
+        assert_eq!(error.path, "info.files[2].length");
+
+        let single = b"d8:announce16:http://t.invalid4:infod6:lengthi-6e4:name1:a12:piece lengthi4e6:pieces0:ee";
+        let error = Torrent::new(single.to_vec()).unwrap_err();
+        let error = error.downcast_ref::<BencodeError>().unwrap();
+        assert_eq!(error.path, "info.length");
+    }
 
-        result.into()
+    fn find(data: &[u8], needle: &[u8]) -> usize {
+        data.windows(needle.len())
+            .position(|window| window == needle)
+            .unwrap()
     }
 }
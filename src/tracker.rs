@@ -0,0 +1,681 @@
+use crate::bencode::{self, BencodeError};
+use crate::info_hash::InfoHash;
+use crate::peer_id::PeerId;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use url::Url;
+
+/// Trackers grouped into BEP 12 tiers, tried in order. Each tier is
+/// shuffled once when the list is made, and a tracker that answers is
+/// moved to the front of its tier so it is tried first next time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    pub fn new(mut tiers: Vec<Vec<String>>) -> Self {
+        tiers.retain(|tier| !tier.is_empty());
+        let random = RandomState::new();
+        for tier in &mut tiers {
+            // Fisher-Yates, drawing from the randomly keyed std hasher.
+            for index in (1..tier.len()).rev() {
+                let other = (random.hash_one(index) % (index as u64 + 1)) as usize;
+                tier.swap(index, other);
+            }
+        }
+        TrackerTiers { tiers }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn tiers_mut(&mut self) -> &mut [Vec<String>] {
+        &mut self.tiers
+    }
+
+    /// Every tracker in the order to try them, with its tier and position.
+    pub fn candidates(&self) -> Vec<(usize, usize, String)> {
+        self.tiers
+            .iter()
+            .enumerate()
+            .flat_map(|(tier_index, tier)| {
+                tier.iter()
+                    .enumerate()
+                    .map(move |(index, tracker)| (tier_index, index, tracker.clone()))
+            })
+            .collect()
+    }
+
+    /// Moves the tracker at `index` in `tier` to the front of that tier.
+    pub fn promote(&mut self, tier: usize, index: usize) {
+        if let Some(tier) = self.tiers.get_mut(tier) {
+            if index < tier.len() {
+                let tracker = tier.remove(index);
+                tier.insert(0, tracker);
+            }
+        }
+    }
+}
+
+/// The `event` an announce reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    /// The first announce for a torrent.
+    Started,
+    /// The download just finished. Not sent if it was already complete when
+    /// started.
+    Completed,
+    /// The client is shutting down or removing the torrent.
+    Stopped,
+    /// A regular re-announce, sent without an `event` parameter.
+    None,
+}
+
+impl AnnounceEvent {
+    /// The value of the `event` parameter, if one is sent.
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped => Some("stopped"),
+            AnnounceEvent::None => None,
+        }
+    }
+
+    /// The BEP 15 event code.
+    pub fn udp_code(&self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+/// Peers asked for per announce unless overridden.
+pub const DEFAULT_NUMWANT: u32 = 50;
+
+/// Announce parameters that shape the peer list a tracker sends back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerListOptions {
+    /// Ask for the BEP 23 compact format.
+    pub compact: bool,
+    /// How many peers to ask for.
+    pub numwant: u32,
+    /// Ask for dictionary-model peers without their ids. Ignored by
+    /// trackers when `compact` is set.
+    pub no_peer_id: bool,
+}
+
+impl Default for PeerListOptions {
+    fn default() -> Self {
+        PeerListOptions {
+            compact: true,
+            numwant: DEFAULT_NUMWANT,
+            no_peer_id: true,
+        }
+    }
+}
+
+/// One HTTP announce. Every parameter is URL-encoded when the request is
+/// turned into a tracker URL with `url`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnnounceRequest {
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    port: u16,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    event: AnnounceEvent,
+    key: Option<String>,
+    tracker_id: Option<String>,
+    peer_list: PeerListOptions,
+}
+
+impl AnnounceRequest {
+    pub fn new(info_hash: InfoHash, peer_id: PeerId, port: u16) -> Self {
+        AnnounceRequest {
+            info_hash,
+            peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: AnnounceEvent::None,
+            key: None,
+            tracker_id: None,
+            peer_list: PeerListOptions::default(),
+        }
+    }
+
+    pub fn uploaded(mut self, uploaded: u64) -> Self {
+        self.uploaded = uploaded;
+        self
+    }
+
+    pub fn downloaded(mut self, downloaded: u64) -> Self {
+        self.downloaded = downloaded;
+        self
+    }
+
+    pub fn left(mut self, left: u64) -> Self {
+        self.left = left;
+        self
+    }
+
+    pub fn event(mut self, event: AnnounceEvent) -> Self {
+        self.event = event;
+        self
+    }
+
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// The `tracker id` the tracker sent last time, if any.
+    pub fn tracker_id(mut self, tracker_id: Option<String>) -> Self {
+        self.tracker_id = tracker_id;
+        self
+    }
+
+    pub fn peer_list(mut self, peer_list: PeerListOptions) -> Self {
+        self.peer_list = peer_list;
+        self
+    }
+
+    /// The announce URL for `tracker`. A query already in the tracker URL,
+    /// such as a passkey, is kept. A `stopped` announce asks for no peers.
+    pub fn url(&self, tracker: &str) -> Result<Url, url::ParseError> {
+        let mut url = Url::parse(tracker)?;
+
+        // `query_pairs_mut` only takes UTF-8 strings, so the binary values
+        // are percent-encoded byte for byte and written first.
+        let binary = format!(
+            "info_hash={}&peer_id={}",
+            self.info_hash.url_encoded(),
+            self.peer_id.url_encoded()
+        );
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!("{}&{}", existing, binary),
+            _ => binary,
+        };
+        url.set_query(Some(&query));
+
+        let numwant = if self.event == AnnounceEvent::Stopped {
+            0
+        } else {
+            self.peer_list.numwant
+        };
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs
+                .append_pair("port", &self.port.to_string())
+                .append_pair("uploaded", &self.uploaded.to_string())
+                .append_pair("downloaded", &self.downloaded.to_string())
+                .append_pair("left", &self.left.to_string())
+                .append_pair("compact", if self.peer_list.compact { "1" } else { "0" })
+                .append_pair("numwant", &numwant.to_string())
+                .append_pair(
+                    "no_peer_id",
+                    if self.peer_list.no_peer_id { "1" } else { "0" },
+                );
+            if let Some(key) = &self.key {
+                pairs.append_pair("key", key);
+            }
+            if let Some(tracker_id) = &self.tracker_id {
+                pairs.append_pair("trackerid", tracker_id);
+            }
+            if let Some(event) = self.event.as_str() {
+                pairs.append_pair("event", event);
+            }
+        }
+
+        Ok(url)
+    }
+
+    /// The body of a BEP 15 UDP announce, after the connection id, action
+    /// and transaction id. The peer list options have no UDP equivalent
+    /// beyond `numwant`: UDP peer lists are always compact.
+    pub fn udp_body(&self) -> Vec<u8> {
+        let numwant = if self.event == AnnounceEvent::Stopped {
+            0
+        } else {
+            self.peer_list.numwant
+        };
+        // The session key is sent to HTTP trackers as 8 hex digits.
+        let key = self
+            .key
+            .as_deref()
+            .and_then(|key| u32::from_str_radix(key, 16).ok())
+            .unwrap_or(0);
+
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(self.info_hash.as_bytes());
+        body.extend_from_slice(self.peer_id.as_bytes());
+        body.extend_from_slice(&self.downloaded.to_be_bytes());
+        body.extend_from_slice(&self.left.to_be_bytes());
+        body.extend_from_slice(&self.uploaded.to_be_bytes());
+        body.extend_from_slice(&self.event.udp_code().to_be_bytes());
+        // IP address: 0 lets the tracker use the packet's source address.
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&key.to_be_bytes());
+        body.extend_from_slice(&numwant.to_be_bytes());
+        body.extend_from_slice(&self.port.to_be_bytes());
+        body
+    }
+}
+
+/// Tracks which events a tracker has been told about, so every announce
+/// carries the one it is due.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AnnounceLifecycle {
+    started: bool,
+    completed: bool,
+}
+
+impl AnnounceLifecycle {
+    /// The event for the next announce, given the bytes still to download.
+    pub fn next_event(&mut self, left: u64) -> AnnounceEvent {
+        if !self.started {
+            self.started = true;
+            self.completed = left == 0;
+            return AnnounceEvent::Started;
+        }
+        if left == 0 && !self.completed {
+            self.completed = true;
+            return AnnounceEvent::Completed;
+        }
+        AnnounceEvent::None
+    }
+
+    /// The event to send on shutdown: `Stopped` if the tracker was told we
+    /// started, otherwise nothing.
+    pub fn stop(&mut self) -> Option<AnnounceEvent> {
+        if !self.started {
+            return None;
+        }
+        self.started = false;
+        Some(AnnounceEvent::Stopped)
+    }
+}
+
+/// A successful reply to an HTTP or UDP tracker announce.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub struct AnnounceResponse {
+    /// Seconds to wait before the next regular announce.
+    pub interval: u64,
+    /// Seconds the tracker insists on between announces, if stricter.
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<u64>,
+    /// Number of seeders.
+    #[serde(default)]
+    pub complete: Option<u64>,
+    /// Number of leechers.
+    #[serde(default)]
+    pub incomplete: Option<u64>,
+    /// Opaque id to send back as `trackerid` on later announces.
+    #[serde(rename = "tracker id", default)]
+    pub tracker_id: Option<String>,
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
+    /// Peers from either the dictionary model or the BEP 23 compact one.
+    #[serde(default, deserialize_with = "deserialize_peers")]
+    pub peers: Vec<Peer>,
+    /// Our address as the tracker saw it (BEP 24).
+    #[serde(
+        rename = "external ip",
+        default,
+        deserialize_with = "deserialize_external_ip"
+    )]
+    pub external_ip: Option<IpAddr>,
+    /// BEP 7 compact IPv6 peers, merged into `peers` by `from_bytes`.
+    #[serde(default)]
+    peers6: ByteBuf,
+}
+
+/// What one tracker last told us about a torrent, kept with its resume
+/// data so "why am I getting no peers" can be answered after the fact.
+/// Times are seconds since the Unix epoch.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TrackerStats {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_failure: Option<u64>,
+    /// Why the last failed announce failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    /// Peers in the last successful response.
+    #[serde(default)]
+    pub peers: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seeders: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leechers: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_announce: Option<u64>,
+    /// Our address as the tracker last reported it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_ip: Option<IpAddr>,
+}
+
+impl TrackerStats {
+    pub fn record_success(&mut self, at: u64, response: &AnnounceResponse) {
+        self.last_success = Some(at);
+        self.consecutive_failures = 0;
+        self.peers = response.peers.len() as u64;
+        self.seeders = response.complete;
+        self.leechers = response.incomplete;
+        if response.external_ip.is_some() {
+            self.external_ip = response.external_ip;
+        }
+    }
+
+    pub fn record_failure(&mut self, at: u64, error: impl Into<String>) {
+        self.last_failure = Some(at);
+        self.last_error = Some(error.into());
+        self.consecutive_failures += 1;
+    }
+}
+
+/// One entry of a dictionary-model peer list.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Peer {
+    #[serde(rename = "peer id", default)]
+    pub peer_id: Option<ByteBuf>,
+    /// IPv4 or IPv6 address, or a DNS name.
+    pub ip: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnounceError {
+    /// The body is not a valid announce response.
+    Bencode(BencodeError),
+    /// The tracker refused the announce and said why.
+    Failure(String),
+}
+
+impl fmt::Display for AnnounceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnnounceError::Bencode(error) => write!(f, "malformed announce response: {}", error),
+            AnnounceError::Failure(reason) => write!(f, "tracker failure: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for AnnounceError {}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(ByteBuf),
+    Dictionaries(Vec<Peer>),
+}
+
+fn deserialize_peers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Peer>, D::Error> {
+    Ok(match PeerList::deserialize(deserializer)? {
+        PeerList::Compact(bytes) => compact_peers(&bytes).into_iter().map(Peer::from).collect(),
+        PeerList::Dictionaries(peers) => peers,
+    })
+}
+
+/// BEP 24 sends the address as 4 or 16 raw bytes. Any other length is
+/// ignored rather than failing the whole announce.
+fn deserialize_external_ip<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<IpAddr>, D::Error> {
+    let bytes = ByteBuf::deserialize(deserializer)?;
+    let bytes = bytes.as_slice();
+    Ok(if let Ok(octets) = <[u8; 4]>::try_from(bytes) {
+        Some(IpAddr::from(octets))
+    } else if let Ok(octets) = <[u8; 16]>::try_from(bytes) {
+        Some(IpAddr::from(octets))
+    } else {
+        None
+    })
+}
+
+/// Decodes a BEP 23 compact peer list: 4 address bytes and 2 port bytes per
+/// peer, both in network order. A trailing partial entry is ignored.
+pub fn compact_peers(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(6)
+        .map(|entry| {
+            let ip = Ipv4Addr::new(entry[0], entry[1], entry[2], entry[3]);
+            let port = u16::from_be_bytes([entry[4], entry[5]]);
+            SocketAddr::V4(SocketAddrV4::new(ip, port))
+        })
+        .collect()
+}
+
+/// Decodes a BEP 7 compact IPv6 peer list: 16 address bytes and 2 port
+/// bytes per peer.
+pub fn compact_peers6(bytes: &[u8]) -> Vec<SocketAddr> {
+    bytes
+        .chunks_exact(18)
+        .map(|entry| {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&entry[..16]);
+            let port = u16::from_be_bytes([entry[16], entry[17]]);
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+        })
+        .collect()
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer {
+            peer_id: None,
+            ip: addr.ip().to_string(),
+            port: addr.port(),
+        }
+    }
+}
+
+/// What a tracker reports about one torrent in a scrape.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ScrapeStats {
+    /// Seeders.
+    #[serde(default)]
+    pub complete: u64,
+    /// Times a download has completed.
+    #[serde(default)]
+    pub downloaded: u64,
+    /// Leechers.
+    #[serde(default)]
+    pub incomplete: u64,
+}
+
+#[derive(Deserialize)]
+struct ScrapeResponse {
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+/// The scrape URL for an announce URL, by the convention trackers follow:
+/// the last path segment must start with `announce`, which is replaced by
+/// `scrape`. Otherwise the tracker doesn't support scraping. Each of
+/// `info_hashes` is sent as its own `info_hash` parameter, so one request
+/// covers them all.
+pub fn scrape_url(tracker: &Url, info_hashes: &[InfoHash]) -> Option<Url> {
+    let mut url = tracker.clone();
+    let path = url.path().to_string();
+    let (directory, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    url.set_path(&format!("{}/scrape{}", directory, rest));
+
+    let hashes = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={}", info_hash.url_encoded()))
+        .collect::<Vec<_>>()
+        .join("&");
+    let query = match url.query() {
+        Some(existing) if !existing.is_empty() => format!("{}&{}", existing, hashes),
+        _ => hashes,
+    };
+    url.set_query(Some(&query));
+    Some(url)
+}
+
+/// Decodes a scrape response body into the stats of every torrent in it.
+/// Entries whose key is not a 20-byte infohash are skipped.
+pub fn parse_scrape_response(
+    body: &[u8],
+) -> Result<BTreeMap<InfoHash, ScrapeStats>, AnnounceError> {
+    if let Ok(failure) = bencode::decode::<FailureResponse>(body) {
+        return Err(AnnounceError::Failure(failure.failure_reason));
+    }
+    let response: ScrapeResponse = bencode::decode(body).map_err(AnnounceError::Bencode)?;
+    Ok(response
+        .files
+        .into_iter()
+        .filter_map(|(key, stats)| {
+            let bytes = <[u8; 20]>::try_from(key.as_slice()).ok()?;
+            Some((InfoHash::from(bytes), stats))
+        })
+        .collect())
+}
+
+/// Only used to spot a `failure reason` before decoding the full response,
+/// as failures carry none of the other keys.
+#[derive(Deserialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+impl AnnounceResponse {
+    /// A response built from fields decoded elsewhere, e.g. a UDP reply.
+    pub fn new(interval: u64, peers: Vec<Peer>) -> Self {
+        AnnounceResponse {
+            interval,
+            peers,
+            ..AnnounceResponse::default()
+        }
+    }
+
+    /// Decodes an announce response body.
+    pub fn from_bytes(body: &[u8]) -> Result<Self, AnnounceError> {
+        if let Ok(failure) = bencode::decode::<FailureResponse>(body) {
+            return Err(AnnounceError::Failure(failure.failure_reason));
+        }
+        let mut response: AnnounceResponse =
+            bencode::decode(body).map_err(AnnounceError::Bencode)?;
+        let peers6 = std::mem::take(&mut response.peers6);
+        response
+            .peers
+            .extend(compact_peers6(&peers6).into_iter().map(Peer::from));
+        Ok(response)
+    }
+
+    /// Addresses of every peer given as an IP, skipping those given by
+    /// host name.
+    pub fn peer_addrs(&self) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter_map(|peer| {
+                let ip: IpAddr = peer.ip.parse().ok()?;
+                Some(SocketAddr::new(ip, peer.port))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(body: &[u8]) -> Vec<SocketAddr> {
+        AnnounceResponse::from_bytes(body).unwrap().peer_addrs()
+    }
+
+    #[test]
+    fn reads_compact_and_dictionary_peer_lists() {
+        let compact = addrs(
+            b"d8:intervali1800e5:peers13:\x0a\x00\x00\x01\x1a\xe1\xc0\xa8\x00\x02\x00\x50\xffe",
+        );
+        assert_eq!(
+            compact,
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "192.168.0.2:80".parse().unwrap()
+            ]
+        );
+
+        let response = AnnounceResponse::from_bytes(
+            b"d8:intervali1800e5:peersld2:ip8:10.0.0.17:peer id20:-CT0001-0123456789ab4:porti6881eed2:ip11:example.org4:porti80eeee",
+        )
+        .unwrap();
+        assert_eq!(response.peers.len(), 2);
+        assert_eq!(
+            response.peers[0].peer_id.as_ref().map(|id| id.as_slice()),
+            Some(&b"-CT0001-0123456789ab"[..])
+        );
+        // The host name can't be used without a lookup.
+        assert_eq!(response.peer_addrs(), ["10.0.0.1:6881".parse().unwrap()]);
+
+        assert_eq!(compact_peers(&[10, 0, 0, 1, 0x1a]), []);
+        assert_eq!(
+            AnnounceResponse::from_bytes(b"d14:failure reason7:go awaye"),
+            Err(AnnounceError::Failure("go away".to_string()))
+        );
+    }
+
+    #[test]
+    fn merges_compact_ipv6_peers_into_the_peer_list() {
+        let mut body = b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe16:peers640:".to_vec();
+        body.extend(Ipv6Addr::LOCALHOST.octets());
+        body.extend([0xc8, 0xd5]);
+        body.extend("2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend([0x1a, 0xe1]);
+        // A trailing partial entry.
+        body.extend([1, 2, 3, 4]);
+        body.push(b'e');
+        assert_eq!(
+            addrs(&body),
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:51413".parse().unwrap(),
+                "[2001:db8::1]:6881".parse().unwrap(),
+            ]
+        );
+        assert_eq!(compact_peers6(&[0; 17]), []);
+    }
+
+    #[test]
+    fn keeps_the_passkey_and_encodes_binary_parameters() {
+        let info_hash = InfoHash::from([
+            0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf1, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+            0xef, 0x12, 0x34, 0x56, 0x78, 0x9a,
+        ]);
+        let request = AnnounceRequest::new(info_hash, PeerId(*b"-CT0100-ab~c d.e_f/1"), 6881)
+            .left(1024)
+            .key("0a1b2c3d")
+            .event(AnnounceEvent::Started);
+        assert_eq!(
+            request
+                .url("https://tracker.example/announce?passkey=s3cr%2Ft")
+                .unwrap()
+                .as_str(),
+            "https://tracker.example/announce?passkey=s3cr%2Ft\
+             &info_hash=%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A\
+             &peer_id=-CT0100-ab~c%20d.e_f%2F1\
+             &port=6881&uploaded=0&downloaded=0&left=1024&compact=1&numwant=50&no_peer_id=1\
+             &key=0a1b2c3d&event=started"
+        );
+    }
+}
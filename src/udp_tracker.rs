@@ -0,0 +1,432 @@
+use crate::info_hash::InfoHash;
+use crate::tracker::{
+    compact_peers, compact_peers6, AnnounceRequest, AnnounceResponse, Peer, ScrapeStats,
+};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::{lookup_host, UdpSocket};
+use url::Url;
+
+/// The magic constant every BEP 15 connect request starts with.
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a tracker honours a connection id after handing it out.
+pub const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Retransmissions before giving up, per BEP 15: the last one waits
+/// 15 * 2^8 seconds, about an hour.
+pub const MAX_RETRANSMITS: u32 = 8;
+
+/// Infohashes per scrape packet: as many as fit in a reply that stays
+/// under the common 1500-byte Ethernet MTU.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Large enough for an announce reply with a few hundred peers.
+const MAX_PACKET_SIZE: usize = 8192;
+
+/// How long to wait for a reply to the `attempt`th transmission of a
+/// request, counting from 0: 15 * 2^attempt seconds.
+pub fn retransmit_timeout(attempt: u32) -> Duration {
+    Duration::from_secs(15 << attempt.min(MAX_RETRANSMITS))
+}
+
+#[derive(Debug)]
+pub enum UdpTrackerError {
+    /// The tracker URL has no host or port, or the host didn't resolve.
+    Address(String),
+    Io(io::Error),
+    /// No reply after every retransmission.
+    TimedOut,
+    /// The reply is too short or carries an unexpected action.
+    Malformed,
+    /// The tracker refused the request and said why.
+    Failure(String),
+}
+
+impl fmt::Display for UdpTrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UdpTrackerError::Address(tracker) => write!(f, "cannot reach {}", tracker),
+            UdpTrackerError::Io(error) => write!(f, "{}", error),
+            UdpTrackerError::TimedOut => write!(f, "UDP tracker did not answer"),
+            UdpTrackerError::Malformed => write!(f, "malformed UDP tracker reply"),
+            UdpTrackerError::Failure(reason) => write!(f, "tracker failure: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for UdpTrackerError {}
+
+impl From<io::Error> for UdpTrackerError {
+    fn from(error: io::Error) -> Self {
+        UdpTrackerError::Io(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    id: u64,
+    obtained: Instant,
+}
+
+/// A BEP 15 UDP tracker client. Connection ids are cached per tracker
+/// address for `CONNECTION_ID_LIFETIME`, so announces to the same tracker
+/// within a minute skip the connect round trip. Proxies and DNS-over-HTTPS
+/// don't apply to UDP.
+#[derive(Debug)]
+pub struct UdpTracker {
+    bind_address: Option<IpAddr>,
+    max_retransmits: u32,
+    connections: Mutex<HashMap<SocketAddr, Connection>>,
+}
+
+impl UdpTracker {
+    pub fn new(bind_address: Option<IpAddr>) -> Self {
+        UdpTracker {
+            bind_address,
+            max_retransmits: MAX_RETRANSMITS,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gives up after `max_retransmits` retransmissions instead of
+    /// `MAX_RETRANSMITS`, for callers that can't wait an hour.
+    pub fn max_retransmits(mut self, max_retransmits: u32) -> Self {
+        self.max_retransmits = max_retransmits.min(MAX_RETRANSMITS);
+        self
+    }
+
+    pub async fn announce(
+        &self,
+        tracker: &Url,
+        request: &AnnounceRequest,
+    ) -> Result<AnnounceResponse, UdpTrackerError> {
+        let (socket, address) = self.open(tracker).await?;
+        let reply = self
+            .request(&socket, address, ACTION_ANNOUNCE, &request.udp_body())
+            .await?;
+
+        // interval, leechers and seeders, then the peers, which are IPv6
+        // when we reached the tracker over IPv6.
+        if reply.len() < 12 {
+            return Err(UdpTrackerError::Malformed);
+        }
+        let interval = read_u32(&reply, 0);
+        let leechers = read_u32(&reply, 4);
+        let seeders = read_u32(&reply, 8);
+        let peers = if address.is_ipv6() {
+            compact_peers6(&reply[12..])
+        } else {
+            compact_peers(&reply[12..])
+        };
+
+        let mut response = AnnounceResponse::new(
+            u64::from(interval),
+            peers.into_iter().map(Peer::from).collect(),
+        );
+        response.complete = Some(u64::from(seeders));
+        response.incomplete = Some(u64::from(leechers));
+        Ok(response)
+    }
+
+    /// Scrapes `info_hashes`, `MAX_SCRAPE_HASHES` per packet, sharing the
+    /// connection id with announces to the same tracker.
+    pub async fn scrape(
+        &self,
+        tracker: &Url,
+        info_hashes: &[InfoHash],
+    ) -> Result<BTreeMap<InfoHash, ScrapeStats>, UdpTrackerError> {
+        let (socket, address) = self.open(tracker).await?;
+        let mut scraped = BTreeMap::new();
+
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let body: Vec<u8> = chunk.iter().flat_map(|hash| *hash.as_bytes()).collect();
+            let reply = self.request(&socket, address, ACTION_SCRAPE, &body).await?;
+
+            // Seeders, completed and leechers for each hash, in order.
+            if reply.len() < chunk.len() * 12 {
+                return Err(UdpTrackerError::Malformed);
+            }
+            for (index, info_hash) in chunk.iter().enumerate() {
+                let at = index * 12;
+                scraped.insert(
+                    *info_hash,
+                    ScrapeStats {
+                        complete: u64::from(read_u32(&reply, at)),
+                        downloaded: u64::from(read_u32(&reply, at + 4)),
+                        incomplete: u64::from(read_u32(&reply, at + 8)),
+                    },
+                );
+            }
+        }
+
+        Ok(scraped)
+    }
+
+    /// Resolves the tracker and opens a socket of the same family.
+    async fn open(&self, tracker: &Url) -> Result<(UdpSocket, SocketAddr), UdpTrackerError> {
+        let unreachable = || UdpTrackerError::Address(tracker.to_string());
+        let host = tracker.host_str().ok_or_else(unreachable)?;
+        let port = tracker.port().ok_or_else(unreachable)?;
+        // IPv6 literals come bracketed in URLs.
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        let address = lookup_host((host, port))
+            .await?
+            .find(|address| {
+                self.bind_address
+                    .is_none_or(|bind| bind.is_ipv4() == address.is_ipv4())
+            })
+            .ok_or_else(unreachable)?;
+        let local = self.bind_address.unwrap_or(if address.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        });
+        let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+        Ok((socket, address))
+    }
+
+    /// Sends one request and returns the reply after its action and
+    /// transaction id. The `attempt`th transmission waits
+    /// `retransmit_timeout(attempt)`, reconnecting first whenever the
+    /// cached connection id has expired.
+    async fn request(
+        &self,
+        socket: &UdpSocket,
+        address: SocketAddr,
+        action: u32,
+        body: &[u8],
+    ) -> Result<Vec<u8>, UdpTrackerError> {
+        for attempt in 0..=self.max_retransmits {
+            let timeout = retransmit_timeout(attempt);
+            let connection_id = match self.cached_connection(address) {
+                Some(id) => id,
+                None => match self.connect(socket, address, timeout).await? {
+                    Some(id) => id,
+                    None => continue,
+                },
+            };
+
+            let transaction_id = transaction_id();
+            let mut packet = Vec::with_capacity(16 + body.len());
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&action.to_be_bytes());
+            packet.extend_from_slice(&transaction_id.to_be_bytes());
+            packet.extend_from_slice(body);
+            socket.send_to(&packet, address).await?;
+
+            if let Some(reply) = receive(socket, address, action, transaction_id, timeout).await? {
+                return Ok(reply);
+            }
+        }
+        Err(UdpTrackerError::TimedOut)
+    }
+
+    /// Obtains and caches a fresh connection id, or `None` if the tracker
+    /// didn't answer within `timeout`.
+    async fn connect(
+        &self,
+        socket: &UdpSocket,
+        address: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Option<u64>, UdpTrackerError> {
+        let transaction_id = transaction_id();
+        let mut packet = Vec::with_capacity(16);
+        packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        socket.send_to(&packet, address).await?;
+
+        let Some(reply) = receive(socket, address, ACTION_CONNECT, transaction_id, timeout).await?
+        else {
+            return Ok(None);
+        };
+        if reply.len() < 8 {
+            return Err(UdpTrackerError::Malformed);
+        }
+        let id = u64::from(read_u32(&reply, 0)) << 32 | u64::from(read_u32(&reply, 4));
+
+        self.connections.lock().unwrap().insert(
+            address,
+            Connection {
+                id,
+                obtained: Instant::now(),
+            },
+        );
+        Ok(Some(id))
+    }
+
+    fn cached_connection(&self, address: SocketAddr) -> Option<u64> {
+        let mut connections = self.connections.lock().unwrap();
+        match connections.get(&address) {
+            Some(connection) if connection.obtained.elapsed() < CONNECTION_ID_LIFETIME => {
+                Some(connection.id)
+            }
+            Some(_) => {
+                connections.remove(&address);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Waits up to `timeout` for the reply to `transaction_id` from `address`
+/// and returns it after the header. Stray packets, such as late replies to
+/// an earlier transmission, are skipped.
+async fn receive(
+    socket: &UdpSocket,
+    address: SocketAddr,
+    action: u32,
+    transaction_id: u32,
+    timeout: Duration,
+) -> Result<Option<Vec<u8>>, UdpTrackerError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut buffer = vec![0; MAX_PACKET_SIZE];
+
+    loop {
+        let received = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await;
+        let Ok(received) = received else {
+            return Ok(None);
+        };
+        let (length, from) = received?;
+        let reply = &buffer[..length];
+        if from != address || reply.len() < 8 || read_u32(reply, 4) != transaction_id {
+            continue;
+        }
+
+        let reply_action = read_u32(reply, 0);
+        let rest = reply[8..].to_vec();
+        return match reply_action {
+            ACTION_ERROR => Err(UdpTrackerError::Failure(
+                String::from_utf8_lossy(&rest).into_owned(),
+            )),
+            reply_action if reply_action == action => Ok(Some(rest)),
+            _ => Err(UdpTrackerError::Malformed),
+        };
+    }
+}
+
+fn transaction_id() -> u32 {
+    RandomState::new().hash_one(SystemTime::now()) as u32
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_id::PeerId;
+    use crate::tracker::AnnounceEvent;
+
+    /// Receives the next packet on the fake tracker and returns it with
+    /// its transaction id.
+    async fn next_packet(tracker: &UdpSocket) -> (Vec<u8>, SocketAddr, u32) {
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        let (length, from) = tracker.recv_from(&mut buffer).await.unwrap();
+        buffer.truncate(length);
+        let transaction_id = read_u32(&buffer, 12);
+        (buffer, from, transaction_id)
+    }
+
+    async fn reply(tracker: &UdpSocket, to: SocketAddr, action: u32, id: u32, body: &[u8]) {
+        let packet = [&action.to_be_bytes()[..], &id.to_be_bytes(), body].concat();
+        tracker.send_to(&packet, to).await.unwrap();
+    }
+
+    #[test]
+    fn speaks_the_bep_15_packet_layouts() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let url = Url::parse(&format!("udp://{}", tracker.local_addr().unwrap())).unwrap();
+            let client = UdpTracker::new(None).max_retransmits(0);
+            let request = AnnounceRequest::new(InfoHash([7; 20]), PeerId([9; 20]), 6881)
+                .downloaded(1)
+                .left(2)
+                .uploaded(3)
+                .key("0a1b2c3d")
+                .event(AnnounceEvent::Started);
+
+            let serving = async {
+                let (connect, from, id) = next_packet(&tracker).await;
+                assert_eq!(connect.len(), 16);
+                assert_eq!(connect[..8], PROTOCOL_ID.to_be_bytes());
+                assert_eq!(read_u32(&connect, 8), ACTION_CONNECT);
+                // A stray reply to some other transaction is skipped.
+                reply(&tracker, from, ACTION_CONNECT, id ^ 1, &[0xff; 8]).await;
+                reply(
+                    &tracker,
+                    from,
+                    ACTION_CONNECT,
+                    id,
+                    &[1, 2, 3, 4, 5, 6, 7, 8],
+                )
+                .await;
+
+                let (announce, from, id) = next_packet(&tracker).await;
+                let expected = [
+                    &[1, 2, 3, 4, 5, 6, 7, 8][..],
+                    &ACTION_ANNOUNCE.to_be_bytes(),
+                    &id.to_be_bytes(),
+                    &[7; 20],
+                    &[9; 20],
+                    &1u64.to_be_bytes(),
+                    &2u64.to_be_bytes(),
+                    &3u64.to_be_bytes(),
+                    &2u32.to_be_bytes(),
+                    &[0; 4],
+                    &[0x0a, 0x1b, 0x2c, 0x3d],
+                    &50u32.to_be_bytes(),
+                    &6881u16.to_be_bytes(),
+                ]
+                .concat();
+                assert_eq!(announce, expected);
+                let mut body = [1800u32, 3, 5].map(u32::to_be_bytes).concat();
+                body.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+                reply(&tracker, from, ACTION_ANNOUNCE, id, &body).await;
+
+                // The cached connection id is reused, so no connect.
+                let (announce, from, id) = next_packet(&tracker).await;
+                assert_eq!(announce[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+                reply(&tracker, from, ACTION_ERROR, id, b"unregistered torrent").await;
+
+                let (_, from, id) = next_packet(&tracker).await;
+                reply(&tracker, from, ACTION_SCRAPE, id, &[]).await;
+            };
+            let announcing = async {
+                let response = client.announce(&url, &request).await.unwrap();
+                assert_eq!(response.interval, 1800);
+                assert_eq!((response.incomplete, response.complete), (Some(3), Some(5)));
+                assert_eq!(response.peer_addrs(), ["10.0.0.1:6881".parse().unwrap()]);
+
+                assert!(matches!(
+                    client.announce(&url, &request).await,
+                    Err(UdpTrackerError::Failure(reason)) if reason == "unregistered torrent"
+                ));
+                assert!(matches!(
+                    client.announce(&url, &request).await,
+                    Err(UdpTrackerError::Malformed)
+                ));
+            };
+            futures_util::future::join(serving, announcing).await;
+        });
+    }
+}
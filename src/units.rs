@@ -0,0 +1,146 @@
+use std::fmt;
+use std::time::Duration;
+
+/// A value with a missing or unknown unit, or an unparseable number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseUnitError {
+    value: String,
+    expected: &'static str,
+}
+
+impl fmt::Display for ParseUnitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\" is not {}", self.value, self.expected)
+    }
+}
+
+impl std::error::Error for ParseUnitError {}
+
+const SIZE_UNITS: &[(&str, f64)] = &[
+    ("", 1.0),
+    ("b", 1.0),
+    ("k", 1e3),
+    ("kb", 1e3),
+    ("kib", 1024.0),
+    ("m", 1e6),
+    ("mb", 1e6),
+    ("mib", 1024.0 * 1024.0),
+    ("g", 1e9),
+    ("gb", 1e9),
+    ("gib", 1024.0 * 1024.0 * 1024.0),
+    ("t", 1e12),
+    ("tb", 1e12),
+    ("tib", 1024.0 * 1024.0 * 1024.0 * 1024.0),
+];
+
+const DURATION_UNITS: &[(&str, f64)] = &[
+    ("ms", 0.001),
+    ("s", 1.0),
+    ("m", 60.0),
+    ("min", 60.0),
+    ("h", 3600.0),
+    ("d", 86400.0),
+];
+
+/// Parses a byte count such as `512`, `16KiB`, `1.5 GB` or `2m`. Units are
+/// case-insensitive; `k`, `m`, `g` and `t` alone are decimal.
+pub fn parse_size(value: &str) -> Result<u64, ParseUnitError> {
+    parse_with_units(value, SIZE_UNITS)
+        .filter(|bytes| bytes.round() <= u64::MAX as f64)
+        .map(|bytes| bytes.round() as u64)
+        .ok_or_else(|| ParseUnitError {
+            value: value.to_string(),
+            expected: "a size like 512, 16KiB or 1.5GB",
+        })
+}
+
+/// Parses a transfer rate in bytes per second such as `2MiB/s` or `500k`.
+/// The `/s` suffix is optional.
+pub fn parse_rate(value: &str) -> Result<u64, ParseUnitError> {
+    let trimmed = value.trim();
+    let size = trimmed
+        .strip_suffix("/s")
+        .or_else(|| trimmed.strip_suffix("/S"))
+        .unwrap_or(trimmed);
+    parse_size(size).map_err(|_| ParseUnitError {
+        value: value.to_string(),
+        expected: "a rate like 500k or 2MiB/s",
+    })
+}
+
+/// Parses a duration such as `30s`, `90m`, `1.5h` or `2d`. A bare number
+/// is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, ParseUnitError> {
+    let error = || ParseUnitError {
+        value: value.to_string(),
+        expected: "a duration like 30s, 90m or 1.5h",
+    };
+    let trimmed = value.trim();
+    let seconds = if trimmed.bytes().all(|b| b.is_ascii_digit() || b == b'.') {
+        trimmed.parse::<f64>().ok()
+    } else {
+        parse_with_units(trimmed, DURATION_UNITS)
+    };
+    // Too many seconds for a `Duration` is as wrong as a bad unit.
+    seconds
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(error)
+}
+
+/// Splits `value` into a non-negative number and a unit from `units`,
+/// returning the number scaled by the unit's factor.
+fn parse_with_units(value: &str, units: &[(&str, f64)]) -> Option<f64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let number: f64 = number.parse().ok()?;
+    let unit = unit.trim().to_ascii_lowercase();
+    let (_, factor) = units.iter().find(|(name, _)| *name == unit)?;
+
+    Some(number * factor).filter(|scaled| scaled.is_finite())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes_and_rates_with_units() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("16KiB"), Ok(16 * 1024));
+        assert_eq!(parse_size("1.5 GB"), Ok(1_500_000_000));
+        assert_eq!(parse_size("2m"), Ok(2_000_000));
+        assert_eq!(parse_size("0.5kib"), Ok(512));
+        assert_eq!(parse_rate("2MiB/s"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_rate("500k"), Ok(500_000));
+        for garbage in ["", "KiB", "12 parsecs", "-1", "1.2.3"] {
+            assert!(parse_size(garbage).is_err(), "{}", garbage);
+        }
+        assert!(parse_size("99999999999TiB").is_err());
+        assert_eq!(
+            parse_rate("fast").unwrap_err().to_string(),
+            "\"fast\" is not a rate like 500k or 2MiB/s"
+        );
+    }
+
+    #[test]
+    fn parses_durations_with_units() {
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("2min"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration(" 2d "), Ok(Duration::from_secs(2 * 86400)));
+        assert_eq!(parse_duration("0.5"), Ok(Duration::from_millis(500)));
+        for garbage in ["", "s", "5 fortnights", "-5s", "1..5h"] {
+            assert!(parse_duration(garbage).is_err(), "{}", garbage);
+        }
+        // More seconds than a `Duration` holds.
+        assert!(parse_duration("99999999999999999999999").is_err());
+        assert!(parse_duration("99999999999999999d").is_err());
+    }
+}
@@ -0,0 +1,177 @@
+use crate::torrent::Torrent;
+use std::fmt;
+use url::Url;
+
+const MIN_PIECE_LENGTH: i64 = 16 * 1024;
+const MAX_PIECE_LENGTH: i64 = 128 * 1024 * 1024;
+
+/// A problem found in a torrent's metainfo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// Piece length is not a power of two between 16 KiB and 128 MiB.
+    PieceLength(i64),
+    /// `pieces` is not made of whole 20-byte hashes.
+    PiecesLength(usize),
+    /// The number of hashes doesn't match the number of pieces the content needs.
+    PieceCount {
+        expected: u64,
+        actual: usize,
+    },
+    NegativeFileLength {
+        file_index: usize,
+        length: i64,
+    },
+    InvalidTrackerUrl {
+        url: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::PieceLength(length) => write!(
+                f,
+                "piece length {} is not a power of two between {} and {}",
+                length, MIN_PIECE_LENGTH, MAX_PIECE_LENGTH
+            ),
+            Violation::PiecesLength(length) => {
+                write!(f, "pieces is {} bytes, not a multiple of 20", length)
+            }
+            Violation::PieceCount { expected, actual } => write!(
+                f,
+                "content needs {} pieces but {} hashes are present",
+                expected, actual
+            ),
+            Violation::NegativeFileLength { file_index, length } => {
+                write!(f, "file {} has negative length {}", file_index, length)
+            }
+            Violation::InvalidTrackerUrl { url, reason } => {
+                write!(f, "tracker \"{}\" is not a valid URL: {}", url, reason)
+            }
+        }
+    }
+}
+
+impl Torrent {
+    /// Checks the metainfo for structural problems, returning every
+    /// violation found. An empty list means the torrent is well formed.
+    pub fn validate(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        let piece_length = self.info.piece_length;
+        let piece_length_valid = (MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length)
+            && (piece_length as u64).is_power_of_two();
+        if !piece_length_valid {
+            violations.push(Violation::PieceLength(piece_length));
+        }
+
+        let pieces_length = self.info.pieces.len();
+        if !pieces_length.is_multiple_of(20) {
+            violations.push(Violation::PiecesLength(pieces_length));
+        }
+
+        let lengths = match self.info.length {
+            Some(length) if self.info.files.is_empty() => vec![length],
+            _ => self.info.files.iter().map(|file| file.length).collect(),
+        };
+        let mut lengths_valid = true;
+        for (file_index, &length) in lengths.iter().enumerate() {
+            if length < 0 {
+                lengths_valid = false;
+                violations.push(Violation::NegativeFileLength { file_index, length });
+            }
+        }
+
+        let total = lengths
+            .iter()
+            .try_fold(0u64, |total, length| total.checked_add(*length as u64));
+        if let (true, true, Some(total)) = (
+            piece_length > 0 && lengths_valid,
+            pieces_length.is_multiple_of(20),
+            total,
+        ) {
+            let expected = total.div_ceil(piece_length as u64);
+            let actual = pieces_length / 20;
+            if expected != actual as u64 {
+                violations.push(Violation::PieceCount { expected, actual });
+            }
+        }
+
+        for url in self.tracker_tiers().into_iter().flatten() {
+            if let Err(error) = Url::parse(&url) {
+                violations.push(Violation::InvalidTrackerUrl {
+                    url,
+                    reason: error.to_string(),
+                });
+            }
+        }
+
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Files of `lengths` bytes in `piece_length` pieces with `hashes`
+    /// piece hashes, announcing to `announce`.
+    fn metainfo(piece_length: i64, lengths: &[i64], hashes: usize, announce: &str) -> Torrent {
+        let mut torrent = format!("d8:announce{}:{}4:infod5:filesl", announce.len(), announce);
+        for (index, length) in lengths.iter().enumerate() {
+            torrent.push_str(&format!("d6:lengthi{}e4:pathl1:{}ee", length, index));
+        }
+        torrent.push_str(&format!(
+            "e4:name3:dir12:piece lengthi{}e6:pieces{}:{}ee",
+            piece_length,
+            hashes,
+            "x".repeat(hashes)
+        ));
+        Torrent::new(torrent.into_bytes()).unwrap()
+    }
+
+    #[test]
+    fn finds_nothing_wrong_with_a_well_formed_torrent() {
+        let torrent = metainfo(16384, &[20000, 30000], 4 * 20, "http://t.invalid/announce");
+        assert_eq!(torrent.validate(), []);
+    }
+
+    #[test]
+    fn reports_each_violation() {
+        let tracker = "http://t.invalid/announce";
+        assert_eq!(
+            metainfo(1000, &[500], 20, tracker).validate(),
+            [Violation::PieceLength(1000)]
+        );
+        assert_eq!(
+            metainfo(16384, &[500], 30, tracker).validate(),
+            [Violation::PiecesLength(30)]
+        );
+        assert_eq!(
+            metainfo(16384, &[20000, 30000], 3 * 20, tracker).validate(),
+            [Violation::PieceCount {
+                expected: 4,
+                actual: 3
+            }]
+        );
+        assert_eq!(
+            metainfo(16384, &[500], 20, "not a url").validate(),
+            [Violation::InvalidTrackerUrl {
+                url: "not a url".to_string(),
+                reason: "relative URL without a base".to_string()
+            }]
+        );
+
+        // Reading refuses negative lengths, but the fields are public.
+        let mut torrent = metainfo(16384, &[500, 500], 20, tracker);
+        torrent.info.files[1].length = -5;
+        assert_eq!(
+            torrent.validate(),
+            [Violation::NegativeFileLength {
+                file_index: 1,
+                length: -5
+            }]
+        );
+    }
+}
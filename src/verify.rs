@@ -0,0 +1,225 @@
+use crate::sanitize::{RootFolder, SanitizeMode};
+use crate::throttle::Throttle;
+use crate::torrent::{hex, Torrent};
+use anyhow::Result;
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Result of checking one file against its `md5sum`/`sha1` fields.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileCheck {
+    Match,
+    Mismatch,
+    Missing,
+    /// The torrent carries no checksum for this file.
+    NoChecksum,
+}
+
+/// Hashes the torrent's data under `download_dir` and reports which pieces
+/// match. Missing or short files simply fail their pieces, and padding
+/// files are read as the zeros they stand for. When
+/// `max_read_rate` is set, disk reads are limited to that many bytes per
+/// second so a recheck does not starve other users of the disk.
+pub fn recheck(
+    torrent: &Torrent,
+    download_dir: &Path,
+    root_folder: RootFolder,
+    max_read_rate: Option<u64>,
+) -> Result<Vec<bool>> {
+    let paths = torrent.sanitized_paths(SanitizeMode::Reject, root_folder)?;
+    let mut throttle = max_read_rate.map(Throttle::new);
+    let mut open_file: Option<(usize, File)> = None;
+    let mut verified = Vec::with_capacity(torrent.piece_count());
+    let mut buffer = Vec::new();
+
+    for piece in torrent.pieces() {
+        buffer.clear();
+        buffer.resize(piece.length as usize, 0);
+
+        let mut position = 0;
+        let mut complete = true;
+        for segment in torrent.piece_segments(piece.index) {
+            let end = position + segment.length as usize;
+            let Some(path) = &paths[segment.file_index] else {
+                buffer[position..end].fill(0);
+                position = end;
+                continue;
+            };
+
+            let reused = matches!(&open_file, Some((index, _)) if *index == segment.file_index);
+            if !reused {
+                open_file = File::open(download_dir.join(path))
+                    .ok()
+                    .map(|file| (segment.file_index, file));
+            }
+
+            let read = match &mut open_file {
+                Some((_, file)) => file
+                    .seek(SeekFrom::Start(segment.offset))
+                    .and_then(|_| file.read_exact(&mut buffer[position..end]))
+                    .is_ok(),
+                None => false,
+            };
+            if !read {
+                complete = false;
+                break;
+            }
+            position = end;
+        }
+
+        if let Some(throttle) = &mut throttle {
+            throttle.consume(position as u64);
+        }
+
+        verified.push(complete && Sha1::digest(&buffer)[..] == piece.hash[..]);
+    }
+
+    Ok(verified)
+}
+
+/// Checks every completed file under `download_dir` against the per-file
+/// `md5sum` and `sha1` fields, returning each file's index, path and result.
+/// Padding files are skipped.
+pub fn verify_files(
+    torrent: &Torrent,
+    download_dir: &Path,
+    root_folder: RootFolder,
+) -> Result<Vec<(usize, PathBuf, FileCheck)>> {
+    let paths = torrent.sanitized_paths(SanitizeMode::Reject, root_folder)?;
+    let mut results = Vec::new();
+
+    for (file_index, path) in paths.into_iter().enumerate() {
+        let Some(path) = path else {
+            continue;
+        };
+
+        let (md5sum, sha1) = match torrent.info.files.get(file_index) {
+            Some(file) => (file.md5sum.as_deref(), file.sha1.as_deref()),
+            None => (torrent.info.md5sum.as_deref(), torrent.info.sha1.as_deref()),
+        };
+
+        let check = if md5sum.is_none() && sha1.is_none() {
+            FileCheck::NoChecksum
+        } else {
+            match hash_file(&download_dir.join(&path)) {
+                Ok((actual_md5, actual_sha1)) => {
+                    let md5_matches = md5sum
+                        .is_none_or(|expected| expected.eq_ignore_ascii_case(&hex(&actual_md5)));
+                    let sha1_matches = sha1.is_none_or(|expected| expected[..] == actual_sha1[..]);
+                    if md5_matches && sha1_matches {
+                        FileCheck::Match
+                    } else {
+                        FileCheck::Mismatch
+                    }
+                }
+                Err(_) => FileCheck::Missing,
+            }
+        };
+
+        results.push((file_index, path, check));
+    }
+
+    Ok(results)
+}
+
+fn hash_file(path: &Path) -> io::Result<([u8; 16], [u8; 20])> {
+    let mut file = File::open(path)?;
+    let mut md5 = Md5::new();
+    let mut sha1 = Sha1::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        md5.update(&buffer[..read]);
+        sha1.update(&buffer[..read]);
+    }
+
+    Ok((md5.finalize().into(), sha1.finalize().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::TorrentBuilder;
+    use std::fs;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "crab_torrent-verify-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("dir")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn fails_only_the_corrupted_piece() {
+        let dir = scratch("recheck");
+        fs::write(dir.join("dir/a"), vec![1; 20000]).unwrap();
+        fs::write(dir.join("dir/b"), vec![2; 30000]).unwrap();
+        let torrent = TorrentBuilder::new(dir.join("dir"))
+            .piece_length(16384)
+            .announce("http://t.invalid/announce")
+            .build()
+            .unwrap();
+        let torrent = Torrent::new(torrent).unwrap();
+        let recheck = || recheck(&torrent, &dir, RootFolder::default(), None).unwrap();
+        assert_eq!(recheck(), [true; 4]);
+
+        // Byte 5000 of `b` lies in piece 1, which spans both files.
+        let mut b = fs::read(dir.join("dir/b")).unwrap();
+        b[5000] ^= 0xff;
+        fs::write(dir.join("dir/b"), b).unwrap();
+        assert_eq!(recheck(), [true, false, true, true]);
+
+        // Cutting `b` at the end of piece 2 fails only the last piece.
+        let b = fs::OpenOptions::new().write(true).open(dir.join("dir/b"));
+        b.unwrap().set_len(29152).unwrap();
+        assert_eq!(recheck(), [true, false, true, false]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checks_files_against_their_md5sum_and_sha1() {
+        let dir = scratch("files");
+        for name in ["a", "b", "c"] {
+            fs::write(dir.join("dir").join(name), name).unwrap();
+        }
+        let md5 = |data: &[u8]| hex(&Md5::digest(data));
+        let mut metainfo = b"d8:announce16:http://t.invalid4:infod5:filesl".to_vec();
+        // `a` matches, `b` doesn't, `c` has no checksum and `d` is missing.
+        metainfo.extend(format!("d6:lengthi1e6:md5sum32:{}4:pathl1:aee", md5(b"a")).bytes());
+        metainfo.extend(b"d6:lengthi1e4:pathl1:be4:sha120:");
+        metainfo.extend(Sha1::digest(b"not b"));
+        metainfo.extend(b"ed6:lengthi1e4:pathl1:cee");
+        metainfo.extend(format!("d6:lengthi1e6:md5sum32:{}4:pathl1:dee", md5(b"d")).bytes());
+        metainfo.extend(b"e4:name3:dir12:piece lengthi16384e6:pieces20:");
+        metainfo.extend([0; 20]);
+        metainfo.extend(b"ee");
+        let torrent = Torrent::new(metainfo).unwrap();
+
+        let checks: Vec<FileCheck> = verify_files(&torrent, &dir, RootFolder::default())
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, check)| check)
+            .collect();
+        assert_eq!(
+            checks,
+            [
+                FileCheck::Match,
+                FileCheck::Mismatch,
+                FileCheck::NoChecksum,
+                FileCheck::Missing
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
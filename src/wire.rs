@@ -0,0 +1,196 @@
+use std::fmt;
+
+/// Largest message accepted from a peer: a 16 KiB block with its header,
+/// or the bitfield of a torrent with about a million pieces.
+pub const MAX_MESSAGE_LEN: usize = 128 * 1024;
+
+/// A peer wire message (BEP 3) after the handshake. On the wire each is
+/// prefixed with its length as a big-endian u32.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A zero-length message that keeps an idle connection open.
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The DHT port of the sender (BEP 5).
+    Port(u16),
+    /// An extension protocol message (BEP 10). Id 0 is the extension
+    /// handshake; the others are whatever ids the receiver asked for.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// A message of an extension this client doesn't know, kept whole so
+    /// it can be skipped.
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// The length prefix exceeds `MAX_MESSAGE_LEN`.
+    TooLong(usize),
+    /// The payload is the wrong size for its message id.
+    Malformed(u8),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::TooLong(len) => {
+                write!(f, "{}-byte message exceeds {}", len, MAX_MESSAGE_LEN)
+            }
+            WireError::Malformed(id) => write!(f, "malformed message with id {}", id),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+impl Message {
+    /// The message with its length prefix, ready to write.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (id, payload) = match self {
+            Message::KeepAlive => return vec![0; 4],
+            Message::Choke => (0, Vec::new()),
+            Message::Unchoke => (1, Vec::new()),
+            Message::Interested => (2, Vec::new()),
+            Message::NotInterested => (3, Vec::new()),
+            Message::Have(index) => (4, index.to_be_bytes().to_vec()),
+            Message::Bitfield(bytes) => (5, bytes.clone()),
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => (6, u32s(&[*index, *begin, *length])),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let mut payload = u32s(&[*index, *begin]);
+                payload.extend_from_slice(block);
+                (7, payload)
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => (8, u32s(&[*index, *begin, *length])),
+            Message::Port(port) => (9, port.to_be_bytes().to_vec()),
+            Message::Extended { id, payload } => {
+                let mut bytes = vec![*id];
+                bytes.extend_from_slice(payload);
+                (20, bytes)
+            }
+            Message::Unknown { id, payload } => (*id, payload.clone()),
+        };
+
+        let mut bytes = Vec::with_capacity(5 + payload.len());
+        bytes.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        bytes.push(id);
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    /// Parses a message body: everything after the length prefix.
+    pub fn parse(body: &[u8]) -> Result<Message, WireError> {
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+        let malformed = || WireError::Malformed(id);
+
+        Ok(match id {
+            0..=3 if !payload.is_empty() => return Err(malformed()),
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => match payload {
+                [a, b, c, d] => Message::Have(u32::from_be_bytes([*a, *b, *c, *d])),
+                _ => return Err(malformed()),
+            },
+            5 => Message::Bitfield(payload.to_vec()),
+            6 | 8 => {
+                if payload.len() != 12 {
+                    return Err(malformed());
+                }
+                let (index, begin, length) = (
+                    read_u32(payload, 0),
+                    read_u32(payload, 4),
+                    read_u32(payload, 8),
+                );
+                if id == 6 {
+                    Message::Request {
+                        index,
+                        begin,
+                        length,
+                    }
+                } else {
+                    Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    }
+                }
+            }
+            7 => {
+                if payload.len() < 8 {
+                    return Err(malformed());
+                }
+                Message::Piece {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            9 => match payload {
+                [a, b] => Message::Port(u16::from_be_bytes([*a, *b])),
+                _ => return Err(malformed()),
+            },
+            20 => match payload.split_first() {
+                Some((&id, payload)) => Message::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                },
+                None => return Err(malformed()),
+            },
+            _ => Message::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        })
+    }
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}